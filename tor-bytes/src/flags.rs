@@ -0,0 +1,183 @@
+//! A macro for decoding a byte of independent boolean flags.
+//!
+//! Several cell formats pack a handful of flags into a single byte,
+//! leaving the remaining bits reserved.  Rather than masking and
+//! shifting by hand in every parser, use [`read_flags!`](crate::read_flags)
+//! to declare a struct with one `bool` per flag.
+
+/// Declare a struct of boolean flags that are packed into one byte.
+///
+/// Each flag is given as `name = bit`, where `bit` is its position
+/// (0 is the least significant bit).  Every bit that isn't named is
+/// reserved.
+///
+/// The generated struct has a `bool` field for each flag, and these
+/// methods, all with the same visibility as the struct:
+///
+/// * `from_byte(u8) -> Result<Self>`: decode a byte, rejecting it with
+///   [`Error::BadMessage`](crate::Error::BadMessage) if any reserved
+///   bit is set.
+/// * `from_byte_lax(u8) -> Self`: decode a byte, ignoring reserved bits.
+/// * `to_byte(self) -> u8`: encode the flags.
+///
+/// Since `to_byte` takes `self` by value, the struct must derive `Copy`.
+///
+/// It also implements [`Readable`](crate::Readable) (using
+/// `from_byte`) and [`Writeable`](crate::Writeable).
+///
+/// # Example
+///
+/// ```
+/// use tor_bytes::{read_flags, Reader};
+///
+/// read_flags! {
+///     /// Flags for some hypothetical cell.
+///     #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+///     pub struct ExampleFlags {
+///         /// The sender wants a reply.
+///         want_reply = 0,
+///         /// The sender is in a hurry.
+///         urgent = 2,
+///     }
+/// }
+///
+/// let f = ExampleFlags::from_byte(0x05)?;
+/// assert!(f.want_reply && f.urgent);
+/// assert_eq!(f.to_byte(), 0x05);
+///
+/// // Bit 1 is reserved.
+/// assert!(ExampleFlags::from_byte(0x02).is_err());
+/// assert_eq!(ExampleFlags::from_byte_lax(0x03), ExampleFlags { want_reply: true, urgent: false });
+///
+/// let mut r = Reader::from_slice(&[0x04]);
+/// let f: ExampleFlags = r.extract()?;
+/// assert!(f.urgent);
+/// # tor_bytes::Result::Ok(())
+/// ```
+#[macro_export]
+macro_rules! read_flags {
+    {
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$fmeta:meta])*
+                $field:ident = $bit:literal
+            ),* $(,)?
+        }
+    } => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$fmeta])*
+                $vis $field: bool,
+            )*
+        }
+
+        impl $name {
+            /// Decode flags from `b`, rejecting it if any reserved bit is
+            /// set.
+            #[allow(dead_code)]
+            $vis fn from_byte(b: u8) -> $crate::Result<Self> {
+                let known = Self::from_byte_lax(0xff).to_byte();
+                if b & !known != 0 {
+                    return Err($crate::Error::BadMessage("reserved flag bits were set"));
+                }
+                Ok(Self::from_byte_lax(b))
+            }
+
+            /// Decode flags from `b`, ignoring any reserved bits.
+            #[allow(dead_code)]
+            $vis fn from_byte_lax(b: u8) -> Self {
+                $name {
+                    $(
+                        $field: b & (1_u8 << $bit) != 0,
+                    )*
+                }
+            }
+
+            /// Encode these flags as a byte.
+            #[allow(dead_code)]
+            $vis fn to_byte(self) -> u8 {
+                let mut b = 0_u8;
+                $(
+                    if self.$field {
+                        b |= 1_u8 << $bit;
+                    }
+                )*
+                b
+            }
+        }
+
+        impl $crate::Readable for $name {
+            fn take_from(r: &mut $crate::Reader<'_>) -> $crate::Result<Self> {
+                Self::from_byte(r.take_u8()?)
+            }
+        }
+
+        impl $crate::Writeable for $name {
+            fn write_onto<B: $crate::Writer + ?Sized>(&self, b: &mut B) {
+                b.write_u8(self.to_byte())
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Reader, Writer};
+
+    read_flags! {
+        /// Flags used for testing.
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+        struct TestFlags {
+            /// Bit 0.
+            low = 0,
+            /// Bit 3.
+            middle = 3,
+            /// Bit 7.
+            high = 7,
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let f = TestFlags::from_byte(0x89).unwrap();
+        assert_eq!(
+            f,
+            TestFlags {
+                low: true,
+                middle: true,
+                high: true
+            }
+        );
+        assert_eq!(f.to_byte(), 0x89);
+
+        let f = TestFlags::from_byte(0x08).unwrap();
+        assert!(!f.low && f.middle && !f.high);
+        assert_eq!(TestFlags::from_byte(0).unwrap(), TestFlags::default());
+    }
+
+    #[test]
+    fn reserved() {
+        for bit in &[1_u8, 2, 4, 5, 6] {
+            let b = 0x01 | (1 << bit);
+            let e = TestFlags::from_byte(b);
+            assert!(matches!(e, Err(Error::BadMessage(_))));
+            // In lax mode we just ignore the reserved bit.
+            let f = TestFlags::from_byte_lax(b);
+            assert_eq!(f.to_byte(), 0x01);
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        let mut r = Reader::from_slice(&[0x80, 0x02]);
+        let f: TestFlags = r.extract().unwrap();
+        assert!(f.high && !f.low);
+        assert!(r.extract::<TestFlags>().is_err());
+
+        let mut v: Vec<u8> = Vec::new();
+        v.write(&f);
+        assert_eq!(&v[..], &[0x80]);
+    }
+}
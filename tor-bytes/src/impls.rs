@@ -33,16 +33,22 @@ impl Writer for bytes::BytesMut {
 
 // ----------------------------------------------------------------------
 
-impl<'a> Writeable for [u8] {
+impl Writeable for [u8] {
     fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
         b.write_all(self)
     }
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
 }
 
 impl Writeable for Vec<u8> {
     fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
         b.write_all(&self[..])
     }
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
 }
 
 /* There is no specialization in Rust yet, or we would make an implementation
@@ -91,9 +97,10 @@ where
     N: generic_array::ArrayLength<T>,
 {
     fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
-        for item in self {
-            item.write_onto(b)
-        }
+        b.write_all_items(&self[..])
+    }
+    fn encoded_len(&self) -> usize {
+        self.iter().map(Writeable::encoded_len).sum()
     }
 }
 
@@ -104,6 +111,9 @@ macro_rules! impl_u {
             fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
                 b.$wrfn(*self)
             }
+            fn encoded_len(&self) -> usize {
+                std::mem::size_of::<$t>()
+            }
         }
         impl Readable for $t {
             fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -119,19 +129,92 @@ impl_u!(u32, write_u32, take_u32);
 impl_u!(u64, write_u64, take_u64);
 impl_u!(u128, write_u128, take_u128);
 
+/// Implementations for reading and writing the signed types: each one is
+/// encoded as the unsigned type of the same width, in two's complement.
+macro_rules! impl_i {
+    ( $t:ty, $ut:ty ) => {
+        impl Writeable for $t {
+            fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+                (*self as $ut).write_onto(b)
+            }
+            fn encoded_len(&self) -> usize {
+                std::mem::size_of::<$t>()
+            }
+        }
+        impl Readable for $t {
+            fn take_from(b: &mut Reader<'_>) -> Result<Self> {
+                Ok(<$ut>::take_from(b)? as $t)
+            }
+        }
+    };
+}
+
+impl_i!(i8, u8);
+impl_i!(i16, u16);
+impl_i!(i32, u32);
+impl_i!(i64, u64);
+
+/// A bool encodes as a single byte: 1 for true, and 0 for false.
+///
+/// When reading, any other byte is an error.
+impl Writeable for bool {
+    fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+        b.write_u8(u8::from(*self))
+    }
+    fn encoded_len(&self) -> usize {
+        1
+    }
+}
+impl Readable for bool {
+    fn take_from(b: &mut Reader<'_>) -> Result<Self> {
+        match b.take_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::BadMessage("invalid boolean")),
+        }
+    }
+}
+
+/// The unit type encodes as nothing at all.
+///
+/// This lets generic code that reads or writes a `T` handle the case
+/// where there's no data to encode, like a `()` tag.
+impl Writeable for () {
+    fn write_onto<B: Writer + ?Sized>(&self, _b: &mut B) {}
+    fn encoded_len(&self) -> usize {
+        0
+    }
+}
+impl Readable for () {
+    fn take_from(_b: &mut Reader<'_>) -> Result<Self> {
+        Ok(())
+    }
+}
+
 // ----------------------------------------------------------------------
 
-/// Implement Readable and Writerable for IPv4 and IPv6 addresses.
+/// Implement Readable and Writerable for IPv4 and IPv6 addresses, and
+/// for socket addresses.
+///
+/// Addresses are encoded as a sequence of octets, not as strings.  A
+/// socket address is its address followed by its port as a big-endian
+/// u16, as in the body of an ORPort link specifier.
 ///
-/// These are encoded as a sequence of octets, not as strings.
+/// There is no address-type byte: the caller has to know which kind of
+/// address to expect.  Formats that do say (like the type and length
+/// bytes of a RESOLVED answer) disagree about how, so their parsers
+/// read and write that part themselves.
 mod net_impls {
     use super::*;
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
     impl Writeable for Ipv4Addr {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(&self.octets()[..])
         }
+        fn encoded_len(&self) -> usize {
+            4
+        }
     }
 
     impl Readable for Ipv4Addr {
@@ -144,12 +227,49 @@ mod net_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(&self.octets()[..])
         }
+        fn encoded_len(&self) -> usize {
+            16
+        }
     }
     impl Readable for Ipv6Addr {
         fn take_from(r: &mut Reader<'_>) -> Result<Self> {
             Ok(r.take_u128()?.into())
         }
     }
+
+    impl Writeable for SocketAddrV4 {
+        fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+            b.write(self.ip());
+            b.write_u16(self.port());
+        }
+        fn encoded_len(&self) -> usize {
+            self.ip().encoded_len() + 2
+        }
+    }
+    impl Readable for SocketAddrV4 {
+        fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+            let ip = r.extract()?;
+            Ok(SocketAddrV4::new(ip, r.take_u16()?))
+        }
+    }
+
+    impl Writeable for SocketAddrV6 {
+        fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+            b.write(self.ip());
+            b.write_u16(self.port());
+        }
+        fn encoded_len(&self) -> usize {
+            self.ip().encoded_len() + 2
+        }
+    }
+    /// The flow info and scope ID aren't encoded, so they're always zero
+    /// when we decode.
+    impl Readable for SocketAddrV6 {
+        fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+            let ip = r.extract()?;
+            Ok(SocketAddrV6::new(ip, r.take_u16()?, 0, 0))
+        }
+    }
 }
 
 /// Implement Readable and Writeable for Ed25519 types.
@@ -162,6 +282,9 @@ mod ed25519_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(self.as_bytes())
         }
+        fn encoded_len(&self) -> usize {
+            32
+        }
     }
     impl Readable for ed25519::PublicKey {
         fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -175,6 +298,9 @@ mod ed25519_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(self.as_bytes())
         }
+        fn encoded_len(&self) -> usize {
+            32
+        }
     }
     impl Readable for ed25519::Ed25519Identity {
         fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -186,6 +312,9 @@ mod ed25519_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(&self.to_bytes()[..])
         }
+        fn encoded_len(&self) -> usize {
+            64
+        }
     }
     impl Readable for ed25519::Signature {
         fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -205,6 +334,9 @@ mod curve25519_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(self.as_bytes())
         }
+        fn encoded_len(&self) -> usize {
+            32
+        }
     }
     impl Readable for PublicKey {
         fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -216,6 +348,9 @@ mod curve25519_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(self.as_bytes())
         }
+        fn encoded_len(&self) -> usize {
+            32
+        }
     }
 }
 
@@ -228,6 +363,9 @@ mod rsa_impls {
         fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
             b.write_all(self.as_bytes())
         }
+        fn encoded_len(&self) -> usize {
+            RSA_ID_LEN
+        }
     }
     impl Readable for RsaIdentity {
         fn take_from(b: &mut Reader<'_>) -> Result<Self> {
@@ -255,33 +393,30 @@ mod mac_impls {
     }
 }
 
-/// Implement readable and writeable for common sizes of u8 arrays.
+/// Implement readable and writeable for u8 arrays of any length.
 mod u8_array_impls {
     use super::*;
-    macro_rules! impl_array {
-        ($n:literal) => {
-            impl Writeable for [u8; $n] {
-                fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
-                    b.write_all(&self[..])
-                }
-            }
-            impl Readable for [u8; $n] {
-                fn take_from(r: &mut Reader<'_>) -> Result<Self> {
-                    let bytes = r.take($n)?;
-                    Ok(array_ref!(bytes, 0, $n).clone())
-                }
-            }
-        };
+    impl<const N: usize> Writeable for [u8; N] {
+        fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+            b.write_all(&self[..])
+        }
+        fn encoded_len(&self) -> usize {
+            N
+        }
+    }
+    impl<const N: usize> Readable for [u8; N] {
+        fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+            let bytes = r.take(N)?;
+            let mut array = [0_u8; N];
+            array.copy_from_slice(bytes);
+            Ok(array)
+        }
     }
-    // These are the lengths we know we need right now.
-    impl_array! {16}
-    impl_array! {20}
-    impl_array! {32}
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Reader, Writer};
+    use crate::{assert_roundtrip, Reader, Writer};
     use hex_literal::hex;
     macro_rules! check_encode {
         ($e:expr, $e2:expr) => {
@@ -290,20 +425,6 @@ mod tests {
             assert_eq!(&w[..], &$e2[..]);
         };
     }
-    macro_rules! check_decode {
-        ($t:ty, $e:expr, $e2:expr) => {
-            let mut r = Reader::from_slice(&$e[..]);
-            let obj: $t = r.extract().unwrap();
-            assert_eq!(obj, $e2);
-            assert!(r.should_be_exhausted().is_ok());
-        };
-    }
-    macro_rules! check_roundtrip {
-        ($t:ty, $e:expr, $e2:expr) => {
-            check_encode!($e, $e2);
-            check_decode!($t, $e2, $e);
-        };
-    }
     macro_rules! check_bad {
         ($t:ty, $e:expr) => {
             let mut r = Reader::from_slice(&$e[..]);
@@ -319,45 +440,145 @@ mod tests {
         check_encode!(v, b"\x01\x02\x03\x04");
     }
 
+    #[test]
+    fn slices() {
+        let v: &[u8] = b"abc";
+        check_encode!(v[..], b"abc");
+
+        let mut w: Vec<u8> = Vec::new();
+        w.write_all_items(&[0x0102_0304_u32, 0x0a0b_0c0d, 7]);
+        assert_eq!(&w[..], b"\x01\x02\x03\x04\x0a\x0b\x0c\x0d\x00\x00\x00\x07");
+        let mut w: Vec<u8> = Vec::new();
+        w.write_all_items::<u16>(&[]);
+        assert!(w.is_empty());
+
+        let mut w: Vec<u8> = Vec::new();
+        w.write_all_items(&[5_u16, 6]);
+        w.write_all_items(&[[1_u8, 2], [3, 4]]);
+        assert_eq!(&w[..], &[0, 5, 0, 6, 1, 2, 3, 4]);
+    }
+
     #[test]
     fn genarray() {
         use generic_array as ga;
         let a: ga::GenericArray<u16, ga::typenum::U7> = [4, 5, 6, 7, 8, 9, 10].into();
-        check_roundtrip!(ga::GenericArray<u16, ga::typenum::U7>,
-                         a,
-                         [0, 4, 0, 5, 0, 6, 0, 7, 0, 8, 0, 9, 0, 10]);
+        assert_roundtrip!(a, [0, 4, 0, 5, 0, 6, 0, 7, 0, 8, 0, 9, 0, 10]);
     }
 
     #[test]
     fn roundtrip_u64() {
-        check_roundtrip!(u64, 0x4040111u64, [0, 0, 0, 0, 4, 4, 1, 17]);
+        assert_roundtrip!(0x4040111u64, [0, 0, 0, 0, 4, 4, 1, 17]);
     }
 
     #[test]
-    fn u8_array() {
-        check_roundtrip!(
-            [u8; 16],
-            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
-            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]
+    fn signed() {
+        assert_roundtrip!(-1_i8, [0xff]);
+        assert_roundtrip!(0x7f_i8, [0x7f]);
+        assert_roundtrip!(-2_i16, [0xff, 0xfe]);
+        assert_roundtrip!(i16::MIN, [0x80, 0x00]);
+        check_bad!(i16, [0xff]);
+    }
+
+    #[test]
+    fn signed_wide() {
+        assert_roundtrip!(-0x1234_5678_i32, hex!("edcba988"));
+        assert_roundtrip!(0x1234_5678_i32, hex!("12345678"));
+        assert_roundtrip!(i64::MIN, hex!("8000000000000000"));
+        assert_roundtrip!(-3_i64, hex!("fffffffffffffffd"));
+        check_bad!(i32, [0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn bool() {
+        assert_roundtrip!(true, [1]);
+        assert_roundtrip!(false, [0]);
+
+        let mut r = Reader::from_slice(&[2_u8][..]);
+        assert_eq!(
+            r.extract::<bool>(),
+            Err(crate::Error::BadMessage("invalid boolean"))
         );
+        assert_eq!(r.remaining(), 1);
+        check_bad!(bool, [0xff]);
+        check_bad!(bool, []);
+    }
+
+    #[test]
+    fn unit() {
+        check_encode!((), []);
+
+        // Reading a () consumes nothing, even from a non-empty reader.
+        let mut r = Reader::from_slice(&[7_u8][..]);
+        assert!(r.extract::<()>().is_ok());
+        assert_eq!(r.remaining(), 1);
+
+        // Generic code can treat () like any other field.
+        fn read_pair<A: crate::Readable, B: crate::Readable>(r: &mut Reader<'_>) -> (A, B) {
+            (r.extract().unwrap(), r.extract().unwrap())
+        }
+        fn write_pair<A: crate::Writeable, B: crate::Writeable>(w: &mut Vec<u8>, pair: &(A, B)) {
+            w.write(&pair.0);
+            w.write(&pair.1);
+        }
+        let mut w = Vec::new();
+        write_pair(&mut w, &((), 0x1234_u16));
+        write_pair(&mut w, &(9_u8, ()));
+        assert_eq!(&w[..], &[0x12, 0x34, 9][..]);
+
+        let mut r = Reader::from_slice(&w[..]);
+        let a: ((), u16) = read_pair(&mut r);
+        let b: (u8, ()) = read_pair(&mut r);
+        assert_eq!(a, ((), 0x1234));
+        assert_eq!(b, (9, ()));
+        assert!(r.should_be_exhausted().is_ok());
+    }
+
+    #[test]
+    fn u8_array() {
+        assert_roundtrip!([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16_u8]);
+        assert_roundtrip!([0x55_u8; 20], [0x55; 20]);
+        assert_roundtrip!([0_u8; 32]);
+        check_bad!([u8; 32], [0_u8; 31]);
+    }
+
+    #[test]
+    fn u8_array_other_lengths() {
+        assert_roundtrip!([0xa5_u8; 24], [0xa5; 24]);
+        assert_roundtrip!([7_u8; 1]);
+        assert_roundtrip!([0_u8; 0], []);
+        check_bad!([u8; 24], [0_u8; 23]);
     }
 
     #[test]
     fn ipv4addr() {
         use std::net::Ipv4Addr;
-        check_roundtrip!(Ipv4Addr, Ipv4Addr::new(192, 168, 0, 1), [192, 168, 0, 1]);
+        assert_roundtrip!(Ipv4Addr::new(192, 168, 0, 1), [192, 168, 0, 1]);
     }
 
     #[test]
     fn ipv6addr() {
         use std::net::Ipv6Addr;
-        check_roundtrip!(
-            Ipv6Addr,
+        assert_roundtrip!(
             Ipv6Addr::new(65535, 77, 1, 1, 1, 0, 0, 0),
             [255, 255, 0, 77, 0, 1, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]
         );
     }
 
+    #[test]
+    fn socketaddr() {
+        use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+        assert_roundtrip!(
+            SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 9001),
+            [192, 168, 0, 1, 0x23, 0x29]
+        );
+        assert_roundtrip!(
+            SocketAddrV6::new(Ipv6Addr::new(65535, 77, 1, 1, 1, 0, 0, 0), 443, 0, 0),
+            hex!("ffff004d000100010001000000000000 01bb")
+        );
+        check_bad!(SocketAddrV4, [192, 168, 0, 1, 0x23]);
+        check_bad!(SocketAddrV6, [0_u8; 16]);
+    }
+
     #[test]
     fn ed25519() {
         use signature::Signature;
@@ -366,11 +587,8 @@ mod tests {
             "68a6cee11d2883661f5876f7aac748992cd140f
              cfc36923aa957d04b5f8967ff"
         );
-        check_roundtrip!(
-            ed25519::PublicKey,
-            ed25519::PublicKey::from_bytes(b).unwrap(),
-            b
-        );
+        assert_roundtrip!(ed25519::PublicKey::from_bytes(b).unwrap(), b);
+        assert_roundtrip!(ed25519::Ed25519Identity::new(*b), b);
         let b = &hex!(
             "68a6cee11d2883661f5876f7aac748992cd140f
              cfc36923aa957d04b5f8967"
@@ -387,11 +605,7 @@ mod tests {
              c804f76a8fa858b9ab43622b9e8335993c422eab15
              6ebb5a047033f35256333a47a508b02699314d22550e"
         );
-        check_roundtrip!(
-            ed25519::Signature,
-            ed25519::Signature::from_bytes(sig).unwrap(),
-            sig
-        );
+        assert_roundtrip!(ed25519::Signature::from_bytes(sig).unwrap(), sig);
         let sig = &hex!(
             "b8842c083a56076fc27c8af21211f9fe57d1c32d9d
              c804f76a8fa858b9ab43622b9e8335993c422eab15
@@ -405,13 +619,36 @@ mod tests {
         use tor_llcrypto::pk::curve25519;
         let b = &hex!("5f6df7a2fe3bcf1c9323e9755250efd79b9db4ed8f3fd21c7515398b6662a365");
         let pk: curve25519::PublicKey = (*b).into();
-        check_roundtrip!(curve25519::PublicKey, pk, b);
+        assert_roundtrip!(pk, b);
     }
 
     #[test]
     fn rsa_id() {
         use tor_llcrypto::pk::rsa::RsaIdentity;
         let b = &hex!("9432D4CEA2621ED09F5A8088BE0E31E0D271435C");
-        check_roundtrip!(RsaIdentity, RsaIdentity::from_bytes(b).unwrap(), b);
+        assert_roundtrip!(RsaIdentity::from_bytes(b).unwrap(), b);
+    }
+
+    #[test]
+    #[should_panic(expected = "encoded_len() disagrees")]
+    fn roundtrip_checks_encoded_len() {
+        use crate::{Readable, Result, Writeable};
+        /// A byte that claims to take up two bytes.
+        #[derive(Debug, PartialEq)]
+        struct Miscounted(u8);
+        impl Writeable for Miscounted {
+            fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+                b.write_u8(self.0)
+            }
+            fn encoded_len(&self) -> usize {
+                2
+            }
+        }
+        impl Readable for Miscounted {
+            fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+                Ok(Miscounted(r.take_u8()?))
+            }
+        }
+        assert_roundtrip!(Miscounted(7));
     }
 }
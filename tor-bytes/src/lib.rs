@@ -36,8 +36,11 @@
 //! This crate is structured around four key types:
 //!
 //! * [`Reader`]: A view of a byte slice, from which data can be decoded.
+//!   ([`OwnedReader`] is a variant that owns its bytes, and
+//!   [`TracingReader`] is a wrapper that can log each field it reads.)
 //! * [`Writer`]: Trait to represent a growable buffer of bytes.
-//!   (Vec<u8> and [`bytes::BytesMut`] implement this.)
+//!   (Vec<u8> and [`bytes::BytesMut`] implement this, and
+//!   [`DigestWriter`] wraps another Writer to digest what it writes.)
 //! * [`Writeable`]: Trait for an object that can be encoded onto a [`Writer`]
 //! * [`Readable`]: Trait for an object that can be decoded from a [`Reader`].
 //!
@@ -71,13 +74,22 @@
 #![warn(clippy::unseparated_literal_suffix)]
 
 mod err;
+mod flags;
 mod impls;
+mod optional;
 mod reader;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
+mod tracereader;
+mod versioned;
 mod writer;
 
 pub use err::Error;
-pub use reader::Reader;
-pub use writer::Writer;
+pub use optional::Flagged;
+pub use reader::{decode_all, OwnedReader, Reader};
+pub use tracereader::TracingReader;
+pub use versioned::{Versioned, VersionedCodec};
+pub use writer::{DigestWriter, Writer};
 
 use arrayref::array_ref;
 
@@ -117,6 +129,17 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub trait Writeable {
     /// Encode this object into the writer `b`.
     fn write_onto<B: Writer + ?Sized>(&self, b: &mut B);
+
+    /// Return the number of bytes that [`Writeable::write_onto`] will
+    /// write for this object.
+    ///
+    /// By default, this encodes the object to find out.  Types whose
+    /// length is fixed, or easy to compute, should override it.
+    fn encoded_len(&self) -> usize {
+        let mut v: Vec<u8> = Vec::new();
+        self.write_onto(&mut v);
+        v.len()
+    }
 }
 
 /// Trait for an object that can be encoded and consumed by a Writer.
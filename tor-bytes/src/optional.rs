@@ -0,0 +1,122 @@
+//! A wrapper for an optional field behind a presence byte.
+
+use crate::{Error, Readable, Reader, Result, Writeable, Writer};
+
+/// An optional value, encoded as a presence byte followed by the value.
+///
+/// `Some(x)` is encoded as the byte 1 followed by the encoding of `x`;
+/// `None` is encoded as the byte 0.  Any other presence byte is an
+/// error when decoding.
+///
+/// Using this type for both directions keeps the encoder and the decoder
+/// from disagreeing about the format.
+///
+/// # Example
+///
+/// ```
+/// use tor_bytes::{Flagged, Reader, Writer};
+///
+/// let mut v: Vec<u8> = Vec::new();
+/// v.write(&Flagged::new(Some(7_u16)));
+/// v.write(&Flagged::<u16>::new(None));
+/// assert_eq!(&v[..], &[1, 0, 7, 0]);
+///
+/// let mut r = Reader::from_slice(&v[..]);
+/// let a: Flagged<u16> = r.extract()?;
+/// let b: Flagged<u16> = r.extract()?;
+/// assert_eq!(a.into_inner(), Some(7));
+/// assert_eq!(b.into_inner(), None);
+/// # tor_bytes::Result::Ok(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flagged<T> {
+    /// The value, if it is present.
+    inner: Option<T>,
+}
+
+impl<T> Flagged<T> {
+    /// Wrap `inner` so that it will be encoded with a presence byte.
+    pub fn new(inner: Option<T>) -> Self {
+        Flagged { inner }
+    }
+
+    /// Return a reference to the value, if it is present.
+    pub fn get(&self) -> Option<&T> {
+        self.inner.as_ref()
+    }
+
+    /// Return the value, if it is present.
+    pub fn into_inner(self) -> Option<T> {
+        self.inner
+    }
+}
+
+impl<T> From<Option<T>> for Flagged<T> {
+    fn from(inner: Option<T>) -> Self {
+        Flagged::new(inner)
+    }
+}
+
+impl<T: Writeable> Writeable for Flagged<T> {
+    fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+        match &self.inner {
+            Some(x) => {
+                b.write_u8(1);
+                b.write(x);
+            }
+            None => b.write_u8(0),
+        }
+    }
+}
+
+impl<T: Readable> Readable for Flagged<T> {
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        match r.take_u8()? {
+            0 => Ok(Flagged::new(None)),
+            1 => Ok(Flagged::new(Some(r.extract()?))),
+            _ => Err(Error::BadMessage(
+                "invalid presence byte for optional field",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let vals = [
+            Flagged::new(Some(0x0102_0304_u32)),
+            Flagged::new(None),
+            Flagged::new(Some(0)),
+        ];
+        let mut v: Vec<u8> = Vec::new();
+        for f in &vals {
+            v.write(f);
+        }
+        assert_eq!(&v[..], &[1, 1, 2, 3, 4, 0, 1, 0, 0, 0, 0]);
+
+        let mut r = Reader::from_slice(&v[..]);
+        for f in &vals {
+            let got: Flagged<u32> = r.extract().unwrap();
+            assert_eq!(&got, f);
+        }
+        assert!(r.should_be_exhausted().is_ok());
+    }
+
+    #[test]
+    fn bad() {
+        // Unknown presence byte.
+        let mut r = Reader::from_slice(&[2, 0, 0, 0, 0]);
+        assert!(matches!(
+            r.extract::<Flagged<u32>>(),
+            Err(Error::BadMessage(_))
+        ));
+
+        // Present, but truncated.
+        let mut r = Reader::from_slice(&[1, 0, 0]);
+        assert!(matches!(r.extract::<Flagged<u32>>(), Err(Error::Truncated)));
+    }
+}
@@ -2,6 +2,8 @@
 
 use crate::{Error, Readable, Result};
 use arrayref::array_ref;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv6Addr};
 
 /// A type for reading messages from a slice of bytes.
 ///
@@ -105,6 +107,38 @@ impl<'a> Reader<'a> {
         }
         Ok(())
     }
+    /// Consume and discard all the remaining bytes in this reader,
+    /// treating them as padding.
+    ///
+    /// Use this instead of [`Reader::should_be_exhausted`] when the
+    /// object is padded out to a fixed size (as relay cells are) and
+    /// the padding, whatever its contents, must be ignored.  Returns
+    /// the number of bytes discarded.
+    pub fn discard_padding(&mut self) -> usize {
+        let n = self.remaining();
+        self.off += n;
+        n
+    }
+    /// Consume and return the first `payload_len` bytes of this reader,
+    /// and discard everything after them as padding.
+    ///
+    /// Returns Err(Error::Truncated), and consumes nothing, if there
+    /// are fewer than `payload_len` bytes.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let m = b"hello\x9c\x21\x00\xfe";
+    /// let mut r = Reader::from_slice(m);
+    /// assert_eq!(r.take_payload_ignoring_padding(5)?, b"hello");
+    /// r.should_be_exhausted()?;
+    /// # Result::Ok(())
+    /// ```
+    pub fn take_payload_ignoring_padding(&mut self, payload_len: usize) -> Result<&'a [u8]> {
+        let payload = self.take(payload_len)?;
+        self.discard_padding();
+        Ok(payload)
+    }
     /// Truncate this reader, so that no more than `n` bytes remain.
     ///
     /// Fewer than `n` bytes may remain if there were not enough bytes
@@ -176,6 +210,46 @@ impl<'a> Reader<'a> {
         let r = u128::from_be_bytes(*array_ref![b, 0, 16]);
         Ok(r)
     }
+    /// Try to consume and return an IP address of `len` bytes from this
+    /// reader.
+    ///
+    /// A 4-byte address is an IPv4 address, and a 16-byte address is an
+    /// IPv6 address.  (Tor encodings usually tell us the length of an
+    /// address, or give a type tag from which we can infer it.)  Returns
+    /// Err(Error::BadMessage) for any other length.
+    ///
+    /// If `unmap_v4` is true, an IPv4-mapped IPv6 address (one in
+    /// `::ffff:0:0/96`, like `::ffff:192.0.2.1`) is returned as the IPv4
+    /// address that it maps.  We don't unmap any other kind of IPv6
+    /// address: in particular, the deprecated "IPv4-compatible" addresses
+    /// in `::/96` stay IPv6.
+    ///
+    /// On failure, consumes nothing.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let msg = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1];
+    /// let mut r = Reader::from_slice(&msg[..]);
+    /// assert_eq!(r.take_ip_addr(16, true)?, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    /// # Result::Ok(())
+    /// ```
+    pub fn take_ip_addr(&mut self, len: usize, unmap_v4: bool) -> Result<IpAddr> {
+        match len {
+            4 => Ok(IpAddr::V4(self.extract()?)),
+            16 => {
+                let addr: Ipv6Addr = self.extract()?;
+                match addr.segments() {
+                    [0, 0, 0, 0, 0, 0xffff, hi, lo] if unmap_v4 => {
+                        Ok(IpAddr::V4(((u32::from(hi) << 16) | u32::from(lo)).into()))
+                    }
+                    _ => Ok(IpAddr::V6(addr)),
+                }
+            }
+            _ => Err(Error::BadMessage("Unrecognized IP address length")),
+        }
+    }
     /// Try to consume and return bytes from this buffer until we
     /// encounter a terminating byte equal to `term`.
     ///
@@ -204,6 +278,44 @@ impl<'a> Reader<'a> {
         self.advance(1)?;
         Ok(result)
     }
+    /// Consume bytes from this reader through the end of the first line
+    /// that begins with `delim`, and return the range of offsets (from
+    /// the start of this reader's input) of the bytes that we consumed.
+    ///
+    /// This is meant for finding the part of a directory document that
+    /// its signatures cover: the bytes from the start of the document
+    /// through the end of the keyword on its first signature line (like
+    /// `b"directory-signature "`).  The returned range can be used to
+    /// slice the original document.
+    ///
+    /// A line begins with `delim` if `delim` appears at the start of
+    /// the input, or immediately after a newline.  Returns
+    /// Err(Error::Truncated) if no such line follows our current
+    /// position, and Err(Error::Internal) if `delim` is empty.  On
+    /// failure, consumes nothing.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let doc = b"hello world\nsig-follows xyz\n";
+    /// let mut r = Reader::from_slice(doc);
+    /// let covered = r.take_signed_region(b"sig-follows ")?;
+    /// assert_eq!(&doc[covered], b"hello world\nsig-follows ");
+    /// assert_eq!(r.into_rest(), b"xyz\n");
+    /// # Result::Ok(())
+    /// ```
+    pub fn take_signed_region(&mut self, delim: &[u8]) -> Result<std::ops::Range<usize>> {
+        if delim.is_empty() {
+            return Err(Error::Internal);
+        }
+        let at_line_start = |pos: usize| pos == 0 || self.b[pos - 1] == b'\n';
+        let pos = (self.off..self.b.len())
+            .find(|&pos| self.b[pos..].starts_with(delim) && at_line_start(pos))
+            .ok_or(Error::Truncated)?;
+        let range = self.off..(pos + delim.len());
+        self.off = range.end;
+        Ok(range)
+    }
     /// Try to decode and remove a Readable from this reader, using its
     /// take_from() method.
     ///
@@ -237,6 +349,295 @@ impl<'a> Reader<'a> {
         }
         Ok(result)
     }
+
+    /// Try to consume all the remaining bytes from this reader as a
+    /// list of `SZ`-byte records.
+    ///
+    /// On success, returns Ok(records).  If the remaining bytes can't be
+    /// divided evenly into records, returns Err(Error::Truncated) and
+    /// consumes nothing.  (It is an internal error to call this with
+    /// `SZ` equal to zero.)
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let m = b"abcdef";
+    /// let mut r = Reader::from_slice(m);
+    /// let records: Vec<[u8; 3]> = r.take_fixed_records()?;
+    /// assert_eq!(records, vec![*b"abc", *b"def"]);
+    /// r.should_be_exhausted()?;
+    /// # Result::Ok(())
+    /// ```
+    pub fn take_fixed_records<const SZ: usize>(&mut self) -> Result<Vec<[u8; SZ]>> {
+        if SZ == 0 {
+            return Err(Error::Internal);
+        }
+        if self.remaining() % SZ != 0 {
+            return Err(Error::Truncated);
+        }
+        let b = self.take(self.remaining())?;
+        Ok(b.chunks_exact(SZ)
+            .map(|chunk| {
+                let mut record = [0_u8; SZ];
+                record.copy_from_slice(chunk);
+                record
+            })
+            .collect())
+    }
+
+    /// Read a length-prefixed nested object, and parse it with `parse`.
+    ///
+    /// The length is a big-endian integer `len_bytes` bytes long; it
+    /// must be 1, 2, 4, or 8, or we return Err(Error::Internal).  We
+    /// call `parse` on a new Reader holding exactly that many bytes,
+    /// and require it to consume all of them: if it leaves any behind,
+    /// we return Err(Error::ExtraneousBytes).
+    ///
+    /// On failure, consumes nothing.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let m = b"\x00\x03\x01\x00\x07!";
+    /// let mut r = Reader::from_slice(m);
+    /// let (a, b) = r.take_nested(2, |r| Ok((r.take_u8()?, r.take_u16()?)))?;
+    /// assert_eq!((a, b), (1, 7));
+    /// assert_eq!(r.take_u8()?, b'!');
+    /// # Result::Ok(())
+    /// ```
+    pub fn take_nested<T, F>(&mut self, len_bytes: usize, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        let off_orig = self.off;
+        let result = self.take_nested_inner(len_bytes, parse);
+        if result.is_err() {
+            // We encountered an error; we should rewind.
+            self.off = off_orig;
+        }
+        result
+    }
+
+    /// Read a nested object with a one-byte length, and parse it with
+    /// `parse`.
+    ///
+    /// This is [`Reader::take_nested`] with a `len_bytes` of 1.
+    pub fn read_nested_u8<T, F>(&mut self, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        self.take_nested(1, parse)
+    }
+
+    /// Read a nested object with a two-byte length, and parse it with
+    /// `parse`.
+    ///
+    /// This is [`Reader::take_nested`] with a `len_bytes` of 2.
+    ///
+    /// # Example
+    ///
+    /// Parsing a list of link-specifier-style entries (each one a type
+    /// byte, a length byte, and a body), which is itself preceded by a
+    /// two-byte length:
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let m = b"\x00\x06\x02\x01\x01A\x09\x00";
+    /// let mut r = Reader::from_slice(m);
+    /// let specs = r.read_nested_u16(|r| {
+    ///     let n = r.take_u8()?;
+    ///     let mut specs = Vec::new();
+    ///     for _ in 0..n {
+    ///         let tp = r.take_u8()?;
+    ///         let body = r.read_nested_u8(|r| Ok(r.take(r.remaining())?))?;
+    ///         specs.push((tp, body));
+    ///     }
+    ///     Ok(specs)
+    /// })?;
+    /// assert_eq!(specs, vec![(1, &b"A"[..]), (9, &b""[..])]);
+    /// r.should_be_exhausted()?;
+    /// # Result::Ok(())
+    /// ```
+    pub fn read_nested_u16<T, F>(&mut self, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        self.take_nested(2, parse)
+    }
+
+    /// Helper for take_nested: does everything but the rewinding.
+    fn take_nested_inner<T, F>(&mut self, len_bytes: usize, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        let len = match len_bytes {
+            1 => self.take_u8()?.into(),
+            2 => self.take_u16()?.into(),
+            4 => usize::try_from(self.take_u32()?)
+                .map_err(|_| Error::BadMessage("nested length too large"))?,
+            8 => usize::try_from(self.take_u64()?)
+                .map_err(|_| Error::BadMessage("nested length too large"))?,
+            _ => return Err(Error::Internal),
+        };
+        let mut sub = Reader::from_slice(self.take(len)?);
+        let result = parse(&mut sub)?;
+        sub.should_be_exhausted()?;
+        Ok(result)
+    }
+
+    /// Run `f` on this reader as a single all-or-nothing step.
+    ///
+    /// If `f` returns an error, we rewind this reader to where it was
+    /// before we called `f`, so that the caller can try parsing the same
+    /// bytes some other way.  This is how [`Reader::extract`] behaves, for
+    /// parsers that aren't worth a [`Readable`] type of their own.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Reader,Result};
+    /// let m = b"\x00\x07\x01";
+    /// let mut r = Reader::from_slice(m);
+    /// assert!(r.transaction(|r| Ok((r.take_u16()?, r.take_u16()?))).is_err());
+    /// assert_eq!(r.consumed(), 0);
+    /// let (a, b) = r.transaction(|r| Ok((r.take_u16()?, r.take_u8()?)))?;
+    /// assert_eq!((a, b), (7, 1));
+    /// # Result::Ok(())
+    /// ```
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        let off_orig = self.off;
+        let result = f(self);
+        if result.is_err() {
+            // We encountered an error; we should rewind.
+            self.off = off_orig;
+        }
+        result
+    }
+}
+
+/// Decode each of `items` as a single `T`, independently.
+///
+/// Returns one result for each item, in order.  An error in one item
+/// doesn't stop us from decoding the rest: this is meant for tools that
+/// want to report every malformed record in a batch (like a capture of
+/// cells), rather than just the first.
+///
+/// Each item must hold exactly one `T`: if any bytes are left over after
+/// decoding it, its result is Err(Error::ExtraneousBytes).
+///
+/// # Example
+/// ```
+/// use tor_bytes::{decode_all,Error};
+/// let items: [&[u8]; 3] = [b"\x00\x07", b"\x01", b"\x00\x01\x02"];
+/// let results = decode_all::<u16, _>(items.iter().copied());
+/// assert_eq!(results, vec![Ok(7), Err(Error::Truncated), Err(Error::ExtraneousBytes)]);
+/// ```
+pub fn decode_all<'a, T, I>(items: I) -> Vec<Result<T>>
+where
+    T: Readable,
+    I: Iterator<Item = &'a [u8]>,
+{
+    items
+        .map(|item| {
+            let mut r = Reader::from_slice(item);
+            let result = r.extract()?;
+            r.should_be_exhausted()?;
+            Ok(result)
+        })
+        .collect()
+}
+
+/// A Reader that owns the bytes it reads from.
+///
+/// A [`Reader`] borrows its input, which makes it awkward to keep a
+/// partially parsed message across an `.await` or to move it into
+/// another task.  An OwnedReader holds its input, along with the
+/// position we've read up to, so it has no lifetime to worry about.
+///
+/// Since a [`Reader`] can't borrow from the OwnedReader that holds it,
+/// you parse through an OwnedReader by calling [`OwnedReader::parse`]
+/// (or [`OwnedReader::extract`]) with a temporary [`Reader`].
+///
+/// # Example
+/// ```
+/// use tor_bytes::{OwnedReader,Result};
+/// let mut r = OwnedReader::from_vec(b"\x00\x05hello".to_vec());
+/// let len: u16 = r.extract()?;
+/// // ... maybe wait for something here ...
+/// let hello = r.parse(|r| Ok(r.take(len as usize)?.to_vec()))?;
+/// assert_eq!(&hello[..], b"hello");
+/// r.should_be_exhausted()?;
+/// # Result::Ok(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct OwnedReader {
+    /// The bytes that we're reading from.
+    b: bytes::Bytes,
+    /// The next position in `b` that we intend to read from.
+    off: usize,
+}
+
+impl OwnedReader {
+    /// Construct a new OwnedReader that takes ownership of `v`.
+    pub fn from_vec(v: Vec<u8>) -> Self {
+        Self::from_bytes(v.into())
+    }
+    /// Construct a new OwnedReader from a 'Bytes' object.
+    pub fn from_bytes(b: bytes::Bytes) -> Self {
+        OwnedReader { b, off: 0 }
+    }
+    /// Return the total length of the bytes in this reader, including
+    /// consumed bytes and remaining bytes.
+    pub fn total_len(&self) -> usize {
+        self.b.len()
+    }
+    /// Return the total number of bytes in this reader that have not
+    /// yet been read.
+    pub fn remaining(&self) -> usize {
+        self.b.len() - self.off
+    }
+    /// Return the total number of bytes in this reader that have
+    /// already been read.
+    pub fn consumed(&self) -> usize {
+        self.off
+    }
+    /// Check whether this reader is exhausted (out of bytes).
+    ///
+    /// Return Ok if it is, and Err(Error::ExtraneousBytes)
+    /// if there were extra bytes.
+    pub fn should_be_exhausted(&self) -> Result<()> {
+        if self.remaining() != 0 {
+            return Err(Error::ExtraneousBytes);
+        }
+        Ok(())
+    }
+    /// Consume this reader, and return the bytes that it did not consume.
+    pub fn into_rest(self) -> bytes::Bytes {
+        self.b.slice(self.off..)
+    }
+    /// Run `f` on a [`Reader`] over the remaining bytes in this reader,
+    /// and advance past whatever `f` consumed.
+    ///
+    /// If `f` returns an error, consumes nothing.
+    pub fn parse<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'_>) -> Result<T>,
+    {
+        let mut r = Reader::from_slice(&self.b[self.off..]);
+        let result = f(&mut r);
+        if result.is_ok() {
+            self.off += r.consumed();
+        }
+        result
+    }
+    /// Try to decode and remove a Readable from this reader, using its
+    /// take_from() method.
+    ///
+    /// On failure, consumes nothing.
+    pub fn extract<E: Readable>(&mut self) -> Result<E> {
+        self.parse(|r| r.extract())
+    }
 }
 
 #[cfg(test)]
@@ -378,6 +779,55 @@ mod tests {
         assert_eq!(r.should_be_exhausted(), Ok(()));
     }
 
+    #[test]
+    fn take_ip_addr() {
+        use hex_literal::hex;
+        use std::net::Ipv4Addr;
+        let v4 = [192, 0, 2, 1];
+        let mut r = Reader::from_slice(&v4[..]);
+        assert_eq!(
+            r.take_ip_addr(4, true).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(r.remaining(), 0);
+
+        let v6 = hex!("20010db8000000000000000000000001");
+        for unmap in [false, true] {
+            let mut r = Reader::from_slice(&v6[..]);
+            assert_eq!(
+                r.take_ip_addr(16, unmap).unwrap(),
+                IpAddr::V6("2001:db8::1".parse().unwrap())
+            );
+        }
+
+        let mapped = hex!("00000000000000000000ffffc0000201");
+        let mut r = Reader::from_slice(&mapped[..]);
+        assert_eq!(
+            r.take_ip_addr(16, true).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        let mut r = Reader::from_slice(&mapped[..]);
+        assert_eq!(
+            r.take_ip_addr(16, false).unwrap(),
+            IpAddr::V6("::ffff:192.0.2.1".parse().unwrap())
+        );
+
+        // "IPv4-compatible" addresses don't get unmapped.
+        let compat = hex!("000000000000000000000000c0000201");
+        let mut r = Reader::from_slice(&compat[..]);
+        assert!(r.take_ip_addr(16, true).unwrap().is_ipv6());
+
+        let mut r = Reader::from_slice(&mapped[..]);
+        assert_eq!(
+            r.take_ip_addr(6, true),
+            Err(Error::BadMessage("Unrecognized IP address length"))
+        );
+        assert_eq!(r.take_ip_addr(16, true).map(|_| ()), Ok(()));
+        let mut r = Reader::from_slice(&v4[..]);
+        assert_eq!(r.take_ip_addr(16, true), Err(Error::Truncated));
+        assert_eq!(r.remaining(), 4);
+    }
+
     #[test]
     fn take_until() {
         let mut r = Reader::from_slice(&b"si vales valeo"[..]);
@@ -430,4 +880,276 @@ mod tests {
         assert_eq!(les.unwrap_err(), Error::Truncated);
         assert_eq!(r.remaining(), 28);
     }
+
+    #[test]
+    fn owned_reader() {
+        let mut r = OwnedReader::from_vec(b"\x00\x03abcdefg!".to_vec());
+        assert_eq!(r.total_len(), 10);
+
+        let n: u16 = r.extract().unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(r.consumed(), 2);
+
+        // The reader can move into another thread halfway through.
+        let mut r = std::thread::spawn(move || {
+            let abc = r.parse(|r| Ok(r.take(n as usize)?.to_vec())).unwrap();
+            assert_eq!(&abc[..], b"abc");
+            r
+        })
+        .join()
+        .unwrap();
+        assert_eq!(r.consumed(), 5);
+        assert_eq!(r.remaining(), 5);
+
+        // A failed parse consumes nothing.
+        let e = r.parse(|r| {
+            r.take(4)?;
+            r.take_u16()
+        });
+        assert_eq!(e, Err(Error::Truncated));
+        assert_eq!(r.remaining(), 5);
+        let e: Result<u64> = r.extract();
+        assert_eq!(e, Err(Error::Truncated));
+        assert_eq!(r.should_be_exhausted(), Err(Error::ExtraneousBytes));
+
+        let defg = r.parse(|r| Ok(r.take(4)?.to_vec())).unwrap();
+        assert_eq!(&defg[..], b"defg");
+        assert_eq!(&r.clone().into_rest()[..], b"!");
+        assert_eq!(r.parse(|r| r.take_u8()), Ok(b'!'));
+        assert_eq!(r.should_be_exhausted(), Ok(()));
+    }
+
+    #[test]
+    fn take_fixed_records() {
+        // Exact multiple of the record size.
+        let mut r = Reader::from_slice(&b"prefix:ABCDEFGHIJKL"[..]);
+        r.advance(7).unwrap();
+        let recs: Vec<[u8; 4]> = r.take_fixed_records().unwrap();
+        assert_eq!(recs, vec![*b"ABCD", *b"EFGH", *b"IJKL"]);
+        assert_eq!(r.remaining(), 0);
+
+        // Not a multiple: we get an error and consume nothing.
+        let mut r = Reader::from_slice(&b"ABCDEFGHIJ"[..]);
+        let recs: Result<Vec<[u8; 4]>> = r.take_fixed_records();
+        assert_eq!(recs, Err(Error::Truncated));
+        assert_eq!(r.remaining(), 10);
+
+        // Nothing left is zero records.
+        let mut r = Reader::from_slice(&b""[..]);
+        let recs: Vec<[u8; 20]> = r.take_fixed_records().unwrap();
+        assert!(recs.is_empty());
+
+        // A record size of zero makes no sense.
+        let mut r = Reader::from_slice(&b"ABCD"[..]);
+        let recs: Result<Vec<[u8; 0]>> = r.take_fixed_records();
+        assert_eq!(recs, Err(Error::Internal));
+        assert_eq!(r.remaining(), 4);
+    }
+
+    #[test]
+    fn padding() {
+        // A cell-like object: a length, a payload, and random padding.
+        let cell = b"\x00\x05hello\x8a\x00\xff\x13\x07";
+        let mut r = Reader::from_slice(&cell[..]);
+        let len = r.take_u16().unwrap() as usize;
+        assert_eq!(r.take_payload_ignoring_padding(len).unwrap(), b"hello");
+        assert_eq!(r.remaining(), 0);
+        r.should_be_exhausted().unwrap();
+
+        // The strict check would have rejected the padding.
+        let mut r = Reader::from_slice(&cell[..]);
+        r.advance(2).unwrap();
+        r.take(len).unwrap();
+        assert_eq!(r.should_be_exhausted(), Err(Error::ExtraneousBytes));
+        assert_eq!(r.discard_padding(), 5);
+        assert_eq!(r.discard_padding(), 0);
+        r.should_be_exhausted().unwrap();
+
+        // A payload that's longer than the object is an error.
+        let mut r = Reader::from_slice(&cell[..]);
+        assert_eq!(r.take_payload_ignoring_padding(20), Err(Error::Truncated));
+        assert_eq!(r.consumed(), 0);
+    }
+
+    #[test]
+    fn take_signed_region() {
+        let doc = b"network-status-version 3 microdesc\n\
+                    valid-after 2021-06-01 00:00:00\n\
+                    footer-directory-signature nope\n\
+                    directory-footer\n\
+                    directory-signature sha256 AAAA BBBB\n\
+                    -----BEGIN SIGNATURE-----\n\
+                    directory-signature sha256 CCCC DDDD\n";
+        let text = std::str::from_utf8(&doc[..]).unwrap();
+        let sig_pos = text.find("\ndirectory-signature ").unwrap() + 1;
+
+        // The covered region runs through the keyword on the first
+        // signature line, skipping lines that merely contain the
+        // keyword.
+        let mut r = Reader::from_slice(&doc[..]);
+        let covered = r.take_signed_region(b"directory-signature ").unwrap();
+        assert_eq!(covered, 0..sig_pos + "directory-signature ".len());
+        assert!(doc[covered.clone()].ends_with(b"directory-footer\ndirectory-signature "));
+        assert_eq!(r.consumed(), covered.end);
+        assert!(r.into_rest().starts_with(b"sha256 AAAA BBBB\n"));
+
+        // If we've already read some of the document, the region starts
+        // where we are, but its offsets are still from the start.
+        let mut r = Reader::from_slice(&doc[..]);
+        r.take_until(b'\n').unwrap();
+        let covered2 = r.take_signed_region(b"directory-signature ").unwrap();
+        assert_eq!(covered2, 35..covered.end);
+        // ... and the next one comes after that.
+        let covered3 = r.take_signed_region(b"directory-signature ").unwrap();
+        assert!(doc[covered3].starts_with(b"sha256 AAAA BBBB\n"));
+
+        // A missing delimiter, or an empty one, is an error, and we
+        // consume nothing.
+        let mut r = Reader::from_slice(&doc[..]);
+        assert_eq!(
+            r.take_signed_region(b"router-signature\n"),
+            Err(Error::Truncated)
+        );
+        assert_eq!(r.take_signed_region(b""), Err(Error::Internal));
+        assert_eq!(r.consumed(), 0);
+
+        // The delimiter can be at the very start of the input.
+        let mut r = Reader::from_slice(&b"sig here"[..]);
+        assert_eq!(r.take_signed_region(b"sig "), Ok(0..4));
+    }
+
+    #[test]
+    fn take_nested() {
+        // A nested structure with a one-byte length.
+        let mut r = Reader::from_slice(&b"\x05hello world"[..]);
+        let hello = r.take_nested(1, |r| r.take(5)).unwrap();
+        assert_eq!(hello, b"hello");
+        assert_eq!(r.remaining(), 6);
+
+        // Nested structures can nest.
+        let mut r = Reader::from_slice(&b"\x00\x03\x02\x00\x09"[..]);
+        let v = r
+            .take_nested(2, |r| r.take_nested(1, |r| r.take_u16()))
+            .unwrap();
+        assert_eq!(v, 9);
+        r.should_be_exhausted().unwrap();
+
+        // Trailing bytes inside the nested structure are an error, and
+        // we consume nothing.
+        let mut r = Reader::from_slice(&b"\x00\x00\x00\x03\x00\x07?!"[..]);
+        let e = r.take_nested(4, |r| r.take_u16());
+        assert_eq!(e, Err(Error::ExtraneousBytes));
+        assert_eq!(r.consumed(), 0);
+
+        // So is a length that runs past the end of the input...
+        let mut r = Reader::from_slice(&b"\x09hello"[..]);
+        assert_eq!(r.take_nested(1, |r| r.take(5)), Err(Error::Truncated));
+        assert_eq!(r.consumed(), 0);
+
+        // ... or a parser that wants more than the nested structure has.
+        let mut r = Reader::from_slice(&b"\x01\x00\x07"[..]);
+        assert_eq!(r.take_nested(1, |r| r.take_u16()), Err(Error::Truncated));
+        assert_eq!(r.consumed(), 0);
+
+        // An eight-byte length never fits in the input, whether or not it
+        // fits in a usize.
+        let mut r = Reader::from_slice(&b"\xff\xff\xff\xff\xff\xff\xff\xffx"[..]);
+        let e = r.take_nested(8, |r| r.take_u8()).unwrap_err();
+        assert!(matches!(e, Error::Truncated | Error::BadMessage(_)));
+        assert_eq!(r.consumed(), 0);
+
+        // We only understand a few length widths.
+        let mut r = Reader::from_slice(&b"\x00\x00\x00\x01x"[..]);
+        assert_eq!(r.take_nested(3, |r| r.take_u8()), Err(Error::Internal));
+        assert_eq!(r.consumed(), 0);
+    }
+
+    #[test]
+    fn read_nested() {
+        let mut r = Reader::from_slice(&b"\x00\x02\x01\x07\x01!"[..]);
+        let v = r
+            .read_nested_u16(|r| r.read_nested_u8(|r| r.take_u8()))
+            .unwrap();
+        assert_eq!(v, 7);
+        assert_eq!(r.read_nested_u8(|r| r.take(1)).unwrap(), b"!");
+        r.should_be_exhausted().unwrap();
+
+        // Trailing bytes: the parser leaves some of the nested bytes.
+        let mut r = Reader::from_slice(&b"\x00\x03\x00\x07?"[..]);
+        let e = r.read_nested_u16(|r| r.take_u16());
+        assert_eq!(e, Err(Error::ExtraneousBytes));
+        assert_eq!(r.consumed(), 0);
+        let mut r = Reader::from_slice(&b"\x02ab"[..]);
+        assert_eq!(
+            r.read_nested_u8(|r| r.take_u8()),
+            Err(Error::ExtraneousBytes)
+        );
+        assert_eq!(r.consumed(), 0);
+
+        // The length exceeds what remains.
+        let mut r = Reader::from_slice(&b"\x01\x00abc"[..]);
+        assert_eq!(r.read_nested_u16(|r| r.take(3)), Err(Error::Truncated));
+        assert_eq!(r.consumed(), 0);
+        let mut r = Reader::from_slice(&b"\x04abc"[..]);
+        assert_eq!(r.read_nested_u8(|r| r.take(3)), Err(Error::Truncated));
+        assert_eq!(r.consumed(), 0);
+    }
+
+    #[test]
+    fn transaction() {
+        let mut r = Reader::from_slice(&b"\x00\x05hello\x01"[..]);
+
+        // A successful transaction consumes what it read.
+        let n = r.transaction(|r| r.take_u16()).unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(r.consumed(), 2);
+
+        // A failing transaction consumes nothing, even if it read
+        // something before it failed.
+        let e = r.transaction(|r| {
+            let hello = r.take(5)?;
+            let n = r.take_u32()?;
+            Ok((hello, n))
+        });
+        assert_eq!(e, Err(Error::Truncated));
+        assert_eq!(r.consumed(), 2);
+        let e = r.transaction(|r| {
+            r.take(6)?;
+            Err::<(), _>(Error::BadMessage("no thanks"))
+        });
+        assert_eq!(e, Err(Error::BadMessage("no thanks")));
+        assert_eq!(r.consumed(), 2);
+
+        // So we can try again another way.
+        let (hello, n) = r.transaction(|r| Ok((r.take(5)?, r.take_u8()?))).unwrap();
+        assert_eq!(hello, b"hello");
+        assert_eq!(n, 1);
+        r.should_be_exhausted().unwrap();
+    }
+
+    #[test]
+    fn decode_all() {
+        let items: Vec<&[u8]> = vec![
+            &b"\x00\x00\x00\x01"[..],
+            &b"\x00\x00"[..],
+            &b"\xff\xff\xff\xff"[..],
+            &b""[..],
+            &b"\x00\x00\x00\x02!"[..],
+            &b"\x00\x00\x00\x03"[..],
+        ];
+        let results = super::decode_all::<u32, _>(items.into_iter());
+        assert_eq!(
+            results,
+            vec![
+                Ok(1),
+                Err(Error::Truncated),
+                Ok(0xffff_ffff),
+                Err(Error::Truncated),
+                Err(Error::ExtraneousBytes),
+                Ok(3),
+            ]
+        );
+
+        assert!(super::decode_all::<u32, _>(std::iter::empty()).is_empty());
+    }
 }
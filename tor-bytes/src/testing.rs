@@ -0,0 +1,67 @@
+//! Helpers for testing implementations of [`Readable`](crate::Readable)
+//! and [`Writeable`](crate::Writeable).
+//!
+//! Only available with the `testing` feature.
+
+/// Assert that a value survives being encoded and decoded.
+///
+/// `assert_roundtrip!(value)` writes `value` onto a new `Vec<u8>`, reads
+/// it back, and asserts that the result equals `value`, that reading
+/// it consumed exactly the bytes that were written, and that
+/// [`Writeable::encoded_len`](crate::Writeable::encoded_len) reports
+/// the length of that encoding.  With a second
+/// argument, `assert_roundtrip!(value, encoded)` also asserts that the
+/// encoding of `value` is `encoded`.
+///
+/// The type of `value` must implement `Readable`, `Writeable`,
+/// `PartialEq`, and `Debug`.  The macro evaluates to the encoding, in
+/// case the caller wants to check anything else about it.
+///
+/// Only available with the `testing` feature.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "testing")] {
+/// use tor_bytes::assert_roundtrip;
+///
+/// assert_roundtrip!(0x1234_u16, [0x12, 0x34]);
+/// let encoded = assert_roundtrip!([7_u8; 16]);
+/// assert_eq!(encoded.len(), 16);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_roundtrip {
+    ($value:expr) => {{
+        /// Helper: extract an object of the same type as `_like`.
+        fn extract_like<T: $crate::Readable>(
+            _like: &T,
+            r: &mut $crate::Reader<'_>,
+        ) -> $crate::Result<T> {
+            r.extract()
+        }
+
+        let value = $value;
+        let mut encoded: Vec<u8> = Vec::new();
+        $crate::Writer::write(&mut encoded, &value);
+        assert_eq!(
+            $crate::Writeable::encoded_len(&value),
+            encoded.len(),
+            "encoded_len() disagrees with the encoding"
+        );
+        let mut reader = $crate::Reader::from_slice(&encoded[..]);
+        let decoded = extract_like(&value, &mut reader).expect("Couldn't decode encoded value");
+        assert_eq!(decoded, value);
+        assert_eq!(
+            reader.consumed(),
+            encoded.len(),
+            "Decoding didn't consume the whole encoding"
+        );
+        encoded
+    }};
+    ($value:expr, $encoded:expr) => {{
+        let encoded = $crate::assert_roundtrip!($value);
+        assert_eq!(&encoded[..], &$encoded[..]);
+        encoded
+    }};
+}
@@ -0,0 +1,240 @@
+//! A [`Reader`] wrapper that can log each field as it is parsed.
+//!
+//! When you're trying to figure out why some cell or document doesn't
+//! parse, it helps to see exactly which bytes went into which field.
+//! [`TracingReader`] wraps a [`Reader`], and takes a name for every
+//! field that it reads.  When the `debug-parse` feature is enabled, it
+//! logs (at trace level) the name, offset, and contents of each field;
+//! otherwise, the names are ignored.
+//!
+//! The plain [`Reader`] never logs anything, so parsers that don't use
+//! this type don't pay for it.
+
+use crate::{Readable, Reader, Result};
+
+/// A [`Reader`] that logs every field that it reads, when the
+/// `debug-parse` feature is enabled.
+///
+/// # Example
+/// ```
+/// use tor_bytes::{Result, TracingReader};
+/// let msg = [0x00, 0x04, 0x7f, 0x00, 0x00, 0x01];
+/// let mut r = TracingReader::from_slice(&msg[..]);
+///
+/// let port = r.take_u16("port")?;
+/// let addr: std::net::Ipv4Addr = r.extract("addr")?;
+/// assert_eq!(port, 4);
+/// assert_eq!(addr, std::net::Ipv4Addr::LOCALHOST);
+/// r.should_be_exhausted()?;
+/// # Result::Ok(())
+/// ```
+pub struct TracingReader<'a> {
+    /// The reader that does the actual work.
+    r: Reader<'a>,
+}
+
+/// Report that we tried to read the field `name` at `offset`, with the
+/// outcome `result`.
+///
+/// `field` is the slice of bytes that the field occupied, if the read
+/// succeeded.
+#[cfg(feature = "debug-parse")]
+fn trace_field<T>(name: &str, offset: usize, field: &[u8], result: &Result<T>) {
+    use std::fmt::Write;
+    match result {
+        Ok(_) => {
+            let mut hex = String::with_capacity(field.len() * 2);
+            for b in field {
+                let _ = write!(hex, "{:02x}", b);
+            }
+            log::trace!(
+                "{}: {} bytes at offset {}: {}",
+                name,
+                field.len(),
+                offset,
+                hex
+            );
+        }
+        Err(e) => log::trace!("{}: failed at offset {}: {}", name, offset, e),
+    }
+}
+
+/// Report that we tried to read the field `name` at `offset`, with the
+/// outcome `result`.
+///
+/// (This does nothing, since the `debug-parse` feature is disabled.)
+#[cfg(not(feature = "debug-parse"))]
+#[inline]
+fn trace_field<T>(_name: &str, _offset: usize, _field: &[u8], _result: &Result<T>) {}
+
+impl<'a> TracingReader<'a> {
+    /// Construct a new TracingReader from a slice of bytes.
+    pub fn from_slice(slice: &'a [u8]) -> Self {
+        Self::new(Reader::from_slice(slice))
+    }
+    /// Construct a new TracingReader from a 'Bytes' object.
+    pub fn from_bytes(b: &'a bytes::Bytes) -> Self {
+        Self::new(Reader::from_bytes(b))
+    }
+    /// Construct a new TracingReader that wraps `r`.
+    ///
+    /// Offsets in the log are relative to the start of `r`'s slice.
+    pub fn new(r: Reader<'a>) -> Self {
+        TracingReader { r }
+    }
+    /// Consume this TracingReader, and return the Reader that it wraps.
+    pub fn into_inner(self) -> Reader<'a> {
+        self.r
+    }
+    /// Return the total number of bytes in this reader that have not
+    /// yet been read.
+    pub fn remaining(&self) -> usize {
+        self.r.remaining()
+    }
+    /// Return the total number of bytes in this reader that have
+    /// already been read.
+    pub fn consumed(&self) -> usize {
+        self.r.consumed()
+    }
+    /// Check whether this reader is exhausted (out of bytes).
+    ///
+    /// As [`Reader::should_be_exhausted`].
+    pub fn should_be_exhausted(&self) -> Result<()> {
+        self.r.should_be_exhausted()
+    }
+
+    /// Run `f` to read the field called `name`, and log the outcome.
+    fn traced<T, F>(&mut self, name: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T>,
+    {
+        let start = self.r.consumed();
+        let rest = self.r.peek(self.r.remaining())?;
+        let result = f(&mut self.r);
+        let len = self.r.consumed().saturating_sub(start);
+        trace_field(name, start, &rest[..len], &result);
+        result
+    }
+
+    /// Skip `n` bytes, logging them as the field `name`.
+    ///
+    /// As [`Reader::advance`].
+    pub fn advance(&mut self, name: &str, n: usize) -> Result<()> {
+        self.traced(name, |r| r.advance(n))
+    }
+    /// Consume and return `n` bytes, logging them as the field `name`.
+    ///
+    /// As [`Reader::take`].
+    pub fn take(&mut self, name: &str, n: usize) -> Result<&'a [u8]> {
+        self.traced(name, |r| r.take(n))
+    }
+    /// Consume and return a u8, logging it as the field `name`.
+    pub fn take_u8(&mut self, name: &str) -> Result<u8> {
+        self.traced(name, |r| r.take_u8())
+    }
+    /// Consume and return a big-endian u16, logging it as the field `name`.
+    pub fn take_u16(&mut self, name: &str) -> Result<u16> {
+        self.traced(name, |r| r.take_u16())
+    }
+    /// Consume and return a big-endian u32, logging it as the field `name`.
+    pub fn take_u32(&mut self, name: &str) -> Result<u32> {
+        self.traced(name, |r| r.take_u32())
+    }
+    /// Consume and return a big-endian u64, logging it as the field `name`.
+    pub fn take_u64(&mut self, name: &str) -> Result<u64> {
+        self.traced(name, |r| r.take_u64())
+    }
+    /// Consume and return a big-endian u128, logging it as the field
+    /// `name`.
+    pub fn take_u128(&mut self, name: &str) -> Result<u128> {
+        self.traced(name, |r| r.take_u128())
+    }
+    /// Consume bytes up to and including `term`, logging them as the
+    /// field `name`.
+    ///
+    /// As [`Reader::take_until`].
+    pub fn take_until(&mut self, name: &str, term: u8) -> Result<&'a [u8]> {
+        self.traced(name, |r| r.take_until(term))
+    }
+    /// Decode and remove a Readable from this reader, logging it as the
+    /// field `name`.
+    ///
+    /// As [`Reader::extract`].
+    pub fn extract<E: Readable>(&mut self, name: &str) -> Result<E> {
+        self.traced(name, |r| r.extract())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn read_fields() {
+        let msg = b"\x01\x02\x03hello\x00world";
+        let mut r = TracingReader::from_slice(&msg[..]);
+        assert_eq!(r.take_u8("a").unwrap(), 1);
+        assert_eq!(r.take_u16("b").unwrap(), 0x0203);
+        assert_eq!(r.take_until("c", 0).unwrap(), b"hello");
+        assert_eq!(r.consumed(), 9);
+        assert_eq!(r.take_u64("d"), Err(Error::Truncated));
+        assert_eq!(r.take("e", 5).unwrap(), b"world");
+        r.should_be_exhausted().unwrap();
+        assert_eq!(r.into_inner().consumed(), msg.len());
+    }
+
+    #[cfg(feature = "debug-parse")]
+    mod capture {
+        use super::*;
+        use std::cell::RefCell;
+
+        thread_local! {
+            /// Log messages captured on this thread.
+            static CAPTURED: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        }
+
+        /// A logger that records every message on the current thread.
+        struct CaptureLogger;
+
+        impl log::Log for CaptureLogger {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record<'_>) {
+                CAPTURED.with(|c| c.borrow_mut().push(record.args().to_string()));
+            }
+            fn flush(&self) {}
+        }
+
+        /// The logger used for these tests.
+        static LOGGER: CaptureLogger = CaptureLogger;
+
+        #[test]
+        fn events_per_field() {
+            static INIT: std::sync::Once = std::sync::Once::new();
+            INIT.call_once(|| {
+                log::set_logger(&LOGGER).unwrap();
+                log::set_max_level(log::LevelFilter::Trace);
+            });
+
+            let msg = [0x00, 0x04, 0x7f, 0x00, 0x00, 0x01, 0xff];
+            let mut r = TracingReader::from_slice(&msg[..]);
+            r.take_u16("port").unwrap();
+            let _: std::net::Ipv4Addr = r.extract("addr").unwrap();
+            assert!(r.take_u16("junk").is_err());
+            r.advance("padding", 1).unwrap();
+
+            let captured = CAPTURED.with(|c| c.borrow().clone());
+            assert_eq!(
+                captured,
+                vec![
+                    "port: 2 bytes at offset 0: 0004",
+                    "addr: 4 bytes at offset 2: 7f000001",
+                    "junk: failed at offset 6: object truncated (or not fully present)",
+                    "padding: 1 bytes at offset 6: ff",
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! A wrapper for a body whose format depends on a leading version byte.
+
+use crate::{Error, Readable, Reader, Result, Writeable, Writer};
+
+/// A type that can be encoded in one of several versioned formats.
+///
+/// Implement this trait, and then use [`Versioned`] to read and write the
+/// version byte, so that every versioned type dispatches on its version
+/// the same way and reports unknown versions the same way.
+pub trait VersionedCodec: Sized {
+    /// Try to decode the body of an object whose version byte was
+    /// `version`.
+    ///
+    /// Return Ok(None) if `version` is not one we recognize.
+    fn decode(version: u8, r: &mut Reader<'_>) -> Result<Option<Self>>;
+
+    /// Encode the body of this object onto `w`, and return the version
+    /// of the format that we used.
+    fn encode<B: Writer + ?Sized>(&self, w: &mut B) -> u8;
+}
+
+/// A value encoded as a version byte followed by a body whose format
+/// depends on that version.
+///
+/// Decoding an unrecognized version gives
+/// `Error::BadMessage("unrecognized version")`.
+///
+/// # Example
+///
+/// ```
+/// use tor_bytes::{Reader, Result, Versioned, VersionedCodec, Writer};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Port(u16);
+/// impl VersionedCodec for Port {
+///     fn decode(version: u8, r: &mut Reader<'_>) -> Result<Option<Self>> {
+///         match version {
+///             1 => Ok(Some(Port(r.take_u16()?))),
+///             _ => Ok(None),
+///         }
+///     }
+///     fn encode<B: Writer + ?Sized>(&self, w: &mut B) -> u8 {
+///         w.write_u16(self.0);
+///         1
+///     }
+/// }
+///
+/// let mut v: Vec<u8> = Vec::new();
+/// v.write(&Versioned::new(Port(443)));
+/// assert_eq!(&v[..], &[1, 1, 0xbb]);
+///
+/// let mut r = Reader::from_slice(&v[..]);
+/// let p: Versioned<Port> = r.extract()?;
+/// assert_eq!(p.into_inner(), Port(443));
+/// # Result::Ok(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Versioned<T> {
+    /// The value.
+    inner: T,
+}
+
+impl<T> Versioned<T> {
+    /// Wrap `inner` so that it will be encoded with a version byte.
+    pub fn new(inner: T) -> Self {
+        Versioned { inner }
+    }
+
+    /// Return a reference to the value.
+    pub fn get(&self) -> &T {
+        &self.inner
+    }
+
+    /// Return the value.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> From<T> for Versioned<T> {
+    fn from(inner: T) -> Self {
+        Versioned::new(inner)
+    }
+}
+
+impl<T: VersionedCodec> Writeable for Versioned<T> {
+    fn write_onto<B: Writer + ?Sized>(&self, b: &mut B) {
+        // We only learn the version once we've encoded the body, so we
+        // encode the body first and then write them in the right order.
+        let mut body = Vec::new();
+        let version = self.inner.encode(&mut body);
+        b.write_u8(version);
+        b.write_all(&body[..]);
+    }
+}
+
+impl<T: VersionedCodec> Readable for Versioned<T> {
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let version = r.take_u8()?;
+        match T::decode(version, r)? {
+            Some(inner) => Ok(Versioned::new(inner)),
+            None => Err(Error::BadMessage("unrecognized version")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A codec with two versions: version 1 holds a u16, and version 2
+    /// holds a u16 and a u32.
+    #[derive(Debug, PartialEq)]
+    enum TwoVersions {
+        /// Encoded in version 1.
+        V1(u16),
+        /// Encoded in version 2.
+        V2(u16, u32),
+    }
+
+    impl VersionedCodec for TwoVersions {
+        fn decode(version: u8, r: &mut Reader<'_>) -> Result<Option<Self>> {
+            Ok(match version {
+                1 => Some(TwoVersions::V1(r.take_u16()?)),
+                2 => Some(TwoVersions::V2(r.take_u16()?, r.take_u32()?)),
+                _ => None,
+            })
+        }
+        fn encode<B: Writer + ?Sized>(&self, w: &mut B) -> u8 {
+            match self {
+                TwoVersions::V1(a) => {
+                    w.write_u16(*a);
+                    1
+                }
+                TwoVersions::V2(a, b) => {
+                    w.write_u16(*a);
+                    w.write_u32(*b);
+                    2
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let vals = [
+            Versioned::new(TwoVersions::V1(0x0102)),
+            Versioned::new(TwoVersions::V2(0x0304, 0x0506_0708)),
+        ];
+        let mut v: Vec<u8> = Vec::new();
+        for x in &vals {
+            v.write(x);
+        }
+        assert_eq!(&v[..], &[1, 1, 2, 2, 3, 4, 5, 6, 7, 8]);
+
+        let mut r = Reader::from_slice(&v[..]);
+        for x in &vals {
+            let got: Versioned<TwoVersions> = r.extract().unwrap();
+            assert_eq!(&got, x);
+        }
+        assert!(r.should_be_exhausted().is_ok());
+    }
+
+    #[test]
+    fn bad() {
+        // Unknown version: rejected, and nothing consumed.
+        let mut r = Reader::from_slice(&[3, 0, 0]);
+        assert_eq!(
+            r.extract::<Versioned<TwoVersions>>(),
+            Err(Error::BadMessage("unrecognized version"))
+        );
+        assert_eq!(r.consumed(), 0);
+
+        // Known version, but truncated.
+        let mut r = Reader::from_slice(&[2, 0, 0, 0]);
+        assert_eq!(r.extract::<Versioned<TwoVersions>>(), Err(Error::Truncated));
+    }
+}
@@ -75,6 +75,15 @@ pub trait Writer {
     fn write<E: Writeable + ?Sized>(&mut self, e: &E) {
         e.write_onto(self)
     }
+    /// Encode each of `items` onto this writer, in order, with no length
+    /// or separator.
+    ///
+    /// (For a slice of bytes, [`Writer::write_all`] is faster.)
+    fn write_all_items<T: Writeable>(&mut self, items: &[T]) {
+        for item in items {
+            self.write(item)
+        }
+    }
     /// Encode a WriteableOnce object onto this writer, using its
     /// write_into method.
     fn write_and_consume<E: WriteableOnce>(&mut self, e: E) {
@@ -82,6 +91,68 @@ pub trait Writer {
     }
 }
 
+/// A [`Writer`] that passes everything written to it both to another
+/// Writer and to a running digest.
+///
+/// This lets us encode an object and compute its digest in a single
+/// pass, as we need to do (for example) when we authenticate relay
+/// cells.
+///
+/// # Example
+/// ```
+/// use tor_bytes::{DigestWriter, Writer};
+/// use tor_llcrypto::d::Sha1;
+/// use digest::Digest;
+///
+/// let mut w = DigestWriter::new(Vec::new(), Sha1::new());
+/// w.write_u16(7);
+/// w.write_all(b"hello");
+/// let (bytes, d) = w.into_parts();
+/// assert_eq!(d.finalize(), Sha1::digest(&bytes[..]));
+/// ```
+#[derive(Clone, Debug)]
+pub struct DigestWriter<W, D> {
+    /// The writer that receives our bytes.
+    inner: W,
+    /// The digest that we update with our bytes.
+    digest: D,
+}
+
+impl<W, D> DigestWriter<W, D>
+where
+    W: Writer,
+    D: digest::Update,
+{
+    /// Construct a new DigestWriter that writes onto `inner`, and
+    /// updates `digest`.
+    pub fn new(inner: W, digest: D) -> Self {
+        DigestWriter { inner, digest }
+    }
+    /// Return a reference to the writer that receives our bytes.
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+    /// Return a reference to the digest that we're updating.
+    pub fn digest(&self) -> &D {
+        &self.digest
+    }
+    /// Consume this DigestWriter, and return its writer and digest.
+    pub fn into_parts(self) -> (W, D) {
+        (self.inner, self.digest)
+    }
+}
+
+impl<W, D> Writer for DigestWriter<W, D>
+where
+    W: Writer,
+    D: digest::Update,
+{
+    fn write_all(&mut self, b: &[u8]) {
+        self.inner.write_all(b);
+        self.digest.update(b);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,4 +201,32 @@ mod tests {
         v.write_and_consume(Sequence(3));
         assert_eq!(&v[..], &[0, 1, 2, 3, 4, 5, 0, 1, 2]);
     }
+
+    #[test]
+    fn digest_writer() {
+        use digest::Digest;
+        use tor_llcrypto::d::{Sha1, Sha256};
+
+        let mut w = DigestWriter::new(Vec::new(), Sha1::new());
+        w.write_u8(3);
+        w.write_u32(0x1234_5678);
+        w.write(&b"relay cell body"[..]);
+        w.write_zeros(10);
+        assert_eq!(w.inner().len(), 30);
+
+        // The digest is the same as if we'd digested the bytes separately.
+        let expected = Sha1::digest(&w.inner()[..]);
+        assert_eq!(w.digest().clone().finalize(), expected);
+        let (bytes, d) = w.into_parts();
+        assert_eq!(d.finalize(), Sha1::digest(&bytes[..]));
+
+        // We can carry on from a digest that already has some input.
+        let mut prefix = Sha256::new();
+        prefix.update(b"earlier cells");
+        let mut w = DigestWriter::new(bytes::BytesMut::new(), prefix);
+        w.write(&b"this cell"[..]);
+        let (bytes, d) = w.into_parts();
+        assert_eq!(&bytes[..], b"this cell");
+        assert_eq!(d.finalize(), Sha256::digest(b"earlier cellsthis cell"));
+    }
 }
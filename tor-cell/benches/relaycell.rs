@@ -0,0 +1,44 @@
+//! Compare parsing and re-encoding a relay cell with and without copying
+//! its message body.
+//!
+//! This is what a relay does to a cell it forwards.  Run with:
+//!
+//! ```text
+//! cargo bench -p tor-cell --bench relaycell
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tor_cell::chancell::RawCellBody;
+use tor_cell::relaycell::{msg, RelayCell, RelayCellRef};
+
+/// Return an encoded DATA cell with `n` bytes of data.
+fn make_cell(n: usize) -> RawCellBody {
+    let data = vec![0x5a_u8; n];
+    RelayCell::new(7.into(), msg::Data::new(&data[..]).into())
+        .encode(&mut rand::thread_rng())
+        .unwrap()
+}
+
+/// Benchmark both ways of forwarding DATA cells of a few sizes.
+fn forward(c: &mut Criterion) {
+    let mut group = c.benchmark_group("relaycell_forward");
+    let mut rng = rand::thread_rng();
+    for n in [16, 256, 498] {
+        let cell = make_cell(n);
+        group.bench_with_input(BenchmarkId::new("owned", n), &cell, |b, cell| {
+            b.iter(|| RelayCell::decode(*cell).unwrap().encode(&mut rng).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("borrowed", n), &cell, |b, cell| {
+            b.iter(|| {
+                RelayCellRef::decode(cell)
+                    .unwrap()
+                    .encode(&mut rng)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, forward);
+criterion_main!(benches);
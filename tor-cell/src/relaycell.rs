@@ -190,26 +190,8 @@ impl RelayCell {
     /// Consume this relay message and encode it as a 509-byte padded cell
     /// body.
     pub fn encode<R: Rng + CryptoRng>(self, rng: &mut R) -> crate::Result<RawCellBody> {
-        /// We skip this much space before adding any random padding to the
-        /// end of the cell
-        const MIN_SPACE_BEFORE_PADDING: usize = 4;
-
         // TODO: This implementation is inefficient; it copies too much.
-        let encoded = self.encode_to_vec();
-        let enc_len = encoded.len();
-        if enc_len > CELL_DATA_LEN {
-            return Err(crate::Error::InternalError(
-                "too many bytes in relay cell".into(),
-            ));
-        }
-        let mut raw = [0_u8; CELL_DATA_LEN];
-        raw[0..enc_len].copy_from_slice(&encoded);
-
-        if enc_len < CELL_DATA_LEN - MIN_SPACE_BEFORE_PADDING {
-            rng.fill_bytes(&mut raw[enc_len + MIN_SPACE_BEFORE_PADDING..]);
-        }
-
-        Ok(raw)
+        pad_cell_body(&self.encode_to_vec(), rng)
     }
 
     /// Consume a relay cell and return its contents, encoded for use
@@ -245,16 +227,128 @@ impl RelayCell {
     /// Requires that the cryptographic checks on the message have already been
     /// performed
     pub fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
-        let cmd = r.take_u8()?.into();
-        r.advance(2)?; // "recognized"
-        let streamid = StreamId(r.take_u16()?);
-        r.advance(4)?; // digest
-        let len = r.take_u16()? as usize;
-        if r.remaining() < len {
-            return Err(Error::BadMessage("Insufficient data in relay cell"));
-        }
+        let (cmd, streamid, len) = decode_header(r)?;
         r.truncate(len);
         let msg = msg::RelayMsg::decode_from_reader(cmd, r)?;
         Ok(RelayCell { streamid, msg })
     }
 }
+
+/// A relay cell whose message we haven't parsed, borrowed from the
+/// buffer that holds it.
+///
+/// This is for code that forwards cells without looking inside them: it
+/// avoids copying the message body into a new [`RelayCell`].  Use
+/// [`RelayCellRef::to_relay_cell`] to parse the message if you need it.
+#[derive(Clone, Copy, Debug)]
+pub struct RelayCellRef<'a> {
+    /// The stream ID for the stream that this cell corresponds to.
+    streamid: StreamId,
+    /// The command for this cell.
+    cmd: RelayCmd,
+    /// The encoded message body, without its header or any padding.
+    body: &'a [u8],
+}
+
+impl<'a> RelayCellRef<'a> {
+    /// Parse the header of a RELAY or RELAY_EARLY cell body into a
+    /// RelayCellRef, borrowing its message body from `body`.
+    ///
+    /// Requires that the cryptographic checks on the message have already been
+    /// performed
+    pub fn decode(body: &'a RawCellBody) -> Result<Self> {
+        let mut reader = Reader::from_slice(&body[..]);
+        RelayCellRef::decode_from_reader(&mut reader)
+    }
+    /// Parse the header of a RELAY or RELAY_EARLY cell body into a
+    /// RelayCellRef from a reader, borrowing its message body.
+    ///
+    /// Requires that the cryptographic checks on the message have already been
+    /// performed
+    pub fn decode_from_reader(r: &mut Reader<'a>) -> Result<Self> {
+        let (cmd, streamid, len) = decode_header(r)?;
+        let body = r.take(len)?;
+        Ok(RelayCellRef {
+            streamid,
+            cmd,
+            body,
+        })
+    }
+    /// Return the stream ID for this cell.
+    pub fn stream_id(&self) -> StreamId {
+        self.streamid
+    }
+    /// Return the command for this cell.
+    pub fn cmd(&self) -> RelayCmd {
+        self.cmd
+    }
+    /// Return the encoded body of this cell's message.
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+    /// Parse this cell's message, and return it as an owned RelayCell.
+    pub fn to_relay_cell(&self) -> Result<RelayCell> {
+        let mut r = Reader::from_slice(self.body);
+        let msg = msg::RelayMsg::decode_from_reader(self.cmd, &mut r)?;
+        Ok(RelayCell::new(self.streamid, msg))
+    }
+    /// Encode this cell as a 509-byte padded cell body.
+    ///
+    /// This gives the same result as parsing the cell with
+    /// [`RelayCellRef::to_relay_cell`] and encoding that, for any message
+    /// that we encode the same way that we parsed it.
+    pub fn encode<R: Rng + CryptoRng>(&self, rng: &mut R) -> crate::Result<RawCellBody> {
+        if self.body.len() > u16::MAX as usize {
+            return Err(crate::Error::InternalError(
+                "too many bytes in relay cell".into(),
+            ));
+        }
+        let mut w = Vec::with_capacity(CELL_DATA_LEN);
+        w.write_u8(self.cmd.into());
+        w.write_u16(0); // "Recognized"
+        w.write_u16(self.streamid.0);
+        w.write_u32(0); // Digest
+        w.write_u16(self.body.len() as u16);
+        w.write_all(self.body);
+        pad_cell_body(&w, rng)
+    }
+}
+
+/// Parse the header of a relay cell body, and return its command, stream
+/// ID, and message length.
+///
+/// Returns an error if there aren't that many bytes left after the header.
+fn decode_header(r: &mut Reader<'_>) -> Result<(RelayCmd, StreamId, usize)> {
+    let cmd = r.take_u8()?.into();
+    r.advance(2)?; // "recognized"
+    let streamid = StreamId(r.take_u16()?);
+    r.advance(4)?; // digest
+    let len = r.take_u16()? as usize;
+    if r.remaining() < len {
+        return Err(Error::BadMessage("Insufficient data in relay cell"));
+    }
+    Ok((cmd, streamid, len))
+}
+
+/// Copy an encoded relay cell into a new 509-byte cell body, and fill
+/// the rest with padding from `rng`.
+fn pad_cell_body<R: Rng + CryptoRng>(encoded: &[u8], rng: &mut R) -> crate::Result<RawCellBody> {
+    /// We skip this much space before adding any random padding to the
+    /// end of the cell
+    const MIN_SPACE_BEFORE_PADDING: usize = 4;
+
+    let enc_len = encoded.len();
+    if enc_len > CELL_DATA_LEN {
+        return Err(crate::Error::InternalError(
+            "too many bytes in relay cell".into(),
+        ));
+    }
+    let mut raw = [0_u8; CELL_DATA_LEN];
+    raw[0..enc_len].copy_from_slice(encoded);
+
+    if enc_len < CELL_DATA_LEN - MIN_SPACE_BEFORE_PADDING {
+        rng.fill_bytes(&mut raw[enc_len + MIN_SPACE_BEFORE_PADDING..]);
+    }
+
+    Ok(raw)
+}
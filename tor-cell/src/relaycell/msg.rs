@@ -654,6 +654,7 @@ impl Body for Extend {
         let port = r.take_u16()?;
         let handshake = r.take(TAP_C_HANDSHAKE_LEN)?.into();
         let rsaid = r.extract()?;
+        r.should_be_exhausted()?;
         Ok(Extend {
             addr,
             port,
@@ -691,6 +692,7 @@ impl Body for Extended {
     }
     fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
         let handshake = r.take(TAP_S_HANDSHAKE_LEN)?.into();
+        r.should_be_exhausted()?;
         Ok(Extended { handshake })
     }
     fn encode_onto(mut self, w: &mut Vec<u8>) {
@@ -753,8 +755,7 @@ impl Body for Extend2 {
         RelayMsg::Extend2(self)
     }
     fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
-        let n = r.take_u8()?;
-        let linkspec = r.extract_n(n as usize)?;
+        let linkspec = LinkSpec::take_list(r)?;
         let handshake_type = r.take_u16()?;
         let hlen = r.take_u16()?;
         let handshake = r.take(hlen as usize)?.into();
@@ -765,11 +766,7 @@ impl Body for Extend2 {
         })
     }
     fn encode_onto(self, w: &mut Vec<u8>) {
-        assert!(self.linkspec.len() <= std::u8::MAX as usize);
-        w.write_u8(self.linkspec.len() as u8);
-        for ls in self.linkspec.iter() {
-            w.write(ls);
-        }
+        LinkSpec::write_list(&self.linkspec[..], w);
         w.write_u16(self.handshake_type);
         assert!(self.handshake.len() <= std::u16::MAX as usize);
         w.write_u16(self.handshake.len() as u16);
@@ -1086,6 +1083,10 @@ impl Unrecognized {
     pub fn cmd(&self) -> RelayCmd {
         self.cmd
     }
+    /// Return the body of this message, exactly as we received it.
+    pub fn body(&self) -> &[u8] {
+        &self.body[..]
+    }
     /// Decode this message, using a provided command.
     pub fn decode_with_cmd(cmd: RelayCmd, r: &mut Reader<'_>) -> Result<Self> {
         let mut r = Unrecognized::decode_from_reader(r)?;
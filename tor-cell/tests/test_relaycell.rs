@@ -1,7 +1,7 @@
 // Tests for encoding/decoding relay messags into relay cell bodies.
 
 use tor_bytes::Error;
-use tor_cell::relaycell::{msg, msg::RelayMsg, RelayCell, RelayCmd, StreamId};
+use tor_cell::relaycell::{msg, msg::RelayMsg, RelayCell, RelayCellRef, RelayCmd, StreamId};
 
 const CELL_BODY_LEN: usize = 509;
 
@@ -45,12 +45,21 @@ fn cell(body: &str, id: StreamId, msg: RelayMsg) {
 
     let decoded = RelayCell::decode(body).unwrap();
 
-    assert_eq!(format!("{:?}", expected), format!("{:?}", decoded));
+    let decoded_debug = format!("{:?}", decoded);
+    assert_eq!(format!("{:?}", expected), decoded_debug);
 
     let encoded1 = decoded.encode(&mut bad_rng).unwrap();
     let encoded2 = expected.encode(&mut bad_rng).unwrap();
 
     assert_eq!(&encoded1[..], &encoded2[..]);
+
+    // Parsing into borrowed form and re-encoding gives the same result.
+    let borrowed = RelayCellRef::decode(&body).unwrap();
+    assert_eq!(borrowed.stream_id(), id);
+    let reparsed = borrowed.to_relay_cell().unwrap();
+    assert_eq!(format!("{:?}", reparsed), decoded_debug);
+    let encoded3 = borrowed.encode(&mut bad_rng).unwrap();
+    assert_eq!(&encoded3[..], &encoded2[..]);
 }
 
 #[test]
@@ -77,6 +86,43 @@ fn test_cells() {
     assert_eq!(s, StreamId::from(0x9999));
 }
 
+#[test]
+fn test_borrowed() {
+    let m = decode("02 0000 9999 12345678 000c 6e6565642d746f2d6b6e6f77 00000000");
+    let c = RelayCellRef::decode(&m).unwrap();
+    assert_eq!(c.cmd(), RelayCmd::DATA);
+    assert_eq!(c.stream_id(), StreamId::from(0x9999));
+    assert_eq!(c.body(), b"need-to-know");
+    // The body really is borrowed from the cell we decoded.
+    assert_eq!(c.body().as_ptr(), m[11..].as_ptr());
+
+    // length too big: 0x1f3 is one byte too many.
+    let m = decode("02 0000 9999 12345678 01f3 6e6565642d746f2d6b6e6f77 00000000");
+    assert_eq!(
+        RelayCellRef::decode(&m).err(),
+        Some(Error::BadMessage("Insufficient data in relay cell"))
+    );
+}
+
+#[test]
+fn test_unrecognized_roundtrip() {
+    // 200 isn't a relay command that we know about.
+    let body = decode("c8 0000 0042 00000000 0009 0102030405060708ff 00000000");
+    let c = RelayCell::decode(body).unwrap();
+    assert_eq!(c.cmd(), RelayCmd::from(200));
+    match c.msg() {
+        RelayMsg::Unrecognized(u) => {
+            assert_eq!(u.cmd(), RelayCmd::from(200));
+            assert_eq!(u.body(), &hex::decode("0102030405060708ff").unwrap()[..]);
+        }
+        m => panic!("Unexpected message {:?}", m),
+    }
+
+    // Encoding it again gives us exactly the cell we started with.
+    let encoded = c.encode(&mut BadRng).unwrap();
+    assert_eq!(&encoded[..], &body[..]);
+}
+
 #[test]
 fn test_streamid() {
     let zero: StreamId = 0.into();
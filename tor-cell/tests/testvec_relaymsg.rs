@@ -34,6 +34,20 @@ fn msg(cmd: RelayCmd, s: &str, msg: &msg::RelayMsg) {
     assert_eq!(body, encoded2);
 }
 
+fn msg_error(cmd: RelayCmd, s: &str, err: tor_bytes::Error) {
+    let body = {
+        let mut s = s.to_string();
+        s.retain(|c| !c.is_whitespace());
+        hex::decode(s).unwrap()
+    };
+    let mut r = tor_bytes::Reader::from_slice(&body[..]);
+
+    assert_eq!(
+        msg::RelayMsg::decode_from_reader(cmd, &mut r).unwrap_err(),
+        err
+    );
+}
+
 #[test]
 fn test_begin() {
     let cmd = RelayCmd::BEGIN;
@@ -168,6 +182,15 @@ fn test_extend() {
     );
 }
 
+#[test]
+fn test_extend_trailing_bytes() {
+    // The extend body from test_extend, with an extra byte at the end.
+    let body = "7F000001 138C
+                71510FC729E1DBE35586F0031D69A38FC684B26D657821EE640C299BA9F8FD38D3A3376F2DD3A79A0B73836AB4B42E5FB3BEE1383F3184A852B292626DCC64AF672A8FAEFC263C38370768EF9EA6C244BA079142D3E23835F6914DE0C7F468316C4265E109F5312987275D61E1DC831A3323195DDE70841CEE2DC30F6DCDBDABA40A75FDFB714431FC5EB8F84D4150EE2C2478A79018F18D7F30F6BB677516CF03390F5180B371DEAEBB89175798864D2130B13ED1D20B254F07
+                CF555174CBE8AD62A7E764A8F3D85D40C5145ABB 00";
+    msg_error(RelayCmd::EXTEND, body, tor_bytes::Error::ExtraneousBytes);
+}
+
 #[test]
 fn test_extended2() {
     let cmd = RelayCmd::EXTENDED2;
@@ -191,6 +214,14 @@ fn test_extended() {
     msg(cmd, body, &msg::Extended::new(handshake).into());
 }
 
+#[test]
+fn test_extended_trailing_bytes() {
+    // The extended body from test_extended, with extra bytes at the end.
+    let body = "2B079274DEB8B0A03F7BCCA65813FF557ECB6362C44BE4AC0374E5255540D2712ADBE0E858FD433DD2EB473D85D3C69A457DDE9B7F28E95833EDA57416B9409B68271FFF420F57C53EC1B823491C543C69D06A56A20AB95DD595EE2B16F1AAB24E6314E36D80DF76A67970263AC4902DE692A6AF2FE0B16DF6A9E9124675FAB94A4CF7D65D0F3EBA05682F9DC76A2C47DD3566B3
+                0102";
+    msg_error(RelayCmd::EXTENDED, body, tor_bytes::Error::ExtraneousBytes);
+}
+
 #[test]
 fn test_truncated() {
     let cmd = RelayCmd::TRUNCATED;
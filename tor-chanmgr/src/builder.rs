@@ -58,6 +58,14 @@ impl<R: Runtime> ChanBuilder<R> {
         use tor_proto::channel::ChannelBuilder;
         use tor_rtcompat::tls::CertifiedConn;
 
+        // 0. Make sure that we can reach the target at all.
+
+        if let Some(transport) = target.transport() {
+            // TODO: Once we can launch pluggable transports, hand the
+            // connection to this one instead.
+            return Err(Error::UnsupportedTransport(transport.into()));
+        }
+
         // 1. Negotiate the TLS connection.
 
         // TODO: This just uses the first address. Instead we could be smarter,
@@ -186,5 +194,20 @@ mod test {
         })
     }
 
+    #[test]
+    fn build_unsupported_transport() {
+        use crate::testing::msgs;
+        let orport: SocketAddr = msgs::ADDR.parse().unwrap();
+        let target = OwnedChanTarget::new(vec![orport], msgs::ED_ID.into(), msgs::RSA_ID.into())
+            .with_transport(Some("obfs4".into()));
+
+        test_with_one_runtime!(|rt| async move {
+            // We give up before touching the network.
+            let builder = ChanBuilder::new(MockNetwork::new().builder().runtime(rt));
+            let r = builder.build_channel(&target).await;
+            assert!(matches!(r, Err(crate::Error::UnsupportedTransport(t)) if t == "obfs4"));
+        })
+    }
+
     // TODO: Write tests for timeout logic, once there is smarter logic.
 }
@@ -11,6 +11,10 @@ pub enum Error {
     #[error("Target was unusable: {0}")]
     UnusableTarget(String),
 
+    /// A ChanTarget needed a pluggable transport that we don't support.
+    #[error("Unsupported pluggable transport: {0}")]
+    UnsupportedTransport(String),
+
     /// We were waiting on a pending channel, but it didn't succeed.
     #[error("Pending channel failed to launch")]
     PendingFailed,
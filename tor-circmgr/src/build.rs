@@ -1,15 +1,118 @@
 //! Facilities to build circuits directly, instead of via a circuit manager.
 
-use crate::path::{OwnedPath, TorPath};
-use crate::Result;
-use futures::task::SpawnExt;
+use crate::path::{weights::WeightOverrides, OwnedPath, TorPath};
+use crate::{Error, Result};
+use futures::future::RemoteHandle;
+use futures::task::{Spawn, SpawnExt};
+use futures::{Future, FutureExt};
+use log::debug;
+use rand::rngs::StdRng;
 use rand::{CryptoRng, Rng};
 use std::convert::TryInto;
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time::Duration;
 use tor_chanmgr::ChanMgr;
-use tor_proto::circuit::{CircParameters, ClientCirc};
-use tor_rtcompat::{Runtime, SleepProviderExt};
+use tor_linkspec::{ChanTarget, CircTarget, OwnedChanTarget};
+use tor_netdir::{NetDir, Relay};
+use tor_proto::channel::Channel;
+use tor_proto::circuit::{CircParameters, CircuitBuildId, ClientCirc};
+use tor_rtcompat::{Runtime, SleepProvider, SleepProviderExt};
+
+/// A map from zero-based hop index to a replacement ntor onion key for
+/// that hop.
+///
+/// Only available with the `testing` feature: see
+/// [`CircuitBuilder::build_with_onion_key_overrides`].
+#[cfg(feature = "testing")]
+pub type OnionKeyOverrides =
+    std::collections::HashMap<usize, tor_llcrypto::pk::curve25519::PublicKey>;
+
+/// Return true if we should extend a circuit to `relay` with the ntor v3
+/// handshake, rather than the original ntor handshake.
+///
+/// Relays that support the Relay=4 subprotocol can answer ntor v3.
+fn use_ntor_v3<Tg: CircTarget + ?Sized>(relay: &Tg) -> bool {
+    relay
+        .protovers()
+        .supports_known_subver(tor_protover::ProtoKind::Relay, 4)
+}
+
+/// Extend `circ` by one hop to `relay`, with the newest handshake that
+/// `relay` supports.
+///
+/// We don't send any ntor v3 extensions yet.
+async fn extend_circ<RNG, Tg>(
+    circ: &ClientCirc,
+    rng: &mut RNG,
+    relay: &Tg,
+    params: &CircParameters,
+) -> Result<()>
+where
+    RNG: CryptoRng + Rng,
+    Tg: CircTarget,
+{
+    if use_ntor_v3(relay) {
+        circ.extend_ntor_v3(rng, relay, params, &[]).await?;
+    } else {
+        circ.extend_ntor(rng, relay, params).await?;
+    }
+    Ok(())
+}
+
+/// Build a circuit along `path`, whose first hop is at the other end of
+/// `chan`, and run its reactor on `spawner`.
+///
+/// Record each hop that we finish building in `progress`, along with a
+/// handle to the circuit's reactor.
+async fn build_on_channel<S, RNG>(
+    spawner: &S,
+    chan: &Arc<Channel>,
+    path: &OwnedPath,
+    params: &CircParameters,
+    rng: &mut RNG,
+    progress: &Progress<Arc<ClientCirc>>,
+) -> Result<Arc<ClientCirc>>
+where
+    S: Spawn,
+    RNG: CryptoRng + Rng,
+{
+    let (pending_circ, reactor) = chan.new_circ(rng).await?;
+
+    progress.set_reactor(ReactorHandle::spawn(spawner, reactor.run())?);
+
+    match path {
+        OwnedPath::ChannelOnly(_) => {
+            let circ = pending_circ.create_firsthop_fast(rng, params).await?;
+            progress.record(&circ, 1);
+            Ok(circ)
+        }
+        OwnedPath::Normal(p) => {
+            assert!(!p.is_empty());
+            let circ = pending_circ
+                .create_firsthop_ntor(rng, &p[0], params)
+                .await?;
+            progress.record(&circ, 1);
+            for (idx, relay) in p[1..].iter().enumerate() {
+                extend_circ(&circ, rng, relay, params).await?;
+                progress.record(&circ, idx + 2);
+            }
+            Ok(circ)
+        }
+        OwnedPath::Bridged(bridge, p) => {
+            let circ = pending_circ
+                .create_firsthop_ntor(rng, bridge.target(), params)
+                .await?;
+            progress.record(&circ, 1);
+            for (idx, relay) in p.iter().enumerate() {
+                extend_circ(&circ, rng, relay, params).await?;
+                progress.record(&circ, idx + 2);
+            }
+            Ok(circ)
+        }
+    }
+}
 
 /// A factory object to build circuits.
 ///
@@ -23,59 +126,180 @@ pub struct CircuitBuilder<R: Runtime> {
     runtime: R,
     /// A channel manager that this circuit builder uses to make chanels.
     chanmgr: Arc<ChanMgr<R>>,
+    /// If present, a seeded random number generator to use for path
+    /// selection in place of the thread RNG.
+    ///
+    /// See [`CircuitBuilder::set_path_selection_seed`].
+    path_rng: Mutex<Option<StdRng>>,
+    /// Replacement weighting functions to use when picking relays for
+    /// some roles.
+    weights: WeightOverrides,
 }
 
 impl<R: Runtime> CircuitBuilder<R> {
     /// Construct a new [`CircuitBuilder`].
     pub fn new(runtime: R, chanmgr: Arc<ChanMgr<R>>) -> Self {
-        CircuitBuilder { runtime, chanmgr }
+        Self::new_with_weight_overrides(runtime, chanmgr, WeightOverrides::new())
+    }
+
+    /// Construct a new [`CircuitBuilder`] that uses `weights` in place
+    /// of the consensus weights, for the roles that it overrides, every
+    /// time it picks a path.
+    pub fn new_with_weight_overrides(
+        runtime: R,
+        chanmgr: Arc<ChanMgr<R>>,
+        weights: WeightOverrides,
+    ) -> Self {
+        CircuitBuilder {
+            runtime,
+            chanmgr,
+            path_rng: Mutex::new(None),
+            weights,
+        }
+    }
+
+    /// Return the weight overrides that we use for path selection.
+    pub(crate) fn weight_overrides(&self) -> &WeightOverrides {
+        &self.weights
+    }
+
+    /// Testing only: make every path that this builder plans from now on
+    /// use a random number generator seeded with `seed`.
+    ///
+    /// Given the same seed, the same network directory, and the same
+    /// sequence of requests, path selection will make the same choices,
+    /// so that a reported selection bug can be replayed.  (This only
+    /// holds within a single build of Arti: the seeded generator's
+    /// output may change between versions of its implementation.)
+    ///
+    /// Only path selection is reproducible.  Building the circuit still
+    /// uses fresh randomness for circuit IDs and handshake keys; that
+    /// randomness comes from a separate generator, so it doesn't disturb
+    /// the seeded sequence.
+    ///
+    /// Only available with the `testing` feature: predictable paths are
+    /// terrible for anonymity, so this must never be used in production.
+    #[cfg(feature = "testing")]
+    pub fn set_path_selection_seed(&self, seed: [u8; 32]) {
+        use rand::SeedableRng;
+        let mut path_rng = self.path_rng.lock().expect("poisoned lock");
+        *path_rng = Some(StdRng::from_seed(seed));
+    }
+
+    /// Call `f` with the random number generator that we should use for
+    /// path selection.
+    pub(crate) fn with_path_rng<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut dyn rand::RngCore) -> T,
+    {
+        let mut path_rng = self.path_rng.lock().expect("poisoned lock");
+        match path_rng.as_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut rand::thread_rng()),
+        }
     }
 
     /// Build a circuit, without performing any timeout operations.
+    ///
+    /// Record each hop that we finish building in `progress`, along with
+    /// a handle to the circuit's reactor.
     async fn build_notimeout<RNG: CryptoRng + Rng>(
         &self,
         path: &OwnedPath,
         params: &CircParameters,
         rng: &mut RNG,
+        progress: &Progress<Arc<ClientCirc>>,
     ) -> Result<Arc<ClientCirc>> {
-        let chan = self.chanmgr.get_or_launch(path.first_hop()?).await?;
-        let (pending_circ, reactor) = chan.new_circ(rng).await?;
-
-        self.runtime.spawn(async {
-            let _ = reactor.run().await;
-        })?;
-
-        match path {
-            OwnedPath::ChannelOnly(_) => {
-                let circ = pending_circ.create_firsthop_fast(rng, params).await?;
-                Ok(circ)
-            }
-            OwnedPath::Normal(p) => {
-                assert!(!p.is_empty());
-                let circ = pending_circ
-                    .create_firsthop_ntor(rng, &p[0], params)
-                    .await?;
-                for relay in p[1..].iter() {
-                    circ.extend_ntor(rng, relay, params).await?;
-                }
-                Ok(circ)
-            }
+        let (params, build_id) = params_with_build_id(params);
+        debug!("{}: Building a circuit", build_id);
+        let result = self
+            .build_notimeout_inner(path, &params, rng, progress, build_id)
+            .await;
+        match &result {
+            Ok(circ) => debug!("{}: Built {}", build_id, circ.unique_id()),
+            Err(e) => debug!("{}: Circuit build failed: {}", build_id, e),
         }
+        result
+    }
+
+    /// Helper for build_notimeout: does everything but the logging of
+    /// the outcome.
+    async fn build_notimeout_inner<RNG: CryptoRng + Rng>(
+        &self,
+        path: &OwnedPath,
+        params: &CircParameters,
+        rng: &mut RNG,
+        progress: &Progress<Arc<ClientCirc>>,
+        build_id: CircuitBuildId,
+    ) -> Result<Arc<ClientCirc>> {
+        let chan = self.chanmgr.get_or_launch(path.first_hop()?).await?;
+        debug!("{}: Got a channel to the first hop", build_id);
+        build_on_channel(&self.runtime, &chan, path, params, rng, progress).await
     }
 
-    /// Build a circuit from an [`OwnedPath`].
+    /// Build a circuit from an [`OwnedPath`], and leave its reactor to
+    /// run on its own.
     pub(crate) async fn build_owned<RNG: CryptoRng + Rng>(
         &self,
         path: &OwnedPath,
         params: &CircParameters,
         rng: &mut RNG,
     ) -> Result<Arc<ClientCirc>> {
+        let (circuit, reactor) = self.build_owned_with_reactor(path, params, rng).await?;
+        reactor.detach();
+        Ok(circuit)
+    }
+
+    /// Build a circuit from an [`OwnedPath`], and return it along with a
+    /// handle to its reactor.
+    ///
+    /// If the build fails, we stop the reactor.
+    async fn build_owned_with_reactor<RNG: CryptoRng + Rng>(
+        &self,
+        path: &OwnedPath,
+        params: &CircParameters,
+        rng: &mut RNG,
+    ) -> Result<(Arc<ClientCirc>, ReactorHandle)> {
         let delay = Duration::from_secs(5); // TODO: make this configurable and inferred.
 
-        let build_future = self.build_notimeout(path, params, rng);
+        let progress = Progress::new();
+        let build_future = self.build_notimeout(path, params, rng, &progress);
         let circuit = self.runtime.timeout(delay, build_future).await??;
+        let reactor = progress
+            .take_reactor()
+            .ok_or_else(|| Error::Internal("Built a circuit without a reactor".into()))?;
 
-        Ok(circuit)
+        Ok((circuit, reactor))
+    }
+
+    /// As [`CircuitBuilder::build`], but if the build times out after
+    /// building one or more hops, return those hops as a
+    /// [`PartialCircuit`] instead of discarding them.
+    ///
+    /// If the build times out before the first hop is built, or fails
+    /// for any other reason, return an error as `build` would.
+    pub async fn build_keeping_partial<RNG: CryptoRng + Rng>(
+        &self,
+        path: &TorPath<'_>,
+        params: &CircParameters,
+        rng: &mut RNG,
+    ) -> Result<BuildOutcome> {
+        let owned: OwnedPath = path.try_into()?;
+        let params = params_for_path(path, params);
+        let delay = Duration::from_secs(5); // TODO: make this configurable and inferred.
+
+        let progress = Progress::new();
+        let build_future = self.build_notimeout(&owned, &params, rng, &progress);
+        let outcome =
+            timeout_keeping_progress(&self.runtime, delay, &progress, build_future).await?;
+        // Whatever we built needs its reactor to keep running.
+        if let Some(reactor) = progress.take_reactor() {
+            reactor.detach();
+        }
+        match outcome {
+            Ok(circ) => Ok(BuildOutcome::Complete(circ)),
+            Err((circ, n_hops)) => Ok(BuildOutcome::Partial(PartialCircuit { circ, n_hops })),
+        }
     }
 
     /// Try to construct a new circuit from a given path, using appropriate
@@ -84,6 +308,16 @@ impl<R: Runtime> CircuitBuilder<R> {
     /// This circuit is _not_ automatically registered with any
     /// circuit manager; if you don't hang on it it, it will
     /// automatically go away when the last reference is dropped.
+    ///
+    /// If `path` is a measurement path (see
+    /// [`TorPath::measures_stream_bytes`]), the circuit counts the bytes
+    /// on each of its streams, whatever `params` says.
+    ///
+    /// Every build has a [`CircuitBuildId`], which we include in our log
+    /// messages and which the circuit includes in its own log messages
+    /// and flow-control events.  To choose it, set it in `params` with
+    /// [`CircParameters::set_build_id`]; otherwise we generate a new
+    /// one, which [`ClientCirc::build_id`] returns.
     pub async fn build<RNG: CryptoRng + Rng>(
         &self,
         path: &TorPath<'_>,
@@ -91,6 +325,526 @@ impl<R: Runtime> CircuitBuilder<R> {
         rng: &mut RNG,
     ) -> Result<Arc<ClientCirc>> {
         let owned = path.try_into()?;
-        self.build_owned(&owned, params, rng).await
+        let params = params_for_path(path, params);
+        self.build_owned(&owned, &params, rng).await
+    }
+
+    /// As [`CircuitBuilder::build`], but instead of leaving the circuit's
+    /// reactor to run on its own, return a [`ReactorHandle`] for it along
+    /// with the circuit.
+    ///
+    /// The reactor runs on our runtime either way.  Await the handle to
+    /// learn when and why the reactor stops (after which the circuit is
+    /// closed), or drop it to stop the reactor and close the circuit: for
+    /// example, when shutting down.
+    pub async fn build_with_reactor<RNG: CryptoRng + Rng>(
+        &self,
+        path: &TorPath<'_>,
+        params: &CircParameters,
+        rng: &mut RNG,
+    ) -> Result<(Arc<ClientCirc>, ReactorHandle)> {
+        let owned = path.try_into()?;
+        let params = params_for_path(path, params);
+        self.build_owned_with_reactor(&owned, &params, rng).await
+    }
+
+    /// Extend `circ` by one hop, to `exit`.
+    ///
+    /// Before extending, make sure that `exit` isn't in the same family
+    /// as any of the circuit's existing hops (according to `netdir`), in
+    /// addition to the checks that [`ClientCirc::extend_to_exit`] makes
+    /// itself.  On a conflict, return an error without extending the
+    /// circuit.
+    pub async fn extend_to_exit<RNG: CryptoRng + Rng>(
+        &self,
+        circ: &ClientCirc,
+        netdir: &NetDir,
+        exit: &Relay<'_>,
+        params: &CircParameters,
+        rng: &mut RNG,
+    ) -> Result<()> {
+        check_exit_family(netdir, &circ.path().await[..], exit)?;
+        circ.extend_to_exit(rng, exit, params).await?;
+        Ok(())
+    }
+
+    /// Testing only: as [`CircuitBuilder::build`], but use the onion keys
+    /// in `overrides` in place of the real onion keys of the
+    /// corresponding hops.
+    ///
+    /// This lets tests deterministically exercise the code paths for
+    /// handshake failures and stale onion keys.  It is only available
+    /// with the `testing` feature, and must never be used in production.
+    #[cfg(feature = "testing")]
+    pub async fn build_with_onion_key_overrides<RNG: CryptoRng + Rng>(
+        &self,
+        path: &TorPath<'_>,
+        params: &CircParameters,
+        rng: &mut RNG,
+        overrides: &OnionKeyOverrides,
+    ) -> Result<Arc<ClientCirc>> {
+        let owned: OwnedPath = path.try_into()?;
+        let owned = owned.with_onion_key_overrides(overrides)?;
+        let params = params_for_path(path, params);
+        self.build_owned(&owned, &params, rng).await
+    }
+}
+
+/// The result of [`CircuitBuilder::build_keeping_partial`].
+#[non_exhaustive]
+pub enum BuildOutcome {
+    /// We built the whole circuit.
+    Complete(Arc<ClientCirc>),
+    /// We ran out of time, but built some of the circuit's hops.
+    Partial(PartialCircuit),
+}
+
+/// A circuit whose build timed out after one or more of its hops were
+/// built.
+///
+/// The hops that we built are usable as a shorter circuit.  Be careful
+/// about extending it, though: the last hop may have received our
+/// EXTEND2 cell for the hop that timed out, and relays refuse to extend
+/// a circuit a second time.
+pub struct PartialCircuit {
+    /// The circuit, as far as we built it.
+    circ: Arc<ClientCirc>,
+    /// The number of hops that we built.
+    n_hops: usize,
+}
+
+impl PartialCircuit {
+    /// Return the circuit, as far as we built it.
+    pub fn circ(&self) -> &Arc<ClientCirc> {
+        &self.circ
+    }
+
+    /// Return the number of hops that we built.
+    pub fn n_hops(&self) -> usize {
+        self.n_hops
+    }
+
+    /// Consume this PartialCircuit and return its circuit.
+    pub fn into_circ(self) -> Arc<ClientCirc> {
+        self.circ
+    }
+}
+
+/// A handle to the task that runs a circuit's reactor.
+///
+/// A circuit's reactor handles every cell that arrives on it; once the
+/// reactor stops, the circuit is closed.  This handle is a future that
+/// resolves to the reactor's result when it stops.  Dropping the handle
+/// stops the reactor (and so closes the circuit), unless you call
+/// [`ReactorHandle::detach`] first.
+///
+/// Returned by [`CircuitBuilder::build_with_reactor`].
+#[must_use = "Dropping a ReactorHandle closes its circuit."]
+pub struct ReactorHandle {
+    /// A handle to the output of the reactor's task.
+    handle: RemoteHandle<tor_proto::Result<()>>,
+}
+
+impl ReactorHandle {
+    /// Spawn `reactor` on `spawner`, and return a handle to it.
+    fn spawn<S, F>(spawner: &S, reactor: F) -> Result<Self>
+    where
+        S: Spawn,
+        F: Future<Output = tor_proto::Result<()>> + Send + 'static,
+    {
+        let (task, handle) = reactor.remote_handle();
+        spawner.spawn(task)?;
+        Ok(ReactorHandle { handle })
+    }
+
+    /// Let the reactor run for as long as its circuit lasts, without
+    /// anyone watching it.
+    pub fn detach(self) {
+        self.handle.forget();
+    }
+}
+
+impl Future for ReactorHandle {
+    type Output = tor_proto::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.handle).poll(cx)
+    }
+}
+
+/// A record of how far a circuit build has gotten, which outlives the
+/// build itself.
+struct Progress<C> {
+    /// The circuit and its number of built hops, once it has any.
+    built: Mutex<Option<(C, usize)>>,
+    /// A handle to the circuit's reactor, once it's running.
+    reactor: Mutex<Option<ReactorHandle>>,
+}
+
+impl<C: Clone> Progress<C> {
+    /// Construct a new Progress with no hops built.
+    fn new() -> Self {
+        Progress {
+            built: Mutex::new(None),
+            reactor: Mutex::new(None),
+        }
+    }
+
+    /// Note that the circuit's reactor is running, with handle `reactor`.
+    fn set_reactor(&self, reactor: ReactorHandle) {
+        *self.reactor.lock().expect("poisoned lock") = Some(reactor);
+    }
+
+    /// Remove and return the handle to the circuit's reactor, if it's
+    /// running.
+    fn take_reactor(&self) -> Option<ReactorHandle> {
+        self.reactor.lock().expect("poisoned lock").take()
+    }
+
+    /// Note that `circ` now has `n_hops` hops built.
+    fn record(&self, circ: &C, n_hops: usize) {
+        let mut built = self.built.lock().expect("poisoned lock");
+        *built = Some((circ.clone(), n_hops));
+    }
+
+    /// Remove and return the circuit and its number of built hops, if
+    /// any hops were built.
+    fn take(&self) -> Option<(C, usize)> {
+        self.built.lock().expect("poisoned lock").take()
+    }
+}
+
+/// Run `build_future` for no more than `delay`.
+///
+/// If it finishes, return its output as `Ok(Ok(_))`.  If it times out
+/// after recording some hops in `progress`, return the circuit and its
+/// number of built hops as `Ok(Err(_))`.  If it times out before
+/// building any hops, return [`Error::CircTimeout`].
+async fn timeout_keeping_progress<SP, C, F>(
+    runtime: &SP,
+    delay: Duration,
+    progress: &Progress<C>,
+    build_future: F,
+) -> Result<std::result::Result<C, (C, usize)>>
+where
+    SP: SleepProvider,
+    C: Clone,
+    F: Future<Output = Result<C>>,
+{
+    match runtime.timeout(delay, build_future).await {
+        Ok(outcome) => Ok(Ok(outcome?)),
+        Err(e) => match progress.take() {
+            Some(partial) => Ok(Err(partial)),
+            None => Err(e.into()),
+        },
+    }
+}
+
+/// Return the parameters to use when building a circuit from `path`,
+/// given that we would otherwise use `params`.
+///
+/// This turns on per-stream byte counting if `path` asks for it.
+pub(crate) fn params_for_path(path: &TorPath<'_>, params: &CircParameters) -> CircParameters {
+    let mut params = params.clone();
+    if path.measures_stream_bytes() {
+        params.set_count_stream_bytes(true);
+    }
+    params
+}
+
+/// Return a copy of `params` that has a build identifier, along with
+/// that identifier.
+///
+/// We keep the identifier from `params` if there is one, and generate a
+/// new one otherwise.
+fn params_with_build_id(params: &CircParameters) -> (CircParameters, CircuitBuildId) {
+    let mut params = params.clone();
+    let build_id = match params.build_id() {
+        Some(id) => id,
+        None => {
+            let id = CircuitBuildId::next_unique();
+            params.set_build_id(id);
+            id
+        }
+    };
+    (params, build_id)
+}
+
+/// Return an error if `exit` is in the same family as any hop in `path`.
+///
+/// Hops that we can't find in `netdir` (like bridges, or hops built with
+/// CREATE_FAST) are ignored.
+fn check_exit_family(
+    netdir: &NetDir,
+    path: &[Option<OwnedChanTarget>],
+    exit: &Relay<'_>,
+) -> Result<()> {
+    for (idx, hop) in path.iter().enumerate() {
+        let relay = hop
+            .as_ref()
+            .and_then(|t| netdir.relays().find(|r| r.id() == t.ed_identity()));
+        if let Some(relay) = relay {
+            if relay.in_same_family(exit) {
+                return Err(tor_proto::Error::HopConflict(format!(
+                    "hop {}: same family as an existing hop",
+                    idx
+                ))
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_await_test::async_test;
+    use tor_netdir::testnet;
+    use tor_rtmock::time::MockSleepProvider;
+
+    #[test]
+    fn handshake_choice() {
+        use tor_linkspec::OwnedCircTarget;
+        let target = |protos: &str| {
+            OwnedCircTarget::new(
+                OwnedChanTarget::new(
+                    vec!["127.0.0.1:9001".parse().unwrap()],
+                    [7; 32].into(),
+                    [7; 20].into(),
+                ),
+                [9; 32].into(),
+                protos.parse().unwrap(),
+            )
+        };
+        assert!(use_ntor_v3(&target("Relay=1-4")));
+        assert!(!use_ntor_v3(&target("Relay=1-3")));
+        assert!(!use_ntor_v3(&target("")));
+    }
+
+    #[test]
+    fn build_ids() {
+        // We generate an identifier if there isn't one...
+        let params = CircParameters::default();
+        let (p1, id1) = params_with_build_id(&params);
+        let (p2, id2) = params_with_build_id(&params);
+        assert_eq!(p1.build_id(), Some(id1));
+        assert_eq!(p2.build_id(), Some(id2));
+        assert_ne!(id1, id2);
+
+        // ... but we keep the caller's.
+        let mut params = CircParameters::default();
+        params.set_build_id(CircuitBuildId::new(7));
+        let (p3, id3) = params_with_build_id(&params);
+        assert_eq!(id3, CircuitBuildId::new(7));
+        assert_eq!(p3.build_id(), Some(id3));
+    }
+
+    /// An object that sets a flag when it's dropped.
+    struct DropFlag(Arc<std::sync::atomic::AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn reactor_handle() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            /// Wait (in real time) for `flag` to be set.
+            async fn wait_for_flag<SP: SleepProvider>(rt: &SP, flag: &AtomicBool) -> bool {
+                for _ in 0..1000 {
+                    if flag.load(Ordering::SeqCst) {
+                        return true;
+                    }
+                    rt.sleep(Duration::from_millis(1)).await;
+                }
+                false
+            }
+
+            // Awaiting the handle gives us the reactor's result.
+            let h =
+                ReactorHandle::spawn(&rt, async { Err(tor_proto::Error::CircuitClosed) }).unwrap();
+            assert!(matches!(h.await, Err(tor_proto::Error::CircuitClosed)));
+
+            // Dropping the handle stops the reactor.
+            let stopped = Arc::new(AtomicBool::new(false));
+            let flag = DropFlag(Arc::clone(&stopped));
+            let h = ReactorHandle::spawn(&rt, async move {
+                let _flag = flag;
+                futures::future::pending().await
+            })
+            .unwrap();
+            assert!(!stopped.load(Ordering::SeqCst));
+            drop(h);
+            assert!(wait_for_flag(&rt, &stopped).await);
+
+            // A detached reactor keeps running until it's done.
+            let finished = Arc::new(AtomicBool::new(false));
+            let flag = DropFlag(Arc::clone(&finished));
+            let (tx, rx) = futures::channel::oneshot::channel::<()>();
+            let h = ReactorHandle::spawn(&rt, async move {
+                let _flag = flag;
+                let _ = rx.await;
+                Ok(())
+            })
+            .unwrap();
+            h.detach();
+            rt.sleep(Duration::from_millis(10)).await;
+            assert!(!finished.load(Ordering::SeqCst));
+            tx.send(()).unwrap();
+            assert!(wait_for_flag(&rt, &finished).await);
+        });
+    }
+
+    #[async_test]
+    async fn timeout_partial() {
+        let sp = MockSleepProvider::new(std::time::SystemTime::now());
+        let delay = Duration::from_secs(5);
+
+        // The first two hops get built, but the third never finishes.
+        let progress = Progress::new();
+        let build = async {
+            progress.record(&"circ", 1);
+            progress.record(&"circ", 2);
+            futures::future::pending::<()>().await;
+            Ok("circ")
+        };
+        let (outcome, _) = futures::join!(
+            timeout_keeping_progress(&sp, delay, &progress, build),
+            sp.advance(Duration::from_secs(10))
+        );
+        assert_eq!(outcome.unwrap(), Err(("circ", 2)));
+
+        // Nothing gets built at all.
+        let progress = Progress::new();
+        let build = async {
+            futures::future::pending::<()>().await;
+            Ok("circ")
+        };
+        let (outcome, _) = futures::join!(
+            timeout_keeping_progress(&sp, delay, &progress, build),
+            sp.advance(Duration::from_secs(10))
+        );
+        assert!(matches!(outcome, Err(Error::CircTimeout)));
+
+        // Everything gets built in time.
+        let progress = Progress::new();
+        let build = async {
+            progress.record(&"circ", 1);
+            Ok("circ")
+        };
+        let outcome = timeout_keeping_progress(&sp, delay, &progress, build).await;
+        assert_eq!(outcome.unwrap(), Ok("circ"));
+
+        // The build fails for some other reason.
+        let progress = Progress::new();
+        let build = async {
+            progress.record(&"circ", 1);
+            Err(Error::PendingFailed)
+        };
+        let outcome = timeout_keeping_progress(&sp, delay, &progress, build).await;
+        assert!(matches!(outcome, Err(Error::PendingFailed)));
+    }
+
+    #[test]
+    fn timeout_on_final_extend() {
+        use tor_linkspec::OwnedCircTarget;
+        use tor_proto::channel::testing::fake_channel;
+
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let (chan, chan_reactor, mut relay) = fake_channel();
+            rt.spawn(chan_reactor.map(|_| ())).unwrap();
+
+            // The first hop is the fake relay, which answers our CREATE2
+            // but never answers the EXTEND2 for the second hop.
+            let second = testnet::construct_netdir()
+                .by_id(&[0x21; 32].into())
+                .map(|r| OwnedCircTarget::from_circ_target(&r))
+                .unwrap();
+            let first = relay.target().clone();
+            let path = OwnedPath::Normal(vec![first.clone(), second]);
+            let relay_fut = async {
+                relay.answer_create2().await.unwrap();
+                assert!(relay.ignore_cell().await);
+                relay
+            };
+
+            let progress = Progress::new();
+            let mut rng = rand::thread_rng();
+            let params = CircParameters::default();
+            let build = build_on_channel(&rt, &chan, &path, &params, &mut rng, &progress);
+            let delay = Duration::from_millis(500);
+            let (outcome, _relay) = futures::join!(
+                timeout_keeping_progress(&rt, delay, &progress, build),
+                relay_fut
+            );
+
+            let (circ, n_hops) = match outcome {
+                Ok(Err(partial)) => partial,
+                _ => panic!("Expected a partial circuit"),
+            };
+            assert_eq!(n_hops, 1);
+            let hops = circ.path().await;
+            assert_eq!(hops.len(), 1);
+            assert_eq!(hops[0].as_ref().unwrap().ed_identity(), first.ed_identity());
+            assert!(!circ.is_closing());
+        });
+    }
+
+    #[test]
+    fn exit_family() {
+        let netdir = testnet::construct_netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+        let path: Vec<_> = [0x20, 0x02]
+            .iter()
+            .map(|idx| Some(OwnedChanTarget::from_chan_target(&relay(*idx))))
+            .collect();
+
+        // In the test network, relays 2 and 3 are in the same family.
+        let e = check_exit_family(&netdir, &path[..], &relay(0x03)).unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Protocol(tor_proto::Error::HopConflict(_))
+        ));
+        assert_eq!(
+            e.to_string(),
+            "Problem building a circuit: conflicting hop: hop 1: same family as an existing hop"
+        );
+
+        assert!(check_exit_family(&netdir, &path[..], &relay(0x13)).is_ok());
+
+        // We don't look at hops that we can't identify.
+        let path = [None, path[1].clone()];
+        assert!(check_exit_family(&netdir, &path[..], &relay(0x21)).is_ok());
+        assert!(check_exit_family(&netdir, &path[..], &relay(0x03)).is_err());
+    }
+
+    #[test]
+    fn measurement_params() {
+        use crate::path::exitpath::ExitPathBuilder;
+        use crate::TargetPort;
+
+        let netdir = testnet::construct_netdir();
+        let mut rng = rand::thread_rng();
+        let params = CircParameters::default();
+
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .pick_path(&mut rng, (&netdir).into())
+            .unwrap();
+        assert!(!path.measures_stream_bytes());
+        assert!(!params_for_path(&path, &params).count_stream_bytes());
+
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .measure_stream_bytes(true)
+            .pick_path(&mut rng, (&netdir).into())
+            .unwrap();
+        assert!(path.measures_stream_bytes());
+        assert!(params_for_path(&path, &params).count_stream_bytes());
+        // The caller's parameters are untouched.
+        assert!(!params.count_stream_bytes());
     }
 }
@@ -44,13 +44,13 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
         usage: &TargetCircUsage,
         dir: DirInfo<'_>,
     ) -> Result<(Plan, SupportedCircUsage)> {
-        let mut rng = rand::thread_rng();
-        let (path, final_spec) = usage.build_path(&mut rng, dir)?;
+        let (path, final_spec) =
+            self.with_path_rng(|mut rng| usage.build_path(&mut rng, dir, self.weight_overrides()))?;
 
         let plan = Plan {
             final_spec: final_spec.clone(),
             path: (&path).try_into()?,
-            params: dir.circ_params(),
+            params: crate::build::params_for_path(&path, &dir.circ_params()),
         };
 
         Ok((plan, final_spec))
@@ -80,3 +80,50 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
         self.launch_parallelism(spec)
     }
 }
+
+#[cfg(all(test, feature = "testing"))]
+mod test {
+    use super::*;
+    use crate::build::CircuitBuilder;
+    use crate::mgr::AbstractCircBuilder;
+    use crate::TargetPort;
+    use tor_chanmgr::ChanMgr;
+    use tor_linkspec::ChanTarget;
+    use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+    /// Helper: return the identities of the relays in `plan`'s path.
+    fn path_ids(plan: &Plan) -> Vec<Ed25519Identity> {
+        match &plan.path {
+            OwnedPath::ChannelOnly(c) => vec![*c.ed_identity()],
+            OwnedPath::Normal(p) => p.iter().map(|r| *r.ed_identity()).collect(),
+            OwnedPath::Bridged(b, p) => std::iter::once(b.target())
+                .chain(p.iter())
+                .map(|r| *r.ed_identity())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn seeded_path_selection() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let netdir = tor_netdir::testnet::construct_netdir();
+            let dirinfo: DirInfo<'_> = (&netdir).into();
+            let usage = TargetCircUsage::Exit(vec![TargetPort::ipv4(80)]);
+            let chanmgr = Arc::new(ChanMgr::new(rt.clone()));
+
+            // Plan a bunch of paths with a builder seeded with `seed`.
+            let plan_paths = |seed: [u8; 32]| {
+                let builder = CircuitBuilder::new(rt.clone(), Arc::clone(&chanmgr));
+                builder.set_path_selection_seed(seed);
+                (0..20)
+                    .map(|_| path_ids(&builder.plan_circuit(&usage, dirinfo).unwrap().0))
+                    .collect::<Vec<_>>()
+            };
+
+            // The same seed gives the same paths; a different one doesn't.
+            let paths = plan_paths([7; 32]);
+            assert_eq!(paths, plan_paths([7; 32]));
+            assert_ne!(paths, plan_paths([8; 32]));
+        });
+    }
+}
@@ -62,7 +62,7 @@ pub mod path;
 mod usage;
 
 pub use err::Error;
-pub use usage::TargetPort;
+pub use usage::{ExitAddrPolicies, PortPolicySummary, TargetAddr, TargetPort};
 
 use usage::TargetCircUsage;
 
@@ -113,9 +113,17 @@ impl<'a> DirInfo<'a> {
         /// code.
         fn from_netparams(inp: &NetParameters) -> CircParameters {
             let mut p = CircParameters::default();
+            // This also bounds the window: it never grows past where it
+            // started.  (We leave the ordinary SENDME increment alone: no
+            // consensus parameter changes it.  The congestion control one
+            // limits what a hop may negotiate, and so ends up in that hop's
+            // window limits.)
             if let Err(e) = p.set_initial_send_window(inp.circuit_window.get() as u16) {
                 warn!("Invalid parameter in directory: {}", e);
             }
+            if let Err(e) = p.set_cc_sendme_increment(inp.cc_sendme_inc.get() as u8) {
+                warn!("Invalid parameter in directory: {}", e);
+            }
             p.set_extend_by_ed25519_id(inp.extend_by_ed25519_id.into());
             p
         }
@@ -143,11 +151,34 @@ pub struct CircMgr<R: Runtime> {
 impl<R: Runtime> CircMgr<R> {
     /// Construct a new circuit manager.
     pub fn new(runtime: R, chanmgr: Arc<ChanMgr<R>>) -> Self {
-        let builder = build::CircuitBuilder::new(runtime.clone(), chanmgr);
+        Self::new_with_weight_overrides(runtime, chanmgr, path::weights::WeightOverrides::new())
+    }
+
+    /// Construct a new circuit manager that uses `weights` in place of
+    /// the consensus weights, for the roles that it overrides, whenever
+    /// it picks relays for a path.
+    pub fn new_with_weight_overrides(
+        runtime: R,
+        chanmgr: Arc<ChanMgr<R>>,
+        weights: path::weights::WeightOverrides,
+    ) -> Self {
+        let builder =
+            build::CircuitBuilder::new_with_weight_overrides(runtime.clone(), chanmgr, weights);
         let mgr = mgr::AbstractCircMgr::new(builder, runtime);
         CircMgr { mgr: Arc::new(mgr) }
     }
 
+    /// Testing only: make all future path selection reproducible from
+    /// `seed`.
+    ///
+    /// See [`CircuitBuilder::set_path_selection_seed`](build::CircuitBuilder::set_path_selection_seed)
+    /// for what this does and does not make reproducible.  Only available
+    /// with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn set_path_selection_seed(&self, seed: [u8; 32]) {
+        self.mgr.peek_builder().set_path_selection_seed(seed);
+    }
+
     /// Return a circuit suitable for sending one-hop BEGINDIR streams,
     /// launching it if necessary.
     pub async fn get_or_launch_dir(&self, netdir: DirInfo<'_>) -> Result<Arc<ClientCirc>> {
@@ -552,11 +552,22 @@ impl<B: AbstractCircBuilder + 'static, R: Runtime> AbstractCircMgr<B, R> {
         }
     }
 
+    /// Return a reference to the builder that this manager uses.
+    #[cfg(feature = "testing")]
+    pub(crate) fn peek_builder(&self) -> &B {
+        &self.builder
+    }
+
     /// Return a circuit suitable for use with a given `usage`,
     /// creating that circuit if necessary, and restricting it
     /// under the assumption that it will be used for that spec.
     ///
     /// This is the primary entry point for AbstractCircMgr.
+    ///
+    /// Concurrent requests share work: if a pending circuit would
+    /// support `usage` (same isolation, and a superset of the ports), we
+    /// wait for it instead of launching another, and every request that
+    /// waited on it gets the same circuit.
     pub(crate) async fn get_or_launch(
         self: &Arc<Self>,
         usage: &<B::Spec as AbstractSpec>::Usage,
@@ -1009,6 +1020,8 @@ mod test {
     struct FakeBuilder<RT: Runtime> {
         runtime: RT,
         script: sync::Mutex<HashMap<FakeSpec, Vec<FakeOp>>>,
+        /// Number of circuits that build_circuit has returned.
+        n_built: AtomicUsize,
     }
 
     #[derive(Debug, Clone)]
@@ -1050,6 +1063,9 @@ mod test {
         async fn build_circuit(&self, plan: FakePlan) -> Result<(FakeSpec, Arc<FakeCirc>)> {
             let op = plan.op;
             self.runtime.sleep(FAKE_CIRC_DELAY).await;
+            if matches!(op, FakeOp::Succeed | FakeOp::WrongSpec(_)) {
+                self.n_built.fetch_add(1, atomic::Ordering::SeqCst);
+            }
             match op {
                 FakeOp::Succeed => Ok((plan.spec, Arc::new(FakeCirc { id: FakeId::next() }))),
                 FakeOp::WrongSpec(s) => Ok((s, Arc::new(FakeCirc { id: FakeId::next() }))),
@@ -1072,6 +1088,7 @@ mod test {
             FakeBuilder {
                 runtime: rt.clone(),
                 script: sync::Mutex::new(HashMap::new()),
+                n_built: AtomicUsize::new(0),
             }
         }
 
@@ -1307,6 +1324,34 @@ mod test {
         });
     }
 
+    #[test]
+    fn concurrent_identical() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = MockSleepRuntime::new(rt);
+            let builder = FakeBuilder::new(&rt);
+            let mgr = Arc::new(AbstractCircMgr::new(builder, rt.clone()));
+
+            // Two requests for the same isolation and ports, made at the
+            // same time: the second should wait for the first one's
+            // pending circuit rather than launching its own.
+            let spec1 = FakeSpec::new(vec![443_u16, 80]).isolated(3);
+            let spec2 = FakeSpec::new(vec![80_u16, 443]).isolated(3);
+            let (c1, c2) = wait_for(
+                &rt,
+                futures::future::join(
+                    mgr.get_or_launch(&spec1, di()),
+                    mgr.get_or_launch(&spec2, di()),
+                ),
+            )
+            .await;
+
+            let c1 = c1.unwrap();
+            let c2 = c2.unwrap();
+            assert!(Arc::ptr_eq(&c1, &c2));
+            assert_eq!(mgr.builder.n_built.load(atomic::Ordering::SeqCst), 1);
+        });
+    }
+
     #[test]
     fn isolated() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -3,21 +3,34 @@
 //! TODO: I'm not sure this belongs in circmgr, but this is the best place
 //! I can think of for now.  I'm also not sure this should be public.
 
+pub mod asn;
 pub mod dirpath;
 pub mod exitpath;
+pub mod geoip;
+pub mod guard;
+pub mod stale;
+pub mod subnet;
+pub mod version;
+pub mod weights;
 
-use tor_linkspec::{ChanTarget, OwnedChanTarget, OwnedCircTarget};
+use tor_linkspec::{ChanTarget, CircTarget, OwnedChanTarget, OwnedCircTarget};
+use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
 use tor_netdir::{fallback::FallbackDir, Relay};
+use tor_protover::ProtoKind;
 
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 
-use crate::usage::ExitPolicy;
+use crate::usage::{ExitPolicy, PortPolicySummary};
 use crate::{Error, Result};
 
 /// A list of Tor relays through the network.
 pub struct TorPath<'a> {
     /// The inner TorPath state.
     inner: TorPathInner<'a>,
+    /// If true, circuits built from this path should count the bytes
+    /// sent and received on each stream.
+    measure_stream_bytes: bool,
 }
 
 /// Non-public helper type to repersent the different kinds of Tor path.
@@ -32,6 +45,53 @@ enum TorPathInner<'a> {
     FallbackOneHop(&'a FallbackDir),
     /// A multi-hop path, containing one or more relays.
     Path(Vec<Relay<'a>>),
+    /// A multi-hop path whose first hop is a bridge, followed by zero or
+    /// more relays from the network directory.
+    BridgeEntry(Box<Bridge>, Vec<Relay<'a>>),
+}
+
+/// A bridge: a relay that isn't listed in the network directory, which we
+/// can use as the first hop of a path.
+///
+/// As a [`ChanTarget`], a bridge reports its pluggable transport, so that
+/// the channel manager knows how to reach it.  (The channel manager can't
+/// launch channels over pluggable transports yet, so building a circuit
+/// through a bridge that needs one fails.)
+#[derive(Clone, Debug)]
+pub struct Bridge {
+    /// The relay we connect to.
+    target: OwnedCircTarget,
+    /// The name of the pluggable transport we need in order to reach
+    /// this bridge, or None if we can connect to it directly.
+    transport: Option<String>,
+}
+
+impl Bridge {
+    /// Construct a new Bridge for `target`, reachable over the pluggable
+    /// transport called `transport` (or directly, if `transport` is None).
+    pub fn new(target: OwnedCircTarget, transport: Option<String>) -> Self {
+        Bridge { target, transport }
+    }
+
+    /// Return the relay that this bridge connects to.
+    pub fn target(&self) -> &OwnedCircTarget {
+        &self.target
+    }
+}
+
+impl ChanTarget for Bridge {
+    fn addrs(&self) -> &[SocketAddr] {
+        self.target.addrs()
+    }
+    fn ed_identity(&self) -> &Ed25519Identity {
+        self.target.ed_identity()
+    }
+    fn rsa_identity(&self) -> &RsaIdentity {
+        self.target.rsa_identity()
+    }
+    fn transport(&self) -> Option<&str> {
+        self.transport.as_deref()
+    }
 }
 
 impl<'a> TorPath<'a> {
@@ -40,6 +100,7 @@ impl<'a> TorPath<'a> {
     pub fn new_one_hop(relay: Relay<'a>) -> Self {
         Self {
             inner: TorPathInner::OneHop(relay),
+            measure_stream_bytes: false,
         }
     }
 
@@ -48,6 +109,7 @@ impl<'a> TorPath<'a> {
     pub fn new_fallback_one_hop(fallback_dir: &'a FallbackDir) -> Self {
         Self {
             inner: TorPathInner::FallbackOneHop(fallback_dir),
+            measure_stream_bytes: false,
         }
     }
 
@@ -55,14 +117,41 @@ impl<'a> TorPath<'a> {
     pub fn new_multihop(relays: impl IntoIterator<Item = Relay<'a>>) -> Self {
         Self {
             inner: TorPathInner::Path(relays.into_iter().collect()),
+            measure_stream_bytes: false,
         }
     }
 
+    /// Create a new multi-hop path whose first hop is `bridge`, followed
+    /// by a given number of ordered relays.
+    pub fn new_bridged(bridge: Bridge, relays: impl IntoIterator<Item = Relay<'a>>) -> Self {
+        Self {
+            inner: TorPathInner::BridgeEntry(Box::new(bridge), relays.into_iter().collect()),
+            measure_stream_bytes: false,
+        }
+    }
+
+    /// Return this path, marked as a measurement path (if `measure` is
+    /// true) or as an ordinary path.
+    pub(crate) fn with_stream_byte_measurement(mut self, measure: bool) -> Self {
+        self.measure_stream_bytes = measure;
+        self
+    }
+
+    /// Return true if circuits built from this path should count the
+    /// bytes of DATA sent and received on each stream.
+    ///
+    /// [`CircuitBuilder::build`](crate::build::CircuitBuilder::build)
+    /// turns on counting for such circuits; read the counts with
+    /// [`ClientCirc::stream_byte_counts`](tor_proto::circuit::ClientCirc::stream_byte_counts).
+    pub fn measures_stream_bytes(&self) -> bool {
+        self.measure_stream_bytes
+    }
+
     /// Return the final relay in this path, if this is a path for use
     /// with exit circuits.
     fn exit_relay(&self) -> Option<&Relay<'a>> {
         match &self.inner {
-            TorPathInner::Path(relays) if !relays.is_empty() => Some(&relays[relays.len() - 1]),
+            TorPathInner::Path(relays) | TorPathInner::BridgeEntry(_, relays) => relays.last(),
             _ => None,
         }
     }
@@ -73,6 +162,13 @@ impl<'a> TorPath<'a> {
         self.exit_relay().map(ExitPolicy::from_relay)
     }
 
+    /// Return a summary of the ports that the final relay in this path
+    /// allows exiting to, or None if this isn't a path for use with exit
+    /// circuits.
+    pub fn exit_port_summary(&self) -> Option<PortPolicySummary> {
+        self.exit_policy().map(|p| p.summary())
+    }
+
     /// Return the number of relays in this path.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
@@ -81,6 +177,80 @@ impl<'a> TorPath<'a> {
             OneHop(_) => 1,
             FallbackOneHop(_) => 1,
             Path(p) => p.len(),
+            BridgeEntry(_, p) => p.len() + 1,
+        }
+    }
+
+    /// Return true if this is a one-hop path for use with a directory
+    /// cache.
+    pub fn is_one_hop(&self) -> bool {
+        use TorPathInner::*;
+        match &self.inner {
+            OneHop(_) | FallbackOneHop(_) => true,
+            Path(_) | BridgeEntry(_, _) => false,
+        }
+    }
+
+    /// Return true if this is a multi-hop path.
+    ///
+    /// (A multi-hop path is one that was constructed with
+    /// [`TorPath::new_multihop`], even if it happens to contain only
+    /// a single relay.)
+    pub fn is_multihop(&self) -> bool {
+        !self.is_one_hop()
+    }
+
+    /// Return the addresses at which we could reach the first hop of
+    /// this path, or an empty list if the path is empty.
+    pub fn first_hop_addrs(&self) -> Vec<SocketAddr> {
+        use TorPathInner::*;
+        match &self.inner {
+            OneHop(r) => r.addrs().to_vec(),
+            FallbackOneHop(f) => f.addrs().to_vec(),
+            Path(p) => p.first().map(|r| r.addrs().to_vec()).unwrap_or_default(),
+            BridgeEntry(b, _) => b.target().addrs().to_vec(),
+        }
+    }
+
+    /// Return the Ed25519 and RSA identities of the first hop of this
+    /// path, so that we can check them against the channel we open to it.
+    ///
+    /// Return None if the path is empty.
+    pub fn first_hop_identities(&self) -> Option<(Ed25519Identity, RsaIdentity)> {
+        use TorPathInner::*;
+        let ids = |t: &dyn ChanTarget| (*t.ed_identity(), *t.rsa_identity());
+        match &self.inner {
+            OneHop(r) => Some(ids(r)),
+            FallbackOneHop(f) => Some(ids(*f)),
+            Path(p) => p.first().map(|r| ids(r)),
+            BridgeEntry(b, _) => Some(ids(b.target())),
+        }
+    }
+
+    /// Return true if every relay in this path supports version `ver` of
+    /// the subprotocol `proto`.
+    pub fn all_hops_support(&self, proto: ProtoKind, ver: u8) -> bool {
+        self.first_hop_lacking(proto, ver).is_none()
+    }
+
+    /// Return the (zero-based) index of the first relay in this path that
+    /// doesn't support version `ver` of the subprotocol `proto`, or None
+    /// if they all support it.
+    ///
+    /// We don't know which protocols a fallback directory supports, so
+    /// a fallback one-hop path always fails this check.
+    pub fn first_hop_lacking(&self, proto: ProtoKind, ver: u8) -> Option<usize> {
+        use TorPathInner::*;
+        let supports = |r: &Relay<'_>| r.protovers().supports_known_subver(proto, ver);
+        match &self.inner {
+            OneHop(r) if supports(r) => None,
+            OneHop(_) => Some(0),
+            FallbackOneHop(_) => Some(0),
+            Path(p) => p.iter().position(|r| !supports(r)),
+            BridgeEntry(b, _) if !b.target().protovers().supports_known_subver(proto, ver) => {
+                Some(0)
+            }
+            BridgeEntry(_, p) => p.iter().position(|r| !supports(r)).map(|idx| idx + 1),
         }
     }
 }
@@ -91,6 +261,9 @@ pub(crate) enum OwnedPath {
     ChannelOnly(OwnedChanTarget),
     /// A path of one or more hops created via normal Tor handshakes.
     Normal(Vec<OwnedCircTarget>),
+    /// A path whose first hop is a bridge, followed by zero or more hops,
+    /// all created via normal Tor handshakes.
+    Bridged(Bridge, Vec<OwnedCircTarget>),
 }
 
 impl<'a> TryFrom<&TorPath<'a>> for OwnedPath {
@@ -107,6 +280,10 @@ impl<'a> TryFrom<&TorPath<'a>> for OwnedPath {
             Path(_) => {
                 return Err(Error::NoRelays("Path with no entries!".into()));
             }
+            BridgeEntry(b, p) => OwnedPath::Bridged(
+                (**b).clone(),
+                p.iter().map(OwnedCircTarget::from_circ_target).collect(),
+            ),
         })
     }
 }
@@ -120,6 +297,99 @@ impl OwnedPath {
                 Err(Error::NoRelays("Path with no entries!".into()))
             }
             OwnedPath::Normal(p) => Ok(&p[0]),
+            OwnedPath::Bridged(b, _) => Ok(b),
+        }
+    }
+}
+
+/// A view of an [`OwnedCircTarget`] whose ntor onion key has been replaced.
+///
+/// Only available with the `testing` feature.
+#[cfg(feature = "testing")]
+struct OnionKeyOverride<'a> {
+    /// The target whose onion key we're replacing.
+    target: &'a OwnedCircTarget,
+    /// The onion key to use in place of the target's real one.
+    ntor_onion_key: tor_llcrypto::pk::curve25519::PublicKey,
+}
+
+#[cfg(feature = "testing")]
+impl<'a> ChanTarget for OnionKeyOverride<'a> {
+    fn addrs(&self) -> &[std::net::SocketAddr] {
+        self.target.addrs()
+    }
+    fn ed_identity(&self) -> &tor_llcrypto::pk::ed25519::Ed25519Identity {
+        self.target.ed_identity()
+    }
+    fn rsa_identity(&self) -> &tor_llcrypto::pk::rsa::RsaIdentity {
+        self.target.rsa_identity()
+    }
+    fn transport(&self) -> Option<&str> {
+        self.target.transport()
+    }
+}
+
+#[cfg(feature = "testing")]
+impl<'a> tor_linkspec::CircTarget for OnionKeyOverride<'a> {
+    fn ntor_onion_key(&self) -> &tor_llcrypto::pk::curve25519::PublicKey {
+        &self.ntor_onion_key
+    }
+    fn protovers(&self) -> &tor_protover::Protocols {
+        self.target.protovers()
+    }
+}
+
+#[cfg(feature = "testing")]
+impl OwnedPath {
+    /// Testing only: Return a copy of this path in which the ntor onion
+    /// key for each hop listed in `overrides` (by zero-based index) is
+    /// replaced with the provided key.
+    ///
+    /// Gives an error if `overrides` names a hop that doesn't exist, or
+    /// if this path doesn't use ntor handshakes at all.
+    pub(crate) fn with_onion_key_overrides(
+        &self,
+        overrides: &crate::build::OnionKeyOverrides,
+    ) -> Result<OwnedPath> {
+        let (bridge, hops) = match self {
+            OwnedPath::ChannelOnly(c) if overrides.is_empty() => {
+                return Ok(OwnedPath::ChannelOnly(c.clone()))
+            }
+            OwnedPath::ChannelOnly(_) => {
+                return Err(Error::Internal(
+                    "Tried to override onion key on a CREATE_FAST path".into(),
+                ))
+            }
+            OwnedPath::Normal(p) => (None, p),
+            OwnedPath::Bridged(b, p) => (Some(b), p),
+        };
+        let n_hops = hops.len() + usize::from(bridge.is_some());
+        if overrides.keys().any(|idx| *idx >= n_hops) {
+            return Err(Error::Internal(
+                "Tried to override onion key for a nonexistent hop".into(),
+            ));
+        }
+        let override_hop = |idx: usize, hop: &OwnedCircTarget| match overrides.get(&idx) {
+            Some(key) => OwnedCircTarget::from_circ_target(&OnionKeyOverride {
+                target: hop,
+                ntor_onion_key: *key,
+            }),
+            None => hop.clone(),
+        };
+        match bridge {
+            None => Ok(OwnedPath::Normal(
+                hops.iter()
+                    .enumerate()
+                    .map(|(idx, hop)| override_hop(idx, hop))
+                    .collect(),
+            )),
+            Some(b) => Ok(OwnedPath::Bridged(
+                Bridge::new(override_hop(0, b.target()), b.transport.clone()),
+                hops.iter()
+                    .enumerate()
+                    .map(|(idx, hop)| override_hop(idx + 1, hop))
+                    .collect(),
+            )),
         }
     }
 }
@@ -151,8 +421,121 @@ fn assert_same_path_when_owned(path: &TorPath<'_>) {
                 owned.first_hop().unwrap().ed_identity()
             );
         }
+        (OwnedPath::Bridged(b1, p1), TorPathInner::BridgeEntry(b2, p2)) => {
+            assert_eq!(b1.target().ed_identity(), b2.target().ed_identity());
+            assert_eq!(b1.transport(), b2.transport());
+            assert_eq!(b1.transport(), owned.first_hop().unwrap().transport());
+            assert_eq!(p1.len(), p2.len());
+            for (n1, n2) in p1.iter().zip(p2.iter()) {
+                assert_eq!(n1.ed_identity(), n2.ed_identity());
+            }
+            assert_eq!(
+                b1.target().ed_identity(),
+                owned.first_hop().unwrap().ed_identity()
+            );
+        }
         (_, _) => {
             panic!("Mismatched path types.")
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tor_netdir::testnet;
+
+    #[test]
+    fn protocol_support() {
+        let netdir = testnet::construct_netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+
+        // In the test network, only even-numbered relays are directory
+        // caches.
+        let good = TorPath::new_multihop(vec![relay(0x02), relay(0x14), relay(0x26)]);
+        assert!(good.all_hops_support(ProtoKind::DirCache, 2));
+        assert_eq!(good.first_hop_lacking(ProtoKind::DirCache, 2), None);
+
+        let bad = TorPath::new_multihop(vec![relay(0x02), relay(0x14), relay(0x27)]);
+        assert!(!bad.all_hops_support(ProtoKind::DirCache, 2));
+        assert_eq!(bad.first_hop_lacking(ProtoKind::DirCache, 2), Some(2));
+
+        let one_hop = TorPath::new_one_hop(relay(0x11));
+        assert_eq!(one_hop.first_hop_lacking(ProtoKind::DirCache, 2), Some(0));
+        let one_hop = TorPath::new_one_hop(relay(0x10));
+        assert!(one_hop.all_hops_support(ProtoKind::DirCache, 2));
+
+        // Nobody in the test network claims to support this.
+        assert_eq!(good.first_hop_lacking(ProtoKind::FlowCtrl, 1), Some(0));
+    }
+
+    #[test]
+    fn first_hop_addrs() {
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|idx| format!("10.{}.0.1:9001", idx).parse().unwrap())
+            .netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+
+        let guard = relay(0x20);
+        let path = TorPath::new_multihop(vec![guard.clone(), relay(0x02), relay(0x11)]);
+        assert_eq!(path.first_hop_addrs(), guard.addrs());
+        assert_eq!(
+            path.first_hop_addrs(),
+            vec!["10.32.0.1:9001".parse::<SocketAddr>().unwrap()]
+        );
+
+        let path = TorPath::new_one_hop(relay(0x10));
+        assert_eq!(path.first_hop_addrs(), relay(0x10).addrs());
+
+        let path = TorPath::new_multihop(vec![]);
+        assert!(path.first_hop_addrs().is_empty());
+    }
+
+    #[test]
+    fn first_hop_identities() {
+        let netdir = testnet::construct_netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+
+        let guard = relay(0x20);
+        let path = TorPath::new_multihop(vec![guard.clone(), relay(0x02), relay(0x11)]);
+        let (ed, rsa) = path.first_hop_identities().unwrap();
+        assert_eq!(&ed, guard.ed_identity());
+        assert_eq!(&rsa, guard.rsa_identity());
+        assert_eq!(ed, [0x20; 32].into());
+
+        let path = TorPath::new_one_hop(relay(0x10));
+        let (ed, rsa) = path.first_hop_identities().unwrap();
+        assert_eq!(&ed, relay(0x10).ed_identity());
+        assert_eq!(&rsa, relay(0x10).rsa_identity());
+
+        let bridge = Bridge::new(OwnedCircTarget::from_circ_target(&relay(0x05)), None);
+        let path = TorPath::new_bridged(bridge, vec![relay(0x02), relay(0x11)]);
+        let (ed, rsa) = path.first_hop_identities().unwrap();
+        assert_eq!(&ed, relay(0x05).ed_identity());
+        assert_eq!(&rsa, relay(0x05).rsa_identity());
+
+        let path = TorPath::new_multihop(vec![]);
+        assert!(path.first_hop_identities().is_none());
+    }
+
+    #[test]
+    fn exit_port_summary() {
+        let netdir = testnet::construct_netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+
+        // Odd-numbered exits in the test network allow only ports 80
+        // and 443.
+        let exit = relay(0x11);
+        let path = TorPath::new_multihop(vec![relay(0x20), relay(0x02), exit.clone()]);
+        let summary = path.exit_port_summary().unwrap();
+        assert_eq!(summary.ipv4(), &**exit.ipv4_policy());
+        assert_eq!(summary.ipv6(), &**exit.ipv6_policy());
+        assert_eq!(summary.ipv4().to_string(), "accept 80,443");
+        assert!(summary
+            .to_string()
+            .starts_with("IPv4: accept 80,443; IPv6: "));
+
+        let path = TorPath::new_one_hop(relay(0x11));
+        assert!(path.exit_port_summary().is_none());
+    }
+}
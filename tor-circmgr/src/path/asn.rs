@@ -0,0 +1,62 @@
+//! Autonomous system lookups for relays, for use in path selection.
+//!
+//! Relays in the same autonomous system (AS) share a network operator,
+//! who could watch traffic entering and leaving the Tor network at both
+//! ends of a path.  Arti doesn't ship an AS database of its own: callers
+//! who want AS-aware path selection need to supply one, by implementing
+//! [`AsnLookup`].
+
+use std::fmt;
+use std::net::IpAddr;
+use tor_linkspec::ChanTarget;
+use tor_netdir::Relay;
+
+/// An autonomous system number, like AS64496.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Asn(u32);
+
+impl Asn {
+    /// Construct a new Asn from its number.
+    pub fn new(asn: u32) -> Self {
+        Asn(asn)
+    }
+
+    /// Return the number of this AS.
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Asn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AS{}", self.0)
+    }
+}
+
+/// A caller-supplied source of autonomous system information for IP
+/// addresses.
+pub trait AsnLookup: Send + Sync {
+    /// Return the AS that announces `addr`, if known.
+    fn asn_for_addr(&self, addr: IpAddr) -> Option<Asn>;
+
+    /// Return the AS in which `relay` is located, if known.
+    ///
+    /// By default, this is the AS of the first of the relay's addresses
+    /// that has a known AS.
+    fn asn_for_relay(&self, relay: &Relay<'_>) -> Option<Asn> {
+        relay.addrs().iter().find_map(|a| self.asn_for_addr(a.ip()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn asn() {
+        let a = Asn::new(64496);
+        assert_eq!(a.get(), 64496);
+        assert_eq!(a.to_string(), "AS64496");
+        assert!(a < Asn::new(64497));
+    }
+}
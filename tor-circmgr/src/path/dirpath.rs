@@ -1,4 +1,5 @@
 //! Code to construct paths to a directory for non-anonymous downloads
+use super::weights::WeightOverrides;
 use super::TorPath;
 use crate::{DirInfo, Error, Result};
 use tor_netdir::{Relay, WeightRole};
@@ -7,7 +8,10 @@ use rand::{seq::SliceRandom, Rng};
 
 /// A PathBuilder that can connect to a directory.
 #[non_exhaustive]
-pub struct DirPathBuilder {}
+pub struct DirPathBuilder {
+    /// Replacement weighting functions for some roles, if any.
+    weights: WeightOverrides,
+}
 
 impl Default for DirPathBuilder {
     fn default() -> Self {
@@ -18,7 +22,16 @@ impl Default for DirPathBuilder {
 impl DirPathBuilder {
     /// Create a new DirPathBuilder.
     pub fn new() -> Self {
-        DirPathBuilder {}
+        DirPathBuilder {
+            weights: WeightOverrides::new(),
+        }
+    }
+
+    /// Use `weights` in place of the consensus weights for the roles
+    /// that it overrides.
+    pub fn weight_overrides(&mut self, weights: WeightOverrides) -> &mut Self {
+        self.weights = weights;
+        self
     }
 
     /// Try to create and return a path corresponding to the requirements of
@@ -33,7 +46,9 @@ impl DirPathBuilder {
                 }
             }
             DirInfo::Directory(netdir) => {
-                let relay = netdir.pick_relay(rng, WeightRole::BeginDir, Relay::is_dir_cache);
+                let relay =
+                    self.weights
+                        .pick_relay(netdir, rng, WeightRole::BeginDir, Relay::is_dir_cache);
                 if let Some(r) = relay {
                     return Ok(TorPath::new_one_hop(r));
                 }
@@ -63,6 +78,8 @@ mod test {
             let p = p.unwrap();
             assert!(p.exit_relay().is_none());
             assert_eq!(p.len(), 1);
+            assert!(p.is_one_hop());
+            assert!(!p.is_multihop());
             assert_same_path_when_owned(&p);
             if let crate::path::TorPathInner::OneHop(r) = p.inner {
                 assert!(r.is_dir_cache());
@@ -96,6 +113,8 @@ mod test {
             let p = p.unwrap();
             assert!(p.exit_relay().is_none());
             assert_eq!(p.len(), 1);
+            assert!(p.is_one_hop());
+            assert!(!p.is_multihop());
             assert_same_path_when_owned(&p);
 
             if let crate::path::TorPathInner::FallbackOneHop(f) = p.inner {
@@ -1,51 +1,1033 @@
 //! Code for building paths to an exit relay.
 
-use super::TorPath;
-use crate::{DirInfo, Error, Result, TargetPort};
+use super::asn::{Asn, AsnLookup};
+use super::geoip::{CountryCode, GeoIp};
+use super::guard::GuardSet;
+use super::subnet::{HopPosition, SubnetDiversity};
+use super::version::VersionSpec;
+use super::weights::WeightOverrides;
+use super::{Bridge, TorPath, TorPathInner};
+use crate::{DirInfo, Error, ExitAddrPolicies, Result, TargetAddr, TargetPort};
+use log::{info, trace};
 use rand::Rng;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tor_linkspec::{ChanTarget, CircTarget};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
 use tor_netdir::{NetDir, Relay, WeightRole};
+use tor_netdoc::doc::netstatus::RelayFlags;
+use tor_netdoc::types::policy::{AddrPolicy, PortRange};
+use tor_protover::Protocols;
+
+/// How much more likely we are to pick an exit in the preferred country
+/// than its bandwidth alone would suggest.
+const PREFERRED_COUNTRY_FACTOR: u64 = 10;
+
+/// Timing information about how long it took to select the relays for
+/// a path.
+///
+/// This only covers choosing the relays: it doesn't include any of the
+/// time spent actually building a circuit along the path.
+#[derive(Clone, Debug, Default)]
+pub struct PathSelectionMetrics {
+    /// Time spent choosing the exit relay.
+    exit: Duration,
+    /// Time spent choosing the middle relay.
+    middle: Duration,
+    /// Time spent choosing the entry relay, or None if we didn't choose
+    /// one (because we're using a bridge).
+    entry: Option<Duration>,
+    /// Time spent on path selection as a whole.
+    total: Duration,
+}
+
+impl PathSelectionMetrics {
+    /// Return the time spent choosing the exit relay.
+    pub fn exit(&self) -> Duration {
+        self.exit
+    }
+    /// Return the time spent choosing the middle relay.
+    pub fn middle(&self) -> Duration {
+        self.middle
+    }
+    /// Return the time spent choosing the entry relay, or None if the
+    /// path starts at a bridge.
+    pub fn entry(&self) -> Option<Duration> {
+        self.entry
+    }
+    /// Return the time spent on path selection as a whole.
+    ///
+    /// This is at least the sum of the per-hop times.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+}
+
+/// How far an [`ExitPathBuilder`] may relax its diversity requirements
+/// when it can't find a path that meets all of them.
+///
+/// Each level includes the relaxations of the levels before it.  We
+/// only move to a level when we've failed to find a path at every
+/// stricter level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum RelaxedDiversity {
+    /// Never relax anything.  This is the default.
+    #[default]
+    Strict,
+    /// If necessary, ignore our subnet diversity rules.
+    Subnet,
+    /// If necessary, ignore our subnet diversity rules, and then allow
+    /// relays in the same family too.  We still never use the same
+    /// relay twice.
+    Family,
+}
+
+impl RelaxedDiversity {
+    /// Return the next more relaxed level after this one, if any.
+    fn next(self) -> Option<Self> {
+        match self {
+            RelaxedDiversity::Strict => Some(RelaxedDiversity::Subnet),
+            RelaxedDiversity::Subnet => Some(RelaxedDiversity::Family),
+            RelaxedDiversity::Family => None,
+        }
+    }
+}
+
+/// Settings for keeping the hops of a path in different countries.
+#[derive(Clone, Default)]
+struct GeoDiversity {
+    /// Where to look up relays' countries, or None if we aren't
+    /// enforcing geographic diversity.
+    geoip: Option<Arc<dyn GeoIp>>,
+    /// If true, the middle relay must also be in a different country
+    /// from the other hops.
+    include_middle: bool,
+    /// If true, we may use relays whose country we don't know; if false,
+    /// we exclude them.
+    allow_unknown: bool,
+}
+
+/// Settings for keeping the hops of a path in different autonomous
+/// systems.
+#[derive(Clone, Default)]
+struct AsDiversity {
+    /// Where to look up relays' autonomous systems, or None if we aren't
+    /// enforcing AS diversity.
+    lookup: Option<Arc<dyn AsnLookup>>,
+    /// If true, the middle relay must also be in a different AS from
+    /// the other hops.
+    include_middle: bool,
+    /// If true, we may use relays whose AS we don't know; if false, we
+    /// exclude them.
+    allow_unknown: bool,
+}
+
+/// An [`ExitAddrPolicies`] that doesn't know any relay's address policy.
+struct NoAddrPolicies;
+
+impl ExitAddrPolicies for NoAddrPolicies {
+    fn addr_policy(&self, _relay: &Relay<'_>) -> Option<Arc<AddrPolicy>> {
+        None
+    }
+}
 
 /// Internal representation of PathBuilder.
+#[derive(Clone)]
 enum ExitPathBuilderInner<'a> {
     /// Request a path that allows exit to the given TargetPort's.
     WantsPorts(Vec<TargetPort>),
 
+    /// Request a path that allows exit to the given TargetAddr's,
+    /// checking exits' complete address policies where we know them.
+    WantsAddrs(Vec<TargetAddr>, Arc<dyn ExitAddrPolicies>),
+
     /// Request a path that uses a given relay as exit node.
     ChosenExit(Relay<'a>),
 }
 
 /// A PathBuilder that builds a path to an exit relay supporting a given
 /// set of ports.
+#[derive(Clone)]
 pub struct ExitPathBuilder<'a> {
     /// The inner ExitPathBuilder state.
     inner: ExitPathBuilderInner<'a>,
+    /// Identities of the relays run by this client's own operator.
+    ///
+    /// No relay with one of these identities will be used for any hop
+    /// in the path.
+    own_relays: HashSet<Ed25519Identity>,
+    /// Identities of relays that the caller has told us to avoid, such as
+    /// the ones from a circuit that just failed.
+    ///
+    /// No relay with one of these identities will be used for any hop
+    /// in the path.
+    avoided_relays: HashSet<Ed25519Identity>,
+    /// A source of country information for relays, if we have one.
+    geoip: Option<Arc<dyn GeoIp>>,
+    /// A country in which we'd like our exit to be, if any.
+    preferred_exit_country: Option<CountryCode>,
+    /// A bridge to use as the first hop of the path, in place of a
+    /// relay from the network directory.
+    bridge: Option<Bridge>,
+    /// The guard that we use as the first hop of the path, if we're
+    /// using one.
+    guards: Option<Arc<GuardSet>>,
+    /// Our requirements, if any, for putting the hops of the path in
+    /// different countries.
+    geo_diversity: GeoDiversity,
+    /// Our requirements, if any, for putting the hops of the path in
+    /// different autonomous systems.
+    as_diversity: AsDiversity,
+    /// For each pair of hops, the subnets (if any) that those hops must
+    /// not share.
+    ///
+    /// Indexed by [`HopPosition::pair_index`].
+    subnet_diversity: [Option<SubnetDiversity>; HopPosition::N_PAIRS],
+    /// If present, protocol versions that every hop must support.
+    required_protocols: Option<Protocols>,
+    /// Patterns for software versions that no relay in the path may
+    /// advertise.
+    excluded_versions: Vec<VersionSpec>,
+    /// How far we're allowed to relax our diversity requirements.
+    max_relaxation: RelaxedDiversity,
+    /// How far we've relaxed our diversity requirements for the current
+    /// attempt at building a path.
+    relaxation: RelaxedDiversity,
+    /// Replacement weighting functions for some roles, if any.
+    weights: WeightOverrides,
+    /// A set of flags, and how much more likely we are to pick a relay
+    /// that has all of them.
+    preferred_flags: Option<(RelayFlags, f64)>,
+    /// The fraction of our candidate exits, heaviest first, that we
+    /// refuse to pick.
+    avoid_top_fraction: Option<f64>,
+    /// If true, circuits built from our paths should count the bytes
+    /// sent and received on each stream.
+    measure_stream_bytes: bool,
+    /// Relays to use as the middle hops of the path, in order.  If this
+    /// is empty, we pick `n_middles` middle relays ourselves.
+    fixed_middles: Vec<Relay<'a>>,
+    /// The number of middle relays to pick, if we aren't using
+    /// `fixed_middles`.
+    n_middles: usize,
 }
 
 impl<'a> ExitPathBuilder<'a> {
+    /// Create a new builder for `inner`, with every other option at its
+    /// default.
+    fn with_inner(inner: ExitPathBuilderInner<'a>) -> Self {
+        Self {
+            inner,
+            own_relays: HashSet::new(),
+            avoided_relays: HashSet::new(),
+            geoip: None,
+            preferred_exit_country: None,
+            bridge: None,
+            guards: None,
+            geo_diversity: GeoDiversity::default(),
+            as_diversity: AsDiversity::default(),
+            subnet_diversity: [Some(SubnetDiversity::default()); HopPosition::N_PAIRS],
+            required_protocols: None,
+            excluded_versions: Vec::new(),
+            max_relaxation: RelaxedDiversity::Strict,
+            relaxation: RelaxedDiversity::Strict,
+            weights: WeightOverrides::new(),
+            preferred_flags: None,
+            avoid_top_fraction: None,
+            measure_stream_bytes: false,
+            fixed_middles: Vec::new(),
+            n_middles: 1,
+        }
+    }
+
     /// Create a new builder that will try to get an exit relay
     /// containing all the ports in `ports`.
     pub fn from_target_ports(wantports: impl IntoIterator<Item = TargetPort>) -> Self {
-        Self {
-            inner: ExitPathBuilderInner::WantsPorts(wantports.into_iter().collect()),
-        }
+        Self::with_inner(ExitPathBuilderInner::WantsPorts(
+            wantports.into_iter().collect(),
+        ))
+    }
+
+    /// Create a new builder that will try to get an exit relay that
+    /// allows connections to every address and port in `wantaddrs`.
+    ///
+    /// We use `policies` to look up the complete address policy of each
+    /// candidate exit, so that we can avoid exits that reject one of
+    /// our addresses.  For exits whose complete policy `policies` doesn't
+    /// know, we only check the port.
+    pub fn from_target_addrs(
+        wantaddrs: impl IntoIterator<Item = TargetAddr>,
+        policies: Arc<dyn ExitAddrPolicies>,
+    ) -> Self {
+        Self::with_inner(ExitPathBuilderInner::WantsAddrs(
+            wantaddrs.into_iter().collect(),
+            policies,
+        ))
     }
 
     /// Create a new builder that will try to build a path with the given exit
     /// relay as the last hop.
     pub fn from_chosen_exit(exit_relay: Relay<'a>) -> Self {
-        Self {
-            inner: ExitPathBuilderInner::ChosenExit(exit_relay),
+        Self::with_inner(ExitPathBuilderInner::ChosenExit(exit_relay))
+    }
+
+    /// Create a new builder for a path to an exit relay that allows
+    /// connections to `port` on `host`.
+    ///
+    /// If `host` is an IPv4 or IPv6 address literal (optionally in
+    /// brackets, like "\[::1\]"), the exit must allow connections to that
+    /// address family and port, as with
+    /// [`ExitPathBuilder::from_target_addrs`].  We don't know any exit's
+    /// complete address policy here, so we only check the port: callers
+    /// who know those policies should use `from_target_addrs` instead.
+    ///
+    /// Otherwise, `host` is a hostname that the exit will resolve, and the
+    /// exit only has to allow `port` over IPv4.
+    pub fn for_target(host: &str, port: u16) -> Self {
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        match literal.parse::<IpAddr>() {
+            Ok(addr) => {
+                Self::from_target_addrs(vec![TargetAddr::new(addr, port)], Arc::new(NoAddrPolicies))
+            }
+            Err(_) => Self::from_target_ports(vec![TargetPort::ipv4(port)]),
+        }
+    }
+
+    /// Never use any relay whose Ed25519 identity is in `ids` for any
+    /// hop of the path.
+    ///
+    /// This is meant for clients whose operator also runs one or more
+    /// relays: we don't want those relays to show up in our own
+    /// circuits.
+    pub fn exclude_self(&mut self, ids: HashSet<Ed25519Identity>) -> &mut Self {
+        self.own_relays = ids;
+        self
+    }
+
+    /// Never use any relay whose Ed25519 identity is in `ids` for any
+    /// hop of the path.
+    ///
+    /// This is meant for retrying after a circuit fails: pass the
+    /// identities of the relays that it used.  Unlike
+    /// [`ExitPathBuilder::exclude_self`], calling this more than once
+    /// avoids the relays from every call.
+    pub fn avoid_relays(&mut self, ids: impl IntoIterator<Item = Ed25519Identity>) -> &mut Self {
+        self.avoided_relays.extend(ids);
+        self
+    }
+
+    /// Use `geoip` to look up the countries of relays.
+    pub fn geoip(&mut self, geoip: Arc<dyn GeoIp>) -> &mut Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Make exits in the country `cc` more likely to be chosen.
+    ///
+    /// This is meant for latency-sensitive applications that want an
+    /// exit close to the client.
+    ///
+    /// # Anonymity
+    ///
+    /// Using this option makes your circuits stand out, and makes it
+    /// much easier for an adversary in (or watching) that country to
+    /// see both ends of your traffic.  Don't use it unless you only
+    /// need to defend against a weak adversary.
+    ///
+    /// This has no effect unless a [`GeoIp`] has been set with
+    /// [`ExitPathBuilder::geoip`], and no effect on a builder created
+    /// with [`ExitPathBuilder::from_chosen_exit`].
+    pub fn prefer_exit_country(&mut self, cc: CountryCode) -> &mut Self {
+        self.preferred_exit_country = Some(cc);
+        self
+    }
+
+    /// Use `bridge` as the first hop of the path.
+    ///
+    /// The remaining hops still come from the network directory.
+    pub fn use_bridge(&mut self, bridge: Bridge) -> &mut Self {
+        self.bridge = Some(bridge);
+        self
+    }
+
+    /// Pick the first hop of the path from the guards in `guards`.
+    ///
+    /// We use the first guard in `guards` that suits the path, and only
+    /// pick some other entry if none does.  Without this, we pick a new
+    /// entry for every path.  This has no effect if we're using a bridge.
+    pub fn guards(&mut self, guards: Arc<GuardSet>) -> &mut Self {
+        self.guards = Some(guards);
+        self
+    }
+
+    /// Use `geoip` to make sure that the first hop and the exit of the
+    /// path are in different countries.
+    ///
+    /// This is in addition to our usual requirement that no two hops
+    /// are in the same family.  By default, relays whose country
+    /// `geoip` doesn't know are never used for a hop that we check;
+    /// see [`ExitPathBuilder::geo_diversity_allows_unknown`].
+    ///
+    /// If we're using a bridge, its country is looked up from its
+    /// addresses.
+    pub fn require_geo_diversity(&mut self, geoip: Arc<dyn GeoIp>) -> &mut Self {
+        self.geo_diversity.geoip = Some(geoip);
+        self
+    }
+
+    /// If `include_middle` is true, then when geographic diversity is
+    /// required, the middle relay must also be in a different country
+    /// from both the first hop and the exit.
+    ///
+    /// This has no effect unless [`ExitPathBuilder::require_geo_diversity`]
+    /// has been called.
+    pub fn geo_diversity_includes_middle(&mut self, include_middle: bool) -> &mut Self {
+        self.geo_diversity.include_middle = include_middle;
+        self
+    }
+
+    /// If `allow_unknown` is true, then when geographic diversity is
+    /// required, we may use relays whose country we don't know.
+    ///
+    /// Such relays are assumed to be in a different country from every
+    /// other hop.  This makes more relays available, at the cost of a
+    /// weaker guarantee.
+    ///
+    /// This has no effect unless [`ExitPathBuilder::require_geo_diversity`]
+    /// has been called.
+    pub fn geo_diversity_allows_unknown(&mut self, allow_unknown: bool) -> &mut Self {
+        self.geo_diversity.allow_unknown = allow_unknown;
+        self
+    }
+
+    /// Use `lookup` to make sure that the first hop and the exit of the
+    /// path are in different autonomous systems.
+    ///
+    /// This is in addition to our other diversity requirements.  By
+    /// default, relays whose AS `lookup` doesn't know are never used for
+    /// a hop that we check; see
+    /// [`ExitPathBuilder::as_diversity_allows_unknown`].
+    ///
+    /// If we're using a bridge, its AS is looked up from its addresses.
+    pub fn require_as_diversity(&mut self, lookup: Arc<dyn AsnLookup>) -> &mut Self {
+        self.as_diversity.lookup = Some(lookup);
+        self
+    }
+
+    /// If `include_middle` is true, then when AS diversity is required,
+    /// the middle relay must also be in a different AS from both the
+    /// first hop and the exit.
+    ///
+    /// This has no effect unless [`ExitPathBuilder::require_as_diversity`]
+    /// has been called.
+    pub fn as_diversity_includes_middle(&mut self, include_middle: bool) -> &mut Self {
+        self.as_diversity.include_middle = include_middle;
+        self
+    }
+
+    /// If `allow_unknown` is true, then when AS diversity is required, we
+    /// may use relays whose AS we don't know.
+    ///
+    /// Such relays are assumed to be in a different AS from every other
+    /// hop.
+    ///
+    /// This has no effect unless [`ExitPathBuilder::require_as_diversity`]
+    /// has been called.
+    pub fn as_diversity_allows_unknown(&mut self, allow_unknown: bool) -> &mut Self {
+        self.as_diversity.allow_unknown = allow_unknown;
+        self
+    }
+
+    /// Never put any two hops in the same subnet, as judged by
+    /// `subnets`.
+    ///
+    /// This replaces the rule for every pair of hops.  By default, no two
+    /// hops may share an IPv4 /16 or an IPv6 /32
+    /// ([`SubnetDiversity::default`]); use `SubnetDiversity::new(0, 0)`
+    /// to turn the check off.
+    pub fn subnet_diversity(&mut self, subnets: SubnetDiversity) -> &mut Self {
+        self.subnet_diversity = [Some(subnets); HopPosition::N_PAIRS];
+        self
+    }
+
+    /// Never put the hops at positions `a` and `b` in the same subnet,
+    /// as judged by `subnets`.
+    ///
+    /// Each pair of hops has its own rule, so you can (for example)
+    /// keep adjacent hops out of the same /16, but the entry and exit
+    /// out of the same /8.  A new rule for a pair replaces any earlier
+    /// one, including the default one described in
+    /// [`ExitPathBuilder::subnet_diversity`].
+    ///
+    /// Use `HopPosition::Middle` for both `a` and `b` to set the rule
+    /// between the middle hops of a path longer than three hops.
+    /// Otherwise, this has no effect if `a` and `b` are the same.
+    pub fn require_subnet_diversity(
+        &mut self,
+        a: HopPosition,
+        b: HopPosition,
+        subnets: SubnetDiversity,
+    ) -> &mut Self {
+        if let Some(idx) = HopPosition::pair_index(a, b) {
+            self.subnet_diversity[idx] = Some(subnets);
+        }
+        self
+    }
+
+    /// Only use relays that support every protocol version in
+    /// `protocols`, for every hop of the path.
+    ///
+    /// This is meant for testing new protocol features: it lets you
+    /// build paths entirely through relays running recent versions of
+    /// Tor.  If we're using a bridge, it must support these versions
+    /// too.
+    pub fn require_recent(&mut self, protocols: Protocols) -> &mut Self {
+        self.required_protocols = Some(protocols);
+        self
+    }
+
+    /// Return true if `target` supports the protocol versions that we
+    /// require of every hop.
+    fn is_recent_enough<C: CircTarget + ?Sized>(&self, target: &C) -> bool {
+        match &self.required_protocols {
+            Some(p) => target.protovers().supports_all(p),
+            None => true,
+        }
+    }
+
+    /// Never use any relay whose advertised version matches one of
+    /// `patterns`, for any hop of the path.
+    ///
+    /// This is meant for investigating bugs in particular versions of
+    /// Tor.  Relays that don't advertise a version are never excluded,
+    /// and neither is our bridge (if we have one).
+    pub fn exclude_versions(&mut self, patterns: Vec<VersionSpec>) -> &mut Self {
+        self.excluded_versions = patterns;
+        self
+    }
+
+    /// Return true if `relay` advertises a version that we've been told
+    /// to avoid.
+    fn has_excluded_version(&self, relay: &Relay<'_>) -> bool {
+        match relay.version() {
+            Some(v) => self.excluded_versions.iter().any(|p| p.matches(v)),
+            None => false,
+        }
+    }
+
+    /// Return an error saying that we couldn't find a suitable relay for
+    /// the hop called `hop`.
+    fn no_relay_found(&self, hop: &str) -> Error {
+        if self.required_protocols.is_some() {
+            Error::NoRelays(format!(
+                "No {} relay found among relays with the required protocol versions",
+                hop
+            ))
+        } else {
+            Error::NoRelays(format!("No {} relay found", hop))
+        }
+    }
+
+    /// If we can't find a path that meets all of our diversity
+    /// requirements, relax them as far as `levels`, one step at a time,
+    /// until we can.
+    ///
+    /// This is meant for small test or private networks, where there
+    /// may not be enough relays for a fully diverse path.  Don't use it
+    /// on the public network: a path without diversity is much easier to
+    /// observe end-to-end.
+    pub fn allow_relaxed_diversity(&mut self, levels: RelaxedDiversity) -> &mut Self {
+        self.max_relaxation = levels;
+        self
+    }
+
+    /// Use `weights` in place of the consensus weights for the roles
+    /// that it overrides.
+    pub fn weight_overrides(&mut self, weights: WeightOverrides) -> &mut Self {
+        self.weights = weights;
+        self
+    }
+
+    /// Make relays that have all of `flags` (like `Stable` and `Fast`)
+    /// `boost` times as likely to be chosen for each hop as their weight
+    /// alone would suggest.
+    ///
+    /// This biases our paths toward more reliable relays, without ruling
+    /// out the others: a `boost` between 0 and 1 makes relays with the
+    /// flags _less_ likely to be chosen instead.  If `boost` is negative
+    /// or not finite, we ignore this call.  Calling this again replaces
+    /// the earlier preference.
+    ///
+    /// This applies on top of any [`WeightOverrides`], and has no effect
+    /// on a chosen exit, a bridge, or pinned middles.
+    pub fn prefer_flags(&mut self, flags: RelayFlags, boost: f64) -> &mut Self {
+        if boost.is_finite() && boost >= 0.0 {
+            self.preferred_flags = Some((flags, boost));
+        }
+        self
+    }
+
+    /// Never pick an exit from among the top `fraction` of our candidate
+    /// exits, ranked by the weight that we'd otherwise pick them with.
+    ///
+    /// For example, with a `fraction` of 0.1 and 50 exits that could
+    /// carry our traffic, we ignore the 5 heaviest.  This spreads load
+    /// away from the highest-bandwidth exits, but it costs both
+    /// performance (the exits we're left with are slower) and anonymity
+    /// (clients that use this setting pick from a smaller, unusual set
+    /// of exits, which makes their circuits easier to tell apart).
+    ///
+    /// If `fraction` isn't at least 0 and less than 1, we ignore this
+    /// call.  Calling this again replaces the earlier fraction.  This
+    /// has no effect on a chosen exit.
+    pub fn avoid_top_fraction(&mut self, fraction: f64) -> &mut Self {
+        if (0.0..1.0).contains(&fraction) {
+            self.avoid_top_fraction = Some(fraction);
+        }
+        self
+    }
+
+    /// If `measure` is true, build a "measurement exit" path: circuits
+    /// built from it count the bytes of DATA sent and received on each
+    /// stream.
+    ///
+    /// This is off by default, since normal circuits don't need the
+    /// counts.  See [`TorPath::measures_stream_bytes`].
+    pub fn measure_stream_bytes(&mut self, measure: bool) -> &mut Self {
+        self.measure_stream_bytes = measure;
+        self
+    }
+
+    /// Use `relays` as the middle hops of the path, in order, instead of
+    /// picking a single middle relay.
+    ///
+    /// The entry and exit are still chosen by the usual rules, and must
+    /// be diverse from every pinned middle.  The pinned middles must be
+    /// usable as middle hops, and diverse from one another (that is, in
+    /// different families, and in different countries or autonomous
+    /// systems if the corresponding diversity includes the middle): if
+    /// they aren't, picking a path fails.
+    ///
+    /// This is meant for experiments with custom middle selection.
+    /// Passing an empty list goes back to picking a middle ourselves.
+    pub fn with_fixed_middles(&mut self, relays: Vec<Relay<'a>>) -> &mut Self {
+        self.fixed_middles = relays;
+        self
+    }
+
+    /// Build paths with `n_hops` hops in all, counting the entry (or
+    /// bridge) and the exit.  The default is 3.
+    ///
+    /// A 2-hop path has no middle relay: it's faster, but the entry
+    /// learns both who we are and where we're going.  A path with more
+    /// than 3 hops has more middle relays: each of them obeys the rules
+    /// that a single middle would, and is in a different family from the
+    /// others.
+    ///
+    /// `n_hops` must be at least 2.  This has no effect if we've been
+    /// given middle relays with [`ExitPathBuilder::with_fixed_middles`].
+    pub fn with_length(&mut self, n_hops: usize) -> &mut Self {
+        self.n_middles = n_hops.max(2) - 2;
+        self
+    }
+
+    /// Return true if our family rules forbid using `a` and `b` in the
+    /// same path.
+    fn families_conflict(&self, a: &Relay<'_>, b: &Relay<'_>) -> bool {
+        if self.relaxation >= RelaxedDiversity::Family {
+            a.same_relay(b)
+        } else {
+            a.in_same_family(b)
+        }
+    }
+
+    /// Return true if `relay` is the bridge that we've been told to use
+    /// as our first hop.
+    ///
+    /// (A bridge can also be listed as a public relay; we must not use
+    /// it twice in the same path.)
+    fn is_bridge(&self, relay: &Relay<'_>) -> bool {
+        match &self.bridge {
+            Some(b) => b.target().ed_identity() == relay.id(),
+            None => false,
+        }
+    }
+
+    /// Return the weight to give `relay`, given that its weight would
+    /// otherwise be `w`, after applying our flag preferences.
+    fn flag_weight(&self, relay: &Relay<'_>, w: u64) -> u64 {
+        match self.preferred_flags {
+            // (Casting a float to u64 saturates.)
+            Some((flags, boost)) if relay.flags().contains(flags) => (w as f64 * boost) as u64,
+            _ => w,
+        }
+    }
+
+    /// Return true if we know that `relay` is in the country where we'd
+    /// prefer our exit to be.
+    fn in_preferred_exit_country(&self, relay: &Relay<'_>) -> bool {
+        match (&self.geoip, &self.preferred_exit_country) {
+            (Some(geoip), Some(cc)) => geoip.country_for_relay(relay).as_ref() == Some(cc),
+            _ => false,
+        }
+    }
+
+    /// Return true if geographic diversity lets us use a relay in the
+    /// country `cc` for a hop, given that the hops we've already chosen
+    /// are in the countries `taken`.
+    ///
+    /// (`cc` is None if we don't know the relay's country.)
+    fn geo_allows(&self, cc: Option<CountryCode>, taken: &[CountryCode]) -> bool {
+        match cc {
+            _ if self.geo_diversity.geoip.is_none() => true,
+            Some(cc) => !taken.contains(&cc),
+            None => self.geo_diversity.allow_unknown,
+        }
+    }
+
+    /// Return the country of `relay`, if we're enforcing geographic
+    /// diversity and we know it.
+    fn diversity_country(&self, relay: &Relay<'_>) -> Option<CountryCode> {
+        self.geo_diversity
+            .geoip
+            .as_ref()
+            .and_then(|g| g.country_for_relay(relay))
+    }
+
+    /// Return the country of our bridge, if we have a bridge, we're
+    /// enforcing geographic diversity, and we know the bridge's country.
+    fn bridge_country(&self) -> Option<CountryCode> {
+        match (&self.geo_diversity.geoip, &self.bridge) {
+            (Some(g), Some(b)) => b
+                .target()
+                .addrs()
+                .iter()
+                .find_map(|a| g.country_for_addr(a.ip())),
+            _ => None,
+        }
+    }
+
+    /// Return true if AS diversity lets us use a relay in the AS `asn`
+    /// for a hop, given that the hops we've already chosen are in the
+    /// ASes `taken`.
+    ///
+    /// (`asn` is None if we don't know the relay's AS.)
+    fn as_allows(&self, asn: Option<Asn>, taken: &[Asn]) -> bool {
+        match asn {
+            _ if self.as_diversity.lookup.is_none() => true,
+            Some(asn) => !taken.contains(&asn),
+            None => self.as_diversity.allow_unknown,
+        }
+    }
+
+    /// Return the AS of `relay`, if we're enforcing AS diversity and we
+    /// know it.
+    fn diversity_asn(&self, relay: &Relay<'_>) -> Option<Asn> {
+        self.as_diversity
+            .lookup
+            .as_ref()
+            .and_then(|l| l.asn_for_relay(relay))
+    }
+
+    /// Return the AS of our bridge, if we have a bridge, we're enforcing
+    /// AS diversity, and we know the bridge's AS.
+    fn bridge_asn(&self) -> Option<Asn> {
+        match (&self.as_diversity.lookup, &self.bridge) {
+            (Some(l), Some(b)) => b
+                .target()
+                .addrs()
+                .iter()
+                .find_map(|a| l.asn_for_addr(a.ip())),
+            _ => None,
+        }
+    }
+
+    /// Return true if our subnet diversity rules let us use `ta` at
+    /// position `a` and `tb` at position `b` in the same path.
+    fn subnets_allow<A, B>(&self, a: HopPosition, ta: &A, b: HopPosition, tb: &B) -> bool
+    where
+        A: ChanTarget + ?Sized,
+        B: ChanTarget + ?Sized,
+    {
+        if self.relaxation >= RelaxedDiversity::Subnet {
+            return true;
+        }
+        match HopPosition::pair_index(a, b).and_then(|idx| self.subnet_diversity[idx]) {
+            Some(subnets) => !subnets.any_in_same_subnet(ta.addrs(), tb.addrs()),
+            None => true,
+        }
+    }
+
+    /// Return true if our subnet diversity rules let us use `relay` at
+    /// position `pos`, given our bridge (if we have one).
+    fn bridge_subnets_allow(&self, pos: HopPosition, relay: &Relay<'_>) -> bool {
+        match &self.bridge {
+            Some(b) => self.subnets_allow(HopPosition::Entry, b.target(), pos, relay),
+            None => true,
+        }
+    }
+
+    /// Return true if our family and subnet rules let us use `relay` at
+    /// position `pos` in the same path as each of the middle relays in
+    /// `middles`.
+    fn middles_allow(&self, pos: HopPosition, relay: &Relay<'_>, middles: &[Relay<'_>]) -> bool {
+        middles.iter().all(|m| {
+            !self.families_conflict(relay, m)
+                && self.subnets_allow(pos, relay, HopPosition::Middle, m)
+        })
+    }
+
+    /// Make sure that our pinned middle relays (if any) are usable as
+    /// middle hops, and diverse from one another and from our bridge.
+    ///
+    /// On success, add their countries and ASes to `taken` and
+    /// `taken_asns`, if our diversity rules include the middle.
+    fn check_fixed_middles(
+        &self,
+        taken: &mut Vec<CountryCode>,
+        taken_asns: &mut Vec<Asn>,
+    ) -> Result<()> {
+        for (idx, middle) in self.fixed_middles.iter().enumerate() {
+            let cc = self.diversity_country(middle);
+            let asn = self.diversity_asn(middle);
+            let problem = if self.is_own_relay(middle) {
+                Some("is one of our own relays")
+            } else if self.is_avoided(middle) {
+                Some("is one of the relays we were told to avoid")
+            } else if self.is_bridge(middle) {
+                Some("is our bridge")
+            } else if !self.is_recent_enough(middle) {
+                Some("doesn't support the required protocol versions")
+            } else if self.has_excluded_version(middle) {
+                Some("runs a version that we were told to avoid")
+            } else if !self.bridge_subnets_allow(HopPosition::Middle, middle) {
+                Some("is in the same subnet as our bridge")
+            } else if self.fixed_middles[..idx]
+                .iter()
+                .any(|m| self.families_conflict(middle, m))
+            {
+                Some("is in the same family as an earlier pinned middle relay")
+            } else if !self.fixed_middles[..idx]
+                .iter()
+                .all(|m| self.subnets_allow(HopPosition::Middle, middle, HopPosition::Middle, m))
+            {
+                Some("is in the same subnet as an earlier pinned middle relay")
+            } else if self.geo_diversity.include_middle && !self.geo_allows(cc, taken) {
+                Some("is not geographically diverse from the other hops")
+            } else if self.as_diversity.include_middle && !self.as_allows(asn, taken_asns) {
+                Some("is not in a different AS from the other hops")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                return Err(Error::NoRelays(format!(
+                    "Pinned middle relay {} {}",
+                    idx, problem
+                )));
+            }
+            if self.geo_diversity.include_middle {
+                taken.extend(cc);
+            }
+            if self.as_diversity.include_middle {
+                taken_asns.extend(asn);
+            }
         }
+        Ok(())
+    }
+
+    /// Return true if `relay` is one of the relays that we have been told
+    /// belong to our own operator.
+    fn is_own_relay(&self, relay: &Relay<'_>) -> bool {
+        self.own_relays.contains(relay.id())
+    }
+
+    /// Return true if `relay` is one of the relays that we have been told
+    /// to avoid.
+    fn is_avoided(&self, relay: &Relay<'_>) -> bool {
+        self.avoided_relays.contains(relay.id())
+    }
+
+    /// Pick an exit relay from the network directory that is not
+    /// excluded, and for which `supports_targets` returns true.
+    ///
+    /// The exit must not be in any of the countries in `taken`, or any
+    /// of the autonomous systems in `taken_asns`, and must be diverse from
+    /// our pinned middle relays, if we have any.
+    fn pick_exit_by<R, F>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        taken: &[CountryCode],
+        taken_asns: &[Asn],
+        supports_targets: F,
+    ) -> Result<Relay<'a>>
+    where
+        R: Rng,
+        F: Fn(&Relay<'a>) -> bool,
+    {
+        let exit_weight = |r: &Relay<'a>, w: u64| {
+            if self.is_own_relay(r)
+                || self.is_avoided(r)
+                || self.is_bridge(r)
+                || r.is_middle_only()
+                || !supports_targets(r)
+                || !self.is_recent_enough(r)
+                || self.has_excluded_version(r)
+                || !self.bridge_subnets_allow(HopPosition::Exit, r)
+                || !self.geo_allows(self.diversity_country(r), taken)
+                || !self.as_allows(self.diversity_asn(r), taken_asns)
+                || !self.middles_allow(HopPosition::Exit, r, &self.fixed_middles)
+            {
+                0
+            } else if self.in_preferred_exit_country(r) {
+                self.flag_weight(r, w)
+                    .saturating_mul(PREFERRED_COUNTRY_FACTOR)
+            } else {
+                self.flag_weight(r, w)
+            }
+        };
+        let too_heavy = self.heaviest_exits(netdir, exit_weight);
+        self.weights
+            .pick_relay_by_weight(netdir, rng, WeightRole::Exit, |r, w| {
+                if too_heavy.contains(r.id()) {
+                    0
+                } else {
+                    exit_weight(r, w)
+                }
+            })
+            .ok_or_else(|| self.no_relay_found("exit"))
+    }
+
+    /// Return the identities of the exits that
+    /// [`ExitPathBuilder::avoid_top_fraction`] tells us not to pick, given
+    /// that `exit_weight` gives the weight we'd otherwise pick each one
+    /// with.
+    fn heaviest_exits<F>(&self, netdir: &'a NetDir, exit_weight: F) -> HashSet<Ed25519Identity>
+    where
+        F: Fn(&Relay<'a>, u64) -> u64,
+    {
+        let fraction = match self.avoid_top_fraction {
+            Some(f) => f,
+            None => return HashSet::new(),
+        };
+        let mut candidates: Vec<_> = netdir
+            .relays()
+            .filter_map(|r| {
+                let w = self.weights.relay_weight(netdir, &r, WeightRole::Exit);
+                match exit_weight(&r, w) {
+                    0 => None,
+                    w => Some((w, *r.id())),
+                }
+            })
+            .collect();
+        // Heaviest first; break ties by identity, so that we're
+        // consistent about which of two equal exits we avoid.
+        candidates.sort_unstable_by(|(w_a, id_a), (w_b, id_b)| {
+            w_b.cmp(w_a)
+                .then_with(|| id_b.as_bytes().cmp(id_a.as_bytes()))
+        });
+        let n_avoid = (candidates.len() as f64 * fraction) as usize;
+        candidates
+            .into_iter()
+            .take(n_avoid)
+            .map(|(_, id)| id)
+            .collect()
     }
 
     /// Find a suitable exit node from either the chosen exit or from the network directory.
-    fn pick_exit<R: Rng>(&self, rng: &mut R, netdir: &'a NetDir) -> Result<Relay<'a>> {
+    ///
+    /// The exit must not be in any of the countries in `taken`, or any
+    /// of the autonomous systems in `taken_asns`.
+    fn pick_exit<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        taken: &[CountryCode],
+        taken_asns: &[Asn],
+    ) -> Result<Relay<'a>> {
         match &self.inner {
-            ExitPathBuilderInner::WantsPorts(wantports) => Ok(netdir
-                .pick_relay(rng, WeightRole::Exit, |r| {
+            ExitPathBuilderInner::WantsPorts(wantports) => {
+                self.pick_exit_by(rng, netdir, taken, taken_asns, |r| {
                     wantports.iter().all(|p| p.is_supported_by(r))
                 })
-                .ok_or_else(|| Error::NoRelays("No exit relay found".into()))?),
+            }
+
+            ExitPathBuilderInner::WantsAddrs(wantaddrs, policies) => {
+                self.pick_exit_by(rng, netdir, taken, taken_asns, |r| {
+                    wantaddrs
+                        .iter()
+                        .all(|a| a.is_supported_by(r, policies.as_ref()))
+                })
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay) if self.is_own_relay(exit_relay) => Err(
+                Error::NoRelays("Chosen exit relay is one of our own relays".into()),
+            ),
+
+            ExitPathBuilderInner::ChosenExit(exit_relay) if self.is_avoided(exit_relay) => Err(
+                Error::NoRelays("Chosen exit relay is one we were told to avoid".into()),
+            ),
+
+            ExitPathBuilderInner::ChosenExit(exit_relay) if exit_relay.is_middle_only() => Err(
+                Error::NoRelays("Chosen exit relay is only usable as a middle relay".into()),
+            ),
+
+            ExitPathBuilderInner::ChosenExit(exit_relay) if !self.is_recent_enough(exit_relay) => {
+                Err(Error::NoRelays(
+                    "Chosen exit relay doesn't support the required protocol versions".into(),
+                ))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay)
+                if self.has_excluded_version(exit_relay) =>
+            {
+                Err(Error::NoRelays(
+                    "Chosen exit relay runs a version that we were told to avoid".into(),
+                ))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay) if self.is_bridge(exit_relay) => {
+                Err(Error::NoRelays("Chosen exit relay is our bridge".into()))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay)
+                if !self.bridge_subnets_allow(HopPosition::Exit, exit_relay) =>
+            {
+                Err(Error::NoRelays(
+                    "Chosen exit relay is in the same subnet as our bridge".into(),
+                ))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay)
+                if !self.geo_allows(self.diversity_country(exit_relay), taken) =>
+            {
+                Err(Error::NoRelays(
+                    "Chosen exit relay is not geographically diverse from our bridge".into(),
+                ))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay)
+                if !self.as_allows(self.diversity_asn(exit_relay), taken_asns) =>
+            {
+                Err(Error::NoRelays(
+                    "Chosen exit relay is not in a different AS from our bridge".into(),
+                ))
+            }
+
+            ExitPathBuilderInner::ChosenExit(exit_relay)
+                if !self.middles_allow(HopPosition::Exit, exit_relay, &self.fixed_middles) =>
+            {
+                Err(Error::NoRelays(
+                    "Chosen exit relay conflicts with a pinned middle relay".into(),
+                ))
+            }
 
             ExitPathBuilderInner::ChosenExit(exit_relay) => Ok(exit_relay.clone()),
         }
@@ -54,104 +1036,1643 @@ impl<'a> ExitPathBuilder<'a> {
     /// Try to create and return a path corresponding to the requirements of
     /// this builder.
     pub fn pick_path<R: Rng>(&self, rng: &mut R, netdir: DirInfo<'a>) -> Result<TorPath<'a>> {
-        // TODO: implement guards
+        self.pick_path_with_metrics(rng, netdir)
+            .map(|(path, _metrics)| path)
+    }
+
+    /// As [`ExitPathBuilder::pick_path`], but also return how long it
+    /// took to select each hop.
+    ///
+    /// If we had to relax our diversity requirements, the per-hop times
+    /// are for the attempt that succeeded, but the total includes every
+    /// attempt.
+    pub fn pick_path_with_metrics<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: DirInfo<'a>,
+    ) -> Result<(TorPath<'a>, PathSelectionMetrics)> {
+        self.pick_path_relaxing(rng, netdir)
+            .map(|(path, metrics, _builder)| (path, metrics))
+    }
+
+    /// As [`ExitPathBuilder::pick_path`], but also return a second exit
+    /// relay that could replace the path's exit if it fails.
+    ///
+    /// The fallback exit meets all of the same requirements as the
+    /// path's own exit, including diversity from the path's other
+    /// hops, so the caller can swap it in without picking a whole new
+    /// path.  It is None if there's no other suitable exit, or if this
+    /// builder was created with [`ExitPathBuilder::from_chosen_exit`].
+    pub fn pick_path_with_fallback<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: DirInfo<'a>,
+    ) -> Result<(TorPath<'a>, Option<Relay<'a>>)> {
+        let (path, _metrics, builder) = self.pick_path_relaxing(rng, netdir)?;
+        let fallback = match netdir {
+            DirInfo::Directory(d) => builder.pick_fallback_exit(rng, d, &path),
+            DirInfo::Fallbacks(_) => None,
+        };
+        Ok((path, fallback))
+    }
+
+    /// Try to pick a path, relaxing our diversity requirements as far as
+    /// we're allowed to until we succeed.
+    ///
+    /// Return the path, its metrics, and the builder (at the level of
+    /// relaxation that we used) that picked it.
+    fn pick_path_relaxing<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: DirInfo<'a>,
+    ) -> Result<(TorPath<'a>, PathSelectionMetrics, Cow<'_, Self>)> {
+        let start = Instant::now();
+        let mut builder = Cow::Borrowed(self);
+        let (path, mut metrics) = loop {
+            match builder.pick_path_once(rng, netdir) {
+                Err(Error::NoRelays(why)) => {
+                    let next = match builder.relaxation.next() {
+                        Some(next) if next <= self.max_relaxation => next,
+                        _ => return Err(Error::NoRelays(why)),
+                    };
+                    info!(
+                        "Couldn't build a path ({}); relaxing diversity to {:?}",
+                        why, next
+                    );
+                    builder.to_mut().relaxation = next;
+                }
+                other => break other?,
+            }
+        };
+
+        metrics.total = start.elapsed();
+        trace!("Selected a path in {:?}: {:?}", metrics.total, metrics);
+        Ok((path, metrics, builder))
+    }
+
+    /// Pick an exit relay, other than the exit of `path`, that could
+    /// replace that exit without breaking any of our rules.
+    ///
+    /// Return None if there is no such relay, or if we were told which
+    /// exit to use.
+    fn pick_fallback_exit<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        path: &TorPath<'a>,
+    ) -> Option<Relay<'a>> {
+        // The hops before the exit, and their positions.  (There may be
+        // several middle hops, if we were given pinned middles.)
+        let (hops, has_entry) = match &path.inner {
+            TorPathInner::Path(p) => (p, true),
+            TorPathInner::BridgeEntry(_, p) => (p, false),
+            _ => return None,
+        };
+        let (exit, others) = hops.split_last()?;
+        let positions: Vec<_> = (0..others.len())
+            .map(|idx| {
+                if has_entry && idx == 0 {
+                    HopPosition::Entry
+                } else {
+                    HopPosition::Middle
+                }
+            })
+            .collect();
+
+        // Countries that the new exit must avoid, for geographic
+        // diversity.
+        let mut taken = Vec::new();
+        if self.bridge.is_some() {
+            taken.extend(self.bridge_country());
+        }
+        for (hop, pos) in others.iter().zip(&positions) {
+            if *pos == HopPosition::Entry || self.geo_diversity.include_middle {
+                taken.extend(self.diversity_country(hop));
+            }
+        }
+        // Likewise for AS diversity.
+        let mut taken_asns = Vec::new();
+        if self.bridge.is_some() {
+            taken_asns.extend(self.bridge_asn());
+        }
+        for (hop, pos) in others.iter().zip(&positions) {
+            if *pos == HopPosition::Entry || self.as_diversity.include_middle {
+                taken_asns.extend(self.diversity_asn(hop));
+            }
+        }
+
+        let diverse = |r: &Relay<'a>| {
+            !r.same_relay(exit)
+                && others.iter().zip(&positions).all(|(hop, pos)| {
+                    !self.families_conflict(r, hop)
+                        && self.subnets_allow(HopPosition::Exit, r, *pos, hop)
+                })
+        };
+        let fallback = match &self.inner {
+            ExitPathBuilderInner::WantsPorts(wantports) => {
+                self.pick_exit_by(rng, netdir, &taken, &taken_asns, |r| {
+                    diverse(r) && wantports.iter().all(|p| p.is_supported_by(r))
+                })
+            }
+            ExitPathBuilderInner::WantsAddrs(wantaddrs, policies) => {
+                self.pick_exit_by(rng, netdir, &taken, &taken_asns, |r| {
+                    diverse(r)
+                        && wantaddrs
+                            .iter()
+                            .all(|a| a.is_supported_by(r, policies.as_ref()))
+                })
+            }
+            ExitPathBuilderInner::ChosenExit(_) => return None,
+        };
+        fallback.ok()
+    }
+
+    /// Try once to pick a path, with our current level of relaxation.
+    ///
+    /// Doesn't fill in the total time in the metrics.
+    fn pick_path_once<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: DirInfo<'a>,
+    ) -> Result<(TorPath<'a>, PathSelectionMetrics)> {
+        let mut metrics = PathSelectionMetrics::default();
+
         let netdir = match netdir {
             DirInfo::Fallbacks(_) => return Err(Error::NeedConsensus),
             DirInfo::Directory(d) => d,
         };
-        let exit = self.pick_exit(rng, netdir)?;
 
-        let middle = netdir
-            .pick_relay(rng, WeightRole::Middle, |r| !r.in_same_family(&exit))
-            .ok_or_else(|| Error::NoRelays("No middle relay found".into()))?;
+        if let Some(bridge) = &self.bridge {
+            if !self.is_recent_enough(bridge.target()) {
+                return Err(Error::NoRelays(
+                    "Bridge doesn't support the required protocol versions".into(),
+                ));
+            }
+        }
+
+        // Countries of the hops we've chosen so far, for geographic
+        // diversity.  (If we're using a bridge, it's already chosen.)
+        let mut taken = Vec::new();
+        if self.bridge.is_some() && self.geo_diversity.geoip.is_some() {
+            let cc = self.bridge_country();
+            if !self.geo_allows(cc, &taken) {
+                return Err(Error::NoRelays("Bridge is in an unknown country".into()));
+            }
+            taken.extend(cc);
+        }
+        // Likewise for the autonomous systems of the hops.
+        let mut taken_asns = Vec::new();
+        if self.bridge.is_some() && self.as_diversity.lookup.is_some() {
+            let asn = self.bridge_asn();
+            if !self.as_allows(asn, &taken_asns) {
+                return Err(Error::NoRelays("Bridge is in an unknown AS".into()));
+            }
+            taken_asns.extend(asn);
+        }
+        // Our pinned middles, if we have any, are already chosen too.
+        self.check_fixed_middles(&mut taken, &mut taken_asns)?;
+
+        let hop_start = Instant::now();
+        let exit = self.pick_exit(rng, netdir, &taken, &taken_asns)?;
+        taken.extend(self.diversity_country(&exit));
+        taken_asns.extend(self.diversity_asn(&exit));
+        metrics.exit = hop_start.elapsed();
+
+        let hop_start = Instant::now();
+        let middles = if self.fixed_middles.is_empty() {
+            let mut middles = Vec::with_capacity(self.n_middles);
+            for _ in 0..self.n_middles {
+                let middle =
+                    self.pick_middle(rng, netdir, &exit, &middles, &mut taken, &mut taken_asns)?;
+                middles.push(middle);
+            }
+            middles
+        } else {
+            self.fixed_middles.clone()
+        };
+        metrics.middle = hop_start.elapsed();
+
+        let path = if let Some(bridge) = &self.bridge {
+            TorPath::new_bridged(bridge.clone(), middles.into_iter().chain(Some(exit)))
+        } else {
+            let hop_start = Instant::now();
+            let usable = |r: &Relay<'a>| {
+                !self.is_own_relay(r)
+                    && !self.is_avoided(r)
+                    && !r.is_middle_only()
+                    && !self.families_conflict(r, &exit)
+                    && self.is_recent_enough(r)
+                    && !self.has_excluded_version(r)
+                    && self.middles_allow(HopPosition::Entry, r, &middles)
+                    && self.subnets_allow(HopPosition::Entry, r, HopPosition::Exit, &exit)
+                    && self.geo_allows(self.diversity_country(r), &taken)
+                    && self.as_allows(self.diversity_asn(r), &taken_asns)
+            };
+            let mut pick_entry = || {
+                self.weights
+                    .pick_relay_by_weight(netdir, rng, WeightRole::Guard, |r, w| {
+                        if usable(r) {
+                            self.flag_weight(r, w)
+                        } else {
+                            0
+                        }
+                    })
+            };
+            let entry = match &self.guards {
+                Some(guards) => guards.pick_guard(netdir, usable, pick_entry),
+                None => pick_entry(),
+            }
+            .ok_or_else(|| self.no_relay_found("entry"))?;
+            metrics.entry = Some(hop_start.elapsed());
+
+            TorPath::new_multihop(std::iter::once(entry).chain(middles).chain(Some(exit)))
+        };
+
+        Ok((
+            path.with_stream_byte_measurement(self.measure_stream_bytes),
+            metrics,
+        ))
+    }
 
-        let entry = netdir
-            .pick_relay(rng, WeightRole::Guard, |r| {
-                !r.in_same_family(&middle) && !r.in_same_family(&exit)
+    /// Pick a middle relay for a path whose exit is `exit`, and whose
+    /// other middle relays (so far) are `middles`.
+    ///
+    /// If our diversity rules include the middle, the middle must not be
+    /// in any of the countries in `taken` or the ASes in `taken_asns`,
+    /// and we add its country and AS to them.
+    fn pick_middle<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        exit: &Relay<'a>,
+        middles: &[Relay<'a>],
+        taken: &mut Vec<CountryCode>,
+        taken_asns: &mut Vec<Asn>,
+    ) -> Result<Relay<'a>> {
+        let middle = self
+            .weights
+            .pick_relay_by_weight(netdir, rng, WeightRole::Middle, |r, w| {
+                if !self.is_own_relay(r)
+                    && !self.is_avoided(r)
+                    && !self.is_bridge(r)
+                    && !self.families_conflict(r, exit)
+                    && self.middles_allow(HopPosition::Middle, r, middles)
+                    && self.is_recent_enough(r)
+                    && !self.has_excluded_version(r)
+                    && self.subnets_allow(HopPosition::Middle, r, HopPosition::Exit, exit)
+                    && self.bridge_subnets_allow(HopPosition::Middle, r)
+                    && (!self.geo_diversity.include_middle
+                        || self.geo_allows(self.diversity_country(r), taken))
+                    && (!self.as_diversity.include_middle
+                        || self.as_allows(self.diversity_asn(r), taken_asns))
+                {
+                    self.flag_weight(r, w)
+                } else {
+                    0
+                }
             })
-            .ok_or_else(|| Error::NoRelays("No entry relay found".into()))?;
+            .ok_or_else(|| self.no_relay_found("middle"))?;
+        if self.geo_diversity.include_middle {
+            taken.extend(self.diversity_country(&middle));
+        }
+        if self.as_diversity.include_middle {
+            taken_asns.extend(self.diversity_asn(&middle));
+        }
+        Ok(middle)
+    }
+}
+
+/// A set of TCP ports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortSet {
+    /// The ranges of ports in this set: sorted, and with no two of them
+    /// overlapping or adjacent.
+    ranges: Vec<PortRange>,
+}
+
+impl PortSet {
+    /// Return the union of the ports in `ranges`.
+    fn from_ranges(ranges: impl IntoIterator<Item = PortRange>) -> Self {
+        let mut sorted: Vec<PortRange> = ranges.into_iter().collect();
+        sorted.sort_by_key(|r| r.lo);
+        let mut merged: Vec<PortRange> = Vec::new();
+        for r in sorted {
+            match merged.last_mut() {
+                Some(last) if u32::from(r.lo) <= u32::from(last.hi) + 1 => {
+                    last.hi = last.hi.max(r.hi);
+                }
+                _ => merged.push(r),
+            }
+        }
+        PortSet { ranges: merged }
+    }
 
-        Ok(TorPath::new_multihop(vec![entry, middle, exit]))
+    /// Return true if `port` is in this set.
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|r| r.contains(port))
+    }
+
+    /// Return true if this set has no ports.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Return the ranges of ports in this set.
+    ///
+    /// The ranges are sorted, and no two of them overlap or are
+    /// adjacent.
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.ranges[..]
+    }
+}
+
+impl std::fmt::Display for PortSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut comma = "";
+        for range in self.ranges.iter() {
+            write!(f, "{}{}", comma, range)?;
+            comma = ",";
+        }
+        Ok(())
     }
 }
 
+/// Return the set of ports to which at least one exit in `netdir` allows
+/// IPv4 connections.
+///
+/// This is meant for telling a user which ports they can currently
+/// reach.  We only count relays that we might pick as exits: not ones
+/// with the BadExit or MiddleOnly flags.  Like all exit policy summaries,
+/// this only tells us which ports are _probably_ reachable: see
+/// [`PortPolicy`](tor_netdoc::types::policy::PortPolicy) for details.
+pub fn reachable_ports(netdir: &NetDir) -> PortSet {
+    PortSet::from_ranges(
+        netdir
+            .relays()
+            .filter(|r| !r.flags().contains(RelayFlags::BAD_EXIT) && !r.is_middle_only())
+            .flat_map(|r| r.ipv4_policy().allowed_ranges().to_vec()),
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::path::{assert_same_path_when_owned, OwnedPath, TorPathInner};
+    use crate::path::{assert_same_path_when_owned, OwnedPath};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
     use std::convert::TryInto;
-    use tor_linkspec::ChanTarget;
     use tor_netdir::testnet;
 
-    fn assert_exit_path_ok<'a>(relays: &[Relay<'a>]) {
-        assert_eq!(relays.len(), 3);
+    /// Return an RNG with a fixed seed, so that each test sees the same
+    /// paths every time it runs.
+    fn test_rng() -> StdRng {
+        StdRng::seed_from_u64(0x7061_7468)
+    }
 
-        // TODO: Eventually assert that r1 has Guard, once we enforce that.
+    /// Pick `n` paths with `builder`, check that each one is an ordinary
+    /// multi-hop path that's the same once it's owned, and return the
+    /// relays in each.
+    fn pick_paths<'a, R: Rng>(
+        builder: &ExitPathBuilder<'a>,
+        rng: &mut R,
+        dirinfo: DirInfo<'a>,
+        n: usize,
+    ) -> Vec<Vec<Relay<'a>>> {
+        (0..n)
+            .map(|_| {
+                let path = builder.pick_path(rng, dirinfo).unwrap();
+                assert_same_path_when_owned(&path);
+                assert!(path.is_multihop());
+                match path.inner {
+                    TorPathInner::Path(p) => p,
+                    _ => panic!("Generated the wrong kind of path"),
+                }
+            })
+            .collect()
+    }
+
+    fn assert_exit_path_ok<'a>(relays: &[Relay<'a>]) {
+        assert_exit_path_len_ok(relays, 3);
+    }
 
-        let r1 = &relays[0];
-        let r2 = &relays[1];
-        let r3 = &relays[2];
+    fn assert_exit_path_len_ok<'a>(relays: &[Relay<'a>], len: usize) {
+        assert_eq!(relays.len(), len);
 
-        assert!(r1.ed_identity() != r2.ed_identity());
-        assert!(r1.ed_identity() != r3.ed_identity());
-        assert!(r2.ed_identity() != r3.ed_identity());
+        // TODO: Eventually assert that the first relay has Guard, once we
+        // enforce that.
 
-        assert!(!r1.in_same_family(r2));
-        assert!(!r1.in_same_family(r3));
-        assert!(!r2.in_same_family(r3));
+        for (i, r1) in relays.iter().enumerate() {
+            for r2 in &relays[i + 1..] {
+                assert!(r1.ed_identity() != r2.ed_identity());
+                assert!(!r1.in_same_family(r2));
+            }
+        }
     }
 
     #[test]
     fn by_ports() {
-        let mut rng = rand::thread_rng();
+        let mut rng = test_rng();
         let netdir = testnet::construct_netdir();
         let ports = vec![TargetPort::ipv4(443), TargetPort::ipv4(1119)];
         let dirinfo = (&netdir).into();
 
-        for _ in 0..1000 {
-            let path = ExitPathBuilder::from_target_ports(ports.clone())
-                .pick_path(&mut rng, dirinfo)
-                .unwrap();
+        let builder = ExitPathBuilder::from_target_ports(ports);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            let exit = &p[2];
+            assert!(exit.ipv4_policy().allows_port(1119));
+        }
 
-            assert_same_path_when_owned(&path);
+        let chosen = netdir.by_id(&[0x20; 32].into()).unwrap();
+
+        let builder = ExitPathBuilder::from_chosen_exit(chosen.clone());
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            let exit = &p[2];
+            assert_eq!(exit.ed_identity(), chosen.ed_identity());
+        }
+    }
+
+    #[test]
+    fn exit_weights() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // Leave two exits: 0x0a, with weight 1000, and 0x13, with weight
+        // 10000.  The non-exits (some of them as heavy as 0x13) don't
+        // support our port, so they must not dilute the weighting.
+        let light: Ed25519Identity = [0x0a; 32].into();
+        let heavy: Ed25519Identity = [0x13; 32].into();
+        let others = (0x0a..=0x13_u8)
+            .chain(0x1e..=0x27)
+            .map(|idx| -> Ed25519Identity { [idx; 32].into() })
+            .filter(|id| id != &light && id != &heavy);
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.avoid_relays(others);
+        let (mut n_light, mut n_heavy) = (0_u32, 0_u32);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 5000) {
+            let exit = p[2].id();
+            if exit == &light {
+                n_light += 1;
+            } else if exit == &heavy {
+                n_heavy += 1;
+            } else {
+                panic!("Picked an exit we were told to avoid");
+            }
+        }
+        // We expect a ratio of 10.  (With 5000 paths, this range is about
+        // 5 standard deviations either way, so it doesn't depend on our
+        // choice of seed.)
+        let ratio = f64::from(n_heavy) / f64::from(n_light);
+        assert!((7.0..14.0).contains(&ratio), "ratio was {}", ratio);
+    }
+
+    #[test]
+    fn by_ipv6_ports() {
+        let mut rng = test_rng();
+        // The odd-numbered exits allow 80 and 443 on IPv4, but only 80 on
+        // IPv6.  The even-numbered exits allow everything on both.
+        let netdir = testnet::NetworkSpec::new()
+            .ipv6_policies(|idx| {
+                Some(
+                    if idx % 2 == 1 {
+                        "accept 80"
+                    } else {
+                        "accept 1-65535"
+                    }
+                    .into(),
+                )
+            })
+            .netdir();
+        let dirinfo = (&netdir).into();
+
+        let builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(443)]);
+        let paths = pick_paths(&builder, &mut rng, dirinfo, 1000);
+        assert!(paths.iter().any(|p| p[2].id().as_bytes()[0] % 2 == 1));
+
+        let builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv6(443)]);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            let exit = &p[2];
+            assert_eq!(exit.id().as_bytes()[0] % 2, 0);
+            assert!(exit.ipv6_policy().allows_port(443));
+        }
+
+        // Nobody allows IPv6 connections in the usual test network.
+        let netdir = testnet::construct_netdir();
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv6(80)])
+            .pick_path(&mut rng, (&netdir).into());
+        assert!(path.is_err());
+    }
+
+    #[test]
+    fn path_length() {
+        use std::net::IpAddr;
+
+        let mut rng = test_rng();
+        // Relays share a /16 in groups of four, so that the default
+        // subnet rule has something to do.
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|idx| std::net::SocketAddr::new([10, idx / 4, idx, 1].into(), 9001))
+            .netdir();
+        let dirinfo = (&netdir).into();
+        let slash16 = |r: &Relay<'_>| match r.addrs()[0].ip() {
+            IpAddr::V4(a) => a.octets()[..2].to_vec(),
+            IpAddr::V6(_) => panic!("Unexpected IPv6 address"),
+        };
+
+        for n_hops in 2..=5 {
+            let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+            builder.with_length(n_hops);
+            for p in pick_paths(&builder, &mut rng, dirinfo, 200) {
+                assert_exit_path_len_ok(&p[..], n_hops);
+                assert!(p[n_hops - 1].ipv4_policy().allows_port(80));
+                // No two hops are in the same /16, not even two
+                // middles.
+                for (i, r1) in p.iter().enumerate() {
+                    for r2 in &p[i + 1..] {
+                        assert_ne!(slash16(r1), slash16(r2));
+                    }
+                }
+            }
+        }
+
+        // Pinned middles decide the length.
+        let middle = netdir.by_id(&[0x02; 32].into()).unwrap();
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .with_length(5)
+            .with_fixed_middles(vec![middle])
+            .pick_path(&mut rng, dirinfo)
+            .unwrap();
+        assert_eq!(path.len(), 3);
+
+        // Too short a length is treated as 2.
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .with_length(0)
+            .pick_path(&mut rng, dirinfo)
+            .unwrap();
+        assert_eq!(path.len(), 2);
+    }
+
+    #[test]
+    fn guard_rotation() {
+        use crate::path::guard::{GuardSet, LifetimeRotation};
+        use std::time::SystemTime;
+        use tor_rtmock::time::MockSleepProvider;
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let day = Duration::from_secs(86400);
+        let start = SystemTime::UNIX_EPOCH + day * 1000;
+        let clock = MockSleepProvider::new(start);
+        let mut guards = GuardSet::new(clock.clone(), Arc::new(LifetimeRotation::new(day * 30)));
+        guards.set_sample_size(1);
+        let guards = Arc::new(guards);
+        let entry_of = |path: TorPath<'_>| match path.inner {
+            TorPathInner::Path(p) => *p[0].id(),
+            _ => panic!("Generated the wrong kind of path"),
+        };
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(1119)]);
+        builder.guards(Arc::clone(&guards));
+
+        let guard = entry_of(builder.pick_path(&mut rng, dirinfo).unwrap());
+        assert_eq!(guards.guards(), vec![(guard, start)]);
+        // Until its lifetime is up, we keep the same guard.  (Paths whose
+        // other hops conflict with it use another entry.)
+        clock.jump_to(start + day * 29);
+        let mut n_with_guard = 0;
+        for _ in 0..100 {
+            let path = builder.pick_path(&mut rng, dirinfo).unwrap();
+            if entry_of(path) == guard {
+                n_with_guard += 1;
+            }
+            assert_eq!(guards.guards(), vec![(guard, start)]);
+        }
+        assert!(n_with_guard > 0);
+
+        // Past the guard's lifetime, we select a new guard.
+        let later = start + day * 31;
+        clock.jump_to(later);
+        let new_guard = entry_of(builder.pick_path(&mut rng, dirinfo).unwrap());
+        assert_eq!(guards.guards(), vec![(new_guard, later)]);
+    }
+
+    #[test]
+    fn guards_reused() {
+        use crate::path::guard::{GuardSet, LifetimeRotation};
+        use std::time::SystemTime;
+        use tor_rtmock::time::MockSleepProvider;
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let clock = MockSleepProvider::new(SystemTime::UNIX_EPOCH);
+        let guards = Arc::new(GuardSet::new(clock, Arc::new(LifetimeRotation::default())));
+
+        // With the other hops fixed, our first guard always suits the
+        // path, so every path uses it.
+        let exit = netdir.by_id(&[0x20; 32].into()).unwrap();
+        let middle = netdir.by_id(&[0x02; 32].into()).unwrap();
+        let mut builder = ExitPathBuilder::from_chosen_exit(exit);
+        builder
+            .with_fixed_middles(vec![middle])
+            .guards(Arc::clone(&guards));
+
+        let entries: HashSet<_> = pick_paths(&builder, &mut rng, dirinfo, 100)
+            .iter()
+            .map(|p| *p[0].id())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        let sampled: Vec<_> = guards.guards().into_iter().map(|g| g.0).collect();
+        assert_eq!(sampled, entries.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn exclude_self() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // Exclude a couple of exits, a couple of guards, and a couple of
+        // plain relays.
+        let own: HashSet<Ed25519Identity> = [0x03, 0x07, 0x0a, 0x0b, 0x1c, 0x24]
+            .iter()
+            .map(|idx| [*idx; 32].into())
+            .collect();
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.exclude_self(own.clone());
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            for r in p.iter() {
+                assert!(!own.contains(r.ed_identity()));
+            }
+        }
+
+        // If our chosen exit is one of our own relays, we refuse.
+        let chosen = netdir.by_id(&[0x24; 32].into()).unwrap();
+        let path = ExitPathBuilder::from_chosen_exit(chosen)
+            .exclude_self(own)
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+    }
+
+    #[test]
+    fn avoid_relays() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // Pretend that a circuit through this path failed.
+        let failed = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .pick_path(&mut rng, dirinfo)
+            .unwrap();
+        let failed: Vec<Ed25519Identity> = match failed.inner {
+            TorPathInner::Path(p) => p.iter().map(|r| *r.id()).collect(),
+            _ => panic!("Generated the wrong kind of path"),
+        };
+        let failed_exit = failed[2];
+
+        // Avoiding the exit alone, and then the other relays too, we
+        // never use any of them again.
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder
+            .avoid_relays(vec![failed_exit])
+            .avoid_relays(failed[..2].iter().cloned());
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert_ne!(p[2].id(), &failed_exit);
+            for r in p.iter() {
+                assert!(!failed.contains(r.id()));
+            }
+        }
+
+        // If our chosen exit is one we were told to avoid, we refuse.
+        let chosen = netdir.by_id(&failed_exit).unwrap();
+        let path = ExitPathBuilder::from_chosen_exit(chosen)
+            .avoid_relays(vec![failed_exit])
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+    }
+
+    #[test]
+    fn prefer_exit_country() {
+        use crate::path::geoip::{CountryCode, GeoIp};
+        use std::net::IpAddr;
 
+        /// A fake GeoIp that puts relays 10 through 13 in Germany,
+        /// and everybody else in Japan.
+        struct FakeGeoIp;
+        impl GeoIp for FakeGeoIp {
+            fn country_for_addr(&self, _addr: IpAddr) -> Option<CountryCode> {
+                None
+            }
+            fn country_for_relay(&self, relay: &Relay<'_>) -> Option<CountryCode> {
+                match relay.id().as_bytes()[0] {
+                    10..=13 => CountryCode::new("DE"),
+                    _ => CountryCode::new("JP"),
+                }
+            }
+        }
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let geoip: Arc<dyn GeoIp> = Arc::new(FakeGeoIp);
+        let de = CountryCode::new("DE").unwrap();
+
+        // Count the good exit paths with a German exit among 1000 paths
+        // from `builder`.
+        let mut count_german = |builder: &ExitPathBuilder<'_>| {
+            let paths = pick_paths(builder, &mut rng, dirinfo, 1000);
+            paths
+                .iter()
+                .inspect(|p| assert_exit_path_ok(&p[..]))
+                .filter(|p| geoip.country_for_relay(&p[2]) == Some(de))
+                .count()
+        };
+
+        // Count how often we pick a German exit, with and without
+        // asking for one.
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.geoip(Arc::clone(&geoip));
+        let n_de_plain = count_german(&builder);
+        builder.prefer_exit_country(de);
+        let n_de_preferred = count_german(&builder);
+        // The German exits have 10/110 of the exit bandwidth, so
+        // preferring them should make about half of our exits German.
+        assert!(n_de_plain < 200);
+        assert!(n_de_preferred > 350);
+    }
+
+    #[test]
+    fn by_addrs() {
+        use std::net::IpAddr;
+        use tor_netdoc::types::policy::{AddrPolicy, RuleKind};
+
+        /// A fake source of exit policies that knows the complete policy
+        /// for only one exit, which rejects 203.0.113.0/24.
+        struct FakePolicies {
+            /// The policy for relay 0x13.
+            policy: Arc<AddrPolicy>,
+        }
+        impl ExitAddrPolicies for FakePolicies {
+            fn addr_policy(&self, relay: &Relay<'_>) -> Option<Arc<AddrPolicy>> {
+                if relay.id() == &Ed25519Identity::from([0x13; 32]) {
+                    Some(Arc::clone(&self.policy))
+                } else {
+                    None
+                }
+            }
+        }
+
+        let mut policy = AddrPolicy::new();
+        policy.push(RuleKind::Reject, "203.0.113.0/24:*".parse().unwrap());
+        policy.push(RuleKind::Accept, "*:*".parse().unwrap());
+        let policies: Arc<dyn ExitAddrPolicies> = Arc::new(FakePolicies {
+            policy: Arc::new(policy),
+        });
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let rejected: IpAddr = "203.0.113.5".parse().unwrap();
+        let allowed: IpAddr = "198.51.100.1".parse().unwrap();
+        let bad_exit: Ed25519Identity = [0x13; 32].into();
+
+        // Relay 0x13 allows port 443 in general, so it gets picked some of
+        // the time for an address it doesn't reject...
+        let builder = ExitPathBuilder::from_target_addrs(
+            vec![TargetAddr::new(allowed, 443)],
+            Arc::clone(&policies),
+        );
+        let paths = pick_paths(&builder, &mut rng, dirinfo, 1000);
+        paths.iter().for_each(|p| assert_exit_path_ok(&p[..]));
+        assert!(paths.iter().any(|p| p[2].id() == &bad_exit));
+
+        // ... but never for an address that it rejects.
+        let builder = ExitPathBuilder::from_target_addrs(
+            vec![TargetAddr::new(rejected, 443)],
+            Arc::clone(&policies),
+        );
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert!(p[2].id() != &bad_exit);
+            assert!(p[2].ipv4_policy().allows_port(443));
+        }
+    }
+
+    #[test]
+    fn for_target() {
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        match ExitPathBuilder::for_target("203.0.113.7", 443).inner {
+            ExitPathBuilderInner::WantsAddrs(addrs, _) => {
+                assert_eq!(addrs, vec![TargetAddr::new(v4, 443)]);
+            }
+            _ => panic!("wrong target kind"),
+        }
+
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        for host in ["2001:db8::7", "[2001:db8::7]"] {
+            match ExitPathBuilder::for_target(host, 80).inner {
+                ExitPathBuilderInner::WantsAddrs(addrs, _) => {
+                    assert_eq!(addrs, vec![TargetAddr::new(v6, 80)]);
+                    assert_eq!(addrs[0].target_port(), TargetPort::ipv6(80));
+                }
+                _ => panic!("wrong target kind"),
+            }
+        }
+
+        for host in ["www.torproject.org", "[www.torproject.org]", "2001:db8::7]"] {
+            match ExitPathBuilder::for_target(host, 443).inner {
+                ExitPathBuilderInner::WantsPorts(ports) => {
+                    assert_eq!(ports, vec![TargetPort::ipv4(443)]);
+                }
+                _ => panic!("wrong target kind"),
+            }
+        }
+
+        // The builders all produce working paths.
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        for host in ["203.0.113.7", "www.torproject.org"] {
+            let builder = ExitPathBuilder::for_target(host, 443);
+            for p in pick_paths(&builder, &mut rng, dirinfo, 1) {
+                assert_exit_path_ok(&p[..]);
+                assert!(TargetPort::ipv4(443).is_supported_by(&p[2]));
+            }
+        }
+    }
+
+    #[test]
+    fn bridge_entry() {
+        use tor_linkspec::{OwnedChanTarget, OwnedCircTarget};
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // A bridge that isn't in the network directory.
+        let target = OwnedCircTarget::new(
+            OwnedChanTarget::new(
+                vec!["192.0.2.7:443".parse().unwrap()],
+                [0x99; 32].into(),
+                [0x99; 20].into(),
+            ),
+            [0x42; 32].into(),
+            tor_protover::Protocols::default(),
+        );
+        let bridge = Bridge::new(target, Some("obfs4".into()));
+
+        for _ in 0..1000 {
+            let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+                .use_bridge(bridge.clone())
+                .pick_path(&mut rng, dirinfo)
+                .unwrap();
+            assert_same_path_when_owned(&path);
+            assert!(path.is_multihop());
+            assert_eq!(path.len(), 3);
+            if let TorPathInner::BridgeEntry(b, p) = &path.inner {
+                assert_eq!(b.target().ed_identity(), &Ed25519Identity::from([0x99; 32]));
+                assert_eq!(b.transport(), Some("obfs4"));
+                assert_eq!(p.len(), 2);
+                assert!(!p[0].in_same_family(&p[1]));
+                assert!(p[1].ipv4_policy().allows_port(80));
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+
+        // If the bridge is also a public relay, we don't use it again.
+        let listed = netdir.by_id(&[0x24; 32].into()).unwrap();
+        let bridge = Bridge::new(OwnedCircTarget::from_circ_target(&listed), None);
+        for _ in 0..1000 {
+            let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+                .use_bridge(bridge.clone())
+                .pick_path(&mut rng, dirinfo)
+                .unwrap();
+            if let TorPathInner::BridgeEntry(_, p) = &path.inner {
+                for r in p.iter() {
+                    assert!(r.ed_identity() != listed.ed_identity());
+                }
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+        let path = ExitPathBuilder::from_chosen_exit(listed)
+            .use_bridge(bridge)
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+    }
+
+    #[test]
+    fn fallback_exit() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let ports = vec![TargetPort::ipv4(1119)];
+
+        for _ in 0..1000 {
+            let (path, fallback) = ExitPathBuilder::from_target_ports(ports.clone())
+                .pick_path_with_fallback(&mut rng, dirinfo)
+                .unwrap();
+            let fallback = fallback.unwrap();
             if let TorPathInner::Path(p) = path.inner {
                 assert_exit_path_ok(&p[..]);
-                let exit = &p[2];
-                assert!(exit.ipv4_policy().allows_port(1119));
+                assert!(!fallback.same_relay(&p[2]));
+                assert!(fallback.ipv4_policy().allows_port(1119));
+                // Swapping in the fallback still gives a good path.
+                assert_exit_path_ok(&[p[0].clone(), p[1].clone(), fallback]);
             } else {
                 panic!("Generated the wrong kind of path");
             }
         }
 
+        // If there's only one usable exit, there's no fallback.
+        let own: HashSet<_> = netdir
+            .relays()
+            .filter(|r| r.ipv4_policy().allows_port(1119) && r.id().as_bytes()[0] != 0x20)
+            .map(|r| *r.id())
+            .collect();
+        let (path, fallback) = ExitPathBuilder::from_target_ports(ports)
+            .exclude_self(own)
+            .pick_path_with_fallback(&mut rng, dirinfo)
+            .unwrap();
+        assert_eq!(path.exit_relay().unwrap().id().as_bytes()[0], 0x20);
+        assert!(fallback.is_none());
+
+        // Nor is there one for a chosen exit.
         let chosen = netdir.by_id(&[0x20; 32].into()).unwrap();
+        let (_, fallback) = ExitPathBuilder::from_chosen_exit(chosen)
+            .pick_path_with_fallback(&mut rng, dirinfo)
+            .unwrap();
+        assert!(fallback.is_none());
+    }
+
+    #[test]
+    fn fixed_middles() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+        let pinned = vec![relay(0x10), relay(0x14)];
 
         for _ in 0..1000 {
-            let path = ExitPathBuilder::from_chosen_exit(chosen.clone())
-                .pick_path(&mut rng, dirinfo)
+            let (path, fallback) = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+                .with_fixed_middles(pinned.clone())
+                .pick_path_with_fallback(&mut rng, dirinfo)
                 .unwrap();
             assert_same_path_when_owned(&path);
             if let TorPathInner::Path(p) = path.inner {
-                assert_exit_path_ok(&p[..]);
-                let exit = &p[2];
-                assert_eq!(exit.ed_identity(), chosen.ed_identity());
+                assert_eq!(p.len(), 4);
+                assert!(p[1].same_relay(&pinned[0]));
+                assert!(p[2].same_relay(&pinned[1]));
+                for (idx, r) in p.iter().enumerate() {
+                    for other in &p[idx + 1..] {
+                        assert!(!r.in_same_family(other));
+                    }
+                }
+                let fallback = fallback.unwrap();
+                assert!(p[..3].iter().all(|r| !r.in_same_family(&fallback)));
             } else {
                 panic!("Generated the wrong kind of path");
             }
         }
+
+        // The pinned middles must be diverse from one another.
+        for bad in [
+            vec![relay(0x02), relay(0x03)],
+            vec![relay(0x10), relay(0x10)],
+        ] {
+            let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+                .with_fixed_middles(bad)
+                .pick_path(&mut rng, dirinfo);
+            assert!(matches!(path, Err(Error::NoRelays(_))));
+        }
+
+        // A chosen exit must be diverse from them too.
+        let path = ExitPathBuilder::from_chosen_exit(relay(0x15))
+            .with_fixed_middles(pinned)
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+
+        // The error says which rule the pinned middles broke.  Here,
+        // relays share a /16 in groups of four.
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|idx| std::net::SocketAddr::new([10, idx / 4, idx, 1].into(), 9001))
+            .netdir();
+        let relay = |idx: u8| netdir.by_id(&[idx; 32].into()).unwrap();
+        let mut problem = |middles: Vec<Relay<'_>>| match ExitPathBuilder::from_target_ports(vec![
+            TargetPort::ipv4(80),
+        ])
+        .with_fixed_middles(middles)
+        .pick_path(&mut rng, (&netdir).into())
+        {
+            Err(Error::NoRelays(msg)) => msg,
+            _ => panic!("Expected NoRelays"),
+        };
+        let msg = problem(vec![relay(0x04), relay(0x05)]);
+        assert!(msg.contains("same family"), "{}", msg);
+        let msg = problem(vec![relay(0x04), relay(0x06)]);
+        assert!(msg.contains("same subnet"), "{}", msg);
+    }
+
+    #[test]
+    fn selection_metrics() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        let (path, metrics) = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .pick_path_with_metrics(&mut rng, dirinfo)
+            .unwrap();
+        assert_eq!(path.len(), 3);
+        let entry = metrics.entry().unwrap();
+        let sum = metrics.exit() + metrics.middle() + entry;
+        assert!(sum <= metrics.total());
+        // The bookkeeping between hops should be cheap; allow plenty of
+        // slack so this isn't flaky on a loaded machine.
+        assert!(metrics.total() - sum < Duration::from_millis(500));
+
+        // With a bridge, there's no entry to select.
+        use tor_linkspec::OwnedCircTarget;
+        let listed = netdir.by_id(&[0x24; 32].into()).unwrap();
+        let bridge = Bridge::new(OwnedCircTarget::from_circ_target(&listed), None);
+        let (path, metrics) = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .use_bridge(bridge)
+            .pick_path_with_metrics(&mut rng, dirinfo)
+            .unwrap();
+        assert_eq!(path.len(), 3);
+        assert!(metrics.entry().is_none());
+        assert!(metrics.exit() + metrics.middle() <= metrics.total());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn onion_key_overrides() {
+        use crate::build::OnionKeyOverrides;
+        use tor_linkspec::CircTarget;
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .pick_path(&mut rng, (&netdir).into())
+            .unwrap();
+        let owned: OwnedPath = (&path).try_into().unwrap();
+
+        let fake_key: tor_llcrypto::pk::curve25519::PublicKey = [0x42; 32].into();
+        let mut overrides = OnionKeyOverrides::new();
+        overrides.insert(1, fake_key);
+        let replaced = owned.with_onion_key_overrides(&overrides).unwrap();
+
+        match (&owned, &replaced) {
+            (OwnedPath::Normal(orig), OwnedPath::Normal(new)) => {
+                assert_eq!(orig.len(), new.len());
+                for (idx, (o, n)) in orig.iter().zip(new.iter()).enumerate() {
+                    assert_eq!(o.ed_identity(), n.ed_identity());
+                    if idx == 1 {
+                        assert_eq!(n.ntor_onion_key().as_bytes(), fake_key.as_bytes());
+                    } else {
+                        assert_eq!(n.ntor_onion_key().as_bytes(), o.ntor_onion_key().as_bytes());
+                    }
+                }
+            }
+            _ => panic!("Generated the wrong kind of path"),
+        }
+
+        // Overriding a hop that isn't there is an error.
+        overrides.insert(3, fake_key);
+        assert!(owned.with_onion_key_overrides(&overrides).is_err());
+    }
+
+    #[test]
+    fn geo_diversity() {
+        use crate::path::geoip::{CountryCode, GeoIp};
+        use std::net::IpAddr;
+
+        /// Puts relays 0x1e and 0x20 in Germany, relays 0x00 through 0x09
+        /// nowhere we know, and every other relay in a country of its own.
+        struct FakeGeoIp;
+        impl GeoIp for FakeGeoIp {
+            fn country_for_addr(&self, _addr: IpAddr) -> Option<CountryCode> {
+                None
+            }
+            fn country_for_relay(&self, relay: &Relay<'_>) -> Option<CountryCode> {
+                match relay.id().as_bytes()[0] {
+                    0x00..=0x09 => None,
+                    0x1e | 0x20 => CountryCode::new("DE"),
+                    idx => {
+                        let cc = [b'A' + idx / 26, b'A' + idx % 26];
+                        CountryCode::new(std::str::from_utf8(&cc).unwrap())
+                    }
+                }
+            }
+        }
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let geoip: Arc<dyn GeoIp> = Arc::new(FakeGeoIp);
+        let is_german = |r: &Relay<'_>| matches!(r.id().as_bytes()[0], 0x1e | 0x20);
+        let is_unknown = |r: &Relay<'_>| r.id().as_bytes()[0] < 0x0a;
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.require_geo_diversity(Arc::clone(&geoip));
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert!(!(is_german(&p[0]) && is_german(&p[2])));
+            assert!(!is_unknown(&p[0]) && !is_unknown(&p[2]));
+        }
+
+        // Now check the middle too, and allow unknown countries.
+        builder
+            .geo_diversity_includes_middle(true)
+            .geo_diversity_allows_unknown(true);
+        let paths = pick_paths(&builder, &mut rng, dirinfo, 1000);
+        for p in paths.iter() {
+            assert_exit_path_ok(&p[..]);
+            assert!(p.iter().filter(|r| is_german(r)).count() <= 1);
+        }
+        assert!(paths.iter().any(|p| p.iter().any(is_unknown)));
+
+        // With a chosen German exit, the entry is never German.
+        let chosen = netdir.by_id(&[0x20; 32].into()).unwrap();
+        let mut builder = ExitPathBuilder::from_chosen_exit(chosen);
+        builder.require_geo_diversity(Arc::clone(&geoip));
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert!(!is_german(&p[0]));
+        }
+    }
+
+    #[test]
+    fn as_diversity() {
+        use crate::path::asn::{Asn, AsnLookup};
+        use std::net::IpAddr;
+
+        /// Puts relays 0x00 through 0x03 in no AS we know, and every other
+        /// relay in one of four ASes, by the low bits of its ID.
+        struct FakeAsnLookup;
+        impl AsnLookup for FakeAsnLookup {
+            fn asn_for_addr(&self, _addr: IpAddr) -> Option<Asn> {
+                None
+            }
+            fn asn_for_relay(&self, relay: &Relay<'_>) -> Option<Asn> {
+                match relay.id().as_bytes()[0] {
+                    0x00..=0x03 => None,
+                    idx => Some(Asn::new(64496 + u32::from(idx % 4))),
+                }
+            }
+        }
+
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+        let lookup: Arc<dyn AsnLookup> = Arc::new(FakeAsnLookup);
+        let asn = |r: &Relay<'_>| lookup.asn_for_relay(r);
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.require_as_diversity(Arc::clone(&lookup));
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert!(asn(&p[0]).is_some() && asn(&p[2]).is_some());
+            assert_ne!(asn(&p[0]), asn(&p[2]));
+        }
+
+        // Now check the middle too, and allow unknown ASes.
+        builder
+            .as_diversity_includes_middle(true)
+            .as_diversity_allows_unknown(true);
+        let mut saw_unknown = false;
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            let known: Vec<_> = p.iter().filter_map(asn).collect();
+            for (i, a) in known.iter().enumerate() {
+                assert!(!known[i + 1..].contains(a));
+            }
+            saw_unknown |= known.len() < 3;
+        }
+        assert!(saw_unknown);
+
+        // With a chosen exit, the entry is never in the exit's AS.
+        let chosen = netdir.by_id(&[0x21; 32].into()).unwrap();
+        let mut builder = ExitPathBuilder::from_chosen_exit(chosen.clone());
+        builder.require_as_diversity(Arc::clone(&lookup));
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_ne!(asn(&p[0]), asn(&chosen));
+        }
+    }
+
+    #[test]
+    fn subnet_diversity() {
+        use crate::path::subnet::{HopPosition, SubnetDiversity};
+
+        // Relay number idx is at 10.{idx/4}.{idx}.1, so relays share a
+        // /8, and groups of four relays share a /16.
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|idx| std::net::SocketAddr::new([10, idx / 4, idx, 1].into(), 9001))
+            .netdir();
+        let mut rng = test_rng();
+        let dirinfo = (&netdir).into();
+        let octets = |r: &Relay<'_>| match r.addrs()[0].ip() {
+            std::net::IpAddr::V4(a) => a.octets(),
+            std::net::IpAddr::V6(_) => panic!("Unexpected IPv6 address"),
+        };
+        let slash16 = |r: &Relay<'_>| octets(r)[..2].to_vec();
+        let slash8 = |r: &Relay<'_>| octets(r)[0];
+
+        // By default, no two hops share a /16.
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert_ne!(slash16(&p[0]), slash16(&p[1]));
+            assert_ne!(slash16(&p[1]), slash16(&p[2]));
+            assert_ne!(slash16(&p[0]), slash16(&p[2]));
+        }
+
+        // If we turn off the check for the entry and exit, they can
+        // share a /16, but adjacent hops still can't.
+        builder.require_subnet_diversity(
+            HopPosition::Entry,
+            HopPosition::Exit,
+            SubnetDiversity::new(0, 0),
+        );
+        let mut saw_shared_slash16 = false;
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert_ne!(slash16(&p[0]), slash16(&p[1]));
+            assert_ne!(slash16(&p[1]), slash16(&p[2]));
+            saw_shared_slash16 |= slash16(&p[0]) == slash16(&p[2]);
+        }
+        assert!(saw_shared_slash16);
+
+        // With a /8 rule everywhere, no path fits: every relay is in 10/8.
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .subnet_diversity(SubnetDiversity::new(8, 32))
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+
+        // Now move relays 0x14 through 0x1d (our plain guards) into
+        // another /8, and require the entry and exit to be in
+        // different /8s, while the middle only needs a different /16.
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|idx| {
+                let first = if (0x14..=0x1d).contains(&idx) { 11 } else { 10 };
+                std::net::SocketAddr::new([first, idx / 4, idx, 1].into(), 9001)
+            })
+            .netdir();
+        let dirinfo = (&netdir).into();
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder
+            .require_subnet_diversity(
+                HopPosition::Exit,
+                HopPosition::Entry,
+                SubnetDiversity::new(8, 16),
+            )
+            .require_subnet_diversity(
+                HopPosition::Entry,
+                HopPosition::Middle,
+                SubnetDiversity::default(),
+            )
+            .require_subnet_diversity(
+                HopPosition::Middle,
+                HopPosition::Exit,
+                SubnetDiversity::default(),
+            );
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert_ne!(slash8(&p[0]), slash8(&p[2]));
+            assert_ne!(slash16(&p[0]), slash16(&p[1]));
+            assert_ne!(slash16(&p[1]), slash16(&p[2]));
+        }
+    }
+
+    #[test]
+    fn require_recent() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // In the test network, only the even-numbered relays support
+        // DirCache=2.
+        let recent: Protocols = "DirCache=2".parse().unwrap();
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.require_recent(recent.clone());
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            for r in p.iter() {
+                assert_eq!(r.id().as_bytes()[0] % 2, 0);
+            }
+        }
+
+        // An old chosen exit is refused.
+        let chosen = netdir.by_id(&[0x21; 32].into()).unwrap();
+        let path = ExitPathBuilder::from_chosen_exit(chosen)
+            .require_recent(recent)
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+
+        // If no relay is recent enough, we say why.
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .require_recent("DirCache=2 Link=5".parse().unwrap())
+            .pick_path(&mut rng, dirinfo);
+        match path {
+            Err(Error::NoRelays(msg)) => assert_eq!(
+                msg,
+                "No exit relay found among relays with the required protocol versions"
+            ),
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn exclude_versions() {
+        use crate::path::version::VersionSpec;
+
+        let mut rng = test_rng();
+        // Relays whose number is a multiple of 3 run 0.4.7; the others
+        // run 0.4.6, except for relay 1, which doesn't say.
+        let netdir = testnet::NetworkSpec::new()
+            .versions(|idx| match idx {
+                1 => None,
+                _ if idx % 3 == 0 => Some(format!("Tor 0.4.7.{}", idx)),
+                _ => Some("Tor 0.4.6.7".to_string()),
+            })
+            .netdir();
+        let dirinfo = (&netdir).into();
+        let spec = |s: &str| VersionSpec::new(s).unwrap();
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.exclude_versions(vec![spec("0.4.7.*")]);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            for r in p.iter() {
+                assert_ne!(r.id().as_bytes()[0] % 3, 0);
+            }
+        }
+
+        // An excluded chosen exit is refused.
+        let chosen = netdir.by_id(&[0x21; 32].into()).unwrap();
+        let path = ExitPathBuilder::from_chosen_exit(chosen)
+            .exclude_versions(vec![spec("0.4.7.33")])
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+
+        // If we exclude every version, only relay 1 is left, which isn't
+        // enough.
+        let path = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)])
+            .exclude_versions(vec![spec("0.4.7.*"), spec("0.4.6.*")])
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+    }
+
+    #[test]
+    fn middle_only() {
+        use tor_netdoc::doc::netstatus::RelayFlags;
+
+        let mut rng = test_rng();
+        // Every third relay is MiddleOnly, whatever its other flags.
+        let netdir = testnet::NetworkSpec::new()
+            .extra_flags(|idx| {
+                if idx % 3 == 0 {
+                    RelayFlags::MIDDLE_ONLY
+                } else {
+                    RelayFlags::empty()
+                }
+            })
+            .netdir();
+        let dirinfo = (&netdir).into();
+
+        let mut middle_only_middles = 0;
+        let builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 1000) {
+            assert_exit_path_ok(&p[..]);
+            assert!(!p[0].is_middle_only());
+            assert!(!p[2].is_middle_only());
+            if p[1].is_middle_only() {
+                middle_only_middles += 1;
+            }
+        }
+        assert!(middle_only_middles > 0);
+
+        // A MiddleOnly chosen exit is refused.
+        let chosen = netdir.by_id(&[0x21; 32].into()).unwrap();
+        assert!(chosen.is_middle_only());
+        let path = ExitPathBuilder::from_chosen_exit(chosen).pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+    }
+
+    #[test]
+    fn prefer_flags() {
+        let mut rng = test_rng();
+        // Even-numbered relays are Stable and Fast; odd-numbered relays
+        // are only Fast.
+        let netdir = testnet::NetworkSpec::new()
+            .extra_flags(|idx| {
+                if idx % 2 == 0 {
+                    RelayFlags::STABLE | RelayFlags::FAST
+                } else {
+                    RelayFlags::FAST
+                }
+            })
+            .netdir();
+        let dirinfo = (&netdir).into();
+        let wanted = RelayFlags::STABLE | RelayFlags::FAST;
+
+        // Count the hops with both flags in 1000 paths.
+        let mut count_wanted = |b: &ExitPathBuilder<'_>| {
+            let mut n = 0;
+            for p in pick_paths(b, &mut rng, dirinfo, 1000) {
+                assert_exit_path_ok(&p[..]);
+                n += p.iter().filter(|r| r.flags().contains(wanted)).count();
+            }
+            n
+        };
+
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        let n_plain = count_wanted(&builder);
+        builder.prefer_flags(wanted, 10.0);
+        let n_boosted = count_wanted(&builder);
+        // Without a preference, about half of the 3000 hops have the
+        // flags; with one, nearly all of them do.
+        assert!(n_plain < 2000, "{}", n_plain);
+        assert!(n_boosted > 2500, "{}", n_boosted);
+
+        // Bad boosts are ignored.
+        builder.prefer_flags(wanted, f64::NAN);
+        builder.prefer_flags(wanted, -1.0);
+        assert_eq!(builder.preferred_flags, Some((wanted, 10.0)));
+    }
+
+    #[test]
+    fn avoid_top_fraction() {
+        let mut rng = test_rng();
+        let netdir = testnet::construct_netdir();
+        let dirinfo = (&netdir).into();
+
+        // Find the heaviest fifth of the exits that could carry our
+        // traffic.
+        let mut exits: Vec<_> = netdir
+            .relays()
+            .filter(|r| r.supports_exit_port_ipv4(80))
+            .map(|r| (netdir.relay_weight(&r, WeightRole::Exit), *r.id()))
+            .filter(|(w, _)| *w > 0)
+            .collect();
+        exits.sort_by_key(|(w, _)| std::cmp::Reverse(*w));
+        let n_heavy = exits.len() / 5;
+        assert!(n_heavy > 0);
+        let heavy: HashSet<_> = exits[..n_heavy].iter().map(|(_, id)| *id).collect();
+
+        let mut count_heavy = |b: &ExitPathBuilder<'_>| {
+            let paths = pick_paths(b, &mut rng, dirinfo, 1000);
+            paths.iter().for_each(|p| assert_exit_path_ok(&p[..]));
+            paths.iter().filter(|p| heavy.contains(p[2].id())).count()
+        };
+
+        // Ordinarily, the heaviest exits get plenty of use; if we avoid
+        // them, they get none.
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        assert!(count_heavy(&builder) > 100);
+        builder.avoid_top_fraction(0.2);
+        assert_eq!(count_heavy(&builder), 0);
+
+        // Bad fractions are ignored.
+        builder.avoid_top_fraction(1.0);
+        builder.avoid_top_fraction(f64::NAN);
+        builder.avoid_top_fraction(-0.5);
+        assert_eq!(builder.avoid_top_fraction, Some(0.2));
+    }
+
+    #[test]
+    fn reachable() {
+        let ports =
+            |s: &str| PortSet::from_ranges(s.split(',').map(|r| r.parse::<PortRange>().unwrap()));
+
+        // Ranges get sorted and merged.
+        let set = ports("443,1-79,80,8000-9000,8500-8600,9001");
+        assert_eq!(set.to_string(), "1-80,443,8000-9001");
+        assert!(set.contains(80));
+        assert!(!set.contains(81));
+        assert!(set.contains(8555));
+        assert!(PortSet::default().is_empty());
+
+        // Every exit in the test network allows 80 and 443, and the
+        // even-numbered ones allow everything.
+        let netdir = testnet::construct_netdir();
+        assert_eq!(reachable_ports(&netdir), ports("1-65535"));
+
+        // If the even-numbered exits are BadExits or MiddleOnly, only
+        // 80 and 443 are left.
+        let netdir = testnet::NetworkSpec::new()
+            .extra_flags(|idx| match idx % 4 {
+                0 => RelayFlags::BAD_EXIT,
+                2 => RelayFlags::MIDDLE_ONLY,
+                _ => RelayFlags::empty(),
+            })
+            .netdir();
+        let reachable = reachable_ports(&netdir);
+        assert_eq!(reachable.to_string(), "80,443");
+        assert!(!reachable.contains(22));
+
+        // If nobody is a usable exit, nothing is reachable.
+        let netdir = testnet::NetworkSpec::new()
+            .extra_flags(|_| RelayFlags::BAD_EXIT)
+            .netdir();
+        assert!(reachable_ports(&netdir).is_empty());
+    }
+
+    #[test]
+    fn relaxed_diversity() {
+        use crate::path::subnet::{HopPosition, SubnetDiversity};
+
+        let mut rng = test_rng();
+        // Give every relay in the test network the same address.
+        let netdir = testnet::NetworkSpec::new()
+            .addrs(|_| "127.0.0.1:9001".parse().unwrap())
+            .netdir();
+        let dirinfo = (&netdir).into();
+        let same_subnet = || {
+            let mut b = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+            b.require_subnet_diversity(
+                HopPosition::Middle,
+                HopPosition::Exit,
+                SubnetDiversity::default(),
+            );
+            b
+        };
+
+        // By default, we don't relax anything.
+        let path = same_subnet().pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
+
+        // If we may, we give up on subnets, but we still keep families
+        // apart, since that's enough.
+        let mut builder = same_subnet();
+        builder.allow_relaxed_diversity(RelaxedDiversity::Family);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 100) {
+            assert_exit_path_ok(&p[..]);
+        }
+
+        // When there's nothing to relax, relaxing changes nothing.
+        let mut builder = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(80)]);
+        builder.allow_relaxed_diversity(RelaxedDiversity::Family);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 100) {
+            assert_exit_path_ok(&p[..]);
+        }
+
+        // Now leave only two families: an exit and its sibling, and a
+        // pair of guards.  There's no family-diverse path.
+        let keep = [0x0a, 0x0b, 0x14, 0x15];
+        let own: HashSet<Ed25519Identity> = (0..40_u8)
+            .filter(|idx| !keep.contains(idx))
+            .map(|idx| [idx; 32].into())
+            .collect();
+        let exit = netdir.by_id(&[0x0a; 32].into()).unwrap();
+        let small = || {
+            let mut b = ExitPathBuilder::from_chosen_exit(exit.clone());
+            b.exclude_self(own.clone());
+            b
+        };
+        for level in &[RelaxedDiversity::Strict, RelaxedDiversity::Subnet] {
+            let path = small()
+                .allow_relaxed_diversity(*level)
+                .pick_path(&mut rng, dirinfo);
+            assert!(matches!(path, Err(Error::NoRelays(_))));
+        }
+        let mut builder = small();
+        builder.allow_relaxed_diversity(RelaxedDiversity::Family);
+        for p in pick_paths(&builder, &mut rng, dirinfo, 100) {
+            assert!(!p[0].same_relay(&p[1]));
+            assert!(!p[0].same_relay(&p[2]));
+            assert!(!p[1].same_relay(&p[2]));
+            assert!(p.iter().all(|r| keep.contains(&r.id().as_bytes()[0])));
+        }
+
+        // Even at our most relaxed, we never use a relay twice: with
+        // only two relays, there's no path.
+        let own: HashSet<Ed25519Identity> = (0..40_u8)
+            .filter(|idx| *idx != 0x0a && *idx != 0x0b)
+            .map(|idx| [idx; 32].into())
+            .collect();
+        let path = ExitPathBuilder::from_chosen_exit(exit)
+            .exclude_self(own)
+            .allow_relaxed_diversity(RelaxedDiversity::Family)
+            .pick_path(&mut rng, dirinfo);
+        assert!(matches!(path, Err(Error::NoRelays(_))));
     }
 
     #[test]
     fn empty_path() {
         // This shouldn't actually be constructable IRL, but let's test to
         // make sure our code can handle it.
-        let bogus_path = TorPath {
-            inner: TorPathInner::Path(vec![]),
-        };
+        let bogus_path = TorPath::new_multihop(vec![]);
 
         assert!(bogus_path.exit_relay().is_none());
         assert!(bogus_path.exit_policy().is_none());
         assert_eq!(bogus_path.len(), 0);
+        assert!(bogus_path.is_multihop());
 
         let owned: Result<OwnedPath> = (&bogus_path).try_into();
         assert!(owned.is_err());
@@ -0,0 +1,79 @@
+//! Country lookups for relays, for use in path selection.
+//!
+//! Arti doesn't ship a GeoIP database of its own: callers who want
+//! country-aware path selection need to supply one, by implementing
+//! [`GeoIp`].
+
+use std::fmt;
+use std::net::IpAddr;
+use tor_linkspec::ChanTarget;
+use tor_netdir::Relay;
+
+/// A two-letter ISO 3166-1 country code, like "DE" or "US".
+///
+/// Country codes are always stored in upper case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    /// Construct a new CountryCode from a two-letter string.
+    ///
+    /// Return None if `cc` isn't exactly two ASCII letters.
+    pub fn new(cc: &str) -> Option<Self> {
+        match cc.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => Some(CountryCode([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+            ])),
+            _ => None,
+        }
+    }
+
+    /// Return this country code as a string.
+    pub fn as_str(&self) -> &str {
+        // We only construct these from ASCII letters.
+        std::str::from_utf8(&self.0).unwrap_or("??")
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A caller-supplied source of country information for IP addresses.
+pub trait GeoIp: Send + Sync {
+    /// Return the country in which `addr` is located, if known.
+    fn country_for_addr(&self, addr: IpAddr) -> Option<CountryCode>;
+
+    /// Return the country in which `relay` is located, if known.
+    ///
+    /// By default, this is the country of the first of the relay's
+    /// addresses that has a known country.
+    fn country_for_relay(&self, relay: &Relay<'_>) -> Option<CountryCode> {
+        relay
+            .addrs()
+            .iter()
+            .find_map(|a| self.country_for_addr(a.ip()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn country_code() {
+        let de = CountryCode::new("de").unwrap();
+        assert_eq!(de, CountryCode::new("DE").unwrap());
+        assert_eq!(de.as_str(), "DE");
+        assert_eq!(de.to_string(), "DE");
+
+        assert!(CountryCode::new("").is_none());
+        assert!(CountryCode::new("D").is_none());
+        assert!(CountryCode::new("DEU").is_none());
+        assert!(CountryCode::new("D1").is_none());
+        assert!(CountryCode::new("ü").is_none());
+    }
+}
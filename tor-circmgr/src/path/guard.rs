@@ -0,0 +1,278 @@
+//! Code to remember which guards we're using, and to rotate them.
+//!
+//! A client that picked a new first hop for every path would sooner or
+//! later pick one run by an adversary.  Instead, we stick with a small
+//! sample of guards for a long time, and only replace them on a schedule
+//! (or when they leave the network).  A [`GuardRotationPolicy`] decides
+//! when a guard is too old to keep.
+
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_netdir::{NetDir, Relay};
+use tor_rtcompat::SleepProvider;
+
+/// How long Tor keeps a guard, by default.
+const DEFAULT_GUARD_LIFETIME: Duration = Duration::from_secs(120 * 86400);
+
+/// How many guards we sample, by default.  (This is Tor's number of
+/// primary guards.)
+const DEFAULT_SAMPLE_SIZE: usize = 3;
+
+/// A rule for deciding when to stop using a guard.
+pub trait GuardRotationPolicy: Send + Sync {
+    /// Return true if a guard that we started using at `selected_at`
+    /// should be retired at `now`.
+    fn should_rotate(&self, selected_at: SystemTime, now: SystemTime) -> bool;
+}
+
+/// A [`GuardRotationPolicy`] that retires each guard once it has been in
+/// use for a fixed lifetime.
+#[derive(Clone, Debug)]
+pub struct LifetimeRotation {
+    /// How long we use a guard before replacing it.
+    lifetime: Duration,
+}
+
+impl LifetimeRotation {
+    /// Construct a new LifetimeRotation that keeps each guard for
+    /// `lifetime`.
+    pub fn new(lifetime: Duration) -> Self {
+        LifetimeRotation { lifetime }
+    }
+}
+
+impl Default for LifetimeRotation {
+    /// Keep each guard for 120 days, as Tor does.
+    fn default() -> Self {
+        LifetimeRotation::new(DEFAULT_GUARD_LIFETIME)
+    }
+}
+
+impl GuardRotationPolicy for LifetimeRotation {
+    fn should_rotate(&self, selected_at: SystemTime, now: SystemTime) -> bool {
+        match now.duration_since(selected_at) {
+            Ok(age) => age >= self.lifetime,
+            // The clock went backwards: keep the guard until it's had
+            // its full lifetime.
+            Err(_) => false,
+        }
+    }
+}
+
+/// One of the guards in a [`GuardSet`]'s sample.
+#[derive(Clone, Debug)]
+struct SampledGuard {
+    /// The guard's identity.
+    id: Ed25519Identity,
+    /// When we started using it.
+    selected_at: SystemTime,
+}
+
+/// The guards that we use as the first hop of our paths, and the policy
+/// for replacing them.
+///
+/// Give one to [`ExitPathBuilder::guards`](super::exitpath::ExitPathBuilder::guards)
+/// to have the builder use it.
+pub struct GuardSet {
+    /// A function to tell us what time it is.
+    clock: Box<dyn Fn() -> SystemTime + Send + Sync>,
+    /// The policy that decides when to retire our guards.
+    policy: Arc<dyn GuardRotationPolicy>,
+    /// The largest number of guards that we sample.
+    sample_size: usize,
+    /// The guards that we've sampled, in order of preference.
+    sample: Mutex<Vec<SampledGuard>>,
+}
+
+impl GuardSet {
+    /// Construct a new GuardSet with no guards yet, that uses `runtime`'s
+    /// wall clock to decide when `policy` says to rotate.
+    pub fn new<R>(runtime: R, policy: Arc<dyn GuardRotationPolicy>) -> Self
+    where
+        R: SleepProvider + Send + Sync + 'static,
+    {
+        GuardSet {
+            clock: Box::new(move || runtime.wallclock()),
+            policy,
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            sample: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sample at most `n` guards.  The default is 3.
+    ///
+    /// `n` must be at least 1.
+    pub fn set_sample_size(&mut self, n: usize) -> &mut Self {
+        self.sample_size = n.max(1);
+        self
+    }
+
+    /// Return the identity of each guard that we've sampled, and when we
+    /// started using it, in order of preference.
+    pub fn guards(&self) -> Vec<(Ed25519Identity, SystemTime)> {
+        self.sample
+            .lock()
+            .expect("poisoned lock")
+            .iter()
+            .map(|g| (g.id, g.selected_at))
+            .collect()
+    }
+
+    /// Return the guard to use as the first hop of a path.
+    ///
+    /// We return the first of our guards that is still listed in
+    /// `netdir`, is not due for rotation, and that `usable` says can go
+    /// in this path.  If there's none, we call `pick_new` to pick another
+    /// relay, and add it to our sample if there's room.  (If there isn't,
+    /// we use it for this path only: a guard that just doesn't suit one
+    /// path stays in our sample.)
+    pub(crate) fn pick_guard<'a, U, P>(
+        &self,
+        netdir: &'a NetDir,
+        usable: U,
+        pick_new: P,
+    ) -> Option<Relay<'a>>
+    where
+        U: Fn(&Relay<'a>) -> bool,
+        P: FnOnce() -> Option<Relay<'a>>,
+    {
+        let now = (self.clock)();
+        let mut sample = self.sample.lock().expect("poisoned lock");
+
+        let mut listed = Vec::new();
+        sample.retain(|guard| {
+            if self.policy.should_rotate(guard.selected_at, now) {
+                info!("Retiring guard {}: it's due for rotation.", guard.id);
+                return false;
+            }
+            match netdir.relays().find(|r| r.id() == &guard.id) {
+                Some(relay) => {
+                    listed.push(relay);
+                    true
+                }
+                None => {
+                    info!("Retiring guard {}: it isn't listed any more.", guard.id);
+                    false
+                }
+            }
+        });
+        if let Some(relay) = listed.into_iter().find(|r| usable(r)) {
+            return Some(relay);
+        }
+
+        let relay = pick_new()?;
+        if sample.len() < self.sample_size {
+            sample.push(SampledGuard {
+                id: *relay.id(),
+                selected_at: now,
+            });
+        }
+        Some(relay)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tor_netdir::testnet;
+    use tor_rtmock::time::MockSleepProvider;
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    #[test]
+    fn lifetime() {
+        let p = LifetimeRotation::new(DAY * 30);
+        let t = SystemTime::UNIX_EPOCH + DAY * 1000;
+        assert!(!p.should_rotate(t, t));
+        assert!(!p.should_rotate(t, t + DAY * 29));
+        assert!(p.should_rotate(t, t + DAY * 30));
+        assert!(!p.should_rotate(t, t - DAY));
+    }
+
+    #[test]
+    fn rotation() {
+        let netdir = testnet::construct_netdir();
+        let start = SystemTime::UNIX_EPOCH + DAY * 1000;
+        let clock = MockSleepProvider::new(start);
+        let mut guards = GuardSet::new(clock.clone(), Arc::new(LifetimeRotation::new(DAY * 30)));
+        guards.set_sample_size(1);
+        let relay = |n: u8| netdir.by_id(&[n; 32].into());
+
+        assert!(guards.guards().is_empty());
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x05));
+        assert_eq!(g.unwrap().id(), &[0x05; 32].into());
+        assert_eq!(guards.guards(), vec![([0x05; 32].into(), start)]);
+
+        // Before the guard's lifetime is up, we keep using it.
+        clock.jump_to(start + DAY * 29);
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x06));
+        assert_eq!(g.unwrap().id(), &[0x05; 32].into());
+
+        // If it doesn't suit a path, we use another relay for that path,
+        // but keep it as our guard.
+        let g = guards.pick_guard(&netdir, |r| r.id() != &[0x05; 32].into(), || relay(0x06));
+        assert_eq!(g.unwrap().id(), &[0x06; 32].into());
+        assert_eq!(guards.guards(), vec![([0x05; 32].into(), start)]);
+
+        // Once its lifetime is up, we pick a new one.
+        let later = start + DAY * 31;
+        clock.jump_to(later);
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x07));
+        assert_eq!(g.unwrap().id(), &[0x07; 32].into());
+        assert_eq!(guards.guards(), vec![([0x07; 32].into(), later)]);
+    }
+
+    #[test]
+    fn sample() {
+        let netdir = testnet::construct_netdir();
+        let clock = MockSleepProvider::new(SystemTime::UNIX_EPOCH);
+        let guards = GuardSet::new(clock, Arc::new(LifetimeRotation::default()));
+        let relay = |n: u8| netdir.by_id(&[n; 32].into());
+        let id = |n: u8| -> Ed25519Identity { [n; 32].into() };
+        let sampled = || guards.guards().into_iter().map(|g| g.0).collect::<Vec<_>>();
+
+        // We fill up the sample only as guards turn out not to suit a path.
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x05));
+        assert_eq!(g.unwrap().id(), &id(0x05));
+        let g = guards.pick_guard(&netdir, |r| r.id() != &id(0x05), || relay(0x06));
+        assert_eq!(g.unwrap().id(), &id(0x06));
+        let g = guards.pick_guard(&netdir, |r| r.id() == &id(0x07), || relay(0x07));
+        assert_eq!(g.unwrap().id(), &id(0x07));
+        assert_eq!(sampled(), vec![id(0x05), id(0x06), id(0x07)]);
+
+        // We prefer our guards in the order we sampled them.
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x08));
+        assert_eq!(g.unwrap().id(), &id(0x05));
+        let g = guards.pick_guard(&netdir, |r| r.id() != &id(0x05), || relay(0x08));
+        assert_eq!(g.unwrap().id(), &id(0x06));
+
+        // The sample is full, so when none of them suits a path, we use
+        // another relay without adding it.
+        let g = guards.pick_guard(&netdir, |r| r.id() == &id(0x08), || relay(0x08));
+        assert_eq!(g.unwrap().id(), &id(0x08));
+        assert_eq!(sampled(), vec![id(0x05), id(0x06), id(0x07)]);
+    }
+
+    #[test]
+    fn unlisted() {
+        let netdir = testnet::construct_netdir();
+        let clock = MockSleepProvider::new(SystemTime::UNIX_EPOCH);
+        let guards = GuardSet::new(clock, Arc::new(LifetimeRotation::default()));
+        let relay = |n: u8| netdir.by_id(&[n; 32].into());
+        guards.pick_guard(&netdir, |_| true, || relay(0x05));
+
+        // Pretend that our guard has left the network.
+        guards.sample.lock().unwrap()[0] = SampledGuard {
+            id: [0xEE; 32].into(),
+            selected_at: SystemTime::UNIX_EPOCH,
+        };
+        let g = guards.pick_guard(&netdir, |_| true, || relay(0x08));
+        assert_eq!(g.unwrap().id(), &[0x08; 32].into());
+        assert_eq!(
+            guards.guards(),
+            vec![([0x08; 32].into(), SystemTime::UNIX_EPOCH)]
+        );
+    }
+}
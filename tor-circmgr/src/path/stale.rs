@@ -0,0 +1,193 @@
+//! Code to notice when the relays in our cached paths leave the network.
+//!
+//! When a new network directory replaces an old one, some of the relays
+//! that we used to build paths may no longer be listed.  Any path through
+//! one of those relays is now stale, and anybody holding such a path
+//! (like a pool of preemptively built paths) should discard it.
+
+use super::{TorPath, TorPathInner};
+use futures::channel::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_netdir::NetDir;
+
+/// An observer that tracks the relays used by a set of cached paths,
+/// and tells its subscribers which of those relays have disappeared
+/// whenever the network directory is replaced.
+///
+/// Whoever replaces the network directory is responsible for calling
+/// [`StalePathWatcher::netdir_replaced`].
+pub struct StalePathWatcher {
+    /// The mutable state of this watcher.
+    inner: Mutex<WatcherInner>,
+}
+
+/// Interior (locked) state for a [`StalePathWatcher`].
+struct WatcherInner {
+    /// Map from the identity of each relay in a tracked path to the
+    /// number of tracked paths that use it.
+    tracked: HashMap<Ed25519Identity, usize>,
+    /// Channels to notify when tracked relays leave the network.
+    subscribers: Vec<mpsc::UnboundedSender<Vec<Ed25519Identity>>>,
+}
+
+impl Default for StalePathWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return the identities of the relays in `path` that come from a
+/// network directory.
+///
+/// (Fallback directories and bridges aren't necessarily listed in the
+/// consensus, so we don't report them.)
+fn netdir_relay_ids(path: &TorPath<'_>) -> Vec<Ed25519Identity> {
+    match &path.inner {
+        TorPathInner::OneHop(r) => vec![*r.id()],
+        TorPathInner::FallbackOneHop(_) => Vec::new(),
+        TorPathInner::Path(p) | TorPathInner::BridgeEntry(_, p) => {
+            p.iter().map(|r| *r.id()).collect()
+        }
+    }
+}
+
+impl StalePathWatcher {
+    /// Construct a new StalePathWatcher, with no tracked paths and no
+    /// subscribers.
+    pub fn new() -> Self {
+        StalePathWatcher {
+            inner: Mutex::new(WatcherInner {
+                tracked: HashMap::new(),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Return a new stream of notifications.
+    ///
+    /// Each time the network directory is replaced and one or more
+    /// tracked relays are missing from the new directory, the stream
+    /// yields a list of their identities.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Vec<Ed25519Identity>> {
+        let (send, recv) = mpsc::unbounded();
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.subscribers.push(send);
+        recv
+    }
+
+    /// Start tracking the relays in `path`.
+    pub fn track_path(&self, path: &TorPath<'_>) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        for id in netdir_relay_ids(path) {
+            *inner.tracked.entry(id).or_insert(0) += 1;
+        }
+    }
+
+    /// Stop tracking the relays in `path`, which was previously passed to
+    /// [`StalePathWatcher::track_path`].
+    pub fn untrack_path(&self, path: &TorPath<'_>) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        for id in netdir_relay_ids(path) {
+            if let Some(n) = inner.tracked.get_mut(&id) {
+                *n -= 1;
+                if *n == 0 {
+                    inner.tracked.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Tell this watcher that `netdir` has replaced the previous
+    /// network directory.
+    ///
+    /// Every tracked relay that isn't usable in `netdir` is reported to
+    /// our subscribers, and then forgotten.  Return the list of those
+    /// relays' identities.
+    pub fn netdir_replaced(&self, netdir: &NetDir) -> Vec<Ed25519Identity> {
+        let present: HashSet<Ed25519Identity> = netdir.relays().map(|r| *r.id()).collect();
+
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let absent: Vec<Ed25519Identity> = inner
+            .tracked
+            .keys()
+            .filter(|id| !present.contains(*id))
+            .copied()
+            .collect();
+
+        if absent.is_empty() {
+            return absent;
+        }
+
+        for id in absent.iter() {
+            inner.tracked.remove(id);
+        }
+        // Notify everybody who is still listening, and forget about
+        // everybody who isn't.
+        inner
+            .subscribers
+            .retain(|s| s.unbounded_send(absent.clone()).is_ok());
+
+        absent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tor_netdir::{testnet, MdReceiver, PartialNetDir};
+
+    #[test]
+    fn notice_missing_relay() {
+        let netdir = testnet::construct_netdir();
+        let watcher = StalePathWatcher::new();
+        let mut events = watcher.subscribe();
+
+        let gone_id: Ed25519Identity = [0x05; 32].into();
+        let path = TorPath::new_multihop(vec![
+            netdir.by_id(&gone_id).unwrap(),
+            netdir.by_id(&[0x08; 32].into()).unwrap(),
+            netdir.by_id(&[0x20; 32].into()).unwrap(),
+        ]);
+        watcher.track_path(&path);
+
+        // Replacing the directory with an identical one doesn't tell
+        // us anything.
+        assert!(watcher.netdir_replaced(&netdir).is_empty());
+        assert!(events.try_next().is_err());
+
+        // Now make a directory that's missing the microdescriptor for
+        // one of our relays.
+        let (consensus, microdescs) = testnet::construct_network();
+        let mut dir = PartialNetDir::new(consensus, None);
+        for md in microdescs {
+            if md.ed25519_id() != &gone_id {
+                dir.add_microdesc(md);
+            }
+        }
+        let new_netdir = dir.unwrap_if_sufficient().unwrap();
+
+        assert_eq!(watcher.netdir_replaced(&new_netdir), vec![gone_id]);
+        assert_eq!(events.try_next().unwrap(), Some(vec![gone_id]));
+
+        // The missing relay was forgotten, so we don't hear about it twice.
+        assert!(watcher.netdir_replaced(&new_netdir).is_empty());
+        assert!(events.try_next().is_err());
+    }
+
+    #[test]
+    fn untrack() {
+        let netdir = testnet::construct_netdir();
+        let watcher = StalePathWatcher::default();
+
+        let relay = netdir.by_id(&[0x06; 32].into()).unwrap();
+        let path = TorPath::new_one_hop(relay);
+        watcher.track_path(&path);
+        watcher.track_path(&path);
+        watcher.untrack_path(&path);
+        assert_eq!(watcher.inner.lock().unwrap().tracked.len(), 1);
+        watcher.untrack_path(&path);
+        assert!(watcher.inner.lock().unwrap().tracked.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//! Rules for keeping the hops of a path on different networks.
+//!
+//! Relays that share a network (as judged by a common address prefix)
+//! are more likely to be run by, or visible to, the same party.  An
+//! [`ExitPathBuilder`](super::exitpath::ExitPathBuilder) can be told to
+//! keep any pair of hops out of the same subnet, with a different
+//! prefix length for each pair.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// A position in a path.
+///
+/// A path has one entry and one exit.  A three-hop path has one middle
+/// hop; longer paths have several, and shorter ones have none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HopPosition {
+    /// The first hop (a guard, or a bridge).
+    Entry,
+    /// Any hop between the entry and the exit.
+    Middle,
+    /// The last hop.
+    Exit,
+}
+
+impl HopPosition {
+    /// The number of distinct values that [`HopPosition::pair_index`]
+    /// can return.
+    pub(crate) const N_PAIRS: usize = 4;
+
+    /// Return an index for the unordered pair of hops at `a` and `b`, or
+    /// None if they can only be the same hop.
+    ///
+    /// Two middle hops are a pair of their own, since a path can have
+    /// more than one middle.
+    pub(crate) fn pair_index(a: HopPosition, b: HopPosition) -> Option<usize> {
+        use HopPosition::*;
+        match (a, b) {
+            (Entry, Middle) | (Middle, Entry) => Some(0),
+            (Middle, Exit) | (Exit, Middle) => Some(1),
+            (Entry, Exit) | (Exit, Entry) => Some(2),
+            (Middle, Middle) => Some(3),
+            (Entry, Entry) | (Exit, Exit) => None,
+        }
+    }
+}
+
+/// How wide a subnet two hops must not share.
+///
+/// Two addresses are in the same subnet if they are of the same family
+/// and agree on their first `ipv4_bits` (for IPv4) or `ipv6_bits` (for
+/// IPv6) bits.  A prefix length of zero turns off the check for that
+/// address family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubnetDiversity {
+    /// Prefix length to compare for IPv4 addresses.
+    ipv4_bits: u8,
+    /// Prefix length to compare for IPv6 addresses.
+    ipv6_bits: u8,
+}
+
+impl Default for SubnetDiversity {
+    /// Return the usual rule: no two hops in the same IPv4 /16 or IPv6
+    /// /32.
+    fn default() -> Self {
+        SubnetDiversity::new(16, 32)
+    }
+}
+
+impl SubnetDiversity {
+    /// Construct a new SubnetDiversity that compares the first
+    /// `ipv4_bits` of IPv4 addresses and the first `ipv6_bits` of IPv6
+    /// addresses.
+    ///
+    /// Prefix lengths longer than the address are treated as the whole
+    /// address.
+    pub fn new(ipv4_bits: u8, ipv6_bits: u8) -> Self {
+        SubnetDiversity {
+            ipv4_bits: ipv4_bits.min(32),
+            ipv6_bits: ipv6_bits.min(128),
+        }
+    }
+
+    /// Return the prefix length we compare for IPv4 addresses.
+    pub fn ipv4_bits(&self) -> u8 {
+        self.ipv4_bits
+    }
+
+    /// Return the prefix length we compare for IPv6 addresses.
+    pub fn ipv6_bits(&self) -> u8 {
+        self.ipv6_bits
+    }
+
+    /// Return true if `a` and `b` are in the same subnet under this
+    /// rule.
+    pub fn addrs_in_same_subnet(&self, a: &IpAddr, b: &IpAddr) -> bool {
+        match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let bits = u32::from(self.ipv4_bits);
+                bits != 0 && (u32::from(*a) ^ u32::from(*b)) >> (32 - bits) == 0
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let bits = u32::from(self.ipv6_bits);
+                bits != 0 && (u128::from(*a) ^ u128::from(*b)) >> (128 - bits) == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Return true if any address in `a` is in the same subnet as any
+    /// address in `b`.
+    pub fn any_in_same_subnet(&self, a: &[SocketAddr], b: &[SocketAddr]) -> bool {
+        a.iter().any(|a| {
+            b.iter()
+                .any(|b| self.addrs_in_same_subnet(&a.ip(), &b.ip()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_subnet() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let d16 = SubnetDiversity::default();
+        assert!(d16.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.1.200.4")));
+        assert!(!d16.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.2.2.3")));
+        assert!(d16.addrs_in_same_subnet(&ip("2001:db8::1"), &ip("2001:db8:ff::1")));
+        assert!(!d16.addrs_in_same_subnet(&ip("2001:db8::1"), &ip("2001:db9::1")));
+        assert!(!d16.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("::ffff:10.1.2.3")));
+
+        let d8 = SubnetDiversity::new(8, 16);
+        assert!(d8.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.2.2.3")));
+        assert!(!d8.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("11.1.2.3")));
+
+        let whole = SubnetDiversity::new(200, 200);
+        assert_eq!(whole.ipv4_bits(), 32);
+        assert_eq!(whole.ipv6_bits(), 128);
+        assert!(whole.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.1.2.3")));
+        assert!(!whole.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.1.2.4")));
+
+        let off = SubnetDiversity::new(0, 0);
+        assert!(!off.addrs_in_same_subnet(&ip("10.1.2.3"), &ip("10.1.2.3")));
+        assert!(!off.addrs_in_same_subnet(&ip("::1"), &ip("::1")));
+
+        let sa = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(d16.any_in_same_subnet(
+            &[sa("192.0.2.1:9001"), sa("10.1.2.3:443")],
+            &[sa("10.1.9.9:443")]
+        ));
+        assert!(!d16.any_in_same_subnet(&[sa("192.0.2.1:9001")], &[sa("10.1.9.9:443")]));
+        assert!(!d16.any_in_same_subnet(&[], &[sa("10.1.9.9:443")]));
+    }
+
+    #[test]
+    fn pair_index() {
+        use HopPosition::*;
+        let all = [Entry, Middle, Exit];
+        let mut seen = std::collections::HashSet::new();
+        for a in &all {
+            for b in &all {
+                let idx = HopPosition::pair_index(*a, *b);
+                assert_eq!(idx, HopPosition::pair_index(*b, *a));
+                if let Some(idx) = idx {
+                    assert!(idx < HopPosition::N_PAIRS);
+                    seen.insert(idx);
+                }
+            }
+        }
+        assert_eq!(seen.len(), HopPosition::N_PAIRS);
+        assert!(HopPosition::pair_index(Middle, Middle).is_some());
+        assert!(HopPosition::pair_index(Entry, Entry).is_none());
+        assert!(HopPosition::pair_index(Exit, Exit).is_none());
+    }
+}
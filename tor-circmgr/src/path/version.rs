@@ -0,0 +1,139 @@
+//! Patterns for matching the software versions that relays advertise.
+//!
+//! These are meant for operators investigating a bug in some version of
+//! Tor: an [`ExitPathBuilder`](super::exitpath::ExitPathBuilder) can be
+//! told to avoid every relay whose version matches a pattern.
+
+use std::fmt;
+
+/// A pattern that matches a set of Tor versions, like "0.4.7.*" or
+/// "0.4.6.7".
+///
+/// A pattern is a dot-separated list of components, each of which is
+/// either a number or `*`.  A `*` matches any single component, except
+/// at the end of the pattern, where it matches all of the remaining
+/// components.  Any status tag on a version (like the "-alpha" in
+/// "0.4.7.1-alpha") is ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionSpec {
+    /// The components of this pattern; None stands for `*`.
+    components: Vec<Option<u32>>,
+}
+
+impl VersionSpec {
+    /// Construct a new VersionSpec from a pattern like "0.4.7.*".
+    ///
+    /// Return None if `pattern` isn't a well-formed pattern.
+    pub fn new(pattern: &str) -> Option<Self> {
+        let components = pattern
+            .split('.')
+            .map(|c| match c {
+                "*" => Some(None),
+                _ if !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit()) => {
+                    c.parse().ok().map(Some)
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(VersionSpec { components })
+    }
+
+    /// Return true if `version` (a version as a relay advertises it, like
+    /// "Tor 0.4.7.8") matches this pattern.
+    ///
+    /// Versions that we can't parse never match.
+    pub fn matches(&self, version: &str) -> bool {
+        let version = match parse_version(version) {
+            Some(v) => v,
+            None => return false,
+        };
+        let (last, init) = match self.components.split_last() {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let init_matches = |version: &[u32]| {
+            init.iter().zip(version).all(|(p, v)| match p {
+                Some(p) => p == v,
+                None => true,
+            })
+        };
+        match last {
+            None => version.len() >= self.components.len() && init_matches(&version),
+            Some(last) => {
+                version.len() == self.components.len()
+                    && init_matches(&version)
+                    && version.last() == Some(last)
+            }
+        }
+    }
+}
+
+impl fmt::Display for VersionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, c) in self.components.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ".")?;
+            }
+            match c {
+                Some(n) => write!(f, "{}", n)?,
+                None => write!(f, "*")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split an advertised version like "Tor 0.4.7.1-alpha" into its numeric
+/// components, ignoring its status tag.
+///
+/// Return None if it doesn't look like a Tor version.
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    let version = version.strip_prefix("Tor ").unwrap_or(version);
+    let version = version.split_whitespace().next()?;
+    let version = version.split('-').next()?;
+    version.split('.').map(|c| c.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let spec = VersionSpec::new("0.4.7.*").unwrap();
+        assert_eq!(spec.to_string(), "0.4.7.*");
+        assert_eq!(VersionSpec::new("0.4.6.7").unwrap().to_string(), "0.4.6.7");
+
+        assert!(VersionSpec::new("").is_none());
+        assert!(VersionSpec::new("0.4.").is_none());
+        assert!(VersionSpec::new("0.4.x").is_none());
+        assert!(VersionSpec::new("0.4.+7").is_none());
+        assert!(VersionSpec::new("0.4.7*").is_none());
+    }
+
+    #[test]
+    fn matching() {
+        let spec = VersionSpec::new("0.4.7.*").unwrap();
+        assert!(spec.matches("Tor 0.4.7.8"));
+        assert!(spec.matches("Tor 0.4.7.1-alpha"));
+        assert!(spec.matches("0.4.7.10"));
+        assert!(!spec.matches("Tor 0.4.6.7"));
+        assert!(!spec.matches("Tor 0.4.7"));
+        assert!(!spec.matches("Tor 0.4.70.1"));
+        assert!(!spec.matches("Arti 0.4.x.1"));
+
+        let spec = VersionSpec::new("0.4.6.7").unwrap();
+        assert!(spec.matches("Tor 0.4.6.7"));
+        assert!(spec.matches("Tor 0.4.6.7-dev"));
+        assert!(!spec.matches("Tor 0.4.6.70"));
+        assert!(!spec.matches("Tor 0.4.6.7.1"));
+
+        let spec = VersionSpec::new("0.*.6.7").unwrap();
+        assert!(spec.matches("Tor 0.3.6.7"));
+        assert!(!spec.matches("Tor 0.3.6.8"));
+
+        let spec = VersionSpec::new("*").unwrap();
+        assert!(spec.matches("Tor 0.4.6.7"));
+        assert!(!spec.matches("garbage"));
+    }
+}
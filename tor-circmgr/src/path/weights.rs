@@ -0,0 +1,166 @@
+//! Overrides for how relays are weighted during path selection.
+//!
+//! By default, we pick relays for each [`WeightRole`] according to the
+//! bandwidth weights in the consensus.  A [`WeightOverrides`] lets a
+//! deployment replace that weighting for some roles, once, rather than
+//! passing a weighting closure to every path builder.
+
+use std::collections::HashMap;
+use std::mem::{discriminant, Discriminant};
+use std::sync::Arc;
+use tor_netdir::{NetDir, Relay, WeightRole};
+
+/// A function to decide how much weight to give a relay.
+///
+/// It receives the relay and the weight that the consensus would give
+/// it, and returns the weight to use instead.  Relays with weight zero
+/// are never picked.
+pub type WeightFn = dyn Fn(&Relay<'_>, u64) -> u64 + Send + Sync;
+
+/// A set of replacement weighting functions, one per [`WeightRole`].
+///
+/// Roles with no override use the consensus bandwidth weights.
+#[derive(Clone, Default)]
+pub struct WeightOverrides {
+    /// The override for each role that has one.
+    overrides: HashMap<Discriminant<WeightRole>, Arc<WeightFn>>,
+}
+
+impl WeightOverrides {
+    /// Return a new WeightOverrides with no overrides.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `f` to weight relays whenever we pick one for `role`.
+    ///
+    /// This replaces any earlier override for `role`.
+    pub fn set(&mut self, role: WeightRole, f: Arc<WeightFn>) -> &mut Self {
+        self.overrides.insert(discriminant(&role), f);
+        self
+    }
+
+    /// Go back to using the consensus weights for `role`.
+    pub fn clear(&mut self, role: WeightRole) -> &mut Self {
+        self.overrides.remove(&discriminant(&role));
+        self
+    }
+
+    /// Return the override for `role`, if there is one.
+    pub fn get(&self, role: WeightRole) -> Option<&Arc<WeightFn>> {
+        self.overrides.get(&discriminant(&role))
+    }
+
+    /// Return a weighting function that makes every relay equally
+    /// likely to be picked.
+    ///
+    /// (Relays that the consensus gives no weight for a role still get
+    /// none.)
+    pub fn uniform() -> Arc<WeightFn> {
+        Arc::new(|_, w| if w > 0 { 1 } else { 0 })
+    }
+
+    /// As [`NetDir::relay_weight`], but using our override for `role` if
+    /// we have one.
+    pub(crate) fn relay_weight(&self, netdir: &NetDir, relay: &Relay<'_>, role: WeightRole) -> u64 {
+        let w = netdir.relay_weight(relay, role);
+        match self.get(role) {
+            Some(f) => f(relay, w),
+            None => w,
+        }
+    }
+
+    /// As [`NetDir::pick_relay`], but using our override for `role` if
+    /// we have one.
+    pub(crate) fn pick_relay<'a, R, P>(
+        &self,
+        netdir: &'a NetDir,
+        rng: &mut R,
+        role: WeightRole,
+        usable: P,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        self.pick_relay_by_weight(netdir, rng, role, |r, w| if usable(r) { w } else { 0 })
+    }
+
+    /// As [`NetDir::pick_relay_by_weight`], but using our override for
+    /// `role` (if we have one) to find the weight that we pass to
+    /// `weight`.
+    pub(crate) fn pick_relay_by_weight<'a, R, F>(
+        &self,
+        netdir: &'a NetDir,
+        rng: &mut R,
+        role: WeightRole,
+        weight: F,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        F: Fn(&Relay<'a>, u64) -> u64,
+    {
+        match self.get(role) {
+            Some(f) => netdir.pick_relay_by_weight(rng, role, |r, w| weight(r, f(r, w))),
+            None => netdir.pick_relay_by_weight(rng, role, weight),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tor_netdir::testnet;
+
+    #[test]
+    fn registry() {
+        let mut o = WeightOverrides::new();
+        assert!(o.get(WeightRole::Exit).is_none());
+        o.set(WeightRole::Exit, WeightOverrides::uniform());
+        assert!(o.get(WeightRole::Exit).is_some());
+        assert!(o.get(WeightRole::Middle).is_none());
+        o.clear(WeightRole::Exit);
+        assert!(o.get(WeightRole::Exit).is_none());
+    }
+
+    #[test]
+    fn uniform_exits() {
+        let netdir = testnet::construct_netdir();
+        let mut rng = rand::thread_rng();
+        let is_exit = |r: &Relay<'_>| r.id().as_bytes()[0] >= 0x0a && r.id().as_bytes()[0] < 0x14;
+
+        // Count how often we pick the lightest exit (0x0a) from among
+        // 0x0a through 0x13, whose bandwidths go from 1000 to 10000.
+        let count_lightest = |o: &WeightOverrides, rng: &mut rand::rngs::ThreadRng| {
+            (0..5000)
+                .filter(|_| {
+                    let r = o
+                        .pick_relay(&netdir, rng, WeightRole::Exit, is_exit)
+                        .unwrap();
+                    assert!(is_exit(&r));
+                    r.id().as_bytes()[0] == 0x0a
+                })
+                .count()
+        };
+
+        // With consensus weights, we pick it about 1/55 of the time;
+        // with uniform weights, about 1/10.
+        let n_consensus = count_lightest(&WeightOverrides::new(), &mut rng);
+        let mut uniform = WeightOverrides::new();
+        uniform.set(WeightRole::Exit, WeightOverrides::uniform());
+        let n_uniform = count_lightest(&uniform, &mut rng);
+        assert!(n_consensus < 200, "{}", n_consensus);
+        assert!(n_uniform > 350, "{}", n_uniform);
+
+        // Overrides only apply to their own role.
+        let n_middle = (0..5000)
+            .filter(|_| {
+                let r = uniform
+                    .pick_relay(&netdir, &mut rng, WeightRole::Middle, is_exit)
+                    .unwrap();
+                r.id().as_bytes()[0] == 0x0a
+            })
+            .count();
+        assert!(n_middle < 200, "{}", n_middle);
+    }
+}
@@ -1,12 +1,15 @@
 //! Code related to tracking what activities a circuit can be used for.
 
 use rand::Rng;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use tor_netdir::Relay;
-use tor_netdoc::types::policy::PortPolicy;
+use tor_netdoc::types::policy::{AddrPolicy, PortPolicy, RuleKind};
 
-use crate::path::{dirpath::DirPathBuilder, exitpath::ExitPathBuilder, TorPath};
+use crate::path::{
+    dirpath::DirPathBuilder, exitpath::ExitPathBuilder, weights::WeightOverrides, TorPath,
+};
 
 use crate::Result;
 
@@ -19,6 +22,36 @@ pub(crate) struct ExitPolicy {
     v6: Arc<PortPolicy>,
 }
 
+/// A summary of the ports to which the exit relay of a path allows
+/// connections, suitable for showing to a user.
+///
+/// Like all exit policy summaries, this only tells us which ports are
+/// _probably_ allowed: see [`PortPolicy`] for details.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PortPolicySummary {
+    /// Ports allowed for IPv4 connections.
+    v4: Arc<PortPolicy>,
+    /// Ports allowed for IPv6 connections.
+    v6: Arc<PortPolicy>,
+}
+
+impl PortPolicySummary {
+    /// Return the ports allowed for IPv4 connections.
+    pub fn ipv4(&self) -> &PortPolicy {
+        &self.v4
+    }
+    /// Return the ports allowed for IPv6 connections.
+    pub fn ipv6(&self) -> &PortPolicy {
+        &self.v6
+    }
+}
+
+impl std::fmt::Display for PortPolicySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IPv4: {}; IPv6: {}", self.v4, self.v6)
+    }
+}
+
 /// A port that we want to connect to as a client.
 ///
 /// Ordinarily, this is a TCP port, plus a flag to indicate whether we
@@ -45,6 +78,9 @@ impl TargetPort {
     }
 
     /// Return true if this port is supported by the provided Relay.
+    ///
+    /// We check the relay's IPv6 exit policy for an IPv6 port, and its
+    /// IPv4 exit policy otherwise.
     pub fn is_supported_by(&self, r: &tor_netdir::Relay<'_>) -> bool {
         if self.ipv6 {
             r.supports_exit_port_ipv6(self.port)
@@ -54,6 +90,76 @@ impl TargetPort {
     }
 }
 
+/// A specific address and port that we want to connect to as a client.
+///
+/// Unlike a [`TargetPort`], this lets us check whether an exit allows
+/// connections to this particular address, when we know the exit's
+/// complete address policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TargetAddr {
+    /// The address that the client wants to connect to.
+    addr: IpAddr,
+    /// The port that the client wants to connect to.
+    port: u16,
+}
+
+impl TargetAddr {
+    /// Create a request to make sure that a circuit supports connecting
+    /// to `port` on `addr`.
+    pub fn new(addr: IpAddr, port: u16) -> TargetAddr {
+        TargetAddr { addr, port }
+    }
+
+    /// Return the address that we want to connect to.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Return the port that we want to connect to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the [`TargetPort`] that a relay must support in order to
+    /// support this address.
+    pub fn target_port(&self) -> TargetPort {
+        TargetPort {
+            ipv6: self.addr.is_ipv6(),
+            port: self.port,
+        }
+    }
+
+    /// Return true if this address and port are supported by the
+    /// provided Relay.
+    ///
+    /// The relay's port summary must allow our port.  Additionally, if
+    /// `policies` knows the relay's complete address policy, that policy
+    /// must not reject our address and port.
+    pub fn is_supported_by(
+        &self,
+        r: &tor_netdir::Relay<'_>,
+        policies: &dyn ExitAddrPolicies,
+    ) -> bool {
+        if !self.target_port().is_supported_by(r) {
+            return false;
+        }
+        match policies.addr_policy(r) {
+            Some(policy) => policy.allows(&self.addr, self.port) != Some(RuleKind::Reject),
+            None => true,
+        }
+    }
+}
+
+/// A caller-supplied source of complete address policies for exit relays.
+///
+/// The network directory only tells us which ports each relay
+/// (probably) allows; to find out which addresses a relay rejects, we
+/// need its complete exit policy, as found in its router descriptor.
+pub trait ExitAddrPolicies: Send + Sync {
+    /// Return the complete address policy for `relay`, if known.
+    fn addr_policy(&self, relay: &Relay<'_>) -> Option<Arc<AddrPolicy>>;
+}
+
 impl ExitPolicy {
     /// Make a new exit policy from a given Relay.
     pub(crate) fn from_relay(relay: &Relay<'_>) -> Self {
@@ -63,6 +169,14 @@ impl ExitPolicy {
         }
     }
 
+    /// Return a user-presentable summary of this ExitPolicy.
+    pub(crate) fn summary(&self) -> PortPolicySummary {
+        PortPolicySummary {
+            v4: Arc::clone(&self.v4),
+            v6: Arc::clone(&self.v6),
+        }
+    }
+
     /// Return true if a given port is contained in this ExitPolicy.
     fn allows_port(&self, p: TargetPort) -> bool {
         let policy = if p.ipv6 { &self.v6 } else { &self.v4 };
@@ -97,18 +211,25 @@ pub(crate) enum SupportedCircUsage {
 impl TargetCircUsage {
     /// Construct path for a given circuit purpose; return it and the
     /// usage that it _actually_ supports.
+    ///
+    /// Relays are weighted according to `weights`.
     pub(crate) fn build_path<'a, R: Rng>(
         &self,
         rng: &mut R,
         netdir: crate::DirInfo<'a>,
+        weights: &WeightOverrides,
     ) -> Result<(TorPath<'a>, SupportedCircUsage)> {
         match self {
             TargetCircUsage::Dir => {
-                let path = DirPathBuilder::new().pick_path(rng, netdir)?;
+                let path = DirPathBuilder::new()
+                    .weight_overrides(weights.clone())
+                    .pick_path(rng, netdir)?;
                 Ok((path, SupportedCircUsage::Dir))
             }
             TargetCircUsage::Exit(p) => {
-                let path = ExitPathBuilder::from_target_ports(p.clone()).pick_path(rng, netdir)?;
+                let path = ExitPathBuilder::from_target_ports(p.clone())
+                    .weight_overrides(weights.clone())
+                    .pick_path(rng, netdir)?;
                 let policy = path
                     .exit_policy()
                     .expect("ExitPathBuilder gave us a one-hop circuit?");
@@ -219,12 +340,16 @@ mod test {
         // Only doing basic tests for now.  We'll test the path
         // building code a lot more closely in the tests for TorPath
         // and friends.
-        let (p_dir, u_dir) = TargetCircUsage::Dir.build_path(&mut rng, di).unwrap();
+        let (p_dir, u_dir) = TargetCircUsage::Dir
+            .build_path(&mut rng, di, &WeightOverrides::new())
+            .unwrap();
         assert!(matches!(u_dir, SupportedCircUsage::Dir));
         assert_eq!(p_dir.len(), 1);
 
         let exit_usage = TargetCircUsage::Exit(vec![TargetPort::ipv4(995)]);
-        let (p_exit, u_exit) = exit_usage.build_path(&mut rng, di).unwrap();
+        let (p_exit, u_exit) = exit_usage
+            .build_path(&mut rng, di, &WeightOverrides::new())
+            .unwrap();
         assert!(matches!(u_exit, SupportedCircUsage::Exit(_)));
         assert!(u_exit.supports(&exit_usage));
         assert_eq!(p_exit.len(), 3);
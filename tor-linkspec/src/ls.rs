@@ -45,24 +45,27 @@ impl Readable for LinkSpec {
             }
         }
         let lstype = r.take_u8()?;
-        let lslen = r.take_u8()? as usize;
         if let Some(wantlen) = lstype_len(lstype) {
-            if wantlen != lslen {
+            // Check the length before we look at the body, so that a
+            // wrong length is reported as such even if it's truncated.
+            if wantlen != r.peek(1)?[0] as usize {
                 return Err(Error::BadMessage("Wrong length for link specifier"));
             }
         }
-        Ok(match lstype {
-            LSTYPE_ORPORT_V4 => {
-                let addr = IpAddr::V4(r.extract()?);
-                LinkSpec::OrPort(addr, r.take_u16()?)
-            }
-            LSTYPE_ORPORT_V6 => {
-                let addr = IpAddr::V6(r.extract()?);
-                LinkSpec::OrPort(addr, r.take_u16()?)
-            }
-            LSTYPE_RSAID => LinkSpec::RsaId(r.extract()?),
-            LSTYPE_ED25519ID => LinkSpec::Ed25519Id(r.extract()?),
-            _ => LinkSpec::Unrecognized(lstype, r.take(lslen)?.into()),
+        r.read_nested_u8(|r| {
+            Ok(match lstype {
+                LSTYPE_ORPORT_V4 => {
+                    let addr = IpAddr::V4(r.extract()?);
+                    LinkSpec::OrPort(addr, r.take_u16()?)
+                }
+                LSTYPE_ORPORT_V6 => {
+                    let addr = IpAddr::V6(r.extract()?);
+                    LinkSpec::OrPort(addr, r.take_u16()?)
+                }
+                LSTYPE_RSAID => LinkSpec::RsaId(r.extract()?),
+                LSTYPE_ED25519ID => LinkSpec::Ed25519Id(r.extract()?),
+                _ => LinkSpec::Unrecognized(lstype, r.take(r.remaining())?.into()),
+            })
         })
     }
 }
@@ -94,7 +97,7 @@ impl Writeable for LinkSpec {
             }
             Unrecognized(tp, vec) => {
                 w.write_u8(*tp);
-                assert!(vec.len() < std::u8::MAX as usize);
+                assert!(vec.len() <= std::u8::MAX as usize);
                 w.write_u8(vec.len() as u8);
                 w.write_all(&vec[..]);
             }
@@ -147,6 +150,27 @@ impl LinkSpec {
     pub fn sort_by_type(lst: &mut [Self]) {
         lst.sort_by_key(LinkSpec::sort_pos)
     }
+
+    /// Read a list of link specifiers, in the format used by EXTEND2
+    /// cells: a one-byte count, followed by that many link specifiers.
+    pub fn take_list(r: &mut Reader<'_>) -> Result<Vec<Self>> {
+        let n = r.take_u8()?;
+        r.extract_n(n as usize)
+    }
+
+    /// Write a list of link specifiers onto `w`, in the format used by
+    /// EXTEND2 cells.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than 255 link specifiers in `lst`.
+    pub fn write_list<B: Writer + ?Sized>(lst: &[Self], w: &mut B) {
+        assert!(lst.len() <= std::u8::MAX as usize);
+        w.write_u8(lst.len() as u8);
+        for ls in lst {
+            w.write(ls);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -199,6 +223,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_list() {
+        let key: ed25519::Ed25519Identity = [0x42; 32].into();
+        let lst = vec![
+            LinkSpec::OrPort(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 80),
+            LinkSpec::RsaId([0x99; 20].into()),
+            LinkSpec::Ed25519Id(key),
+            LinkSpec::OrPort(IpAddr::V6(Ipv6Addr::new(1, 2, 3, 4, 5, 6, 7, 8)), 443),
+            LinkSpec::Unrecognized(77, (&b"strange"[..]).into()),
+            LinkSpec::Unrecognized(200, vec![7; 255]),
+        ];
+        let mut v = Vec::new();
+        LinkSpec::write_list(&lst[..], &mut v);
+        assert_eq!(v[0], 6);
+        assert_eq!(v.len(), 1 + 8 + 22 + 34 + 20 + 9 + 257);
+
+        let mut r = Reader::from_slice(&v[..]);
+        let got = LinkSpec::take_list(&mut r).unwrap();
+        assert_eq!(r.remaining(), 0);
+        assert_eq!(got, lst);
+
+        // An empty list is just a zero.
+        let mut v = Vec::new();
+        LinkSpec::write_list(&[], &mut v);
+        assert_eq!(&v[..], &[0]);
+        let mut r = Reader::from_slice(&v[..]);
+        assert!(LinkSpec::take_list(&mut r).unwrap().is_empty());
+
+        // If the list claims to have more entries than it does, that's
+        // an error.
+        let mut r = Reader::from_slice(&hex!("02 00 06 01020304 0050"));
+        assert_eq!(LinkSpec::take_list(&mut r), Err(Error::Truncated));
+    }
+
     #[test]
     fn test_parse_bad() {
         use tor_bytes::Error;
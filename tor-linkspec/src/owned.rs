@@ -15,6 +15,9 @@ pub struct OwnedChanTarget {
     ed_identity: pk::ed25519::Ed25519Identity,
     /// Copy of the rsa id from the underlying ChanTarget.
     rsa_identity: pk::rsa::RsaIdentity,
+    /// Copy of the pluggable transport name from the underlying
+    /// ChanTarget.
+    transport: Option<String>,
 }
 
 impl ChanTarget for OwnedChanTarget {
@@ -27,6 +30,9 @@ impl ChanTarget for OwnedChanTarget {
     fn rsa_identity(&self) -> &pk::rsa::RsaIdentity {
         &self.rsa_identity
     }
+    fn transport(&self) -> Option<&str> {
+        self.transport.as_deref()
+    }
 }
 
 impl OwnedChanTarget {
@@ -41,9 +47,17 @@ impl OwnedChanTarget {
             addrs,
             ed_identity,
             rsa_identity,
+            transport: None,
         }
     }
 
+    /// Return a copy of this target that we reach over the pluggable
+    /// transport called `transport` (or directly, if `transport` is
+    /// None).
+    pub fn with_transport(self, transport: Option<String>) -> Self {
+        Self { transport, ..self }
+    }
+
     /// Construct a OwnedChanTarget from a given ChanTarget.
     pub fn from_chan_target<C>(target: &C) -> Self
     where
@@ -53,6 +67,7 @@ impl OwnedChanTarget {
             addrs: target.addrs().to_vec(),
             ed_identity: *target.ed_identity(),
             rsa_identity: *target.rsa_identity(),
+            transport: target.transport().map(str::to_owned),
         }
     }
 }
@@ -70,6 +85,23 @@ pub struct OwnedCircTarget {
 }
 
 impl OwnedCircTarget {
+    /// Construct a new OwnedCircTarget from its parts.
+    ///
+    /// This is mainly useful for relays that aren't listed in any
+    /// directory, like bridges.
+    // TODO: Put this function behind a feature.
+    pub fn new(
+        chan_target: OwnedChanTarget,
+        ntor_onion_key: pk::curve25519::PublicKey,
+        protovers: tor_protover::Protocols,
+    ) -> Self {
+        Self {
+            chan_target,
+            ntor_onion_key,
+            protovers,
+        }
+    }
+
     /// Construct an OwnedCircTarget from a given CircTarget.
     pub fn from_circ_target<C>(target: &C) -> Self
     where
@@ -95,6 +127,9 @@ impl ChanTarget for OwnedCircTarget {
     fn rsa_identity(&self) -> &pk::rsa::RsaIdentity {
         self.chan_target.rsa_identity()
     }
+    fn transport(&self) -> Option<&str> {
+        self.chan_target.transport()
+    }
 }
 
 impl CircTarget for OwnedCircTarget {
@@ -121,5 +156,10 @@ mod test {
         assert_eq!(ti.addrs(), ti2.addrs());
         assert_eq!(ti.ed_identity(), ti2.ed_identity());
         assert_eq!(ti.rsa_identity(), ti2.rsa_identity());
+        assert_eq!(ti2.transport(), None);
+
+        let ti = ti.with_transport(Some("obfs4".into()));
+        let ti2 = OwnedChanTarget::from_chan_target(&ti);
+        assert_eq!(ti2.transport(), Some("obfs4"));
     }
 }
@@ -27,6 +27,11 @@ pub trait ChanTarget {
     }
     /// Return the RSA identity for this relay.
     fn rsa_identity(&self) -> &pk::rsa::RsaIdentity;
+    /// Return the name of the pluggable transport that we need in order
+    /// to reach this relay, or None if we can connect to it directly.
+    fn transport(&self) -> Option<&str> {
+        None
+    }
 
     /// Return a new [`crate::OwnedChanTarget`] containing a copy
     /// of the information in this `ChanTarget`.
@@ -0,0 +1,49 @@
+//! Compare batch and one-at-a-time Ed25519 signature validation.
+//!
+//! We use the results to decide how many signatures
+//! `tor_llcrypto::pk::ed25519::validate_batch` needs before it's worth
+//! using batch validation.  Run with:
+//!
+//! ```text
+//! cargo bench -p tor-llcrypto --bench ed25519
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand_core::RngCore;
+use signature::Signer;
+use tor_llcrypto::pk::ed25519::{validate_batch, Keypair, ValidatableEd25519Signature};
+use tor_llcrypto::pk::ValidatableSignature;
+use tor_llcrypto::util::rand_compat::RngCompatExt;
+
+/// Return `n` valid signatures, each on a random 128-byte message.
+fn make_sigs(n: usize) -> Vec<ValidatableEd25519Signature> {
+    let mut rng = rand::thread_rng().rng_compat();
+    (0..n)
+        .map(|_| {
+            let kp = Keypair::generate(&mut rng);
+            let mut bytes = [0_u8; 128];
+            rng.fill_bytes(&mut bytes[..]);
+            let sig = kp.sign(&bytes[..]);
+            ValidatableEd25519Signature::new(kp.public, sig, &bytes[..])
+        })
+        .collect()
+}
+
+/// Benchmark both ways of validating 1 through 4 signatures.
+fn validate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ed25519_validate");
+    for n in 1..=4 {
+        let sigs = make_sigs(n);
+        let refs: Vec<_> = sigs.iter().collect();
+        group.bench_with_input(BenchmarkId::new("batch", n), &refs, |b, refs| {
+            b.iter(|| validate_batch(&refs[..]))
+        });
+        group.bench_with_input(BenchmarkId::new("individual", n), &refs, |b, refs| {
+            b.iter(|| refs.iter().all(|s| s.is_valid()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, validate);
+criterion_main!(benches);
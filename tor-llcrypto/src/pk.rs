@@ -25,13 +25,14 @@ pub mod curve25519 {
 /// of validatable signatures in a way that permits batch signatures for
 /// Ed25519.
 ///
-/// To be used with [`validate_all_sigs`].
+/// To be used with [`validate_all_sigs`] or [`validate_all`].
 pub trait ValidatableSignature {
     /// Check whether this signature is a correct signature for the document.
     fn is_valid(&self) -> bool;
 
-    /// Return this value as a validatable Ed25519 signature, if it is one.
-    fn as_ed25519(&self) -> Option<&ed25519::ValidatableEd25519Signature> {
+    /// If this is a validatable Ed25519 signature, return its public key,
+    /// its signature, and the document that it signs.
+    fn ed25519_parts(&self) -> Option<(&ed25519::PublicKey, &ed25519::Signature, &[u8])> {
         None
     }
 }
@@ -47,19 +48,35 @@ pub trait ValidatableSignature {
 ///
 /// (See [`ed25519::validate_batch`] for caveats.)
 pub fn validate_all_sigs(v: &[Box<dyn ValidatableSignature>]) -> bool {
+    all_sigs_valid(v.iter().map(|sig| sig.as_ref()))
+}
+
+/// Check whether all of the signatures in this slice are valid.
+///
+/// This behaves the same as [`validate_all_sigs`], but takes references
+/// to the signatures, so that callers don't need to box them.
+pub fn validate_all(v: &[&dyn ValidatableSignature]) -> bool {
+    all_sigs_valid(v.iter().copied())
+}
+
+/// Helper: implement [`validate_all_sigs`] and [`validate_all`].
+///
+/// The Ed25519 signatures are checked together in one batch; the rest
+/// are checked one at a time, and only if the batch was valid.
+fn all_sigs_valid<'a>(sigs: impl Iterator<Item = &'a dyn ValidatableSignature>) -> bool {
     // First we break out the ed25519 signatures (if any) so we can do
     // a batch-verification on them.
     let mut ed_sigs = Vec::new();
     let mut non_ed_sigs = Vec::new();
-    for sig in v.iter() {
-        match sig.as_ed25519() {
-            Some(ed_sig) => ed_sigs.push(ed_sig),
+    for sig in sigs {
+        match sig.ed25519_parts() {
+            Some(parts) => ed_sigs.push(parts),
             None => non_ed_sigs.push(sig),
         }
     }
 
     // Find out if the ed25519 batch is valid.
-    let ed_batch_is_valid = crate::pk::ed25519::validate_batch(&ed_sigs[..]);
+    let ed_batch_is_valid = crate::pk::ed25519::batch_parts_are_valid(&ed_sigs[..]);
 
     // if so, verify the rest.
     ed_batch_is_valid && non_ed_sigs.iter().all(|b| b.is_valid())
@@ -67,10 +84,113 @@ pub fn validate_all_sigs(v: &[Box<dyn ValidatableSignature>]) -> bool {
 
 #[cfg(test)]
 mod test {
+    use super::ValidatableSignature;
+    use std::cell::Cell;
+
+    /// A non-Ed25519 signature with a fixed answer, which counts how
+    /// many times it was checked.
+    struct FixedSig {
+        valid: bool,
+        checked: Cell<usize>,
+    }
+    impl FixedSig {
+        fn new(valid: bool) -> Self {
+            FixedSig {
+                valid,
+                checked: Cell::new(0),
+            }
+        }
+    }
+    impl ValidatableSignature for FixedSig {
+        fn is_valid(&self) -> bool {
+            self.checked.set(self.checked.get() + 1);
+            self.valid
+        }
+    }
+
+    #[test]
+    fn validate_mixed() {
+        use super::ed25519::{Keypair, ValidatableEd25519Signature};
+        use super::validate_all;
+        use crate::util::rand_compat::RngCompatExt;
+        use signature::Signer;
+
+        let mut rng = rand::thread_rng().rng_compat();
+        let ed_sigs: Vec<_> = (0_u8..4)
+            .map(|n| {
+                let kp = Keypair::generate(&mut rng);
+                let doc = [n; 17];
+                ValidatableEd25519Signature::new(kp.public, kp.sign(&doc[..]), &doc[..])
+            })
+            .collect();
+        let good = FixedSig::new(true);
+        let bad = FixedSig::new(false);
+
+        let mut sigs: Vec<&dyn ValidatableSignature> = Vec::new();
+        assert!(validate_all(&sigs[..]));
+        sigs.extend(ed_sigs.iter().map(|s| s as &dyn ValidatableSignature));
+        sigs.push(&good);
+        assert!(validate_all(&sigs[..]));
+        assert_eq!(good.checked.get(), 1);
+
+        // One invalid non-Ed25519 signature spoils the batch.
+        sigs.insert(2, &bad);
+        assert!(!validate_all(&sigs[..]));
+        assert_eq!(bad.checked.get(), 1);
+
+        // So does a bad Ed25519 signature; then we don't check the rest.
+        let kp = Keypair::generate(&mut rng);
+        let wrong = ValidatableEd25519Signature::new(kp.public, kp.sign(b"abc"), b"abd");
+        let sigs: Vec<&dyn ValidatableSignature> = vec![&good, &ed_sigs[0], &wrong, &bad];
+        assert!(!validate_all(&sigs[..]));
+        assert_eq!(good.checked.get(), 1);
+        assert_eq!(bad.checked.get(), 1);
+    }
+
+    #[test]
+    fn validate_mixed_borrowed() {
+        use super::ed25519::{
+            Keypair, ValidatableEd25519Signature, ValidatableEd25519SignatureRef,
+        };
+        use super::validate_all;
+        use crate::util::rand_compat::RngCompatExt;
+        use signature::Signer;
+
+        let mut rng = rand::thread_rng().rng_compat();
+        let docs: Vec<[u8; 17]> = (0_u8..4).map(|n| [n; 17]).collect();
+        let kps: Vec<_> = (0..4).map(|_| Keypair::generate(&mut rng)).collect();
+        let borrowed: Vec<_> = kps
+            .iter()
+            .zip(docs.iter())
+            .map(|(kp, doc)| {
+                ValidatableEd25519SignatureRef::new_borrowed(kp.public, kp.sign(&doc[..]), &doc[..])
+            })
+            .collect();
+        let owned = ValidatableEd25519Signature::new(kps[0].public, kps[0].sign(b"xyz"), b"xyz");
+        let good = FixedSig::new(true);
+
+        // Borrowed and owned signatures go into the same batch.
+        assert!(borrowed[0].ed25519_parts().is_some());
+        let mut sigs: Vec<&dyn ValidatableSignature> = vec![&good, &owned];
+        sigs.extend(borrowed.iter().map(|s| s as &dyn ValidatableSignature));
+        assert!(validate_all(&sigs[..]));
+        assert_eq!(good.checked.get(), 1);
+
+        // A bad borrowed signature spoils the batch, so we never get as
+        // far as checking the non-Ed25519 signature that comes before it.
+        let wrong = ValidatableEd25519SignatureRef::new_borrowed(
+            kps[1].public,
+            kps[1].sign(&docs[1][..]),
+            &docs[2][..],
+        );
+        let sigs: Vec<&dyn ValidatableSignature> = vec![&good, &borrowed[0], &wrong, &owned];
+        assert!(!validate_all(&sigs[..]));
+        assert_eq!(good.checked.get(), 1);
+    }
+
     #[test]
     fn validatable_ed_sig() {
         use super::ed25519::{PublicKey, Signature, ValidatableEd25519Signature};
-        use super::ValidatableSignature;
         use hex_literal::hex;
         let pk = PublicKey::from_bytes(&hex!(
             "fc51cd8e6218a1a38da47ed00230f058
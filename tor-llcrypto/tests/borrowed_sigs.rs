@@ -0,0 +1,92 @@
+//! Check that signatures which borrow their documents don't copy them.
+//!
+//! This is in its own test binary, with only one test, since it counts
+//! every allocation that the process makes.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tor_llcrypto as ll;
+
+/// An allocator that counts the bytes that it allocates.
+struct CountingAlloc;
+
+/// The number of bytes that [`CountingAlloc`] has allocated.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn borrowed_sigs_dont_copy() {
+    use ll::pk::ed25519::*;
+    use ll::pk::ValidatableSignature;
+    use ll::util::rand_compat::RngCompatExt;
+    use rand_core::RngCore;
+    use signature::Signer;
+
+    const DOC_LEN: usize = 1 << 16;
+    const N_SIGS: usize = 8;
+
+    // One big document, with a signature on each of several overlapping
+    // prefixes, as with the signatures on a consensus.
+    let mut rng = rand::thread_rng().rng_compat();
+    let mut doc = vec![0_u8; DOC_LEN];
+    rng.fill_bytes(&mut doc[..]);
+    let signed: Vec<_> = (0..N_SIGS)
+        .map(|i| {
+            let kp = Keypair::generate(&mut rng);
+            let text = &doc[..DOC_LEN - i];
+            (kp.public, kp.sign(text), text)
+        })
+        .collect();
+
+    let mut borrowed = Vec::with_capacity(N_SIGS);
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    for (key, sig, text) in &signed {
+        borrowed.push(ValidatableEd25519SignatureRef::new_borrowed(
+            *key, *sig, text,
+        ));
+    }
+    assert_eq!(ALLOCATED.load(Ordering::SeqCst), before);
+
+    // The owned kind copies every document.
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let owned: Vec<_> = borrowed.iter().map(|s| s.to_owned_signature()).collect();
+    assert!(ALLOCATED.load(Ordering::SeqCst) - before >= N_SIGS * (DOC_LEN - N_SIGS));
+
+    // Both kinds validate the same way.
+    let borrowed_refs: Vec<_> = borrowed.iter().collect();
+    let owned_refs: Vec<_> = owned.iter().collect();
+    assert!(validate_batch_borrowed(&borrowed_refs[..]));
+    assert!(validate_batch(&owned_refs[..]));
+    assert!(borrowed.iter().all(|s| s.is_valid()));
+
+    // A borrowed signature on the wrong text is caught, alone or in a
+    // batch.
+    let (key, sig, _) = &signed[0];
+    let wrong = ValidatableEd25519SignatureRef::from_bytes_borrowed(
+        key.as_bytes(),
+        &sig.to_bytes(),
+        &doc[1..],
+    )
+    .unwrap();
+    assert!(!wrong.is_valid());
+    let mut refs = borrowed_refs.clone();
+    refs.push(&wrong);
+    assert!(!validate_batch_borrowed(&refs[..]));
+    assert_eq!(
+        validate_multi_batch_borrowed(&[&refs[..N_SIGS], &refs[N_SIGS..]]),
+        vec![true, false]
+    );
+}
@@ -37,6 +37,86 @@ fn test_ed25519_identity() {
     assert_eq!(ex3.as_bytes(), &example_key[..]);
 }
 
+#[test]
+fn ed25519_identity_as_key() {
+    use ll::pk::ed25519::Ed25519Identity;
+    use std::collections::{BTreeMap, HashMap};
+
+    let id = |n: u8| Ed25519Identity::new([n; 32]);
+
+    let mut by_hash = HashMap::new();
+    for n in 0..10 {
+        by_hash.insert(id(n), n);
+    }
+    assert_eq!(by_hash.len(), 10);
+    assert_eq!(by_hash.get(&id(7)), Some(&7));
+    assert_eq!(by_hash.get(&id(10)), None);
+    // Equal identities are the same key.
+    by_hash.insert(Ed25519Identity::from_bytes(&[3; 32]).unwrap(), 33);
+    assert_eq!(by_hash.len(), 10);
+    assert_eq!(by_hash[&id(3)], 33);
+
+    // Identities sort by their bytes, starting with the first.
+    let mut a = [0_u8; 32];
+    a[31] = 0xff;
+    let mut b = [0_u8; 32];
+    b[0] = 1;
+    assert!(Ed25519Identity::new(a) < Ed25519Identity::new(b));
+    assert!(id(1) > id(0));
+    assert_eq!(id(5).cmp(&id(5)), std::cmp::Ordering::Equal);
+
+    let by_order: BTreeMap<_, _> = (0..10).rev().map(|n| (id(n), n)).collect();
+    assert_eq!(
+        by_order.values().copied().collect::<Vec<_>>(),
+        (0..10).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn ed25519_identity_from_str() {
+    use ll::pk::ed25519::{Ed25519Identity, Ed25519ParseError};
+    use rand::RngCore;
+    use std::convert::TryFrom;
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let mut bytes = [0_u8; 32];
+        rng.fill_bytes(&mut bytes[..]);
+        let id = Ed25519Identity::new(bytes);
+        assert_eq!(id.to_string().parse::<Ed25519Identity>().unwrap(), id);
+    }
+    for bytes in &[[0_u8; 32], [0xff_u8; 32]] {
+        let id = Ed25519Identity::new(*bytes);
+        assert_eq!(Ed25519Identity::try_from(&id.to_string()[..]).unwrap(), id);
+    }
+
+    let id: Ed25519Identity = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        id.as_bytes(),
+        &hex!("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")[..]
+    );
+
+    // 31 bytes.
+    assert_eq!(
+        "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHUQ".parse::<Ed25519Identity>(),
+        Err(Ed25519ParseError::BadKeyLength(31))
+    );
+    assert_eq!(
+        "".parse::<Ed25519Identity>(),
+        Err(Ed25519ParseError::BadKeyLength(0))
+    );
+    assert_eq!(
+        "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHUR!".parse::<Ed25519Identity>(),
+        Err(Ed25519ParseError::BadBase64)
+    );
+    assert_eq!(
+        Ed25519Identity::try_from("not base64 at all"),
+        Err(Ed25519ParseError::BadBase64)
+    );
+}
+
 #[test]
 fn batch_verify() {
     use ll::pk::ed25519::*;
@@ -77,6 +157,125 @@ fn batch_verify() {
     assert!(!validate_batch(&sigrefs[..]));
 }
 
+#[test]
+fn validatable_from_bytes() {
+    use ll::pk::ed25519::*;
+    use ll::pk::ValidatableSignature;
+    use ll::util::rand_compat::RngCompatExt;
+    use signature::Signer;
+
+    let mut rng = rand::thread_rng().rng_compat();
+    let kp = Keypair::generate(&mut rng);
+    let sig = kp.sign(&b"Apples"[..]);
+    let key_bytes = kp.public.as_bytes();
+    let sig_bytes = sig.to_bytes();
+
+    let val = ValidatableEd25519Signature::from_bytes(key_bytes, &sig_bytes, b"Apples").unwrap();
+    assert!(val.is_valid());
+    // Parsing doesn't check the signature.
+    let val = ValidatableEd25519Signature::from_bytes(key_bytes, &sig_bytes, b"Pears").unwrap();
+    assert!(!val.is_valid());
+
+    assert_eq!(
+        ValidatableEd25519Signature::from_bytes(&key_bytes[..31], &sig_bytes, b"Apples").err(),
+        Some(Ed25519ParseError::BadKeyLength(31))
+    );
+    assert_eq!(
+        ValidatableEd25519Signature::from_bytes(&[], &sig_bytes, b"Apples").err(),
+        Some(Ed25519ParseError::BadKeyLength(0))
+    );
+    let mut long_sig = sig_bytes.to_vec();
+    long_sig.push(0);
+    assert_eq!(
+        ValidatableEd25519Signature::from_bytes(key_bytes, &long_sig, b"Apples").err(),
+        Some(Ed25519ParseError::BadSignatureLength(65))
+    );
+    // Right length, but not a point on the curve.
+    let bad_pk = hex!("000aaafaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa000");
+    assert_eq!(
+        ValidatableEd25519Signature::from_bytes(&bad_pk, &sig_bytes, b"Apples").err(),
+        Some(Ed25519ParseError::BadKey)
+    );
+}
+
+#[test]
+fn verify_against_any() {
+    use ll::pk::ed25519::*;
+    use ll::util::rand_compat::RngCompatExt;
+    use signature::Signer;
+
+    let mut rng = rand::thread_rng().rng_compat();
+    let kps: Vec<_> = (0..4).map(|_| Keypair::generate(&mut rng)).collect();
+    let keys: Vec<_> = kps.iter().map(|kp| kp.public).collect();
+    let sig = kps[2].sign(&b"Plums"[..]);
+
+    assert_eq!(
+        ll::pk::ed25519::verify_against_any(&keys[..], &sig, b"Plums"),
+        Some(2)
+    );
+    // If a key appears twice, we find the first.
+    let mut twice = keys.clone();
+    twice.insert(0, keys[2]);
+    assert_eq!(
+        ll::pk::ed25519::verify_against_any(&twice[..], &sig, b"Plums"),
+        Some(0)
+    );
+
+    // No key matches the wrong message, or a list without the signer.
+    assert_eq!(
+        ll::pk::ed25519::verify_against_any(&keys[..], &sig, b"Prunes"),
+        None
+    );
+    assert_eq!(
+        ll::pk::ed25519::verify_against_any(&keys[..2], &sig, b"Plums"),
+        None
+    );
+    assert_eq!(
+        ll::pk::ed25519::verify_against_any(&[], &sig, b"Plums"),
+        None
+    );
+}
+
+#[test]
+fn multi_batch_verify() {
+    use ll::pk::ed25519::*;
+    use ll::util::rand_compat::RngCompatExt;
+    use rand_core::RngCore;
+    use signature::Signer;
+
+    let mut rng = rand::thread_rng().rng_compat();
+    let mut sigs = Vec::new();
+    for _ in 0..6 {
+        let kp = Keypair::generate(&mut rng);
+
+        let mut bytes = [0_u8; 128];
+        rng.fill_bytes(&mut bytes[..]);
+
+        let sig = kp.sign(&bytes[..]);
+        sigs.push(ValidatableEd25519Signature::new(kp.public, sig, &bytes[..]));
+    }
+    // A junk signature.
+    let kp = Keypair::generate(&mut rng);
+    let sig = kp.sign(&b"Apples"[..]);
+    let junk = ValidatableEd25519Signature::new(kp.public, sig, &b"Oranges!"[..]);
+
+    let doc1: Vec<_> = sigs[0..3].iter().collect();
+    let doc2: Vec<_> = sigs[3..5].iter().collect();
+    let doc3: Vec<_> = vec![&sigs[5]];
+    let bad_doc: Vec<_> = vec![&sigs[5], &junk];
+
+    assert!(validate_multi_batch(&[]).is_empty());
+    assert_eq!(
+        validate_multi_batch(&[&doc1[..], &doc2[..], &[], &doc3[..]]),
+        vec![true, true, true, true]
+    );
+    assert_eq!(
+        validate_multi_batch(&[&doc1[..], &bad_doc[..], &doc2[..]]),
+        vec![true, false, true]
+    );
+    assert_eq!(validate_multi_batch(&[&[&junk]]), vec![false]);
+}
+
 #[test]
 fn ser_de_rsaid() {
     use serde_test::{assert_tokens, Configure, Token};
@@ -107,3 +306,29 @@ fn ser_de_edid() {
         &[Token::Bytes(b"this is another key. not valid..")],
     );
 }
+
+#[test]
+fn ser_de_edid_roundtrip() {
+    use ll::pk::ed25519::Ed25519Identity;
+
+    let id = Ed25519Identity::from_bytes(b"this is another key. not valid..").unwrap();
+
+    // Human-readable formats use the same form as Display.
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, format!("\"{}\"", id));
+    let id2: Ed25519Identity = serde_json::from_str(&json).unwrap();
+    assert_eq!(id, id2);
+
+    // Binary formats use the raw bytes.
+    let cbor = serde_cbor::to_vec(&id).unwrap();
+    assert!(cbor.ends_with(b"this is another key. not valid.."));
+    let id3: Ed25519Identity = serde_cbor::from_slice(&cbor).unwrap();
+    assert_eq!(id, id3);
+
+    // Wrong lengths are rejected either way.
+    assert!(serde_json::from_str::<Ed25519Identity>("\"dGhpcyBpcyBhbm90aGVyIGtleS4\"").is_err());
+    let short = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![7; 31])).unwrap();
+    assert!(serde_cbor::from_slice::<Ed25519Identity>(&short).is_err());
+    let long = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![7; 33])).unwrap();
+    assert!(serde_cbor::from_slice::<Ed25519Identity>(&long).is_err());
+}
@@ -60,7 +60,7 @@ pub mod testnet;
 use tor_llcrypto as ll;
 use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
 use tor_netdoc::doc::microdesc::{MdDigest, Microdesc};
-use tor_netdoc::doc::netstatus::{self, MdConsensus, RouterStatus};
+use tor_netdoc::doc::netstatus::{self, MdConsensus, RelayFlags, RouterStatus};
 use tor_netdoc::types::policy::PortPolicy;
 
 use log::warn;
@@ -330,6 +330,13 @@ impl NetDir {
     pub fn params(&self) -> &NetParameters {
         &self.params
     }
+    /// Return the weight that the consensus gives `relay` when we're
+    /// picking a relay for `role`.
+    ///
+    /// This is the weight that [`NetDir::pick_relay`] uses.
+    pub fn relay_weight(&self, relay: &Relay<'_>, role: WeightRole) -> u64 {
+        self.weights.weight_rs_for_role(relay.rs, role)
+    }
     /// Return weighted the fraction of relays we can use.  We only
     /// consider relays that match the predicate `usable`.  We weight
     /// this bandwidth according to the provided `role`.
@@ -392,8 +399,14 @@ impl NetDir {
     /// in the role `role`, and is only selected if the predicate `usable`
     /// returns true for it.
     ///
-    /// This function returns None if (and only if) there are no relays
-    /// with nonzero weight where `usable` returned true.
+    /// If the consensus has no bandwidth weights, and every usable relay
+    /// has zero weight in `role` as a result, we log a warning and choose
+    /// uniformly among the usable relays instead.  If the consensus does
+    /// have bandwidth weights, we respect any zeroes it gives us.
+    ///
+    /// This function returns None if there are no relays where `usable`
+    /// returned true, or if the consensus gives all of them zero weight
+    /// in `role`.
     pub fn pick_relay<'a, R, P>(
         &'a self,
         rng: &mut R,
@@ -404,13 +417,61 @@ impl NetDir {
         R: rand::Rng,
         P: Fn(&Relay<'a>) -> bool,
     {
-        pick::pick_weighted(rng, self.relays(), |r| {
-            if usable(r) {
-                self.weights.weight_rs_for_role(r.rs, role)
+        self.pick_relay_by_weight(rng, role, |r, w| if usable(r) { w } else { 0 })
+    }
+    /// Chose a relay at random, with adjusted weights.
+    ///
+    /// Each relay is chosen with probability proportional to the value
+    /// that `weight` returns for it.  The second argument to `weight` is
+    /// the relay's ordinary weight in the role `role`: return it
+    /// unchanged for the behavior of [`NetDir::pick_relay`], or return
+    /// zero to exclude the relay.
+    ///
+    /// If that leaves no relay with any weight, and the consensus has no
+    /// bandwidth weights, we look at the relays whose ordinary weight in
+    /// `role` is zero.  If `weight` would give any of them nonzero weight
+    /// when their ordinary weight is 1, we log a warning and choose among
+    /// them on that basis instead: this keeps a consensus without
+    /// bandwidth weights from leaving us with no relays at all.
+    ///
+    /// This function returns None if `weight` returned zero for every
+    /// relay (given, in the case above, a weight of 1 for relays whose
+    /// ordinary weight is zero).
+    pub fn pick_relay_by_weight<'a, R, F>(
+        &'a self,
+        rng: &mut R,
+        role: WeightRole,
+        weight: F,
+    ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        F: Fn(&Relay<'a>, u64) -> u64,
+    {
+        let role_weight = |r: &Relay<'a>| self.weights.weight_rs_for_role(r.rs, role);
+        if let Some(r) = pick::pick_weighted(rng, self.relays(), |r| weight(r, role_weight(r))) {
+            return Some(r);
+        }
+
+        // Nothing had any weight.  If the consensus told us about
+        // bandwidth weights, that's what the authorities want.  But if it
+        // didn't, our candidates may have no weight for this role only
+        // because of our defaults: pick among them as if they were all
+        // equally weighted.
+        if self.weights.has_weights() {
+            return None;
+        }
+        let r = pick::pick_weighted(rng, self.relays(), |r| {
+            if role_weight(r) == 0 {
+                weight(r, 1)
             } else {
                 0
             }
-        })
+        })?;
+        warn!(
+            "Consensus has no bandwidth weights, and no usable relay had any weight for {:?}; choosing uniformly instead.",
+            role
+        );
+        Some(r)
     }
 }
 
@@ -466,6 +527,11 @@ impl<'a> Relay<'a> {
     pub fn rsa_id(&self) -> &RsaIdentity {
         self.rs.rsa_identity()
     }
+    /// Return the software version that this relay advertises in the
+    /// consensus, if any (like "Tor 0.4.6.7").
+    pub fn version(&self) -> Option<&str> {
+        self.rs.version().as_deref()
+    }
     /// Return true if this relay and `other` seem to be the same relay.
     ///
     /// (Two relays are the same if they have the same identity.)
@@ -480,6 +546,15 @@ impl<'a> Relay<'a> {
     pub fn supports_exit_port_ipv6(&self, port: u16) -> bool {
         !self.rs.is_flagged_bad_exit() && self.md.ipv6_policy().allows_port(port)
     }
+    /// Return the flags that the consensus lists for this relay.
+    pub fn flags(&self) -> RelayFlags {
+        *self.rs.flags()
+    }
+    /// Return true if the authorities say that this relay should only be
+    /// used in the middle of a path, and never as a guard or an exit.
+    pub fn is_middle_only(&self) -> bool {
+        self.rs.is_flagged_middle_only()
+    }
     /// Return true if this relay is suitable for use as a directory
     /// cache.
     pub fn is_dir_cache(&self) -> bool {
@@ -753,6 +828,103 @@ mod test {
         check_close(picked[39], (total * 10) / 110);
     }
 
+    #[test]
+    fn test_pick_by_weight() {
+        use crate::pick::test::*; // for stochastic testing
+        use tor_linkspec::ChanTarget;
+
+        let dir = crate::testnet::construct_netdir();
+
+        let total = get_iters() as isize;
+        let mut picked = [0_isize; 40];
+        let mut rng = get_rng();
+        for _ in 0..get_iters() {
+            // Only relays 19 and 39 are usable, and 19 counts double.
+            let r = dir.pick_relay_by_weight(&mut rng, WeightRole::Middle, |r, w| {
+                match r.rsa_identity().as_bytes()[0] {
+                    19 => w * 2,
+                    39 => w,
+                    _ => 0,
+                }
+            });
+            let r = r.unwrap();
+            let id_byte = r.rsa_identity().as_bytes()[0];
+            picked[id_byte as usize] += 1;
+        }
+        assert_eq!(picked[19] + picked[39], total);
+        // Relays 19 and 39 have the same bandwidth.
+        check_close(picked[19], (total * 2) / 3);
+        check_close(picked[39], total / 3);
+
+        // If nothing has any weight, we get nothing.
+        assert!(dir
+            .pick_relay_by_weight(&mut rng, WeightRole::Middle, |_, _| 0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_pick_zero_weight() {
+        use crate::pick::test::*; // for stochastic testing
+        use tor_linkspec::ChanTarget;
+
+        let dir = crate::testnet::construct_netdir();
+
+        // There is no Wge, so relays that are exits but not guards have no
+        // weight at all as guards.  But this consensus doesn't list any
+        // bandwidth weights, so that zero isn't the authorities' doing.
+        let exit_only = |r: &Relay<'_>| (10..20).contains(&r.rsa_identity().as_bytes()[0]);
+        for r in dir.relays().filter(exit_only) {
+            assert_eq!(dir.weights.weight_rs_for_role(r.rs, WeightRole::Guard), 0);
+        }
+
+        // We still pick among them, but uniformly, ignoring bandwidth.
+        let total = get_iters() as isize;
+        let mut picked = [0_isize; 40];
+        let mut rng = get_rng();
+        for _ in 0..get_iters() {
+            let r = dir.pick_relay(&mut rng, WeightRole::Guard, exit_only);
+            let r = r.unwrap();
+            let id_byte = r.rsa_identity().as_bytes()[0];
+            picked[id_byte as usize] += 1;
+        }
+        assert_eq!(picked[10..20].iter().sum::<isize>(), total);
+        // (With bandwidth weighting, relay 10 would get 1/55 of the picks.)
+        for n in &picked[10..20] {
+            assert!(*n > total / 20);
+        }
+
+        // Adjustments from the caller still apply.
+        let r = dir
+            .pick_relay_by_weight(&mut rng, WeightRole::Guard, |r, w| {
+                if r.rsa_identity().as_bytes()[0] == 13 {
+                    w
+                } else {
+                    0
+                }
+            })
+            .unwrap();
+        assert_eq!(r.rsa_identity().as_bytes()[0], 13);
+
+        // But if nothing is usable, we still get nothing.
+        assert!(dir
+            .pick_relay(&mut rng, WeightRole::Guard, |_| false)
+            .is_none());
+
+        // When the consensus does have bandwidth weights, a zero weight
+        // means what it says.
+        let dir = crate::testnet::NetworkSpec::new()
+            .bandwidth_weights("Wgg=10000 Wgm=10000 Wmm=0".parse().unwrap())
+            .netdir();
+        assert!(dir
+            .pick_relay(&mut rng, WeightRole::Guard, exit_only)
+            .is_none());
+        let plain = |r: &Relay<'_>| r.rsa_identity().as_bytes()[0] < 10;
+        assert!(dir
+            .pick_relay(&mut rng, WeightRole::Middle, plain)
+            .is_none());
+        assert!(dir.pick_relay(&mut rng, WeightRole::Guard, plain).is_some());
+    }
+
     #[test]
     fn relay_funcs() {
         let (consensus, microdescs) = construct_network();
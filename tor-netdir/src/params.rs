@@ -29,6 +29,8 @@ pub struct NetParameters {
     pub bw_weight_scale: BoundedInt32<0, { i32::MAX }>,
     /// The maximum cell window size?
     pub circuit_window: BoundedInt32<100, 1000>,
+    /// The SENDME increment for circuit hops that use congestion control.
+    pub cc_sendme_inc: BoundedInt32<1, 254>,
     /// The decay parameter for circuit priority
     pub circuit_priority_half_life: IntegerMilliseconds<BoundedInt32<1, { i32::MAX }>>,
     /// Whether to perform circuit extenstions by Ed25519 ID
@@ -46,6 +48,7 @@ impl Default for NetParameters {
         NetParameters {
             bw_weight_scale: BoundedInt32::checked_new(10000).unwrap(),
             circuit_window: BoundedInt32::checked_new(1000).unwrap(),
+            cc_sendme_inc: BoundedInt32::checked_new(31).unwrap(),
             circuit_priority_half_life: IntegerMilliseconds::new(
                 BoundedInt32::checked_new(30000).unwrap(),
             ),
@@ -70,6 +73,9 @@ impl NetParameters {
             "circwindow" => {
                 self.circuit_window = BoundedInt32::saturating_from(value);
             }
+            "cc_sendme_inc" => {
+                self.cc_sendme_inc = BoundedInt32::saturating_from(value);
+            }
             "CircuitPriorityHalflifeMsec" => {
                 self.circuit_priority_half_life =
                     IntegerMilliseconds::new(BoundedInt32::saturating_from(value))
@@ -186,6 +192,19 @@ mod test {
         assert_eq!(x.min_circuit_path_threshold.as_percent().get(), 95);
     }
 
+    #[test]
+    fn cc_sendme_inc() {
+        let mut x = NetParameters::default();
+        assert_eq!(x.cc_sendme_inc.get(), 31);
+        let k = &String::from("cc_sendme_inc");
+        let z = x.saturating_update(vec![(k, &33)].into_iter());
+        assert!(z.is_empty());
+        assert_eq!(x.cc_sendme_inc.get(), 33);
+        let z = x.saturating_update(vec![(k, &0)].into_iter());
+        assert!(z.is_empty());
+        assert_eq!(x.cc_sendme_inc.get(), 1);
+    }
+
     #[test]
     fn good_invalid_rep() {
         let mut x = NetParameters::default();
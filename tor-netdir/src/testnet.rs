@@ -7,9 +7,10 @@
 
 use super::*;
 use hex_literal::hex;
+use std::net::SocketAddr;
 use std::time::{Duration, SystemTime};
 use tor_llcrypto::pk::rsa;
-use tor_netdoc::doc::netstatus::{Lifetime, RelayFlags, RelayWeight};
+use tor_netdoc::doc::netstatus::{Lifetime, NetParams, RelayFlags, RelayWeight};
 
 /// Helper: make a dummy 1024-bit RSA public key.
 ///
@@ -20,20 +21,23 @@ fn rsa_example() -> rsa::PublicKey {
     rsa::PublicKey::from_der(&der).unwrap()
 }
 
+/// Return the address that [`construct_network()`] gives relay number
+/// `idx`.
+fn default_addr(idx: u8) -> SocketAddr {
+    SocketAddr::new([10, idx, 0, 1].into(), 9001)
+}
+
 /// As [`construct_network()`], but return a [`NetDir`].
 pub fn construct_netdir() -> NetDir {
-    let (consensus, microdescs) = construct_network();
-    let mut dir = PartialNetDir::new(consensus, None);
-    for md in microdescs {
-        dir.add_microdesc(md);
-    }
-
-    dir.unwrap_if_sufficient().unwrap()
+    NetworkSpec::new().netdir()
 }
 
 /// Build a fake network with enough information to enable some basic
 /// tests.
 ///
+/// Relay number `idx` has the address `10.idx.0.1:9001`, so that no two
+/// relays share a /16.
+///
 /// The constructed network will contain 40 relays, numbered 0 through
 /// 39. They will have with RSA and Ed25519 identity fingerprints set to
 /// 0x0000...00 through 0x2727...27.  Each pair of relays is in a
@@ -55,7 +59,8 @@ pub fn construct_netdir() -> NetDir {
 /// Every relay is given a measured weight based on its position
 /// within its group of ten.  The weights for the ten relays in each
 /// group are: 100, 200, 300, ... 10000.  There is no additional
-/// flag-based bandwidth weighting.
+/// flag-based bandwidth weighting, since the consensus lists no
+/// bandwidth weights.
 ///
 /// The consensus is declared as using method 34, and as being valid for
 /// one day (in realtime) after the current `SystemTime`.
@@ -69,10 +74,118 @@ pub fn construct_netdir() -> NetDir {
 /// we'll have to throw the whole thing away.  (We ran into this
 /// problem with Tor's unit tests.)
 ///
-/// Instead, I'd suggest refactoring this function so that it takes a
-/// description of what kind of network to build, and then builds it from
-/// that description.
+/// Instead, describe the network you want with a [`NetworkSpec`], which
+/// starts out as this network and lets you change it relay by relay.
 pub fn construct_network() -> (MdConsensus, Vec<Microdesc>) {
+    NetworkSpec::new().network()
+}
+
+/// A description of a fake network to build for testing.
+///
+/// A new `NetworkSpec` describes the network from
+/// [`construct_network()`]; each method changes one property of each
+/// relay, as a function of the relay's number.
+///
+/// # Example
+///
+/// ```
+/// use tor_netdir::testnet::NetworkSpec;
+///
+/// let netdir = NetworkSpec::new()
+///     .addrs(|_| "127.0.0.1:9001".parse().unwrap())
+///     .versions(|idx| Some(format!("Tor 0.4.{}.1", idx % 3)))
+///     .netdir();
+/// assert_eq!(netdir.relays().count(), 40);
+/// ```
+#[derive(Default)]
+pub struct NetworkSpec<'a> {
+    /// The address for each relay, if not the default.
+    addr_for: Option<Box<dyn Fn(u8) -> SocketAddr + 'a>>,
+    /// The version (if any) for each relay.
+    version_for: Option<Box<dyn Fn(u8) -> Option<String> + 'a>>,
+    /// Flags to give each relay, in addition to its usual ones.
+    flags_for: Option<Box<dyn Fn(u8) -> RelayFlags + 'a>>,
+    /// The IPv6 exit policy (if any) for each relay.
+    ipv6_policy_for: Option<Box<dyn Fn(u8) -> Option<String> + 'a>>,
+    /// The bandwidth weights for the consensus, if it has any.
+    weights: Option<NetParams<i32>>,
+}
+
+impl<'a> NetworkSpec<'a> {
+    /// Return a description of the network from [`construct_network()`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give relay number `idx` the address `addr_for(idx)`.
+    pub fn addrs<F>(&mut self, addr_for: F) -> &mut Self
+    where
+        F: Fn(u8) -> SocketAddr + 'a,
+    {
+        self.addr_for = Some(Box::new(addr_for));
+        self
+    }
+
+    /// Give relay number `idx` the version `version_for(idx)`, if that
+    /// isn't None.
+    pub fn versions<F>(&mut self, version_for: F) -> &mut Self
+    where
+        F: Fn(u8) -> Option<String> + 'a,
+    {
+        self.version_for = Some(Box::new(version_for));
+        self
+    }
+
+    /// Give relay number `idx` the flags `flags_for(idx)`, in addition
+    /// to the ones it would usually have.
+    pub fn extra_flags<F>(&mut self, flags_for: F) -> &mut Self
+    where
+        F: Fn(u8) -> RelayFlags + 'a,
+    {
+        self.flags_for = Some(Box::new(flags_for));
+        self
+    }
+
+    /// Give relay number `idx` the IPv6 exit policy `policy_for(idx)`
+    /// (such as `"accept 80,443"`), if that isn't None.
+    ///
+    /// Otherwise, relays allow no IPv6 exits.
+    pub fn ipv6_policies<F>(&mut self, policy_for: F) -> &mut Self
+    where
+        F: Fn(u8) -> Option<String> + 'a,
+    {
+        self.ipv6_policy_for = Some(Box::new(policy_for));
+        self
+    }
+
+    /// Give the consensus the bandwidth weights `weights`.
+    ///
+    /// Otherwise, the consensus has no bandwidth weights.
+    pub fn bandwidth_weights(&mut self, weights: NetParams<i32>) -> &mut Self {
+        self.weights = Some(weights);
+        self
+    }
+
+    /// Build a [`NetDir`] for the network that this describes.
+    pub fn netdir(&self) -> NetDir {
+        let (consensus, microdescs) = self.network();
+        let mut dir = PartialNetDir::new(consensus, None);
+        for md in microdescs {
+            dir.add_microdesc(md);
+        }
+
+        dir.unwrap_if_sufficient().unwrap()
+    }
+
+    /// Build a consensus and microdescriptors for the network that this
+    /// describes.
+    pub fn network(&self) -> (MdConsensus, Vec<Microdesc>) {
+        construct_custom_network(self)
+    }
+}
+
+/// Helper: build the network described by `spec`.
+fn construct_custom_network(spec: &NetworkSpec<'_>) -> (MdConsensus, Vec<Microdesc>) {
     let f = RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::V2DIR;
     // define 4 groups of flags
     let flags = [
@@ -88,14 +201,17 @@ pub fn construct_network() -> (MdConsensus, Vec<Microdesc>) {
     bld.consensus_method(34)
         .lifetime(Lifetime::new(now, now + one_day / 2, now + one_day).unwrap())
         .param("bwweightscale", 1)
-        .weights("".parse().unwrap());
+        .weights(spec.weights.clone().unwrap_or_default());
 
     let mut microdescs = Vec::new();
     for idx in 0..40_u8 {
         // Each relay gets a couple of no-good onion keys.
         // Its identity fingerprints are set to `idx`, repeating.
-        // They all get the same address.
-        let flags = flags[(idx / 10) as usize];
+        // Its address comes from `spec`, if `spec` has one.
+        let mut flags = flags[(idx / 10) as usize];
+        if let Some(flags_for) = &spec.flags_for {
+            flags |= flags_for(idx);
+        }
         let policy = if flags.contains(RelayFlags::EXIT) {
             if idx % 2 == 1 {
                 "accept 80,443"
@@ -109,15 +225,17 @@ pub fn construct_network() -> (MdConsensus, Vec<Microdesc>) {
         let fam_id = [idx ^ 1; 20];
         let family = hex::encode(&fam_id);
 
-        let md = Microdesc::builder()
-            .tap_key(rsa_example())
+        let mut md = Microdesc::builder();
+        md.tap_key(rsa_example())
             .ntor_key((*b"----nothing in dirmgr uses this-").into())
             .ed25519_id([idx; 32].into())
             .family(family.parse().unwrap())
             .parse_ipv4_policy(policy)
-            .unwrap()
-            .testing_md()
             .unwrap();
+        if let Some(ipv6_policy) = spec.ipv6_policy_for.as_ref().and_then(|f| f(idx)) {
+            md.parse_ipv6_policy(&ipv6_policy).unwrap();
+        }
+        let md = md.testing_md().unwrap();
         let protocols = if idx % 2 == 0 {
             // even-numbered relays are dircaches.
             "DirCache=2".parse().unwrap()
@@ -125,15 +243,21 @@ pub fn construct_network() -> (MdConsensus, Vec<Microdesc>) {
             "".parse().unwrap()
         };
         let weight = RelayWeight::Measured(1000 * (idx % 10 + 1) as u32);
-        bld.rs()
-            .identity([idx; 20].into())
-            .add_or_port("127.0.0.1:9001".parse().unwrap())
+        let mut rs = bld.rs();
+        rs.identity([idx; 20].into())
+            .add_or_port(
+                spec.addr_for
+                    .as_ref()
+                    .map_or_else(|| default_addr(idx), |f| f(idx)),
+            )
             .doc_digest(*md.digest())
             .protos(protocols)
             .set_flags(flags)
-            .weight(weight)
-            .build_into(&mut bld)
-            .unwrap();
+            .weight(weight);
+        if let Some(version) = spec.version_for.as_ref().and_then(|f| f(idx)) {
+            rs.version(version);
+        }
+        rs.build_into(&mut bld).unwrap();
         microdescs.push(md);
     }
 
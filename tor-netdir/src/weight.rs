@@ -206,6 +206,8 @@ pub(crate) struct WeightSet {
     /// A set of RelayWeight values, indexed by [`WeightKind::idx`], used
     /// to weight different kinds of relays.
     w: [RelayWeight; 8],
+    /// True if the consensus listed any bandwidth weights at all.
+    has_weights: bool,
 }
 
 impl WeightSet {
@@ -238,6 +240,14 @@ impl WeightSet {
         router_weight >> self.shift
     }
 
+    /// Return true if the consensus listed any bandwidth weights.
+    ///
+    /// If it didn't, then the zero weights we give some relays for some
+    /// roles don't come from the directory authorities.
+    pub(crate) fn has_weights(&self) -> bool {
+        self.has_weights
+    }
+
     /// Compute the correct WeightSet for a provided MdConsensus.
     pub(crate) fn from_consensus(consensus: &MdConsensus, params: &NetParameters) -> Self {
         let bandwidth_fn = pick_bandwidth_fn(consensus.relays().iter().map(|rs| rs.weight()));
@@ -319,6 +329,7 @@ impl WeightSet {
             bandwidth_fn,
             shift,
             w,
+            has_weights: p.iter().next().is_some(),
         }
     }
 }
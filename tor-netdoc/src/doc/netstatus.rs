@@ -391,6 +391,9 @@ bitflags! {
         /// Set if this relay supports a currently recognized version of the
         /// directory protocol.
         const V2DIR = (1<<11);
+        /// Set if this relay should only be used in the middle of a path:
+        /// never as a guard or an exit.
+        const MIDDLE_ONLY = (1<<12);
     }
 }
 
@@ -982,6 +985,7 @@ impl std::str::FromStr for RelayFlags {
             "Running" => RelayFlags::RUNNING,
             "Valid" => RelayFlags::VALID,
             "V2Dir" => RelayFlags::V2DIR,
+            "MiddleOnly" => RelayFlags::MIDDLE_ONLY,
             _ => RelayFlags::empty(),
         })
     }
@@ -1305,11 +1309,22 @@ impl<RS: RouterStatus + ParseRouterStatus> Consensus<RS> {
             signatures.push(sig);
         }
 
-        if first_sig.is_none() {
-            return Err(Error::MissingToken("directory-signature"));
-        }
+        let first_sig = first_sig.ok_or(Error::MissingToken("directory-signature"))?;
 
-        let end_pos = first_sig.unwrap().offset_in(r.str()).unwrap() + "directory-signature ".len();
+        // The signatures cover everything through the keyword on the
+        // first signature line.  The tokenizer already knows where that
+        // is, so we don't need to search for it again.
+        let end_pos = first_sig
+            .offset_in(r.str())
+            .ok_or_else(|| Error::Internal(first_sig.pos()))?
+            + "directory-signature ".len();
+        #[cfg(debug_assertions)]
+        {
+            let mut sr = tor_bytes::Reader::from_slice(r.str().as_bytes());
+            sr.advance(start_pos)?;
+            let region = sr.take_signed_region(b"directory-signature ");
+            assert_eq!(region.map(|range| range.end), Ok(end_pos));
+        }
 
         // Find the appropriate digest.
         let signed_str = &r.str()[start_pos..end_pos];
@@ -143,6 +143,11 @@ macro_rules! implement_accessors {
             pub fn is_flagged_guard(&self) -> bool {
                 self.rs.flags.contains(RelayFlags::GUARD)
             }
+            /// Return true if this routerstatus is listed with the
+            /// MiddleOnly flag.
+            pub fn is_flagged_middle_only(&self) -> bool {
+                self.rs.flags.contains(RelayFlags::MIDDLE_ONLY)
+            }
         }
     };
 }
@@ -106,6 +106,13 @@ impl PortPolicy {
             .binary_search_by(|range| range.compare_to_port(port))
             .is_ok()
     }
+    /// Return the ranges of ports that this policy allows.
+    ///
+    /// The ranges are sorted, and no two of them overlap or are
+    /// adjacent.
+    pub fn allowed_ranges(&self) -> &[PortRange] {
+        &self.allowed[..]
+    }
     /// Replace this PortPolicy with an interned copy, to save memory.
     pub fn intern(self) -> Arc<Self> {
         POLICY_CACHE.intern(self)
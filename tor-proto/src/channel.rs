@@ -57,6 +57,8 @@ mod circmap;
 mod codec;
 mod handshake;
 mod reactor;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod unique_id;
 
 use crate::channel::reactor::{CtrlMsg, CtrlResult};
@@ -0,0 +1,111 @@
+//! Support for testing circuit-building code, in this crate and elsewhere.
+//!
+//! This module is only enabled when the `testing` feature is enabled.
+//! It must never be used in production.
+
+use super::{Channel, UniqId};
+use crate::crypto::handshake::ntor::{NtorSecretKey, NtorServer};
+use crate::crypto::handshake::ServerHandshake;
+use crate::{Error, Result};
+use futures::channel::mpsc;
+use futures::{Future, SinkExt, StreamExt};
+use std::sync::Arc;
+use tor_cell::chancell::{msg, ChanCell};
+use tor_linkspec::{OwnedChanTarget, OwnedCircTarget};
+use tor_llcrypto::pk::curve25519;
+
+/// Type for the cells that a [`FakeRelay`] sends to the client.
+type CodecResult = std::result::Result<ChanCell, tor_cell::Error>;
+
+/// The far end of a channel from [`fake_channel`]: it receives every
+/// cell that the client sends, and answers only when told to.
+pub struct FakeRelay {
+    /// Cells that the client has sent us.
+    from_client: mpsc::Receiver<ChanCell>,
+    /// Where to send cells to the client.
+    to_client: mpsc::Sender<CodecResult>,
+    /// The key that we use to answer ntor handshakes.
+    ntor_key: NtorSecretKey,
+    /// A description of this relay, for building circuits to it.
+    target: OwnedCircTarget,
+}
+
+/// Return a new channel whose far end is a [`FakeRelay`].
+///
+/// The channel only works while the returned future (its reactor) is
+/// running.
+pub fn fake_channel() -> (
+    Arc<Channel>,
+    impl Future<Output = Result<()>> + Send,
+    FakeRelay,
+) {
+    let (to_relay, from_client) = mpsc::channel(64);
+    let (to_client, from_relay) = mpsc::channel(64);
+    let to_relay = to_relay
+        .sink_map_err(|_| tor_cell::Error::InternalError("Fake relay has gone away".into()));
+
+    let ed_id = [0x44; 32].into();
+    let rsa_id = [0x45; 20].into();
+    let (chan, reactor) = Channel::new(
+        4,
+        Box::new(to_relay),
+        from_relay,
+        UniqId::new(),
+        ed_id,
+        rsa_id,
+    );
+
+    let secret = curve25519::StaticSecret::from([0x46; 32]);
+    let public = curve25519::PublicKey::from(&secret);
+    let target = OwnedCircTarget::new(
+        OwnedChanTarget::new(
+            vec!["127.0.0.1:9001".parse().expect("bad address")],
+            ed_id,
+            rsa_id,
+        ),
+        public,
+        tor_protover::Protocols::default(),
+    );
+    let relay = FakeRelay {
+        from_client,
+        to_client,
+        ntor_key: NtorSecretKey::new(secret, public, rsa_id),
+        target,
+    };
+
+    (chan, reactor.run(), relay)
+}
+
+impl FakeRelay {
+    /// Return a description of this relay, to use as the first hop of a
+    /// circuit on its channel.
+    pub fn target(&self) -> &OwnedCircTarget {
+        &self.target
+    }
+
+    /// Wait for the client to send a CREATE2 cell, and answer it by
+    /// completing an ntor handshake.
+    ///
+    /// After this, we ignore any cells for the circuit.
+    pub async fn answer_create2(&mut self) -> Result<()> {
+        let cell = self.from_client.next().await.ok_or(Error::ChannelClosed)?;
+        let create2 = match cell.msg() {
+            msg::ChanMsg::Create2(c) => c,
+            _ => return Err(Error::ChanProto("Expected a CREATE2 cell".into())),
+        };
+        let mut rng = rand::thread_rng();
+        let keys = std::slice::from_ref(&self.ntor_key);
+        let (_, reply) = NtorServer::server(&mut rng, keys, create2.body())?;
+        let created2 = ChanCell::new(cell.circid(), msg::Created2::new(reply).into());
+        self.to_client
+            .send(Ok(created2))
+            .await
+            .map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Wait for the client to send a cell, and return true if it did
+    /// (rather than closing the channel).  We don't answer the cell.
+    pub async fn ignore_cell(&mut self) -> bool {
+        self.from_client.next().await.is_some()
+    }
+}
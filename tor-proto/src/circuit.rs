@@ -38,7 +38,10 @@
 //!
 //! There is no flow-control or rate-limiting or fairness.
 
+mod bytecount;
 pub(crate) mod celltypes;
+mod flowevents;
+mod flowparams;
 pub(crate) mod halfcirc;
 mod halfstream;
 pub(crate) mod reactor;
@@ -47,28 +50,41 @@ mod streammap;
 mod unique_id;
 
 use crate::channel::{Channel, CircDestroyHandle};
+pub use crate::circuit::bytecount::StreamByteCount;
+use crate::circuit::bytecount::{StreamByteCounter, StreamCountIdx};
 use crate::circuit::celltypes::*;
+use crate::circuit::flowevents::FlowEventSender;
+pub use crate::circuit::flowevents::{
+    FlowControlEvent, FlowControlEventKind, FlowControlEvents, FlowControlUpdate,
+};
+pub use crate::circuit::flowparams::FlowControlParams;
 use crate::circuit::reactor::{CtrlMsg, CtrlResult};
-pub use crate::circuit::unique_id::UniqId;
+pub use crate::circuit::sendme::WindowAccounting;
+pub use crate::circuit::unique_id::{CircuitBuildId, UniqId};
 use crate::crypto::cell::{
     ClientLayer, CryptInit, HopNum, InboundClientLayer, OutboundClientCrypt, OutboundClientLayer,
     RelayCellBody,
 };
+pub use crate::crypto::handshake::ntor_v3::NtorV3Extension;
 use crate::crypto::handshake::{ClientHandshake, KeyGenerator};
 use crate::stream::{DataStream, RawCellStream};
 use crate::{Error, Result};
 use tor_cell::chancell::{self, msg::ChanMsg, ChanCell, CircId};
-use tor_cell::relaycell::msg::{RelayMsg, Sendme};
+use tor_cell::relaycell::msg::RelayMsg;
 use tor_cell::relaycell::{RelayCell, RelayCmd, StreamId};
 
-use tor_linkspec::{ChanTarget, CircTarget, LinkSpec};
+use tor_linkspec::{ChanTarget, CircTarget, LinkSpec, OwnedChanTarget};
 
 pub use tor_cell::relaycell::msg::IpVersionPreference;
 
 use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
 use futures::lock::Mutex;
 use futures::sink::SinkExt;
 
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 // use std::time::Duration;
@@ -84,6 +100,19 @@ pub struct ClientCirc {
     closed: AtomicBool,
     /// A unique identifier for this circuit.
     unique_id: UniqId,
+    /// Where to report flow-control events on this circuit.
+    flow_events: Arc<FlowEventSender>,
+    /// How many bytes each stream on this circuit has sent and received,
+    /// if we're counting.
+    stream_bytes: StreamByteCounter,
+    /// The flow-control parameters for each hop of this circuit.
+    flow_params: std::sync::Mutex<Vec<FlowControlParams>>,
+    /// The error that made this circuit's reactor stop, if it stopped
+    /// because of one.
+    close_reason: std::sync::Mutex<Option<Error>>,
+    /// A stream that can be used to send control messages to the reactor
+    /// without locking `c`.
+    control: mpsc::Sender<CtrlResult>,
 
     /// Reference-counted locked reference to the inner circuit object.
     c: Mutex<ClientCircImpl>,
@@ -115,16 +144,38 @@ pub struct PendingClientCirc {
 pub struct CircParameters {
     /// Initial value to use for our outbound circuit-level windows.
     initial_send_window: u16,
+    /// Number of cells that each circuit-level SENDME acknowledges.
+    sendme_increment: u16,
+    /// The network's SENDME increment for hops that agree to congestion
+    /// control.
+    cc_sendme_increment: u8,
     /// Whether we should include ed25519 identities when we send
     /// EXTEND2 cells.
     extend_by_ed25519_id: bool,
+    /// If present, the largest number of bytes of DATA that we'll have
+    /// outstanding (sent but unacknowledged) to any one hop.
+    send_byte_budget: Option<usize>,
+    /// If present, the bounds within which we adapt the increment of our
+    /// circuit-level receive windows, on hops that allow it.
+    adaptive_sendme_bounds: Option<sendme::AdaptiveBounds>,
+    /// Whether we should count the bytes of DATA sent and received on
+    /// each stream.
+    count_stream_bytes: bool,
+    /// An identifier for the build that's using these parameters, if any.
+    build_id: Option<CircuitBuildId>,
 }
 
 impl Default for CircParameters {
     fn default() -> CircParameters {
         CircParameters {
             initial_send_window: 1000,
+            sendme_increment: 100,
+            cc_sendme_increment: 31,
             extend_by_ed25519_id: true,
+            send_byte_budget: None,
+            adaptive_sendme_bounds: None,
+            count_stream_bytes: false,
+            build_id: None,
         }
     }
 }
@@ -150,6 +201,53 @@ impl CircParameters {
         self.initial_send_window
     }
 
+    /// Override the default number of cells that each circuit-level
+    /// SENDME acknowledges, in both directions.  Gives an error on zero,
+    /// or on any value above 1000.
+    ///
+    /// Both ends of a hop have to agree about this, and Tor relays
+    /// always use 100, so you should probably not call this.
+    pub fn set_sendme_increment(&mut self, v: u16) -> Result<()> {
+        if (1..=1000).contains(&v) {
+            self.sendme_increment = v;
+            Ok(())
+        } else {
+            Err(Error::BadConfig(
+                "Tried to set a SENDME increment of zero or over 1000".into(),
+            ))
+        }
+    }
+
+    /// Return the number of cells that each circuit-level SENDME
+    /// acknowledges, as set in this parameter set.
+    pub fn sendme_increment(&self) -> u16 {
+        self.sendme_increment
+    }
+
+    /// Override the default SENDME increment for hops that agree to
+    /// congestion control.  Gives an error on zero.
+    ///
+    /// This should come from the consensus: a hop that negotiates an
+    /// increment more than one away from it is refused, and otherwise
+    /// the negotiated increment replaces the one from
+    /// [`set_sendme_increment`](Self::set_sendme_increment).
+    pub fn set_cc_sendme_increment(&mut self, v: u8) -> Result<()> {
+        if v != 0 {
+            self.cc_sendme_increment = v;
+            Ok(())
+        } else {
+            Err(Error::BadConfig(
+                "Tried to set a congestion control SENDME increment of zero".into(),
+            ))
+        }
+    }
+
+    /// Return the SENDME increment for hops that agree to congestion
+    /// control, as set in this parameter set.
+    pub fn cc_sendme_increment(&self) -> u8 {
+        self.cc_sendme_increment
+    }
+
     /// Override the default decision about whether to use ed25519
     /// identities in outgoing EXTEND2 cells.
     ///
@@ -163,6 +261,82 @@ impl CircParameters {
     pub fn extend_by_ed25519_id(&self) -> bool {
         self.extend_by_ed25519_id
     }
+
+    /// Limit the number of bytes of DATA that we'll send to any one hop
+    /// of the circuit before the hop acknowledges them, or remove the
+    /// limit if `v` is None.
+    ///
+    /// This applies alongside the cell-based send window: when either
+    /// one is exhausted, sending waits for a SENDME.  It is mainly useful
+    /// to keep many streams of small cells from using lots of memory.
+    /// (We always allow at least one cell to be outstanding, even if it
+    /// is larger than the budget.)
+    pub fn set_send_byte_budget(&mut self, v: Option<usize>) {
+        self.send_byte_budget = v;
+    }
+
+    /// Return the byte budget for outstanding DATA, if there is one.
+    pub fn send_byte_budget(&self) -> Option<usize> {
+        self.send_byte_budget
+    }
+
+    /// Let our circuit-level receive windows choose how many cells each
+    /// SENDME acknowledges, between the `(min, max)` in `bounds`, or
+    /// keep the fixed increment if `bounds` is None.  Gives an error
+    /// unless `0 < min <= max`.
+    ///
+    /// The faster we consume a hop's cells, the larger the increment, and
+    /// the fewer SENDMEs we send.  This only applies to hops that agreed
+    /// to congestion control (see [`FlowControlParams::version`]): older
+    /// relays expect a SENDME after every increment that we negotiated.
+    /// It is off by default.
+    pub fn set_adaptive_sendme_bounds(&mut self, bounds: Option<(u16, u16)>) -> Result<()> {
+        self.adaptive_sendme_bounds = match bounds {
+            Some((min, max)) => Some(sendme::AdaptiveBounds::new(min, max)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Return the bounds for adaptive SENDME increments, if we're using
+    /// them.
+    pub fn adaptive_sendme_bounds(&self) -> Option<(u16, u16)> {
+        self.adaptive_sendme_bounds
+            .as_ref()
+            .map(sendme::AdaptiveBounds::increments)
+    }
+
+    /// Override the default decision about whether to count the bytes of
+    /// DATA sent and received on each stream of the circuit.
+    ///
+    /// This is off by default; turn it on to measure bandwidth or debug
+    /// a circuit.  Once any hop of a circuit is added with counting on,
+    /// the circuit counts bytes for the rest of its life.  See
+    /// [`ClientCirc::stream_byte_counts`].
+    pub fn set_count_stream_bytes(&mut self, v: bool) {
+        self.count_stream_bytes = v;
+    }
+
+    /// Return true if we're configured to count the bytes on each stream;
+    /// false otherwise.
+    pub fn count_stream_bytes(&self) -> bool {
+        self.count_stream_bytes
+    }
+
+    /// Label the circuit that we build with these parameters as part of
+    /// the build `id`.
+    ///
+    /// The circuit includes `id` in its log messages and flow-control
+    /// events, so that they can be matched up with those from the rest
+    /// of the build.  See [`ClientCirc::build_id`].
+    pub fn set_build_id(&mut self, id: CircuitBuildId) {
+        self.build_id = Some(id);
+    }
+
+    /// Return the build identifier in these parameters, if there is one.
+    pub fn build_id(&self) -> Option<CircuitBuildId> {
+        self.build_id
+    }
 }
 
 /// A result type used to tell a circuit about some a "meta-cell"
@@ -224,6 +398,8 @@ pub(crate) struct StreamTarget {
     circ: Arc<ClientCirc>,
     /// Window for sending cells on this circuit.
     window: sendme::StreamSendWindow,
+    /// A handle to this stream's byte count, if its circuit counts bytes.
+    byte_count: Option<StreamCountIdx>,
     /// One-shot sender that should get a message once this stream
     /// is dropped.
     stream_closed: Option<oneshot::Sender<CtrlMsg>>,
@@ -240,23 +416,115 @@ pub(crate) struct StreamTarget {
 ///
 /// (see also circuit::reactor::InboundHop)
 struct CircHop {
-    /// If true, this hop is using an older link protocol and we
-    /// shouldn't expect good authenticated SENDMEs from it.
-    auth_sendme_optional: bool,
     /// Window used to say how many cells we can send.
     sendwindow: sendme::CircSendWindow,
+    /// The relay at this hop, if we know which one it is.
+    ///
+    /// (We don't know this for a hop created with CREATE_FAST.)
+    target: Option<OwnedChanTarget>,
 }
 
 impl CircHop {
-    /// Construct a new (sender-side) view of a circuit hop.
-    fn new(auth_sendme_optional: bool, initial_window: u16) -> Self {
-        CircHop {
-            auth_sendme_optional,
-            sendwindow: sendme::CircSendWindow::new(initial_window),
+    /// Initial value for inbound flow-control window on circuits.
+    const RECV_WINDOW_INIT: u16 = 1000;
+
+    /// Decide on the flow-control parameters for a new circuit hop.
+    ///
+    /// If `supports_flowctrl_1` is false, the hop doesn't support
+    /// authenticated SENDMEs.  If `cc_sendme_inc` is present, the hop
+    /// agreed to congestion control with that SENDME increment, which
+    /// takes the place of the one in `params`.
+    fn flow_params(
+        supports_flowctrl_1: bool,
+        cc_sendme_inc: Option<u8>,
+        params: &CircParameters,
+    ) -> FlowControlParams {
+        match cc_sendme_inc {
+            Some(inc) => {
+                // The hop counts SENDMEs from the first cell, so our
+                // window has to hold a whole number of increments.
+                let inc = u16::from(inc);
+                let initial = round_to_increment(params.initial_send_window(), inc);
+                FlowControlParams::new(2, initial, inc)
+            }
+            None => FlowControlParams::new(
+                supports_flowctrl_1.into(),
+                params.initial_send_window(),
+                params.sendme_increment(),
+            ),
+        }
+    }
+
+    /// Construct the send and receive windows for a new circuit hop with
+    /// the flow-control parameters `flow`, and the byte budget from
+    /// `params`.
+    fn windows(
+        flow: &FlowControlParams,
+        params: &CircParameters,
+    ) -> (sendme::CircSendWindow, sendme::CircRecvWindow) {
+        let initial = flow.initial_window();
+        let increment = flow.increment();
+        let recv_init = if flow.version() >= 2 {
+            round_to_increment(Self::RECV_WINDOW_INIT, increment)
+        } else {
+            Self::RECV_WINDOW_INIT
+        };
+        let maximum = initial.max(recv_init).max(increment);
+        let mut settings = sendme::WindowSettings::new(initial, recv_init);
+        settings
+            .limits(sendme::WindowLimits::new(maximum, increment))
+            .send_byte_budget(params.send_byte_budget());
+        if flow.version() == 0 {
+            // The hop doesn't support authenticated SENDMEs, so the send
+            // window doesn't record tags.
+            settings.unauthenticated();
         }
+        if let Some(bounds) = params.adaptive_sendme_bounds {
+            // (This does nothing unless the hop's version allows it.)
+            settings.adaptive(flow.version(), bounds);
+        }
+        sendme::FlowControl::windows(&settings)
+    }
+}
+
+/// Round `window` down to a whole number of `increment`s, but never to
+/// fewer than one.
+fn round_to_increment(window: u16, increment: u16) -> u16 {
+    (window / increment).max(1) * increment
+}
+
+/// Return true if `a` and `b` are close enough that we shouldn't use
+/// relays at both addresses in the same circuit.
+///
+/// As in C Tor, that means being in the same IPv4 /16 or the same
+/// IPv6 /32.
+fn same_subnet(a: &IpAddr, b: &IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.octets()[..4] == b.octets()[..4],
+        (_, _) => false,
     }
 }
 
+/// If `target` can't be used in the same circuit as the relay `hop`,
+/// return a description of why not.
+fn hop_conflict<Tg: ChanTarget + ?Sized>(
+    hop: &OwnedChanTarget,
+    target: &Tg,
+) -> Option<&'static str> {
+    if hop.ed_identity() == target.ed_identity() || hop.rsa_identity() == target.rsa_identity() {
+        return Some("same relay as an existing hop");
+    }
+    let shares_subnet = hop
+        .addrs()
+        .iter()
+        .any(|a| target.addrs().iter().any(|b| same_subnet(&a.ip(), &b.ip())));
+    if shares_subnet {
+        return Some("same subnet as an existing hop");
+    }
+    None
+}
+
 impl ClientCirc {
     /// Helper: return the number of hops for this circuit
     #[cfg(test)]
@@ -272,16 +540,32 @@ impl ClientCirc {
     /// handshake we're doing.  The `key is the relay's onion key that
     /// goes along with the handshake, and the `linkspecs` are the
     /// link specifiers to include in the EXTEND cell to tell the
-    /// current last hop which relay to connect to.
-    async fn extend_impl<R, L, FWD, REV, H>(
+    /// current last hop which relay to connect to.  The `client_aux`
+    /// is sent to the relay along with the handshake, and on success we
+    /// return whatever the relay sent back.
+    ///
+    /// If `avoid_conflicts` is true, we refuse to extend to a `target`
+    /// that conflicts with one of the circuit's existing hops.  We check
+    /// this while holding the lock that we use to send the EXTEND2 cell,
+    /// so that the hops can't change between the check and the extend.
+    ///
+    /// Once the handshake is done, `negotiate_cc` looks at what the relay
+    /// sent back, and returns the SENDME increment for the new hop if
+    /// the relay agreed to congestion control.
+    #[allow(clippy::too_many_arguments)]
+    async fn extend_impl<R, L, FWD, REV, H, F>(
         &self,
         rng: &mut R,
         handshake_id: u16,
         key: &H::KeyType,
+        client_aux: &H::ClientAuxData,
         linkspecs: Vec<LinkSpec>,
         supports_flowctrl_1: bool,
+        target: OwnedChanTarget,
         params: &CircParameters,
-    ) -> Result<()>
+        avoid_conflicts: bool,
+        negotiate_cc: F,
+    ) -> Result<H::ServerAuxData>
     where
         R: Rng + CryptoRng,
         L: CryptInit + ClientLayer<FWD, REV>,
@@ -289,10 +573,11 @@ impl ClientCirc {
         REV: InboundClientLayer + 'static + Send,
         H: ClientHandshake,
         H::KeyGen: KeyGenerator,
+        F: FnOnce(&H::ServerAuxData) -> Result<Option<u8>>,
     {
         use tor_cell::relaycell::msg::{Body, Extend2};
         // Perform the first part of the cryptographic handshake
-        let (state, msg) = H::client1(rng, key)?;
+        let (state, msg) = H::client1(rng, key, client_aux)?;
         // Cloning linkspecs is only necessary because of the log
         // below. Would be nice to fix that.
         let extend_msg = Extend2::new(linkspecs.clone(), handshake_id, msg);
@@ -301,6 +586,9 @@ impl ClientCirc {
         // Now send the EXTEND2 cell to the the last hop...
         let (unique_id, _hop, receiver) = {
             let mut c = self.c.lock().await;
+            if avoid_conflicts {
+                c.check_hop_conflicts(&target)?;
+            }
             let n_hops = c.crypto_out.n_layers();
             let hop = ((n_hops - 1) as u8).into();
             debug!(
@@ -316,7 +604,7 @@ impl ClientCirc {
             // Send the message to the last hop...
             c.send_relay_cell(
                 hop, true, // use a RELAY_EARLY cell
-                cell,
+                cell, None,
             )
             .await?;
 
@@ -371,7 +659,8 @@ impl ClientCirc {
         );
         // Now perform the second part of the handshake, and see if it
         // succeeded.
-        let keygen = H::client2(state, relay_handshake)?;
+        let (server_aux, keygen) = H::client2(state, relay_handshake)?;
+        let cc_sendme_inc = negotiate_cc(&server_aux)?;
         let layer = L::construct(keygen)?;
 
         debug!("{}: Handshake complete; circuit extended.", unique_id);
@@ -380,11 +669,14 @@ impl ClientCirc {
         let (layer_fwd, layer_back) = layer.split();
         self.add_hop(
             supports_flowctrl_1,
+            cc_sendme_inc,
+            Some(target),
             Box::new(layer_fwd),
             Box::new(layer_back),
             params,
         )
-        .await
+        .await?;
+        Ok(server_aux)
     }
 
     /// Add a hop to the end of this circuit.
@@ -396,11 +688,20 @@ impl ClientCirc {
     async fn add_hop<'a>(
         &'a self,
         supports_flowctrl_1: bool,
+        cc_sendme_inc: Option<u8>,
+        target: Option<OwnedChanTarget>,
         fwd: Box<dyn OutboundClientLayer + 'static + Send>,
         rev: Box<dyn InboundClientLayer + 'static + Send>,
         params: &'a CircParameters,
     ) -> Result<()> {
-        let inbound_hop = crate::circuit::reactor::InboundHop::new();
+        let flow = CircHop::flow_params(supports_flowctrl_1, cc_sendme_inc, params);
+        let (sendwindow, recvwindow) = CircHop::windows(&flow, params);
+        let inbound_hop = crate::circuit::reactor::InboundHop::new(
+            recvwindow,
+            sendwindow.new_ref(),
+            flow.version() >= 1,
+        );
+        let hop = CircHop { sendwindow, target };
         let (snd, rcv) = oneshot::channel();
         {
             let mut c = self.c.lock().await;
@@ -417,12 +718,19 @@ impl ClientCirc {
         rcv.await
             .map_err(|_| Error::InternalError("AddHop request cancelled".into()))?;
 
+        if params.count_stream_bytes() {
+            self.stream_bytes.enable();
+        }
+        if let Some(build_id) = params.build_id() {
+            self.flow_events.set_build_id(build_id);
+        }
+
         {
             let mut c = self.c.lock().await;
-            let hop = CircHop::new(supports_flowctrl_1, params.initial_send_window());
             c.hops.push(hop);
             c.crypto_out.add_layer(fwd);
         }
+        self.flow_params.lock().expect("poisoned lock").push(flow);
         Ok(())
     }
 
@@ -436,6 +744,22 @@ impl ClientCirc {
         target: &Tg,
         params: &CircParameters,
     ) -> Result<()>
+    where
+        R: Rng + CryptoRng,
+        Tg: CircTarget,
+    {
+        self.extend_ntor_impl(rng, target, params, false).await
+    }
+
+    /// Helper: implement [`ClientCirc::extend_ntor`] and
+    /// [`ClientCirc::extend_to_exit`].
+    async fn extend_ntor_impl<R, Tg>(
+        &self,
+        rng: &mut R,
+        target: &Tg,
+        params: &CircParameters,
+        avoid_conflicts: bool,
+    ) -> Result<()>
     where
         R: Rng + CryptoRng,
         Tg: CircTarget,
@@ -454,17 +778,134 @@ impl ClientCirc {
         let supports_flowctrl_1 = target
             .protovers()
             .supports_known_subver(tor_protover::ProtoKind::FlowCtrl, 1);
-        self.extend_impl::<R, Tor1RelayCrypto, _, _, NtorClient>(
+        self.extend_impl::<R, Tor1RelayCrypto, _, _, NtorClient, _>(
             rng,
             0x0002,
             &key,
+            &(),
             linkspecs,
             supports_flowctrl_1,
+            target.to_owned(),
             params,
+            avoid_conflicts,
+            // The ntor handshake can't negotiate congestion control.
+            |_| Ok(None),
         )
         .await
     }
 
+    /// Extend the circuit via the ntor v3 handshake to a new target last
+    /// hop, sending `extensions` to it along with the handshake.
+    ///
+    /// On success, return the extensions that the new hop sent back.  If
+    /// we sent a
+    /// [congestion control request](NtorV3Extension::congestion_control_request)
+    /// and the hop agreed, the hop uses the SENDME increment that it asked
+    /// for: see [`ClientCirc::flow_control_params`].  If the hop turns on
+    /// congestion control without our asking, the extend fails.
+    ///
+    /// Only relays that support the Relay=4 subprotocol can answer this
+    /// handshake; callers should check before using it.  The same caveats
+    /// apply as for [`ClientCirc::extend_ntor`].
+    pub async fn extend_ntor_v3<R, Tg>(
+        &self,
+        rng: &mut R,
+        target: &Tg,
+        params: &CircParameters,
+        extensions: &[NtorV3Extension],
+    ) -> Result<Vec<NtorV3Extension>>
+    where
+        R: Rng + CryptoRng,
+        Tg: CircTarget,
+    {
+        use crate::crypto::cell::Tor1RelayCrypto;
+        use crate::crypto::handshake::ntor_v3::{NtorV3Client, NtorV3PublicKey};
+        let key = NtorV3PublicKey {
+            id: *target.ed_identity(),
+            pk: *target.ntor_onion_key(),
+        };
+        let mut linkspecs = target.linkspecs();
+        if !params.extend_by_ed25519_id() {
+            linkspecs.retain(|ls| !matches!(ls, LinkSpec::Ed25519Id(_)));
+        }
+        // FlowCtrl=1 means that this hop supports authenticated SENDMEs
+        let supports_flowctrl_1 = target
+            .protovers()
+            .supports_known_subver(tor_protover::ProtoKind::FlowCtrl, 1);
+        let requested_cc = extensions.contains(&NtorV3Extension::congestion_control_request());
+        let negotiate_cc = |reply: &Vec<NtorV3Extension>| {
+            match reply.iter().find(|e| e.is_congestion_control_response()) {
+                None => Ok(None),
+                Some(_) if !requested_cc => Err(Error::CircProto(
+                    "Relay turned on congestion control without being asked".into(),
+                )),
+                // (The handshake already made sure that this was
+                // well-formed.)
+                Some(e) => match e.congestion_control_sendme_inc() {
+                    // As in C Tor, the relay may only stray by one from
+                    // the increment that the consensus gives.
+                    Some(inc)
+                        if (i16::from(inc) - i16::from(params.cc_sendme_increment())).abs() > 1 =>
+                    {
+                        Err(Error::CircProto(format!(
+                            "Relay negotiated a SENDME increment of {}, but the network uses {}",
+                            inc,
+                            params.cc_sendme_increment()
+                        )))
+                    }
+                    inc => Ok(inc),
+                },
+            }
+        };
+        let reply = self
+            .extend_impl::<R, Tor1RelayCrypto, _, _, NtorV3Client, _>(
+                rng,
+                0x0003,
+                &key,
+                extensions,
+                linkspecs,
+                supports_flowctrl_1,
+                target.to_owned(),
+                params,
+                false,
+                negotiate_cc,
+            )
+            .await?;
+        Ok(reply)
+    }
+
+    /// Return a description of the relay at each hop of this circuit,
+    /// or None for a hop where we don't know which relay it is.
+    pub async fn path(&self) -> Vec<Option<OwnedChanTarget>> {
+        let c = self.c.lock().await;
+        c.hops.iter().map(|hop| hop.target.clone()).collect()
+    }
+
+    /// Extend the circuit via the ntor handshake to `exit`, after making
+    /// sure that `exit` doesn't conflict with any of the circuit's
+    /// existing hops.
+    ///
+    /// Two relays conflict if they are the same relay, or if they have
+    /// addresses in the same IPv4 /16 or IPv6 /32.  (We can't check
+    /// whether they are in the same family here: callers that know about
+    /// families should check that too.)  We don't know which relay a
+    /// CREATE_FAST hop is, so we can't check against it.
+    ///
+    /// On a conflict, returns [`Error::HopConflict`] without trying to
+    /// extend the circuit.
+    pub async fn extend_to_exit<R, Tg>(
+        &self,
+        rng: &mut R,
+        exit: &Tg,
+        params: &CircParameters,
+    ) -> Result<()>
+    where
+        R: Rng + CryptoRng,
+        Tg: CircTarget,
+    {
+        self.extend_ntor_impl(rng, exit, params, true).await
+    }
+
     /// Helper, used to begin a stream.
     ///
     /// This function allocates a stream ID, and sends the message
@@ -476,11 +917,20 @@ impl ClientCirc {
         // TODO: Possibly this should take a hop, rather than just
         // assuming it's the last hop.
 
+        /// Initial value for inbound flow-control window on streams.
+        const STREAM_RECV_INIT: u16 = 500;
+
+        if self.is_closing() {
+            return Err(self.closed_error());
+        }
+
         // XXXX Both a bound and a lack of bound are scary here :/
         let (sender, receiver) = mpsc::channel(128);
 
         let (send_close, recv_close) = oneshot::channel::<CtrlMsg>();
-        let window = sendme::StreamSendWindow::new(StreamTarget::SEND_WINDOW_INIT);
+        let params = sendme::WindowSettings::new(StreamTarget::SEND_WINDOW_INIT, STREAM_RECV_INIT);
+        let (window, recvwindow): (sendme::StreamSendWindow, sendme::StreamRecvWindow) =
+            sendme::FlowControl::windows(&params);
 
         let (id_snd, id_rcv) = oneshot::channel();
         let hopnum;
@@ -509,22 +959,20 @@ impl ClientCirc {
 
         {
             let mut c = self.c.lock().await;
-            c.send_relay_cell(hopnum, false, relaycell).await?;
+            c.send_relay_cell(hopnum, false, relaycell, None).await?;
             c.control
                 .send(Ok(CtrlMsg::Register(recv_close)))
                 .await
                 .map_err(|_| Error::InternalError("Can't queue stream closer".into()))?;
         }
 
-        /// Initial value for inbound flow-control window on streams.
-        const STREAM_RECV_INIT: u16 = 500;
-
         let target = StreamTarget {
             circ: Arc::clone(self),
             stream_id: id,
             hop: hopnum,
             window,
-            recvwindow: sendme::StreamRecvWindow::new(STREAM_RECV_INIT),
+            byte_count: self.stream_bytes.add_stream(id),
+            recvwindow,
             stream_closed: Some(send_close),
         };
 
@@ -578,12 +1026,61 @@ impl ClientCirc {
     /// 'hop'th hop.
     ///
     /// Does not check whether the cell is well-formed or reasonable.
+    ///
+    /// If the cell counts towards the hop's send window, this waits for
+    /// room in that window _before_ it locks `c`, so that the reactor can
+    /// still use `c` (to send SENDMEs or END cells, say) while we wait.
     async fn send_relay_cell(&self, hop: HopNum, early: bool, cell: RelayCell) -> Result<()> {
+        self.send_relay_cell_impl(hop, early, cell, false).await
+    }
+
+    /// As [`ClientCirc::send_relay_cell`], but if the cell counts towards
+    /// the hop's send window, go ahead of any ordinary cells that are
+    /// waiting for room in it.
+    ///
+    /// This is for control cells that the circuit needs to keep working
+    /// or to shut down cleanly, like SENDMEs, END cells, and the last
+    /// cell of a closing stream, so that bulk data can't starve them.
+    pub(crate) async fn send_control_cell(&self, hop: HopNum, cell: RelayCell) -> Result<()> {
+        self.send_relay_cell_impl(hop, false, cell, true).await
+    }
+
+    /// Helper: implement [`ClientCirc::send_relay_cell`] and
+    /// [`ClientCirc::send_control_cell`].
+    async fn send_relay_cell_impl(
+        &self,
+        hop: HopNum,
+        early: bool,
+        cell: RelayCell,
+        priority: bool,
+    ) -> Result<()> {
         if self.closed.load(Ordering::SeqCst) {
-            return Err(Error::CircuitClosed);
+            return Err(self.closed_error());
         }
+        let mut reservation = if sendme::cell_counts_towards_windows(&cell) {
+            let mut sendwindow = {
+                let c = self.c.lock().await;
+                c.hops
+                    .get(Into::<usize>::into(hop))
+                    .ok_or_else(|| {
+                        Error::InternalError("Tried to send a cell to a nonexistent hop".into())
+                    })?
+                    .sendwindow
+                    .new_ref()
+            };
+            // This blocks if the send window is empty.
+            let n_bytes = sendme::cell_data_len(&cell);
+            Some(if priority {
+                sendwindow.reserve_cell_priority(n_bytes).await?
+            } else {
+                sendwindow.reserve_cell(n_bytes).await?
+            })
+        } else {
+            None
+        };
         let mut c = self.c.lock().await;
-        c.send_relay_cell(hop, early, cell).await
+        c.send_relay_cell(hop, early, cell, reservation.as_mut())
+            .await
     }
 
     /// Shut down this circuit immediately, along with all streams that
@@ -605,6 +1102,45 @@ impl ClientCirc {
         }
     }
 
+    /// Shut down this circuit once every hop has acknowledged the cells
+    /// that we've sent it, or once `timeout` finishes, whichever comes
+    /// first.
+    ///
+    /// Stop sending on this circuit before you call this: we wait for
+    /// every SENDME that we're expecting, and then
+    /// [`terminate`](ClientCirc::terminate) the circuit.  Since this
+    /// crate has no runtime of its own, the caller supplies the timeout,
+    /// usually as a sleep future.
+    ///
+    /// Return true if every hop acknowledged everything before we shut
+    /// down, and false if we timed out first.  (Cells sent since a hop's
+    /// last SENDME was due will never be acknowledged, so they don't
+    /// count.)
+    pub async fn terminate_gracefully<F>(&self, timeout: F) -> Result<bool>
+    where
+        F: Future<Output = ()>,
+    {
+        let windows: Vec<_> = {
+            let c = self.c.lock().await;
+            c.hops.iter().map(|hop| hop.sendwindow.new_ref()).collect()
+        };
+        // We don't hold `c` while we wait, so that the circuit keeps
+        // working in the meantime.
+        let timeout = timeout.shared();
+        let mut result = Ok(true);
+        for window in windows {
+            match window.drain(timeout.clone()).await {
+                Ok(true) => {}
+                other => {
+                    result = other;
+                    break;
+                }
+            }
+        }
+        self.terminate().await;
+        result
+    }
+
     /// Called when a circuit-level protocol error has occurred and the
     /// circuit needs to shut down.
     ///
@@ -619,11 +1155,123 @@ impl ClientCirc {
         self.closed.load(Ordering::SeqCst)
     }
 
+    /// Return the error that made this circuit's reactor stop, if it
+    /// stopped because of one.
+    ///
+    /// Once this is set, operations on the circuit fail with
+    /// [`Error::ReactorFailed`] wrapping it, instead of with
+    /// [`Error::CircuitClosed`].
+    pub fn close_reason(&self) -> Option<Error> {
+        self.close_reason.lock().expect("poisoned lock").clone()
+    }
+
+    /// Return the error to report for an operation that failed because
+    /// this circuit is closed.
+    fn closed_error(&self) -> Error {
+        match self.close_reason() {
+            Some(e) => Error::ReactorFailed(Box::new(e)),
+            None => Error::CircuitClosed,
+        }
+    }
+
     /// Return a process-unique identifier for this circui.
     pub fn unique_id(&self) -> UniqId {
         self.unique_id
     }
 
+    /// Return the identifier of the build that created this circuit, if
+    /// it had one.
+    ///
+    /// (This is the identifier from the [`CircParameters`] of the most
+    /// recent hop that had one.)
+    pub fn build_id(&self) -> Option<CircuitBuildId> {
+        self.flow_events.build_id()
+    }
+
+    /// Return a new stream of the flow-control events on this circuit,
+    /// which can hold up to `capacity` undelivered updates.
+    ///
+    /// Only one such stream exists at a time: calling this method again
+    /// ends any stream it returned before.  Reporting events never
+    /// blocks the circuit; if the stream is full, events are dropped
+    /// and the stream later yields [`FlowControlUpdate::Lagged`].
+    pub fn flow_control_events(&self, capacity: usize) -> FlowControlEvents {
+        self.flow_events.subscribe(capacity)
+    }
+
+    /// Return the number of bytes of DATA that each stream on this circuit
+    /// has sent and received, in the order that the streams were opened,
+    /// or None if this circuit isn't counting them.
+    ///
+    /// Streams that have closed are still included.  A circuit only
+    /// counts bytes if it was built with
+    /// [`CircParameters::set_count_stream_bytes`].
+    pub fn stream_byte_counts(&self) -> Option<Vec<StreamByteCount>> {
+        self.stream_bytes.snapshot()
+    }
+
+    /// Return the flow-control parameters in effect for the hop at index
+    /// `hop` (counting from zero), or None if there is no such hop.
+    pub fn flow_control_params(&self, hop: usize) -> Option<FlowControlParams> {
+        self.flow_params
+            .lock()
+            .expect("poisoned lock")
+            .get(hop)
+            .copied()
+    }
+
+    /// Return a snapshot of our send window for the hop at index `hop`
+    /// (counting from zero), along with the numbers of cells and SENDMEs
+    /// behind it, or None if there is no such hop.
+    ///
+    /// This is meant for monitoring tools that want to detect flow
+    /// control bugs: see [`WindowAccounting::sendme_lag`].
+    pub async fn send_window_accounting(&self, hop: u8) -> Option<WindowAccounting> {
+        let c = self.c.lock().await;
+        match c.hops.get(usize::from(hop)) {
+            Some(h) => Some(h.sendwindow.accounting().await),
+            None => None,
+        }
+    }
+
+    /// Pause flow control on every hop of this circuit, so that it
+    /// neither sends nor acknowledges data until
+    /// [`ClientCirc::resume_flow_control`].
+    ///
+    /// While the circuit is paused, sending anything that counts towards
+    /// a circuit window (like DATA on a stream) waits, and we send no
+    /// circuit-level SENDMEs.  The other side doesn't know that we've
+    /// paused: it can keep sending until its own window runs out, and
+    /// then it waits for us to resume.
+    ///
+    /// Pausing a circuit that is already paused does nothing.
+    pub async fn pause_flow_control(&self) -> Result<()> {
+        let (snd, rcv) = oneshot::channel();
+        self.send_control(CtrlMsg::PauseFlowControl(snd)).await?;
+        rcv.await.map_err(|_| self.closed_error())
+    }
+
+    /// Resume flow control on this circuit after
+    /// [`ClientCirc::pause_flow_control`].
+    ///
+    /// We send the circuit-level SENDMEs that came due while we were
+    /// paused, and let anything that was waiting to send go ahead.
+    pub async fn resume_flow_control(&self) -> Result<()> {
+        let (snd, rcv) = oneshot::channel();
+        self.send_control(CtrlMsg::ResumeFlowControl(snd)).await?;
+        rcv.await.map_err(|_| self.closed_error())
+    }
+
+    /// Helper: send `msg` to this circuit's reactor without locking the
+    /// circuit.
+    async fn send_control(&self, msg: CtrlMsg) -> Result<()> {
+        if self.is_closing() {
+            return Err(self.closed_error());
+        }
+        let mut control = self.control.clone();
+        control.send(Ok(msg)).await.map_err(|_| self.closed_error())
+    }
+
     /// Helper: register a meta-handler for this circuit.
     #[cfg(test)]
     async fn register_meta_handler(&self, hop: HopNum) -> Result<oneshot::Receiver<MetaResult>> {
@@ -635,10 +1283,23 @@ impl ClientCirc {
 impl ClientCircImpl {
     /// Return a mutable reference to the nth hop of this circuit, if one
     /// exists.
+    #[cfg(test)]
     fn hop_mut(&mut self, hopnum: HopNum) -> Option<&mut CircHop> {
         self.hops.get_mut(Into::<usize>::into(hopnum))
     }
 
+    /// Return an error if `target` conflicts with any of this circuit's
+    /// hops.
+    fn check_hop_conflicts<Tg: ChanTarget + ?Sized>(&self, target: &Tg) -> Result<()> {
+        for (idx, hop) in self.hops.iter().enumerate() {
+            let conflict = hop.target.as_ref().and_then(|t| hop_conflict(t, target));
+            if let Some(why) = conflict {
+                return Err(Error::HopConflict(format!("hop {}: {}", idx, why)));
+            }
+        }
+        Ok(())
+    }
+
     /// Helper: Register a handler that will be told about the RELAY message
     /// with StreamId 0.
     ///
@@ -680,10 +1341,8 @@ impl ClientCircImpl {
 
     /// Handle a RELAY cell on this circuit with stream ID 0.
     async fn handle_meta_cell(&mut self, hopnum: HopNum, msg: RelayMsg) -> Result<()> {
-        // SENDME cells and TRUNCATED get handled internally by the circuit.
-        if let RelayMsg::Sendme(s) = msg {
-            return self.handle_sendme(hopnum, s).await;
-        }
+        // TRUNCATED cells get handled internally by the circuit.  (The
+        // reactor handles SENDME cells before they get here.)
         if let RelayMsg::Truncated(_) = msg {
             // XXXX need to handle Truncated cells. This isn't the right
             // way, but at least it's safe.
@@ -730,36 +1389,6 @@ impl ClientCircImpl {
         }
     }
 
-    /// Handle a RELAY_SENDME cell on this circuit with stream ID 0.
-    async fn handle_sendme(&mut self, hopnum: HopNum, msg: Sendme) -> Result<()> {
-        // No need to call "shutdown" on errors in this function;
-        // it's called from the reactor task and errors will propagate there.
-        let hop = self
-            .hop_mut(hopnum)
-            .ok_or_else(|| Error::CircProto(format!("Couldn't find {} hop", hopnum)))?;
-
-        let auth: Option<[u8; 20]> = match msg.into_tag() {
-            Some(v) if v.len() == 20 => {
-                // XXXX ugly code.
-                let mut tag = [0_u8; 20];
-                (&mut tag).copy_from_slice(&v[..]);
-                Some(tag)
-            }
-            Some(_) => return Err(Error::CircProto("malformed tag on circuit sendme".into())),
-            None => {
-                if !hop.auth_sendme_optional {
-                    return Err(Error::CircProto("missing tag on circuit sendme".into()));
-                } else {
-                    None
-                }
-            }
-        };
-        match hop.sendwindow.put(auth).await {
-            Some(_) => Ok(()),
-            None => Err(Error::CircProto("bad auth tag on circuit sendme".into())),
-        }
-    }
-
     /// Helper: Put a cell onto this circuit's channel.
     ///
     /// This takes a raw cell that has already been encrypted, puts
@@ -776,8 +1405,23 @@ impl ClientCircImpl {
     /// 'hop'th hop.
     ///
     /// Does not check whether the cell is well-formed or reasonable.
-    async fn send_relay_cell(&mut self, hop: HopNum, early: bool, cell: RelayCell) -> Result<()> {
-        let c_t_w = sendme::cell_counts_towards_windows(&cell);
+    ///
+    /// If the cell counts towards the hop's send window, `reservation`
+    /// must hold room for it in that window.
+    async fn send_relay_cell(
+        &mut self,
+        hop: HopNum,
+        early: bool,
+        cell: RelayCell,
+        reservation: Option<&mut sendme::CircReservation>,
+    ) -> Result<()> {
+        let reservation = if sendme::cell_counts_towards_windows(&cell) {
+            Some(reservation.ok_or_else(|| {
+                Error::InternalError("Tried to send a cell without room in its window".into())
+            })?)
+        } else {
+            None
+        };
         let mut body: RelayCellBody = cell.encode(&mut thread_rng())?.into();
         let tag = self.crypto_out.encrypt(&mut body, hop)?;
         let msg = chancell::msg::Relay::from_raw(body.into());
@@ -786,16 +1430,12 @@ impl ClientCircImpl {
         } else {
             ChanMsg::Relay(msg)
         };
-        // If the cell counted towards our sendme window, decrement
-        // that window, and maybe remember the authentication tag.
-        if c_t_w {
-            // TODO: I'd like to use get_hops_mut here, but the borrow checker
-            // won't let me.
-            // This blocks if the send window is empty.
-            self.hops[Into::<usize>::into(hop)]
-                .sendwindow
-                .take(tag)
-                .await?;
+        // If the cell counted towards our sendme window, use the room
+        // we reserved for it, and maybe remember the authentication tag.
+        if let Some(reservation) = reservation {
+            reservation.consume(tag).await?.ok_or_else(|| {
+                Error::InternalError("Reservation for a cell was already used".into())
+            })?;
         }
         self.send_msg(msg).await
     }
@@ -833,6 +1473,7 @@ impl PendingClientCirc {
         // Should this be bounded, really? XXX
         let (sendctrl, recvctrl) = mpsc::channel::<CtrlResult>(128);
         let hops = Vec::new();
+        let flow_events = Arc::new(FlowEventSender::new());
 
         let circuit_impl = ClientCircImpl {
             id,
@@ -840,22 +1481,34 @@ impl PendingClientCirc {
             crypto_out,
             hops,
             circ_closed,
-            control: sendctrl,
+            control: sendctrl.clone(),
             sendshutdown: Some(sendclosed),
             sendmeta: None,
             unique_id,
         };
         let circuit = ClientCirc {
             closed: AtomicBool::new(false),
+            close_reason: std::sync::Mutex::new(None),
             c: Mutex::new(circuit_impl),
             unique_id,
+            flow_events: Arc::clone(&flow_events),
+            stream_bytes: StreamByteCounter::default(),
+            flow_params: std::sync::Mutex::new(Vec::new()),
+            control: sendctrl,
         };
         let circuit = Arc::new(circuit);
         let pending = PendingClientCirc {
             recvcreated: createdreceiver,
             circ: Arc::clone(&circuit),
         };
-        let reactor = reactor::Reactor::new(&circuit, recvctrl, recvclosed, input, unique_id);
+        let reactor = reactor::Reactor::new(
+            &circuit,
+            recvctrl,
+            recvclosed,
+            input,
+            unique_id,
+            flow_events,
+        );
         (pending, reactor)
     }
 
@@ -885,6 +1538,7 @@ impl PendingClientCirc {
         wrap: &W,
         key: &H::KeyType,
         supports_flowctrl_1: bool,
+        target: Option<OwnedChanTarget>,
         params: &CircParameters,
     ) -> Result<Arc<ClientCirc>>
     where
@@ -892,7 +1546,7 @@ impl PendingClientCirc {
         L: CryptInit + ClientLayer<FWD, REV> + 'static + Send, // need all this?XXXX
         FWD: OutboundClientLayer + 'static + Send,
         REV: InboundClientLayer + 'static + Send,
-        H: ClientHandshake,
+        H: ClientHandshake<ClientAuxData = (), ServerAuxData = ()>,
         W: CreateHandshakeWrap,
         H::KeyGen: KeyGenerator,
     {
@@ -901,7 +1555,7 @@ impl PendingClientCirc {
         // a ClientCirc on success.
 
         let PendingClientCirc { circ, recvcreated } = self;
-        let (state, msg) = H::client1(rng, key)?;
+        let (state, msg) = H::client1(rng, key, &())?;
         let create_cell = wrap.to_chanmsg(msg);
         let unique_id = {
             let mut c = circ.c.lock().await;
@@ -910,6 +1564,9 @@ impl PendingClientCirc {
                 c.unique_id,
                 create_cell.cmd()
             );
+            if let Some(build_id) = params.build_id() {
+                debug!("{}: Building as part of {}", c.unique_id, build_id);
+            }
             c.send_msg(create_cell).await?;
             c.unique_id
         };
@@ -918,8 +1575,8 @@ impl PendingClientCirc {
             .await
             .map_err(|_| Error::CircProto("Circuit closed while waiting".into()))?;
 
-        let relay_handshake = wrap.from_chanmsg(reply)?;
-        let keygen = H::client2(state, relay_handshake)?;
+        let relay_handshake = wrap.decode_chanmsg(reply)?;
+        let ((), keygen) = H::client2(state, relay_handshake)?;
 
         let layer = L::construct(keygen)?;
 
@@ -928,6 +1585,8 @@ impl PendingClientCirc {
         let (layer_fwd, layer_back) = layer.split();
         circ.add_hop(
             supports_flowctrl_1,
+            None,
+            target,
             Box::new(layer_fwd),
             Box::new(layer_back),
             params,
@@ -958,6 +1617,7 @@ impl PendingClientCirc {
             &wrap,
             &(),
             false,
+            None,
             params,
         )
         .await
@@ -999,6 +1659,7 @@ impl PendingClientCirc {
             &wrap,
             &key,
             supports_flowctrl_1,
+            Some(target.to_owned()),
             params,
         )
         .await
@@ -1012,7 +1673,7 @@ trait CreateHandshakeWrap {
     fn to_chanmsg(&self, bytes: Vec<u8>) -> ChanMsg;
     /// Decode a ChanMsg to an appropriate handshake value, checking
     /// its type.
-    fn from_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>>;
+    fn decode_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>>;
 }
 
 /// A CreateHandshakeWrap that generates CREATE_FAST and handles CREATED_FAST.
@@ -1022,7 +1683,7 @@ impl CreateHandshakeWrap for CreateFastWrap {
     fn to_chanmsg(&self, bytes: Vec<u8>) -> ChanMsg {
         chancell::msg::CreateFast::new(bytes).into()
     }
-    fn from_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>> {
+    fn decode_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>> {
         use CreateResponse::*;
         match msg {
             CreatedFast(m) => Ok(m.into_body()),
@@ -1045,7 +1706,7 @@ impl CreateHandshakeWrap for Create2Wrap {
     fn to_chanmsg(&self, bytes: Vec<u8>) -> ChanMsg {
         chancell::msg::Create2::new(self.handshake_type, bytes).into()
     }
-    fn from_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>> {
+    fn decode_chanmsg(&self, msg: CreateResponse) -> Result<Vec<u8>> {
         use CreateResponse::*;
         match msg {
             Created2(m) => Ok(m.into_body()),
@@ -1071,8 +1732,86 @@ impl StreamTarget {
             // Decrement the stream window (and block if it's empty)
             self.window.take(&()).await?;
         }
+        self.send_counted(msg, false).await
+    }
+
+    /// As [`StreamTarget::send`], but go ahead of any ordinary cells
+    /// that are waiting for room in the stream's or the circuit's send
+    /// window, as [`ClientCirc::send_control_cell`] does.
+    pub(crate) async fn send_control(&mut self, msg: RelayMsg) -> Result<()> {
+        if sendme::msg_counts_towards_windows(&msg) {
+            self.window.take_priority(&()).await?;
+        }
+        self.send_counted(msg, true).await
+    }
+
+    /// Deliver as many as possible of `msgs`, in order, for the stream
+    /// that owns this StreamTarget, without waiting for room in the
+    /// stream's send window.  Return the number of messages we sent.
+    ///
+    /// We stop at the first message that counts towards the window once
+    /// the window is empty.  (We can still wait for room in the
+    /// circuit's send window.)
+    pub(crate) async fn send_burst(&mut self, msgs: Vec<RelayMsg>) -> Result<usize> {
+        let n_counted = msgs
+            .iter()
+            .filter(|msg| sendme::msg_counts_towards_windows(msg))
+            .count();
+        let mut reservation = self
+            .window
+            .reserve(u16::try_from(n_counted).unwrap_or(u16::MAX))
+            .await;
+        let mut n_sent = 0;
+        for msg in msgs {
+            if sendme::msg_counts_towards_windows(&msg) {
+                if reservation.remaining() == 0 {
+                    break;
+                }
+                reservation.consume(&()).await?;
+            }
+            self.send_counted(msg, false).await?;
+            n_sent += 1;
+        }
+        Ok(n_sent)
+    }
+
+    /// Helper: deliver `msg`, which has already been counted against the
+    /// stream's send window if it needed to be.  If `priority` is true,
+    /// send it as a control cell.
+    async fn send_counted(&mut self, msg: RelayMsg, priority: bool) -> Result<()> {
+        let is_sendme = matches!(msg, RelayMsg::Sendme(_));
         let cell = RelayCell::new(self.stream_id, msg);
-        self.circ.send_relay_cell(self.hop, false, cell).await
+        let n_data_bytes = sendme::cell_data_len(&cell);
+        if priority {
+            self.circ.send_control_cell(self.hop, cell).await?;
+        } else {
+            self.circ.send_relay_cell(self.hop, false, cell).await?;
+        }
+        if let Some(idx) = self.byte_count {
+            self.circ.stream_bytes.note_sent(idx, n_data_bytes);
+        }
+        if is_sendme {
+            self.circ.flow_events.emit(
+                self.circ.unique_id,
+                self.hop,
+                Some(self.stream_id),
+                FlowControlEventKind::SendmeSent,
+            );
+        }
+        Ok(())
+    }
+
+    /// Return a new handle to this stream's send window.
+    pub(crate) fn send_window_ref(&self) -> sendme::StreamSendWindow {
+        self.window.new_ref()
+    }
+
+    /// Note that the stream that owns this StreamTarget has received `n`
+    /// bytes of DATA.
+    pub(crate) fn note_data_received(&self, n: usize) {
+        if let Some(idx) = self.byte_count {
+            self.circ.stream_bytes.note_received(idx, n);
+        }
     }
 
     /// Called when a circuit-level protocol error has occurred and the
@@ -1108,6 +1847,7 @@ mod test {
     use super::*;
     use crate::channel::test::fake_channel;
     use chanmsg::{ChanMsg, Created2, CreatedFast};
+    use futures::future::FutureExt;
     use futures::io::{AsyncReadExt, AsyncWriteExt};
     use futures::stream::StreamExt;
     use futures_await_test::async_test;
@@ -1129,6 +1869,7 @@ mod test {
     }
 
     struct ExampleTarget {
+        addrs: Vec<std::net::SocketAddr>,
         ntor_key: pk::curve25519::PublicKey,
         protovers: tor_protover::Protocols,
         ed_id: pk::ed25519::Ed25519Identity,
@@ -1136,7 +1877,7 @@ mod test {
     }
     impl tor_linkspec::ChanTarget for ExampleTarget {
         fn addrs(&self) -> &[std::net::SocketAddr] {
-            &[]
+            &self.addrs[..]
         }
         fn ed_identity(&self) -> &pk::ed25519::Ed25519Identity {
             &self.ed_id
@@ -1156,6 +1897,7 @@ mod test {
     /// return an ExampleTarget that can get used for an ntor handshake.
     fn example_target() -> ExampleTarget {
         ExampleTarget {
+            addrs: vec![],
             ntor_key: hex!("395cb26b83b3cd4b91dba9913e562ae87d21ecdd56843da7ca939a6a69001253")
                 .into(),
             protovers: "FlowCtrl=1".parse().unwrap(),
@@ -1234,7 +1976,15 @@ mod test {
 
         let (circ, _, _) = futures::join!(client_fut, reactor_fut, simulate_relay_fut);
 
-        let _circ = circ.unwrap();
+        let circ = circ.unwrap();
+
+        // CREATE_FAST doesn't know whom it's talking to, so it can't
+        // expect authenticated SENDMEs.
+        let fc = circ.flow_control_params(0).unwrap();
+        assert_eq!(fc.version(), if fast { 0 } else { 1 });
+        assert_eq!(fc.initial_window(), 1000);
+        assert_eq!(fc.increment(), 100);
+        assert_eq!(circ.flow_control_params(1), None);
 
         // pfew!  We've build a circuit!  Let's make sure it has one hop.
         /* TODO: reinstate this.
@@ -1329,6 +2079,8 @@ mod test {
             let (hopf, reacf) = futures::join!(
                 circ.add_hop(
                     true,
+                    None,
+                    None,
                     Box::new(DummyCrypto::new(idx == 2)),
                     Box::new(DummyCrypto::new(idx == next_msg_from.into())),
                     &params,
@@ -1354,6 +2106,17 @@ mod test {
         newcirc_ext(chan, 2.into()).await
     }
 
+    #[async_test]
+    async fn drop_reactor() {
+        let (chan, _ch) = fake_channel();
+        let (circ, reactor, _send) = newcirc(chan).await;
+        assert!(!circ.is_closing());
+
+        // Without its reactor, the circuit can't work.
+        drop(reactor);
+        assert!(circ.is_closing());
+    }
+
     // Try sending a cell via send_relay_cell
     #[async_test]
     async fn send_simple() {
@@ -1436,14 +2199,55 @@ mod test {
     }
 
     #[async_test]
-    async fn extend() {
-        use crate::crypto::handshake::{ntor::NtorServer, ServerHandshake};
-
-        let (chan, mut ch) = fake_channel();
-        let (circ, mut reactor, mut sink) = newcirc(chan).await;
-        let params = CircParameters::default();
+    async fn reactor_failure() {
+        let (chan, _ch) = fake_channel();
+        let (circ, reactor, mut sink) = newcirc(chan).await;
+        assert!(circ.close_reason().is_none());
 
-        let extend_fut = async move {
+        // Nobody asked for an EXTENDED2 cell, so getting one stops the
+        // reactor.
+        let extended: RelayMsg = relaymsg::Extended2::new((*b"123").into()).into();
+        sink.send(rmsg_to_ccmsg(0, extended)).await.unwrap();
+        let e = reactor.run().await.err().unwrap();
+        assert!(matches!(e, Error::CircProto(_)));
+
+        // The circuit remembers why.
+        assert!(circ.is_closing());
+        assert!(matches!(circ.close_reason(), Some(Error::CircProto(_))));
+        let e = match Arc::clone(&circ).begin_dir_stream().await {
+            Err(e) => e,
+            Ok(_) => panic!("Opened a stream on a closed circuit"),
+        };
+        assert!(
+            matches!(&e, Error::ReactorFailed(inner) if matches!(**inner, Error::CircProto(_)))
+        );
+        assert_eq!(
+            e.to_string(),
+            "circuit closed: circuit protocol violation: Unexpected EXTENDED2 cell on client circuit"
+        );
+
+        // A reactor that shuts down cleanly leaves no reason.
+        let (chan, _ch) = fake_channel();
+        let (circ, reactor, sink) = newcirc(chan).await;
+        drop(sink);
+        assert!(reactor.run().await.is_ok());
+        assert!(circ.is_closing());
+        assert!(circ.close_reason().is_none());
+        assert!(matches!(
+            Arc::clone(&circ).begin_dir_stream().await,
+            Err(Error::CircuitClosed)
+        ));
+    }
+
+    #[async_test]
+    async fn extend() {
+        use crate::crypto::handshake::{ntor::NtorServer, ServerHandshake};
+
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let params = CircParameters::default();
+
+        let extend_fut = async move {
             let target = example_target();
             let mut rng = thread_rng();
             circ.extend_ntor(&mut rng, &target, &params).await.unwrap();
@@ -1480,6 +2284,280 @@ mod test {
         assert_eq!(circ.n_hops().await, 4);
     }
 
+    // Helper: extend a circuit with the ntor v3 handshake to a relay
+    // that advertises `protovers`, sending it `request`, and acting as a
+    // relay that answers with `answer`.
+    async fn extend_v3_test_impl(
+        protovers: &str,
+        request: Vec<NtorV3Extension>,
+        answer: Vec<NtorV3Extension>,
+    ) -> (Arc<ClientCirc>, Result<Vec<NtorV3Extension>>) {
+        use crate::crypto::handshake::ntor_v3::{
+            decode_extensions, encode_extensions, server_handshake_ntor_v3, NtorV3SecretKey,
+            NTOR3_CIRC_VERIFICATION,
+        };
+
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let params = CircParameters::default();
+        let mut target = example_target();
+        target.protovers = protovers.parse().unwrap();
+        let (snd_done, mut rcv_done) = oneshot::channel::<()>();
+
+        let extend_fut = async {
+            let mut rng = thread_rng();
+            let reply = circ
+                .extend_ntor_v3(&mut rng, &target, &params, &request[..])
+                .await;
+            snd_done.send(()).unwrap();
+            reply
+        };
+        let reply_fut = async {
+            let (_, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                ChanMsg::RelayEarly(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            let e2 = match rmsg.msg() {
+                RelayMsg::Extend2(e2) => e2,
+                _ => panic!(),
+            };
+            assert_eq!(e2.handshake_type(), 0x0003);
+            let key = NtorV3SecretKey::new(
+                hex!("7789d92a89711a7e2874c61ea495452cfd48627b3ca2ea9546aafa5bf7b55803").into(),
+                target.ntor_key,
+                target.ed_id,
+            );
+            let mut rng = thread_rng();
+            let (reply, _) = server_handshake_ntor_v3(
+                &mut rng,
+                |msg| {
+                    let exts = decode_extensions(msg).ok()?;
+                    assert_eq!(exts, request);
+                    encode_extensions(&answer[..]).ok()
+                },
+                &[key],
+                e2.handshake(),
+                NTOR3_CIRC_VERIFICATION,
+            )
+            .unwrap();
+            let extended2 = relaymsg::Extended2::new(reply).into();
+            sink.send(rmsg_to_ccmsg(0, extended2)).await.unwrap();
+        };
+        let reactor_fut = async {
+            loop {
+                futures::select! {
+                    r = reactor.run_once().fuse() => r.unwrap(),
+                    _ = rcv_done => break,
+                }
+            }
+        };
+
+        let (reply, (), ()) = futures::join!(extend_fut, reply_fut, reactor_fut);
+        (circ, reply)
+    }
+
+    #[async_test]
+    async fn extend_ntor_v3() {
+        let request = vec![NtorV3Extension::new(7, b"seven".to_vec())];
+        let answer = vec![NtorV3Extension::new(8, b"eight".to_vec())];
+        let (circ, reply) = extend_v3_test_impl("FlowCtrl=1", request, answer.clone()).await;
+
+        assert_eq!(reply.unwrap(), answer);
+        assert_eq!(circ.n_hops().await, 4);
+
+        // Our example target supports FlowCtrl=1.
+        let fc = circ.flow_control_params(3).unwrap();
+        assert_eq!(fc.version(), 1);
+        assert_eq!(fc.initial_window(), 1000);
+        assert_eq!(fc.increment(), 100);
+    }
+
+    #[async_test]
+    async fn extend_ntor_v3_congestion_control() {
+        // Act as a relay that advertises congestion control, and agrees
+        // to it with a SENDME increment of 31.
+        let request = vec![NtorV3Extension::congestion_control_request()];
+        let answer = vec![NtorV3Extension::new(2, vec![31])];
+        let (circ, reply) = extend_v3_test_impl("FlowCtrl=1-2", request, answer.clone()).await;
+
+        assert_eq!(reply.unwrap(), answer);
+        assert_eq!(circ.n_hops().await, 4);
+
+        // We report what we negotiated, and the hop's window uses it.
+        let fc = circ.flow_control_params(3).unwrap();
+        assert_eq!(fc.version(), 2);
+        assert_eq!(fc.initial_window(), 992);
+        assert_eq!(fc.increment(), 31);
+        let acct = circ.send_window_accounting(3).await.unwrap();
+        assert_eq!(acct.initial(), 992);
+        assert_eq!(acct.increment(), 31);
+
+        // If the relay turns on congestion control when we didn't ask,
+        // we don't add the hop.
+        let request = vec![NtorV3Extension::new(7, b"seven".to_vec())];
+        let (circ, reply) = extend_v3_test_impl("FlowCtrl=1-2", request, answer).await;
+        assert!(matches!(reply, Err(Error::CircProto(_))));
+        assert_eq!(circ.n_hops().await, 3);
+        assert_eq!(circ.flow_control_params(3), None);
+    }
+
+    #[test]
+    fn adaptive_sendme_bounds() {
+        let mut params = CircParameters::default();
+        assert_eq!(params.adaptive_sendme_bounds(), None);
+        assert!(params.set_adaptive_sendme_bounds(Some((0, 10))).is_err());
+        assert!(params.set_adaptive_sendme_bounds(Some((50, 10))).is_err());
+        params.set_adaptive_sendme_bounds(Some((20, 200))).unwrap();
+        assert_eq!(params.adaptive_sendme_bounds(), Some((20, 200)));
+
+        // Only a hop that agreed to congestion control gets an adaptive
+        // receive window; it starts from the negotiated increment.
+        let flow = CircHop::flow_params(true, None, &params);
+        let (_, mut recvw) = CircHop::windows(&flow, &params);
+        assert_eq!(recvw.increment(), 100);
+        for _ in 0..100 {
+            recvw.take().unwrap();
+        }
+        recvw.put().unwrap();
+
+        let flow = CircHop::flow_params(true, Some(31), &params);
+        let (_, mut recvw) = CircHop::windows(&flow, &params);
+        assert_eq!(recvw.increment(), 31);
+        for _ in 0..31 {
+            recvw.take().unwrap();
+        }
+        recvw.put().unwrap();
+        // Our first SENDME sets the clock; the second one follows it
+        // quickly, so the increment grows.
+        for _ in 0..31 {
+            recvw.take().unwrap();
+        }
+        recvw.put().unwrap();
+        assert_eq!(recvw.window(), 992);
+        assert_eq!(recvw.increment(), 62);
+    }
+
+    #[async_test]
+    async fn extend_ntor_v3_cc_sendme_increment() {
+        // A relay may negotiate an increment one away from the network's...
+        let request = vec![NtorV3Extension::congestion_control_request()];
+        let answer = vec![NtorV3Extension::new(2, vec![32])];
+        let (circ, reply) = extend_v3_test_impl("FlowCtrl=1-2", request.clone(), answer).await;
+        assert!(reply.is_ok());
+        let fc = circ.flow_control_params(3).unwrap();
+        assert_eq!(fc.increment(), 32);
+        assert_eq!(fc.initial_window(), 992);
+
+        // ... but no further.
+        let answer = vec![NtorV3Extension::new(2, vec![50])];
+        let (circ, reply) = extend_v3_test_impl("FlowCtrl=1-2", request, answer).await;
+        assert!(matches!(reply, Err(Error::CircProto(_))));
+        assert_eq!(circ.n_hops().await, 3);
+    }
+
+    #[async_test]
+    async fn extend_to_exit() {
+        use crate::crypto::handshake::{ntor::NtorServer, ServerHandshake};
+
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let params = CircParameters::default();
+
+        // Pretend that we know which relays the middle two hops are.
+        {
+            let mut c = circ.c.lock().await;
+            c.hops[1].target = Some(OwnedChanTarget::new(
+                vec!["192.0.2.7:9001".parse().unwrap()],
+                [1; 32].into(),
+                [1; 20].into(),
+            ));
+            c.hops[2].target = Some(OwnedChanTarget::new(
+                vec!["198.51.100.7:9001".parse().unwrap()],
+                [2; 32].into(),
+                [2; 20].into(),
+            ));
+        }
+
+        // An exit that's the same relay as hop 2 is refused...
+        let mut rng = thread_rng();
+        let mut target = example_target();
+        target.ed_id = [2; 32].into();
+        let e = circ
+            .extend_to_exit(&mut rng, &target, &params)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "conflicting hop: hop 2: same relay as an existing hop"
+        );
+
+        // ... and so is one in the same /16 as hop 1.
+        let mut target = example_target();
+        target.addrs = vec!["192.0.200.1:443".parse().unwrap()];
+        let e = circ
+            .extend_to_exit(&mut rng, &target, &params)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "conflicting hop: hop 1: same subnet as an existing hop"
+        );
+        assert_eq!(circ.n_hops().await, 3);
+
+        // Nothing should have been sent for either of those.
+        assert!(ch.cells.next().now_or_never().is_none());
+
+        // But an unrelated exit is fine.
+        let extend_fut = async move {
+            let mut target = example_target();
+            target.addrs = vec!["192.1.0.1:443".parse().unwrap()];
+            circ.extend_to_exit(&mut rng, &target, &params)
+                .await
+                .unwrap();
+            circ // gotta keep the circ alive, or the reactor would exit.
+        };
+        let reply_fut = async move {
+            let (_id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                ChanMsg::RelayEarly(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            let e2 = match rmsg.msg() {
+                RelayMsg::Extend2(e2) => e2,
+                _ => panic!(),
+            };
+            let mut rng = thread_rng();
+            let (_, reply) =
+                NtorServer::server(&mut rng, &[example_ntor_key()], e2.handshake()).unwrap();
+            let extended2 = relaymsg::Extended2::new(reply).into();
+            sink.send(rmsg_to_ccmsg(0, extended2)).await.unwrap();
+            sink // gotta keep the sink alive, or the reactor will exit.
+        };
+        let reactor_fut = async move {
+            reactor.run_once().await.unwrap(); // to deliver the relay cell
+            reactor.run_once().await.unwrap(); // to handle the AddHop
+        };
+
+        let (circ, _, _) = futures::join!(extend_fut, reply_fut, reactor_fut);
+        assert_eq!(circ.n_hops().await, 4);
+        let c = circ.c.lock().await;
+        let new_hop = c.hops[3].target.as_ref().unwrap();
+        assert_eq!(
+            new_hop.ed_identity(),
+            &pk::ed25519::Ed25519Identity::from([6; 32])
+        );
+        drop(c);
+
+        let path = circ.path().await;
+        assert_eq!(path.len(), 4);
+        assert!(path[0].is_none());
+        assert_eq!(
+            path[2].as_ref().unwrap().rsa_identity(),
+            &pk::rsa::RsaIdentity::from([2; 20])
+        );
+    }
+
     async fn bad_extend_test_impl(reply_hop: HopNum, bad_reply: ClientCircChanMsg) -> Error {
         let (chan, _ch) = fake_channel();
         let (circ, mut reactor, mut sink) = newcirc_ext(chan, reply_hop).await;
@@ -1528,7 +2606,8 @@ mod test {
 
     #[async_test]
     async fn bad_extend_wrongtype() {
-        let extended = relaymsg::Extended::new(vec![7; 200]).into();
+        // (A TAP reply is 148 bytes long.)
+        let extended = relaymsg::Extended::new(vec![7; 148]).into();
         let cc = rmsg_to_ccmsg(0, extended);
 
         let error = bad_extend_test_impl(2.into(), cc).await;
@@ -1629,6 +2708,81 @@ mod test {
         let (_stream, _, _) = futures::join!(begin_and_send_fut, reply_fut, reactor_fut);
     }
 
+    #[async_test]
+    async fn count_stream_bytes() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        assert!(circ.stream_byte_counts().is_none());
+        circ.stream_bytes.enable();
+        assert!(circ.stream_byte_counts().unwrap().is_empty());
+
+        let circ_clone = Arc::clone(&circ);
+        let begin_and_send_fut = async move {
+            let mut stream = circ_clone
+                .begin_stream("www.example.com", 80, None)
+                .await
+                .unwrap();
+            stream.write_all(&[7_u8; 600]).await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0_u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(n, 42);
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(n, 0);
+            stream
+        };
+        let reply_fut = async move {
+            let (_id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+            assert!(matches!(rmsg, RelayMsg::Begin(_)));
+            let connected = relaymsg::Connected::new_empty().into();
+            sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+            // Read the data we sent, a DATA cell at a time.
+            let mut bytes_received = 0;
+            while bytes_received < 600 {
+                let (_id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                    _ => panic!(),
+                };
+                match rmsg.into_streamid_and_msg() {
+                    (id, RelayMsg::Data(d)) if id == streamid => bytes_received += d.as_ref().len(),
+                    _ => panic!(),
+                }
+            }
+            assert_eq!(bytes_received, 600);
+
+            let data = relaymsg::Data::new(&[9_u8; 42]).into();
+            sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+            sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+
+            (sink, streamid)
+        };
+        let reactor_fut = async move {
+            reactor.run_once().await.unwrap(); // AddStream
+            reactor.run_once().await.unwrap(); // Register stream closer
+            reactor.run_once().await.unwrap(); // Connected cell
+            reactor.run_once().await.unwrap(); // Data cell
+            reactor.run_once().await.unwrap(); // End cell
+            reactor
+        };
+
+        let (_stream, (_sink, streamid), _) =
+            futures::join!(begin_and_send_fut, reply_fut, reactor_fut);
+
+        let counts = circ.stream_byte_counts().unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].stream_id(), streamid);
+        assert_eq!(counts[0].sent(), 600);
+        assert_eq!(counts[0].received(), 42);
+    }
+
     // Set up a circuit and stream that expects some incoming SENDMEs.
     async fn setup_incoming_sendme_case(
         n_to_send: usize,
@@ -1717,12 +2871,125 @@ mod test {
         (circ, stream, sink, streamid, reactor, cells_received)
     }
 
+    // Helper: open a stream on `circ`, answering its BEGIN cell with a
+    // CONNECTED cell from the other side.
+    async fn begin_test_stream(
+        circ: &Arc<ClientCirc>,
+        reactor: &mut reactor::Reactor,
+        sink: &mut mpsc::Sender<ClientCircChanMsg>,
+        ch: &mut crate::channel::test::FakeChanHandle,
+    ) -> (DataStream, StreamId) {
+        let (snd_done, mut rcv_done) = oneshot::channel::<()>();
+
+        let circ_clone = Arc::clone(circ);
+        let begin_fut = async move {
+            let stream = circ_clone
+                .begin_stream("www.example.com", 443, None)
+                .await
+                .unwrap();
+            snd_done.send(()).unwrap();
+            stream
+        };
+        let connect_fut = async {
+            let (_id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+            assert!(matches!(rmsg, RelayMsg::Begin(_)));
+            let connected = relaymsg::Connected::new_empty().into();
+            sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+            streamid
+        };
+        let reactor_fut = async {
+            loop {
+                futures::select! {
+                    r = reactor.run_once().fuse() => r.unwrap(),
+                    _ = rcv_done => break,
+                }
+            }
+        };
+        let (stream, streamid, ()) = futures::join!(begin_fut, connect_fut, reactor_fut);
+        (stream, streamid)
+    }
+
+    #[async_test]
+    async fn write_backpressure() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let (mut stream, streamid) =
+            begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+        assert!(!stream.is_send_blocked());
+
+        // Write as fast as the stream lets us, taking away every cell that
+        // it sends, until neither side can make progress.
+        let junk = [0_u8; 4096];
+        let mut n_written = 0;
+        let mut n_cells = 0;
+        let mut write_and_drain = |stream: &mut DataStream| loop {
+            let mut progress = false;
+            while let Some(n) = stream.write(&junk[..]).now_or_never() {
+                n_written += n.unwrap();
+                progress = true;
+            }
+            while let Some(Some(_)) = ch.cells.next().now_or_never() {
+                n_cells += 1;
+                progress = true;
+            }
+            if !progress {
+                return (n_written, n_cells);
+            }
+        };
+
+        // We stop once the stream's window is used up, with at most one
+        // more cell's worth of data buffered in the writer.
+        let (written, cells) = write_and_drain(&mut stream);
+        assert_eq!(cells, 500);
+        assert!(written >= 500 * 498);
+        assert!(written <= 501 * 498);
+        assert!(stream.is_send_blocked());
+
+        // Once the other side acknowledges some cells, we can go on.
+        let s_sendme = relaymsg::Sendme::new_empty().into();
+        sink.send(rmsg_to_ccmsg(streamid, s_sendme)).await.unwrap();
+        reactor.run_once().await.unwrap();
+        let (written, cells) = write_and_drain(&mut stream);
+        assert_eq!(cells, 550);
+        assert!(written <= 551 * 498);
+        assert!(stream.is_send_blocked());
+    }
+
+    #[async_test]
+    async fn send_window_accounting() {
+        let (circ, _stream, mut sink, _streamid, mut reactor, _cells_received) =
+            setup_incoming_sendme_case(300 * 498 + 3).await;
+
+        let accounting = circ.send_window_accounting(2).await.unwrap();
+        assert_eq!(accounting.window(), 1000 - 301);
+        assert_eq!(accounting.cells_sent(), 301);
+        assert_eq!(accounting.sendmes_received(), 0);
+        assert_eq!(accounting.sendme_lag(), 3);
+        assert!(circ.send_window_accounting(3).await.is_none());
+
+        let c_sendme =
+            relaymsg::Sendme::new_tag(hex!("6400000000000000000000000000000000000000")).into();
+        sink.send(rmsg_to_ccmsg(0_u16, c_sendme)).await.unwrap();
+        reactor.run_once().await.unwrap();
+
+        let accounting = circ.send_window_accounting(2).await.unwrap();
+        assert_eq!(accounting.window(), 1000 - 201);
+        assert_eq!(accounting.sendmes_received(), 1);
+        assert_eq!(accounting.sendme_lag(), 2);
+    }
+
     #[async_test]
     async fn accept_valid_sendme() {
         let (circ, _stream, mut sink, streamid, mut reactor, cells_received) =
             setup_incoming_sendme_case(300 * 498 + 3).await;
 
         assert_eq!(cells_received, 301);
+        let mut events = circ.flow_control_events(8);
 
         // Make sure that the circuit is indeed expecting the right sendmes
         {
@@ -1768,6 +3035,383 @@ mod test {
             let (window, _tags) = hop.sendwindow.window_and_expected_tags().await;
             assert_eq!(window, 1000 - 201);
         }
+
+        // We heard about both sendmes, in order.
+        let mut next_event = || match events.next().now_or_never() {
+            Some(Some(FlowControlUpdate::Event(e))) => e,
+            other => panic!("Unexpected update {:?}", other),
+        };
+        let e = next_event();
+        assert_eq!(e.circ_id(), circ.unique_id());
+        assert_eq!(e.hop(), 2);
+        assert_eq!(e.stream_id(), None);
+        assert_eq!(
+            e.kind(),
+            &FlowControlEventKind::SendmeReceived { window: 1000 - 201 }
+        );
+        let e = next_event();
+        assert_eq!(e.hop(), 2);
+        assert_eq!(e.stream_id(), Some(streamid));
+        assert!(matches!(
+            e.kind(),
+            FlowControlEventKind::SendmeReceived { .. }
+        ));
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    // Helper: have the reactor receive `n` one-byte DATA cells on the
+    // stream `streamid`.
+    async fn receive_data_cells(
+        reactor: &mut reactor::Reactor,
+        sink: &mut mpsc::Sender<ClientCircChanMsg>,
+        streamid: StreamId,
+        n: usize,
+    ) {
+        for _ in 0..n {
+            let data = relaymsg::Data::new(b"x").into();
+            sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            reactor.run_once().await.unwrap();
+        }
+    }
+
+    // Helper: return the next relay message that we sent on `ch`, with
+    // its stream ID, if we sent one.
+    fn next_relay_msg(
+        ch: &mut crate::channel::test::FakeChanHandle,
+    ) -> Option<(StreamId, RelayMsg)> {
+        let (_id, chmsg) = ch.cells.next().now_or_never()??.into_circid_and_msg();
+        match chmsg {
+            ChanMsg::Relay(r) => Some(
+                RelayCell::decode(r.into_relay_body())
+                    .unwrap()
+                    .into_streamid_and_msg(),
+            ),
+            _ => panic!(),
+        }
+    }
+
+    #[async_test]
+    async fn pause_with_blocked_writer() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let (stream, streamid) = begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+        let (stream2, streamid2) = begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+
+        let (paused, reacf) = futures::join!(circ.pause_flow_control(), reactor.run_once());
+        paused.unwrap();
+        reacf.unwrap();
+
+        // A write waits for the circuit to resume...
+        let (_reader, mut writer) = stream.split();
+        let mut blocked_write = Box::pin(async {
+            writer.write_all(b"hello").await?;
+            writer.flush().await
+        });
+        assert!((&mut blocked_write).now_or_never().is_none());
+
+        // ... but meanwhile, the reactor can still close another stream
+        // and send its END.
+        drop(stream2);
+        let end = loop {
+            if let Some(msg) = next_relay_msg(&mut ch) {
+                break msg;
+            }
+            reactor
+                .run_once()
+                .now_or_never()
+                .expect("reactor is stuck")
+                .unwrap();
+        };
+        assert!(matches!(end, (id, RelayMsg::End(_)) if id == streamid2));
+
+        // Once we resume, the write goes out.
+        let (written, resumed, reacf) = futures::join!(
+            blocked_write,
+            circ.resume_flow_control(),
+            reactor.run_once()
+        );
+        written.unwrap();
+        resumed.unwrap();
+        reacf.unwrap();
+        assert!(matches!(next_relay_msg(&mut ch), Some((id, RelayMsg::Data(_))) if id == streamid));
+        assert!(next_relay_msg(&mut ch).is_none());
+    }
+
+    #[async_test]
+    async fn closing_stream_goes_first() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let (bulk, bulkid) = begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+        let (closing, closingid) = begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+
+        // Use up the last hop's circuit window.
+        let tag = [7_u8; 20];
+        for _ in 0_usize..1000 {
+            let mut c = circ.c.lock().await;
+            c.hop_mut(2.into())
+                .unwrap()
+                .sendwindow
+                .take(&tag)
+                .await
+                .unwrap();
+        }
+
+        // Both streams wait for room.
+        let (_bulk_reader, mut bulk_writer) = bulk.split();
+        let mut bulk_write = Box::pin(async {
+            bulk_writer.write_all(b"bulk").await?;
+            bulk_writer.flush().await
+        });
+        assert!((&mut bulk_write).now_or_never().is_none());
+        let (_closing_reader, mut closing_writer) = closing.split();
+        let mut closing_write = Box::pin(async {
+            closing_writer.write_all(b"bye").await?;
+            closing_writer.close().await
+        });
+        assert!((&mut closing_write).now_or_never().is_none());
+
+        // Once a SENDME makes room, the closing stream's last cell goes
+        // first, even though the bulk stream was waiting longer.
+        {
+            let mut c = circ.c.lock().await;
+            c.hop_mut(2.into())
+                .unwrap()
+                .sendwindow
+                .put(Some(tag))
+                .await
+                .unwrap();
+        }
+        assert!((&mut bulk_write).now_or_never().is_none());
+        closing_write
+            .now_or_never()
+            .expect("still blocked")
+            .unwrap();
+        bulk_write.now_or_never().expect("still blocked").unwrap();
+        assert!(
+            matches!(next_relay_msg(&mut ch), Some((id, RelayMsg::Data(_))) if id == closingid)
+        );
+        assert!(matches!(next_relay_msg(&mut ch), Some((id, RelayMsg::Data(_))) if id == bulkid));
+        assert!(next_relay_msg(&mut ch).is_none());
+    }
+
+    #[async_test]
+    async fn stream_send_headroom() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let (stream, streamid) = begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+        let cell_len = relaymsg::Data::MAXLEN;
+        assert_eq!(stream.send_headroom().await, 500 * cell_len);
+
+        let (_reader, mut writer) = stream.split();
+        writer.write_all(b"hello").await.unwrap();
+        // (Buffered data doesn't use the window until we flush it.)
+        assert_eq!(writer.send_headroom().await, 500 * cell_len);
+        writer.flush().await.unwrap();
+        assert!(matches!(next_relay_msg(&mut ch), Some((id, RelayMsg::Data(_))) if id == streamid));
+        assert_eq!(writer.send_headroom().await, 499 * cell_len);
+    }
+
+    #[async_test]
+    async fn terminate_gracefully() {
+        use futures::future::{pending, ready};
+
+        // Use up part of the last hop's circuit window.
+        let tag = [7_u8; 20];
+        let (chan, _ch) = fake_channel();
+        let (circ, _reactor, _sink) = newcirc(chan).await;
+        for _ in 0_usize..150 {
+            let mut c = circ.c.lock().await;
+            c.hop_mut(2.into())
+                .unwrap()
+                .sendwindow
+                .take(&tag)
+                .await
+                .unwrap();
+        }
+
+        // We wait for the SENDME that we're owed, and not for the
+        // cells after it.
+        let mut terminate = Box::pin(circ.terminate_gracefully(pending()));
+        assert!((&mut terminate).now_or_never().is_none());
+        assert!(!circ.is_closing());
+        {
+            let mut c = circ.c.lock().await;
+            c.hop_mut(2.into())
+                .unwrap()
+                .sendwindow
+                .put(Some(tag))
+                .await
+                .unwrap();
+        }
+        assert!(matches!((&mut terminate).now_or_never(), Some(Ok(true))));
+        assert!(circ.is_closing());
+
+        // If the SENDME never comes, we time out, and close anyway.
+        let (chan, _ch) = fake_channel();
+        let (circ, _reactor, _sink) = newcirc(chan).await;
+        for _ in 0_usize..150 {
+            let mut c = circ.c.lock().await;
+            c.hop_mut(2.into())
+                .unwrap()
+                .sendwindow
+                .take(&tag)
+                .await
+                .unwrap();
+        }
+        assert!(!circ.terminate_gracefully(ready(())).await.unwrap());
+        assert!(circ.is_closing());
+    }
+
+    #[async_test]
+    async fn stream_send_burst() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, _sink) = newcirc(chan).await;
+        let (stream, reacf) = futures::join!(
+            circ.begin_stream_impl(RelayMsg::BeginDir),
+            reactor.run_once()
+        );
+        let stream = stream.unwrap();
+        reacf.unwrap();
+        assert!(matches!(
+            next_relay_msg(&mut ch),
+            Some((_, RelayMsg::BeginDir))
+        ));
+
+        // The stream's send window only has room for 500 cells, so
+        // that's all we send.
+        let msgs = (0..600).map(|_| relaymsg::Data::new(b"z").into()).collect();
+        let (sent, n_received) = futures::join!(stream.send_burst(msgs), async {
+            let mut n_received = 0_usize;
+            while n_received < 500 {
+                let (_id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+                let cell = match chmsg {
+                    ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                    _ => panic!(),
+                };
+                assert!(matches!(cell.msg(), RelayMsg::Data(_)));
+                n_received += 1;
+            }
+            n_received
+        });
+        assert_eq!(sent.unwrap(), 500);
+        assert_eq!(n_received, 500);
+        assert!(next_relay_msg(&mut ch).is_none());
+
+        // Now there's no room at all, but we don't wait for any.
+        let data = relaymsg::Data::new(b"z").into();
+        assert_eq!(stream.send_burst(vec![data]).await.unwrap(), 0);
+        assert!(next_relay_msg(&mut ch).is_none());
+    }
+
+    #[async_test]
+    async fn pause_flow_control() {
+        let (chan, mut ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        let (mut stream, streamid) =
+            begin_test_stream(&circ, &mut reactor, &mut sink, &mut ch).await;
+
+        let (paused, reacf) = futures::join!(circ.pause_flow_control(), reactor.run_once());
+        paused.unwrap();
+        reacf.unwrap();
+
+        // Receive enough data that we owe the last hop a SENDME.
+        receive_data_cells(&mut reactor, &mut sink, streamid, 100).await;
+        // We didn't send it...
+        assert!(next_relay_msg(&mut ch).is_none());
+        // ... and we can't send any data either.
+        let data = RelayCell::new(streamid, relaymsg::Data::new(b"y").into());
+        let mut blocked_send = Box::pin(circ.send_relay_cell(2.into(), false, data));
+        assert!((&mut blocked_send).now_or_never().is_none());
+
+        // Once we resume, the data goes out, and so does the SENDME we
+        // owed, tagged for the cell that made it due.
+        let (sent, resumed, reacf) =
+            futures::join!(blocked_send, circ.resume_flow_control(), reactor.run_once());
+        sent.unwrap();
+        resumed.unwrap();
+        reacf.unwrap();
+        // (They can go out in either order.)
+        let mut got_data = false;
+        let mut got_sendme = false;
+        for _ in 0..2 {
+            match next_relay_msg(&mut ch) {
+                Some((id, RelayMsg::Data(_))) if id == streamid => got_data = true,
+                Some((id, RelayMsg::Sendme(s))) if id.is_zero() => {
+                    assert_eq!(
+                        s.into_tag().unwrap(),
+                        hex!("6400000000000000000000000000000000000000")
+                    );
+                    got_sendme = true;
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+        assert!(got_data && got_sendme);
+        assert!(next_relay_msg(&mut ch).is_none());
+
+        // Then things work normally: once the stream has read what it
+        // got (and sent its own SENDMEs)...
+        let mut buf = [0_u8; 100];
+        stream.read_exact(&mut buf[..]).await.unwrap();
+        for _ in 0..2 {
+            assert!(
+                matches!(next_relay_msg(&mut ch), Some((id, RelayMsg::Sendme(_))) if id == streamid)
+            );
+        }
+        // ... the next SENDME we owe goes out right away.
+        receive_data_cells(&mut reactor, &mut sink, streamid, 100).await;
+        match next_relay_msg(&mut ch) {
+            Some((id, RelayMsg::Sendme(s))) if id.is_zero() => assert_eq!(
+                s.into_tag().unwrap(),
+                hex!("c800000000000000000000000000000000000000")
+            ),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[async_test]
+    async fn build_id_in_events() {
+        let (chan, _ch) = fake_channel();
+        let (circ, mut reactor, mut sink) = newcirc(chan).await;
+        assert_eq!(circ.build_id(), None);
+
+        // Add another hop, as part of a build with an identifier.
+        let build_id = CircuitBuildId::new(99);
+        let mut params = CircParameters::default();
+        params.set_build_id(build_id);
+        assert_eq!(params.build_id(), Some(build_id));
+        let (hopf, reacf) = futures::join!(
+            circ.add_hop(
+                true,
+                None,
+                None,
+                Box::new(DummyCrypto::new(false)),
+                Box::new(DummyCrypto::new(false)),
+                &params,
+            ),
+            reactor.run_once()
+        );
+        hopf.unwrap();
+        reacf.unwrap();
+        assert_eq!(circ.build_id(), Some(build_id));
+
+        // Our events carry the build identifier: here, for a SENDME that
+        // acknowledges data we never sent.
+        let mut events = circ.flow_control_events(8);
+        let c_sendme =
+            relaymsg::Sendme::new_tag(hex!("6400000000000000000000000000000000000000")).into();
+        sink.send(rmsg_to_ccmsg(0_u16, c_sendme)).await.unwrap();
+        assert!(reactor.run_once().await.is_err());
+        match events.next().now_or_never() {
+            Some(Some(FlowControlUpdate::Event(e))) => {
+                assert_eq!(e.build_id(), Some(build_id));
+                assert_eq!(e.circ_id(), circ.unique_id());
+                assert_eq!(e.kind(), &FlowControlEventKind::SendmeRejected);
+            }
+            other => panic!("Unexpected update {:?}", other),
+        }
+        assert_eq!(build_id.to_string(), "Build 99");
+        assert_ne!(CircuitBuildId::next_unique(), CircuitBuildId::next_unique());
     }
 
     #[async_test]
@@ -1809,6 +3453,7 @@ mod test {
         let mut p = CircParameters::default();
         assert_eq!(p.initial_send_window(), 1000);
         assert!(p.extend_by_ed25519_id());
+        assert!(!p.count_stream_bytes());
 
         assert!(p.set_initial_send_window(500).is_ok());
         p.set_extend_by_ed25519_id(false);
@@ -1817,5 +3462,12 @@ mod test {
 
         assert!(p.set_initial_send_window(9000).is_err());
         assert_eq!(p.initial_send_window(), 500);
+
+        assert_eq!(p.sendme_increment(), 100);
+        assert!(p.set_sendme_increment(50).is_ok());
+        assert_eq!(p.sendme_increment(), 50);
+        assert!(p.set_sendme_increment(0).is_err());
+        assert!(p.set_sendme_increment(1001).is_err());
+        assert_eq!(p.sendme_increment(), 50);
     }
 }
@@ -0,0 +1,165 @@
+//! Per-stream byte counts, for circuits that measure them.
+//!
+//! Counting is off by default: it's meant for bandwidth measurement and
+//! debugging, and normal circuits shouldn't pay for it.  Use
+//! [`CircParameters::set_count_stream_bytes`](super::CircParameters::set_count_stream_bytes)
+//! to turn it on for a circuit.
+
+use tor_cell::relaycell::StreamId;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The number of bytes of DATA sent and received on a single stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamByteCount {
+    /// The stream's ID on its circuit.
+    ///
+    /// (Once a stream closes, its ID can be used again for a later
+    /// stream, which gets its own StreamByteCount.)
+    stream_id: StreamId,
+    /// Bytes of DATA that we have sent on this stream.
+    sent: u64,
+    /// Bytes of DATA that we have received on this stream.
+    received: u64,
+}
+
+impl StreamByteCount {
+    /// Return the ID that this stream had on its circuit.
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
+    /// Return the number of bytes of DATA that we have sent on this
+    /// stream.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Return the number of bytes of DATA that we have received on this
+    /// stream.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+/// A handle to the count for a single stream in a [`StreamByteCounter`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StreamCountIdx(usize);
+
+/// A record of the bytes sent and received on each stream of a circuit.
+///
+/// Streams keep their counts after they close, so that a measurement
+/// can read them afterwards.
+#[derive(Debug, Default)]
+pub(crate) struct StreamByteCounter {
+    /// True if we're counting bytes on this circuit.
+    enabled: AtomicBool,
+    /// The counts for each stream that we've opened while counting,
+    /// indexed by [`StreamCountIdx`].
+    counts: Mutex<Vec<StreamByteCount>>,
+}
+
+impl StreamByteCounter {
+    /// Start counting bytes.
+    pub(crate) fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Return true if we're counting bytes.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Start counting bytes for a new stream with ID `stream_id`, and
+    /// return a handle to its count.  Return None if we aren't counting
+    /// bytes.
+    pub(crate) fn add_stream(&self, stream_id: StreamId) -> Option<StreamCountIdx> {
+        if self.is_enabled() {
+            let mut counts = self.counts.lock().expect("poisoned lock");
+            counts.push(StreamByteCount {
+                stream_id,
+                sent: 0,
+                received: 0,
+            });
+            Some(StreamCountIdx(counts.len() - 1))
+        } else {
+            None
+        }
+    }
+
+    /// Apply `f` to the count for the stream with handle `idx`.
+    fn update<F>(&self, idx: StreamCountIdx, f: F)
+    where
+        F: FnOnce(&mut StreamByteCount),
+    {
+        let mut counts = self.counts.lock().expect("poisoned lock");
+        if let Some(count) = counts.get_mut(idx.0) {
+            f(count);
+        }
+    }
+
+    /// Note that we sent `n` bytes of DATA on the stream with handle
+    /// `idx`.
+    pub(crate) fn note_sent(&self, idx: StreamCountIdx, n: usize) {
+        self.update(idx, |c| c.sent += n as u64);
+    }
+
+    /// Note that we received `n` bytes of DATA on the stream with handle
+    /// `idx`.
+    pub(crate) fn note_received(&self, idx: StreamCountIdx, n: usize) {
+        self.update(idx, |c| c.received += n as u64);
+    }
+
+    /// Return a copy of the counts for every stream, in the order that
+    /// the streams were opened, or None if we aren't counting bytes.
+    pub(crate) fn snapshot(&self) -> Option<Vec<StreamByteCount>> {
+        if self.is_enabled() {
+            Some(self.counts.lock().expect("poisoned lock").clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let counter = StreamByteCounter::default();
+        assert!(counter.add_stream(5.into()).is_none());
+        assert!(counter.snapshot().is_none());
+
+        counter.enable();
+        let s1 = counter.add_stream(5.into()).unwrap();
+        let s2 = counter.add_stream(7.into()).unwrap();
+        counter.note_sent(s1, 100);
+        counter.note_received(s1, 498);
+        counter.note_received(s1, 2);
+        counter.note_received(s2, 10);
+
+        // Stream ID 5 gets reused for a new stream, which has its own
+        // count.
+        let s3 = counter.add_stream(5.into()).unwrap();
+        counter.note_sent(s3, 1);
+
+        let counts = counter.snapshot().unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[0].stream_id(), 5.into());
+        assert_eq!(counts[0].sent(), 100);
+        assert_eq!(counts[0].received(), 500);
+        assert_eq!(
+            counts[1],
+            StreamByteCount {
+                stream_id: 7.into(),
+                sent: 0,
+                received: 10
+            }
+        );
+        assert_eq!(counts[2].stream_id(), 5.into());
+        assert_eq!(counts[2].sent(), 1);
+        assert_eq!(counts[2].received(), 0);
+    }
+}
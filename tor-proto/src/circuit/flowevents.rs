@@ -0,0 +1,327 @@
+//! Reporting for flow-control events on a circuit.
+//!
+//! A circuit can report what's happening to its flow-control windows
+//! (SENDMEs received and sent, rejected SENDMEs, and window violations)
+//! on a single bounded stream of [`FlowControlUpdate`]s.
+//!
+//! Reporting an event never blocks the circuit: if the subscriber falls
+//! behind and the stream fills up, we drop the event, and tell the
+//! subscriber how many events it missed (via
+//! [`FlowControlUpdate::Lagged`]) once there is room again.
+
+use crate::circuit::{CircuitBuildId, UniqId};
+use crate::crypto::cell::HopNum;
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tor_cell::relaycell::StreamId;
+
+/// A kind of flow-control event.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FlowControlEventKind {
+    /// We received an acceptable SENDME, and our send window grew to
+    /// the given value.
+    SendmeReceived {
+        /// The new size of the send window.
+        window: u16,
+    },
+    /// We received a SENDME that we had to reject as a protocol
+    /// violation.  (Typically, this means it had the wrong tag, or it
+    /// acknowledged data that we never sent.)
+    SendmeRejected,
+    /// We sent a SENDME to acknowledge data that we have received.
+    SendmeSent,
+    /// The other side sent us more data than our receive window allowed.
+    WindowViolation,
+}
+
+/// A single flow-control event on a circuit or stream.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FlowControlEvent {
+    /// The circuit on which this event happened.
+    circ_id: UniqId,
+    /// The build that created the circuit, if it had an identifier.
+    build_id: Option<CircuitBuildId>,
+    /// The hop of the circuit that this event concerns.
+    hop: HopNum,
+    /// The stream on which this event happened, if it was a stream-level
+    /// event.
+    stream_id: Option<StreamId>,
+    /// When this event happened.
+    when: Instant,
+    /// What happened.
+    kind: FlowControlEventKind,
+}
+
+impl FlowControlEvent {
+    /// Return the unique identifier of the circuit for this event.
+    pub fn circ_id(&self) -> UniqId {
+        self.circ_id
+    }
+    /// Return the identifier of the build that created this event's
+    /// circuit, if it had one.
+    pub fn build_id(&self) -> Option<CircuitBuildId> {
+        self.build_id
+    }
+    /// Return the (zero-based) index of the hop that this event concerns.
+    pub fn hop(&self) -> u8 {
+        self.hop.into()
+    }
+    /// Return the stream that this event happened on, or None if this was
+    /// a circuit-level event.
+    pub fn stream_id(&self) -> Option<StreamId> {
+        self.stream_id
+    }
+    /// Return the time at which this event happened.
+    pub fn when(&self) -> Instant {
+        self.when
+    }
+    /// Return the kind of this event.
+    pub fn kind(&self) -> &FlowControlEventKind {
+        &self.kind
+    }
+}
+
+/// An item yielded by a [`FlowControlEvents`] stream.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum FlowControlUpdate {
+    /// A flow-control event happened.
+    Event(FlowControlEvent),
+    /// The subscriber fell behind, and we dropped this many events
+    /// since the last item.
+    Lagged(usize),
+}
+
+/// A stream of flow-control events from a circuit.
+///
+/// Returned by [`ClientCirc::flow_control_events`](crate::circuit::ClientCirc::flow_control_events).
+pub struct FlowControlEvents {
+    /// The channel on which we receive updates.
+    receiver: mpsc::Receiver<FlowControlUpdate>,
+}
+
+impl Stream for FlowControlEvents {
+    type Item = FlowControlUpdate;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// The sending side of a circuit's [`FlowControlEvents`] stream.
+///
+/// This is shared between a circuit and its reactor.  It's fine to
+/// report events when nobody is subscribed: they are just discarded.
+pub(crate) struct FlowEventSender {
+    /// The current subscriber, if any.
+    inner: Mutex<Option<SenderState>>,
+    /// The build that created our circuit, if it had an identifier.
+    build_id: Mutex<Option<CircuitBuildId>>,
+}
+
+/// Internal state for a [`FlowEventSender`] with a subscriber.
+struct SenderState {
+    /// The channel on which to send updates.
+    sender: mpsc::Sender<FlowControlUpdate>,
+    /// The number of events that we have dropped because the channel
+    /// was full, and not yet reported.
+    lagged: usize,
+}
+
+/// Outcome of trying to put an update onto a subscriber's channel.
+enum SendOutcome {
+    /// The update was queued.
+    Sent,
+    /// The channel was full; the update was dropped.
+    Full,
+    /// The subscriber has gone away.
+    Gone,
+}
+
+impl SenderState {
+    /// Try to queue `update` without blocking.
+    fn try_send(&mut self, update: FlowControlUpdate) -> SendOutcome {
+        match self.sender.try_send(update) {
+            Ok(()) => SendOutcome::Sent,
+            Err(e) if e.is_full() => SendOutcome::Full,
+            Err(_) => SendOutcome::Gone,
+        }
+    }
+}
+
+impl FlowEventSender {
+    /// Construct a new FlowEventSender with no subscriber.
+    pub(crate) fn new() -> Self {
+        FlowEventSender {
+            inner: Mutex::new(None),
+            build_id: Mutex::new(None),
+        }
+    }
+
+    /// Label every event that we report from now on with `build_id`.
+    pub(crate) fn set_build_id(&self, build_id: CircuitBuildId) {
+        *self.build_id.lock().expect("poisoned lock") = Some(build_id);
+    }
+
+    /// Return the build identifier that we label our events with, if any.
+    pub(crate) fn build_id(&self) -> Option<CircuitBuildId> {
+        *self.build_id.lock().expect("poisoned lock")
+    }
+
+    /// Return a new stream of flow-control events that can hold up to
+    /// `capacity` undelivered updates.
+    ///
+    /// There is only one subscriber at a time: this replaces any
+    /// previous subscriber, whose stream will end.
+    pub(crate) fn subscribe(&self, capacity: usize) -> FlowControlEvents {
+        // A futures mpsc channel has room for its buffer size plus one
+        // slot for each sender; we only have one sender.
+        let (sender, receiver) = mpsc::channel(capacity.saturating_sub(1));
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        *inner = Some(SenderState { sender, lagged: 0 });
+        FlowControlEvents { receiver }
+    }
+
+    /// Report that an event of type `kind` has just happened on hop `hop`
+    /// of the circuit `circ_id`, and (if it's a stream-level event) on the
+    /// stream `stream_id`.
+    pub(crate) fn emit(
+        &self,
+        circ_id: UniqId,
+        hop: HopNum,
+        stream_id: Option<StreamId>,
+        kind: FlowControlEventKind,
+    ) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let state = match inner.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+
+        // If we dropped anything earlier, the subscriber needs to hear
+        // about that before it hears about anything newer.
+        if state.lagged > 0 {
+            match state.try_send(FlowControlUpdate::Lagged(state.lagged)) {
+                SendOutcome::Sent => state.lagged = 0,
+                SendOutcome::Full => {
+                    state.lagged += 1;
+                    return;
+                }
+                SendOutcome::Gone => {
+                    *inner = None;
+                    return;
+                }
+            }
+        }
+
+        let event = FlowControlEvent {
+            circ_id,
+            build_id: self.build_id(),
+            hop,
+            stream_id,
+            when: Instant::now(),
+            kind,
+        };
+        match state.try_send(FlowControlUpdate::Event(event)) {
+            SendOutcome::Sent => {}
+            SendOutcome::Full => state.lagged += 1,
+            SendOutcome::Gone => *inner = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Helper: return the next update from `events` if one is ready.
+    fn next_update(events: &mut FlowControlEvents) -> Option<FlowControlUpdate> {
+        events.next().now_or_never().flatten()
+    }
+
+    /// Helper: assert that `update` is an event on stream `stream`.
+    fn assert_event_on_stream(update: Option<FlowControlUpdate>, stream: u16) {
+        match update {
+            Some(FlowControlUpdate::Event(e)) => {
+                assert_eq!(e.stream_id(), Some(stream.into()));
+                assert_eq!(e.hop(), 1);
+                assert_eq!(e.kind(), &FlowControlEventKind::SendmeSent);
+            }
+            other => panic!("Unexpected update {:?}", other),
+        }
+    }
+
+    #[test]
+    fn events_in_order() {
+        let circ_id = UniqId::new(7, 9);
+        let sender = FlowEventSender::new();
+
+        // Nobody is listening: this is a no-op.
+        sender.emit(circ_id, 1.into(), None, FlowControlEventKind::SendmeSent);
+
+        let mut events = sender.subscribe(3);
+        for stream in 1..=5_u16 {
+            sender.emit(
+                circ_id,
+                1.into(),
+                Some(stream.into()),
+                FlowControlEventKind::SendmeSent,
+            );
+        }
+        // The first three arrive; the last two are dropped.
+        assert_event_on_stream(next_update(&mut events), 1);
+
+        // Now there's room for the lag report, but not the new event.
+        sender.emit(
+            circ_id,
+            1.into(),
+            Some(6.into()),
+            FlowControlEventKind::SendmeSent,
+        );
+        assert_event_on_stream(next_update(&mut events), 2);
+        assert_event_on_stream(next_update(&mut events), 3);
+        assert!(matches!(
+            next_update(&mut events),
+            Some(FlowControlUpdate::Lagged(2))
+        ));
+        assert!(next_update(&mut events).is_none());
+
+        sender.emit(
+            circ_id,
+            1.into(),
+            Some(7.into()),
+            FlowControlEventKind::SendmeSent,
+        );
+        assert!(matches!(
+            next_update(&mut events),
+            Some(FlowControlUpdate::Lagged(1))
+        ));
+        assert_event_on_stream(next_update(&mut events), 7);
+        assert!(next_update(&mut events).is_none());
+
+        // A new subscriber replaces the old one.
+        let mut events2 = sender.subscribe(3);
+        sender.emit(
+            circ_id,
+            0.into(),
+            None,
+            FlowControlEventKind::WindowViolation,
+        );
+        assert!(matches!(events.next().now_or_never(), Some(None)));
+        match next_update(&mut events2) {
+            Some(FlowControlUpdate::Event(e)) => {
+                assert_eq!(e.circ_id(), circ_id);
+                assert_eq!(e.hop(), 0);
+                assert_eq!(e.stream_id(), None);
+                assert_eq!(e.kind(), &FlowControlEventKind::WindowViolation);
+            }
+            other => panic!("Unexpected update {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! A summary of the flow-control settings in effect on a circuit hop.
+
+/// The flow-control parameters that one hop of a circuit ended up with.
+///
+/// Returned by [`ClientCirc::flow_control_params`](super::ClientCirc::flow_control_params).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowControlParams {
+    /// The version of the FlowCtrl subprotocol that we're using with the
+    /// hop: 2 if it agreed to congestion control, 1 if it sends
+    /// authenticated SENDMEs, and 0 otherwise.
+    version: u8,
+    /// The send window that the hop started with.
+    initial_window: u16,
+    /// The number of cells that each SENDME acknowledges.
+    increment: u16,
+}
+
+impl FlowControlParams {
+    /// Construct a new FlowControlParams.
+    pub(crate) fn new(version: u8, initial_window: u16, increment: u16) -> Self {
+        FlowControlParams {
+            version,
+            initial_window,
+            increment,
+        }
+    }
+
+    /// Return the version of the FlowCtrl subprotocol that we're using
+    /// with the hop: 2 if it agreed to congestion control, 1 if it sends
+    /// authenticated SENDMEs, and 0 otherwise.
+    ///
+    /// Arti doesn't implement a congestion window yet: with version 2,
+    /// the hop's windows just use the SENDME increment that it asked for.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Return the send window that the hop started with.
+    pub fn initial_window(&self) -> u16 {
+        self.initial_window
+    }
+
+    /// Return the number of cells that each SENDME acknowledges.
+    ///
+    /// With congestion control, this is the increment that the hop
+    /// negotiated, not the one from our own parameters.
+    pub fn increment(&self) -> u16 {
+        self.increment
+    }
+}
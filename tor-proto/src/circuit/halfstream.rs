@@ -49,9 +49,10 @@ impl HalfStream {
     pub(super) async fn handle_msg(&mut self, msg: &RelayMsg) -> Result<()> {
         match msg {
             RelayMsg::Sendme(_) => {
-                self.sendw.put(Some(())).await.ok_or_else(|| {
-                    Error::CircProto("Too many sendmes on a closed stream!".into())
-                })?;
+                self.sendw
+                    .put(Some(()))
+                    .await
+                    .map_err(|_| Error::CircProto("Too many sendmes on a closed stream!".into()))?;
                 Ok(())
             }
             RelayMsg::Data(_) => {
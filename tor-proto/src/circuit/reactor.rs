@@ -8,6 +8,7 @@
 
 use super::streammap::{ShouldSendEnd, StreamEnt};
 use crate::circuit::celltypes::ClientCircChanMsg;
+use crate::circuit::flowevents::{FlowControlEventKind, FlowEventSender};
 use crate::circuit::unique_id::UniqId;
 use crate::circuit::{sendme, streammap};
 use crate::crypto::cell::{HopNum, InboundClientCrypt, InboundClientLayer};
@@ -55,6 +56,13 @@ pub(super) enum CtrlMsg {
         Box<dyn InboundClientLayer + Send>,
         oneshot::Sender<()>,
     ),
+    /// Pause flow control on every hop of the circuit, and then tell us
+    /// when we have done so.
+    PauseFlowControl(oneshot::Sender<()>),
+    /// Resume flow control on every hop of the circuit, sending any
+    /// SENDMEs that came due while it was paused, and then tell us when
+    /// we have done so.
+    ResumeFlowControl(oneshot::Sender<()>),
 }
 
 impl std::fmt::Debug for CtrlMsg {
@@ -66,6 +74,8 @@ impl std::fmt::Debug for CtrlMsg {
             CloseStream(h, s, _) => write!(f, "CloseStream({:?}, {:?}, _)", h, s),
             AddStream(h, _, _, _) => write!(f, "AddStream({:?}, _, _, _)", h),
             AddHop(_, _, _) => write!(f, "AddHop(_, _, _)"),
+            PauseFlowControl(_) => write!(f, "PauseFlowControl(_)"),
+            ResumeFlowControl(_) => write!(f, "ResumeFlowControl(_)"),
         }
     }
 }
@@ -92,14 +102,37 @@ pub(super) struct InboundHop {
     map: streammap::StreamMap,
     /// Window used to say how many cells we can receive.
     recvwindow: sendme::CircRecvWindow,
+    /// A reference to the window used to say how many cells we can
+    /// send, shared with the circuit's view of this hop.
+    ///
+    /// We keep it here so that we can apply circuit SENDMEs, and pause
+    /// the window, without locking the circuit.
+    sendwindow: sendme::CircSendWindow,
+    /// If true, this hop is using an older link protocol and we
+    /// shouldn't expect good authenticated SENDMEs from it.
+    auth_sendme_optional: bool,
+    /// The tags for the SENDMEs that came due while our flow control was
+    /// paused, oldest first.
+    deferred_sendmes: Vec<sendme::CircTag>,
 }
 
 impl InboundHop {
-    /// Create a new hop.
-    pub(super) fn new() -> Self {
+    /// Create a new hop with the receive window `recvwindow`, sharing
+    /// `sendwindow` with the circuit.
+    ///
+    /// If `supports_flowctrl_1` is false, the hop doesn't support
+    /// authenticated SENDMEs, so we accept SENDMEs from it without tags.
+    pub(super) fn new(
+        recvwindow: sendme::CircRecvWindow,
+        sendwindow: sendme::CircSendWindow,
+        supports_flowctrl_1: bool,
+    ) -> Self {
         InboundHop {
             map: streammap::StreamMap::new(),
-            recvwindow: sendme::CircRecvWindow::new(1000),
+            recvwindow,
+            sendwindow,
+            auth_sendme_optional: !supports_flowctrl_1,
+            deferred_sendmes: Vec::new(),
         }
     }
 }
@@ -108,6 +141,9 @@ impl InboundHop {
 ///
 /// This type is returned when you finish a circuit; you need to spawn a
 /// new task that calls `run()` on it.
+///
+/// Dropping a Reactor, whether or not `run()` was called, closes its
+/// circuit.
 #[must_use = "If you don't call run() on a reactor, the circuit won't work."]
 pub struct Reactor {
     /// A stream of oneshot receivers that tell this reactor about things it
@@ -138,6 +174,21 @@ pub struct Reactor {
     hops: Vec<InboundHop>,
     /// An identifier for logging about this reactor's circuit.
     unique_id: UniqId,
+    /// Where to report flow-control events on this circuit.
+    flow_events: Arc<FlowEventSender>,
+    /// True if flow control on this circuit is paused.
+    flow_control_paused: bool,
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        // If we're dropped without `run` finishing (say, because the task
+        // running us was cancelled), nothing else will tell the circuit
+        // that it's unusable.
+        if let Some(circ) = self.circuit.upgrade() {
+            circ.closed.store(true, Ordering::SeqCst);
+        }
+    }
 }
 
 impl Reactor {
@@ -148,6 +199,7 @@ impl Reactor {
         closeflag: oneshot::Receiver<CtrlMsg>,
         input: mpsc::Receiver<ClientCircChanMsg>,
         unique_id: UniqId,
+        flow_events: Arc<FlowEventSender>,
     ) -> Self {
         let oneshots = stream::FuturesUnordered::new();
         oneshots.push(closeflag);
@@ -159,6 +211,8 @@ impl Reactor {
             crypto_in: InboundClientCrypt::new(),
             hops: Vec::new(),
             unique_id,
+            flow_events,
+            flow_control_paused: false,
         }
     }
 
@@ -184,18 +238,27 @@ impl Reactor {
             }
         };
         debug!("{}: Circuit reactor stopped: {:?}", self.unique_id, result);
+        if let (Err(e), Some(circ)) = (&result, self.circuit.upgrade()) {
+            *circ.close_reason.lock().expect("poisoned lock") = Some(e.clone());
+        }
         self.propagate_close().await;
         result
     }
 
     /// Tell the circuit that this reactor has been closed.
     pub(super) async fn propagate_close(self) {
+        // Close our send windows, so that nobody waits for room in them
+        // that will never come.
+        for hop in self.hops.iter() {
+            hop.sendwindow.close();
+        }
         if let Some(circ) = self.circuit.upgrade() {
             // TODO: should this call terminate?
             circ.closed.store(true, Ordering::SeqCst);
+            let err = circ.closed_error();
             let mut circ = circ.c.lock().await;
             if let Some((_, sender)) = circ.sendmeta.take() {
-                let _ignore_err = sender.send(Err(Error::CircuitClosed));
+                let _ignore_err = sender.send(Err(err));
             }
         }
     }
@@ -251,12 +314,59 @@ impl Reactor {
                 // If there was no hop with this index, dropping the sender
                 // will cancel the attempt to add the stream.
             }
-            CtrlMsg::AddHop(hop, layer, sender) => {
+            CtrlMsg::AddHop(mut hop, layer, sender) => {
+                if self.flow_control_paused {
+                    sendme::FlowControl::pause(&hop.sendwindow, &mut hop.recvwindow);
+                }
                 self.hops.push(hop);
                 self.crypto_in.add_layer(layer);
                 // XXXX not sure if this is right to ignore
                 let _ignore = sender.send(());
             }
+            CtrlMsg::PauseFlowControl(sender) => {
+                self.flow_control_paused = true;
+                for (idx, hop) in self.hops.iter_mut().enumerate() {
+                    sendme::FlowControl::pause(&hop.sendwindow, &mut hop.recvwindow);
+                    trace!(
+                        "{}: Paused flow control on hop {}: send window {}, receive window {}",
+                        self.unique_id,
+                        idx + 1,
+                        hop.sendwindow.window().await,
+                        hop.recvwindow.window()
+                    );
+                }
+                let _ignore = sender.send(());
+            }
+            CtrlMsg::ResumeFlowControl(sender) => {
+                self.resume_flow_control().await?;
+                let _ignore = sender.send(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume flow control on every hop of this circuit, and send the
+    /// SENDMEs that we owe for the cells we received while it was paused.
+    async fn resume_flow_control(&mut self) -> Result<()> {
+        self.flow_control_paused = false;
+        // Resume every hop before we send anything, so that sending a
+        // SENDME never has to wait behind a hop that's still paused.
+        let mut due = Vec::with_capacity(self.hops.len());
+        for hop in self.hops.iter_mut() {
+            let n_due = sendme::FlowControl::resume(&hop.sendwindow, &mut hop.recvwindow);
+            let tags = std::mem::take(&mut hop.deferred_sendmes);
+            if usize::from(n_due) != tags.len() {
+                return Err(Error::InternalError(
+                    "Lost track of deferred circuit SENDMEs".into(),
+                ));
+            }
+            due.push(tags);
+        }
+        for (idx, tags) in due.into_iter().enumerate() {
+            let hopnum = (idx as u8).into();
+            for tag in tags {
+                self.send_circ_sendme(hopnum, tag).await?;
+            }
         }
         Ok(())
     }
@@ -288,7 +398,7 @@ impl Reactor {
         if should_send_end == ShouldSendEnd::Send {
             let end_cell = RelayCell::new(id, End::new_misc().into());
             if let Some(circ) = self.circuit.upgrade() {
-                circ.send_relay_cell(hopnum, false, end_cell).await?;
+                circ.send_control_cell(hopnum, end_cell).await?;
             } else {
                 return Err(Error::CircuitClosed);
             }
@@ -347,20 +457,30 @@ impl Reactor {
             let hop = self
                 .hop_mut(hopnum)
                 .ok_or_else(|| Error::CircProto("Sendme from nonexistent hop".into()))?;
-            hop.recvwindow.take()?
+            match hop.recvwindow.take_deferrable() {
+                Ok(sendme::SendmeDue::Now) => true,
+                Ok(sendme::SendmeDue::Deferred) => {
+                    // We'll send this one when we resume.
+                    hop.deferred_sendmes.push(tag);
+                    false
+                }
+                Ok(sendme::SendmeDue::No) => false,
+                Err(e) => {
+                    self.flow_events.emit(
+                        self.unique_id,
+                        hopnum,
+                        None,
+                        FlowControlEventKind::WindowViolation,
+                    );
+                    return Err(e);
+                }
+            }
         } else {
             false
         };
         // If we do need to send a circuit-level SENDME cell, do so.
         if send_circ_sendme {
-            let sendme = Sendme::new_tag(tag);
-            let cell = RelayCell::new(0.into(), sendme.into());
-            if let Some(circ) = self.circuit.upgrade() {
-                circ.send_relay_cell(hopnum, false, cell).await?;
-            } else {
-                return Err(Error::CircuitClosed);
-            }
-            self.hop_mut(hopnum).unwrap().recvwindow.put();
+            self.send_circ_sendme(hopnum, tag).await?;
         }
 
         // Break the message apart into its streamID and message.
@@ -379,6 +499,11 @@ impl Reactor {
         // If this has a reasonable streamID value of 0, it's a meta cell,
         // not meant for a particular stream.
         if streamid.is_zero() {
+            // We handle circuit SENDMEs here, like stream SENDMEs below,
+            // so that we never need to lock the circuit for them.
+            if let RelayMsg::Sendme(s) = msg {
+                return self.handle_sendme(hopnum, s).await;
+            }
             if let Some(circ) = self.circuit.upgrade() {
                 let mut circ = circ.c.lock().await;
                 return circ.handle_meta_cell(hopnum, msg).await;
@@ -398,7 +523,20 @@ impl Reactor {
                     // We need to handle sendmes here, not in the stream's
                     // recv() method, or else we'd never notice them if the
                     // stream isn't reading.
-                    w.put(Some(())).await;
+                    let kind = match w.put(Some(())).await {
+                        Ok(window) => FlowControlEventKind::SendmeReceived { window },
+                        Err(e) => {
+                            self.flow_events.emit(
+                                self.unique_id,
+                                hopnum,
+                                Some(streamid),
+                                FlowControlEventKind::SendmeRejected,
+                            );
+                            return Err(e);
+                        }
+                    };
+                    self.flow_events
+                        .emit(self.unique_id, hopnum, Some(streamid), kind);
                     return Ok(());
                 }
 
@@ -439,6 +577,77 @@ impl Reactor {
         }
     }
 
+    /// Send a circuit-level SENDME cell with `tag` to the hop `hopnum`,
+    /// and note that we did.
+    async fn send_circ_sendme(&mut self, hopnum: HopNum, tag: sendme::CircTag) -> Result<()> {
+        let sendme = Sendme::new_tag(tag);
+        let cell = RelayCell::new(0.into(), sendme.into());
+        if let Some(circ) = self.circuit.upgrade() {
+            circ.send_control_cell(hopnum, cell).await?;
+        } else {
+            return Err(Error::CircuitClosed);
+        }
+        let unique_id = self.unique_id;
+        let recvwindow = &mut self
+            .hop_mut(hopnum)
+            .ok_or_else(|| Error::InternalError("Sent a SENDME to nonexistent hop".into()))?
+            .recvwindow;
+        recvwindow.put()?;
+        trace!(
+            "{}: Sent SENDME to hop {}; receive window now {}",
+            unique_id,
+            hopnum,
+            recvwindow.window()
+        );
+        self.flow_events.emit(
+            self.unique_id,
+            hopnum,
+            None,
+            FlowControlEventKind::SendmeSent,
+        );
+        Ok(())
+    }
+
+    /// Handle a RELAY_SENDME cell on this circuit with stream ID 0.
+    async fn handle_sendme(&mut self, hopnum: HopNum, msg: Sendme) -> Result<()> {
+        // No need to call "shutdown" on errors in this function;
+        // errors will propagate to the reactor loop and shut it down.
+        let kind = match self.check_sendme(hopnum, msg).await {
+            Ok(window) => FlowControlEventKind::SendmeReceived { window },
+            Err(e) => {
+                self.flow_events.emit(
+                    self.unique_id,
+                    hopnum,
+                    None,
+                    FlowControlEventKind::SendmeRejected,
+                );
+                return Err(e);
+            }
+        };
+        self.flow_events.emit(self.unique_id, hopnum, None, kind);
+        Ok(())
+    }
+
+    /// Helper for handle_sendme: check a circuit SENDME and apply it to
+    /// our send window. Return the new size of the window.
+    async fn check_sendme(&mut self, hopnum: HopNum, msg: Sendme) -> Result<u16> {
+        let hop = self
+            .hop_mut(hopnum)
+            .ok_or_else(|| Error::CircProto(format!("Couldn't find {} hop", hopnum)))?;
+
+        let auth: Option<sendme::CircTag> = match msg.into_tag() {
+            Some(v) => Some(sendme::tag_from_slice(&v[..])?),
+            None => {
+                if !hop.auth_sendme_optional {
+                    return Err(Error::CircProto("missing tag on circuit sendme".into()));
+                } else {
+                    None
+                }
+            }
+        };
+        hop.sendwindow.put(auth).await
+    }
+
     /// Helper: process a destroy cell.
     fn handle_destroy_cell(&mut self) -> Result<()> {
         // I think there is nothing more to do here.
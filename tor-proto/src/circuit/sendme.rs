@@ -10,30 +10,74 @@
 //! other side of the circuit really has read all of the data that it's
 //! acknowledging.
 
+use futures::future::{self, Either};
 use futures::lock::Mutex;
 
 use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tor_cell::relaycell::msg::RelayMsg;
 use tor_cell::relaycell::RelayCell;
 
-use crate::{Error, Result};
+use log::warn;
 
-// XXXX Three problems with this tag:
-// XXXX - First, we need to support unauthenticated flow control.
-// XXXX - Second, this tag type could be different for each layer, if we
-// XXXX   eventually have an authenticator that isn't 20 bytes long.
-// XXXX - Third, we want the comparison to happen with a constant-time
-// XXXX   operation.
+use crate::util::bytebudget::ByteBudget;
+use crate::{Error, Result};
 
 /// Tag type used in regular v1 sendme cells.
+///
+/// A future authenticator with a different length can use a byte array
+/// of its own length (like `[u8; 32]`) as the tag type of its
+/// [`SendWindow`], and [`tag_from_slice`] to decode its tags.
 pub(crate) type CircTag = [u8; 20];
 /// Absence of a tag, as with stream cells.
 pub(crate) type NoTag = ();
 
+/// A tag that a SENDME can carry to acknowledge the cells we sent.
+pub(crate) trait SendmeTag: Clone {
+    /// Return true if `self` and `other` are the same tag.
+    fn tag_eq(&self, other: &Self) -> bool;
+}
+
+impl<const N: usize> SendmeTag for [u8; N] {
+    /// Circuit tags are authenticators, so we compare them in constant
+    /// time: otherwise, somebody guessing at a tag could learn how many
+    /// of its leading bytes were right.
+    fn tag_eq(&self, other: &Self) -> bool {
+        use subtle::ConstantTimeEq;
+        self[..].ct_eq(&other[..]).into()
+    }
+}
+
+/// Decode an `N`-byte circuit tag from `tag`, as we got it from a SENDME
+/// cell or from the crypto layer.
+///
+/// Return an error if `tag` isn't exactly `N` bytes long.  (A tag of the
+/// wrong type can't be passed to [`SendWindow::put`] at all.)
+pub(crate) fn tag_from_slice<const N: usize>(tag: &[u8]) -> Result<[u8; N]> {
+    <[u8; N]>::try_from(tag).map_err(|_| {
+        Error::CircProto(format!(
+            "malformed tag on circuit sendme: expected {} bytes, got {}",
+            N,
+            tag.len()
+        ))
+    })
+}
+
+impl SendmeTag for NoTag {
+    fn tag_eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
 /// A circuit's send window.
 pub(crate) type CircSendWindow = SendWindow<CircParams, CircTag>;
+/// A set of cells reserved in a circuit's send window.
+pub(crate) type CircReservation = Reservation<CircParams, CircTag>;
 /// A stream's send window.
 pub(crate) type StreamSendWindow = SendWindow<StreamParams, NoTag>;
 
@@ -50,29 +94,220 @@ pub(crate) type StreamRecvWindow = RecvWindow<StreamParams>;
 pub(crate) struct SendWindow<P, T>
 where
     P: WindowParams,
-    T: PartialEq + Eq + Clone,
+    T: SendmeTag,
 {
     // TODO could use a bilock if that becomes non-experimental.
     // TODO I wish we could do this without locking; we could make a bunch
     // of these functions non-async if that happened.
     /// Actual SendWindow object.
     w: Arc<Mutex<SendWindowInner<T>>>,
+    /// Signals used to coordinate tasks that are waiting for room in
+    /// the window.
+    signals: Arc<WindowSignals>,
     /// Marker type to tell the compiler that the P type is used.
     _dummy: std::marker::PhantomData<P>,
 }
 
+/// Unlocked state shared by the handles to a SendWindow.
+///
+/// This lives outside the lock so that code that can't wait for the
+/// lock, like a destructor or [`SendWindow::close`], can update it.
+struct WindowSignals {
+    /// An event to wait on if we find that we can't take from the window.
+    ///
+    /// Any number of takes can wait on this at once.  We wake all of them
+    /// whenever there might be room, and each one checks again: waking
+    /// only as many as fit could strand a priority take queued behind
+    /// ordinary takes that then decline to go first.
+    unblock: event_listener::Event,
+    /// The number of priority takes (like [`SendWindow::take_priority`])
+    /// that are waiting for room in the window.  While this is nonzero,
+    /// ordinary takes wait.
+    priority_waiters: AtomicUsize,
+    /// The number of cells in the window that are held by
+    /// [`Reservation`]s, and so can't be taken by anybody else.
+    reserved: AtomicUsize,
+    /// If present, the budget for the bytes in the cells that we've sent
+    /// (or reserved) but that haven't been acknowledged yet.
+    byte_budget: Option<ByteBudget>,
+    /// The number of takes that are currently waiting for room in the
+    /// window.
+    parked: AtomicUsize,
+    /// True if this window has been paused with [`FlowControl::pause`].
+    paused: AtomicBool,
+    /// An event to wait on for the window to have no unacknowledged tags,
+    /// or to be closed.
+    drained: event_listener::Event,
+    /// True if this window has been closed with [`SendWindow::close`].
+    closed: AtomicBool,
+}
+
+/// A marker for a take that is waiting for room in the window.
+///
+/// While this exists, [`SendWindow::is_blocked`] returns true.
+struct Parked<'a> {
+    /// The signals for the window we're waiting on.
+    signals: &'a WindowSignals,
+}
+
+impl<'a> Parked<'a> {
+    /// Record that a take is waiting on `signals`.
+    fn new(signals: &'a WindowSignals) -> Self {
+        signals.parked.fetch_add(1, Ordering::SeqCst);
+        Parked { signals }
+    }
+}
+
+impl<'a> Drop for Parked<'a> {
+    fn drop(&mut self) {
+        self.signals.parked.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A marker for a priority take in progress.
+///
+/// Ordinary takes defer to priority takes for as long as this exists.
+struct PriorityWaiter<'a> {
+    /// The signals for the window we're waiting on.
+    signals: &'a WindowSignals,
+}
+
+impl<'a> PriorityWaiter<'a> {
+    /// Register a new priority waiter on `signals`.
+    fn new(signals: &'a WindowSignals) -> Self {
+        signals.priority_waiters.fetch_add(1, Ordering::SeqCst);
+        PriorityWaiter { signals }
+    }
+}
+
+impl<'a> Drop for PriorityWaiter<'a> {
+    fn drop(&mut self) {
+        if self.signals.priority_waiters.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // We were the last priority waiter: any ordinary takes that
+            // were deferring to us can try again.
+            self.signals.unblock.notify(usize::MAX);
+        }
+    }
+}
+
 /// Interior (locked) code for SendWindowInner.
 struct SendWindowInner<T>
 where
-    T: PartialEq + Eq + Clone,
+    T: SendmeTag,
 {
     /// Current value for this window
     window: u16,
     /// Tag values that incoming "SENDME" messages need to match in order
     /// for us to send more data.
+    ///
+    /// This never holds more than [`WindowLimits::max_tags`] entries.
+    ///
+    /// (Always empty if this window is unauthenticated.)
     tags: VecDeque<T>,
-    /// An event to wait on if we find that we are out of cells.
-    unblock: event_listener::Event,
+    /// False if this window doesn't record or check tags at all.
+    authenticated: bool,
+    /// The number of SENDMEs that we're expecting but didn't record tags
+    /// for, because this window is unauthenticated.
+    untagged: usize,
+    /// For each SENDME that we're expecting, oldest first, the number of
+    /// bytes in the cells that it will acknowledge.
+    ///
+    /// (Only tracked if we have a byte budget.)
+    sendme_bytes: VecDeque<usize>,
+    /// The number of bytes in the cells that we've sent since the last
+    /// one that a SENDME will acknowledge.
+    ///
+    /// (Only tracked if we have a byte budget.)
+    unmarked_bytes: usize,
+    /// The value that this window started at.
+    initial: u16,
+    /// The maximum and increment for this window.
+    limits: WindowLimits,
+    /// The number of cells that we've taken from this window, ever.
+    cells_sent: u64,
+    /// The number of SENDMEs that we've applied to this window, ever.
+    sendmes_received: u64,
+    /// True if we've already warned that the other side acknowledged
+    /// cells that we didn't count.
+    warned_about_lag: bool,
+}
+
+/// A snapshot of a send window, along with the counts of cells and
+/// SENDMEs behind it.
+///
+/// The other side sends a SENDME for every `increment` cells that it
+/// counts, so the SENDMEs we receive show how many of our cells it has
+/// counted.  If it acknowledges more cells than we've counted as sent,
+/// we and the other side disagree about which cells count towards the
+/// window.  Monitoring tools can watch [`WindowAccounting::sendme_lag`]
+/// to notice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowAccounting {
+    /// The value that the window started at.
+    initial: u16,
+    /// The current value of the window.
+    window: u16,
+    /// The number of cells that each SENDME acknowledges.
+    increment: u16,
+    /// The number of cells that we've sent on the window.
+    cells_sent: u64,
+    /// The number of SENDMEs that we've received for the window.
+    sendmes_received: u64,
+    /// The byte budget for the window, and the number of bytes counted
+    /// against it, if it has one.
+    bytes: Option<(usize, usize)>,
+}
+
+impl WindowAccounting {
+    /// Return the value that the window started at.
+    pub fn initial(&self) -> u16 {
+        self.initial
+    }
+
+    /// Return the value of the window when this snapshot was taken.
+    pub fn window(&self) -> u16 {
+        self.window
+    }
+
+    /// Return the number of cells that each SENDME acknowledges.
+    pub fn increment(&self) -> u16 {
+        self.increment
+    }
+
+    /// Return the number of cells that we've sent on the window.
+    pub fn cells_sent(&self) -> u64 {
+        self.cells_sent
+    }
+
+    /// Return the number of SENDMEs that we've received for the window.
+    pub fn sendmes_received(&self) -> u64 {
+        self.sendmes_received
+    }
+
+    /// Return the largest number of bytes of DATA that the window allows
+    /// to be unacknowledged at once, or None if it has no byte budget.
+    pub fn byte_budget(&self) -> Option<usize> {
+        self.bytes.map(|(cap, _)| cap)
+    }
+
+    /// Return the number of bytes of DATA that we've sent on the window
+    /// without their being acknowledged, or None if the window has no byte
+    /// budget.
+    pub fn bytes_outstanding(&self) -> Option<usize> {
+        self.bytes.map(|(_, used)| used)
+    }
+
+    /// Return the number of SENDMEs that the other side owes us for the
+    /// cells we've counted as sent, less the number it has sent.
+    ///
+    /// In correct operation, this is never negative: it's the number of
+    /// SENDMEs still on their way to us.  It's negative if the other side
+    /// has acknowledged cells that we didn't count, which means that we
+    /// disagree about which cells count towards the window.
+    pub fn sendme_lag(&self) -> i64 {
+        let increment = u64::from(self.increment.max(1));
+        (self.cells_sent / increment) as i64 - self.sendmes_received as i64
+    }
 }
 
 /// Helper: parameterizes a window to determine its maximum and its increment.
@@ -83,6 +318,69 @@ pub(crate) trait WindowParams {
     fn increment() -> u16;
 }
 
+/// The maximum and increment for a single window, chosen at runtime.
+///
+/// The [`WindowParams`] for a window give its defaults; use this instead
+/// to override them, as the network's parameters require.  Both sides of
+/// a hop or stream must agree on the increment, so the overrides have to
+/// match on each end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct WindowLimits {
+    /// Largest allowable value for the window.
+    maximum: u16,
+    /// Number of cells that each SENDME acknowledges.
+    increment: u16,
+}
+
+impl WindowLimits {
+    /// Construct a new WindowLimits.
+    ///
+    /// The increment is clamped so that it is at least 1, and no more
+    /// than `maximum` (unless `maximum` is 0).
+    pub(crate) fn new(maximum: u16, increment: u16) -> Self {
+        WindowLimits {
+            maximum,
+            increment: increment.min(maximum).max(1),
+        }
+    }
+
+    /// Return the default limits for windows parameterized by `P`.
+    pub(crate) fn default_for<P: WindowParams>() -> Self {
+        Self::new(P::maximum(), P::increment())
+    }
+
+    /// Return the largest allowable value for the window.
+    pub(crate) fn maximum(&self) -> u16 {
+        self.maximum
+    }
+
+    /// Return the number of cells that each SENDME acknowledges.
+    pub(crate) fn increment(&self) -> u16 {
+        self.increment
+    }
+
+    /// Return the largest number of tags that a send window with these
+    /// limits will remember at once.
+    ///
+    /// We record one tag for every `increment` cells we send, and the
+    /// other side must acknowledge them before the window runs out, so
+    /// in correct operation we never need more than this many.
+    fn max_tags(&self) -> usize {
+        self.tags_below(self.maximum())
+    }
+
+    /// Return the number of multiples of the increment that lie below
+    /// `window`: that is, the number of tags that we'd record while
+    /// sending `window` cells.
+    ///
+    /// (We compute this as a usize, since `window + increment` can be
+    /// too big for a u16.)
+    fn tags_below(&self, window: u16) -> usize {
+        let increment = usize::from(self.increment());
+        (usize::from(window) + increment - 1) / increment
+    }
+}
+
 /// Parameters used for SENDME windows on circuits: limit at 1000 cells,
 /// and each SENDME adjusts by 100.
 pub(crate) struct CircParams;
@@ -111,27 +409,109 @@ impl WindowParams for StreamParams {
 impl<P, T> SendWindow<P, T>
 where
     P: WindowParams,
-    T: PartialEq + Eq + Clone,
+    T: SendmeTag,
 {
     /// Construct a new SendWindow.
+    ///
+    /// Outside of tests, windows come from [`FlowControl::windows`].
+    #[cfg(test)]
     pub(crate) fn new(window: u16) -> SendWindow<P, T> {
-        let increment = P::increment();
-        let capacity = (window + increment - 1) / increment;
+        Self::new_with_byte_budget(window, None)
+    }
+
+    /// Construct a new SendWindow that also limits the number of bytes
+    /// that can be outstanding at once to `byte_budget`, if provided.
+    ///
+    /// Only bytes passed to [`SendWindow::take_bytes`] count towards
+    /// the budget.
+    #[cfg(test)]
+    pub(crate) fn new_with_byte_budget(
+        window: u16,
+        byte_budget: Option<usize>,
+    ) -> SendWindow<P, T> {
+        Self::new_with_limits(window, byte_budget, WindowLimits::default_for::<P>())
+    }
+
+    /// Construct a new SendWindow that uses `limits` in place of the
+    /// defaults from `P`.
+    ///
+    /// As with [`SendWindow::new_with_byte_budget`], we limit the bytes
+    /// outstanding to `byte_budget` if it's present.
+    pub(crate) fn new_with_limits(
+        window: u16,
+        byte_budget: Option<usize>,
+        limits: WindowLimits,
+    ) -> SendWindow<P, T> {
+        Self::new_impl(window, byte_budget, true, limits)
+    }
+
+    /// Construct a new SendWindow, like [`SendWindow::new_with_limits`],
+    /// that never records tags.
+    ///
+    /// This is for talking to relays that don't support authenticated
+    /// SENDMEs (FlowCtrl=1): such a window accepts any SENDME that
+    /// acknowledges data we've sent, whatever its tag.
+    pub(crate) fn new_unauthenticated(
+        window: u16,
+        byte_budget: Option<usize>,
+        limits: WindowLimits,
+    ) -> SendWindow<P, T> {
+        Self::new_impl(window, byte_budget, false, limits)
+    }
+
+    /// Helper: construct a new SendWindow that records tags if and only
+    /// if `authenticated` is true.
+    fn new_impl(
+        window: u16,
+        byte_budget: Option<usize>,
+        authenticated: bool,
+        limits: WindowLimits,
+    ) -> SendWindow<P, T> {
+        let capacity = if authenticated {
+            limits.tags_below(window)
+        } else {
+            0
+        };
         let inner = SendWindowInner {
             window,
-            tags: VecDeque::with_capacity(capacity as usize),
-            unblock: event_listener::Event::new(),
+            tags: VecDeque::with_capacity(capacity),
+            authenticated,
+            untagged: 0,
+            sendme_bytes: VecDeque::new(),
+            unmarked_bytes: 0,
+            initial: window,
+            limits,
+            cells_sent: 0,
+            sendmes_received: 0,
+            warned_about_lag: false,
         };
         SendWindow {
             w: Arc::new(Mutex::new(inner)),
+            signals: Arc::new(WindowSignals {
+                unblock: event_listener::Event::new(),
+                priority_waiters: AtomicUsize::new(0),
+                reserved: AtomicUsize::new(0),
+                byte_budget: byte_budget.map(ByteBudget::new),
+                parked: AtomicUsize::new(0),
+                paused: AtomicBool::new(false),
+                drained: event_listener::Event::new(),
+                closed: AtomicBool::new(false),
+            }),
             _dummy: std::marker::PhantomData,
         }
     }
 
+    /// Return the number of SENDMEs that we're expecting on `w`, whether
+    /// or not we recorded tags for them.
+    fn outstanding_sendmes(w: &SendWindowInner<T>) -> usize {
+        w.tags.len() + w.untagged
+    }
+
     /// Add a reference-count to SendWindow and return a new handle to it.
     pub(crate) fn new_ref(&self) -> Self {
         SendWindow {
             w: Arc::clone(&self.w),
+            signals: Arc::clone(&self.signals),
             _dummy: std::marker::PhantomData,
         }
     }
@@ -143,63 +523,426 @@ where
     /// need to check for it later.
     ///
     /// Return the number of cells left in the window.
+    ///
+    /// Gives an error, and leaves the window unchanged, if we would need
+    /// to remember more than [`WindowLimits::max_tags`] tags.
+    ///
+    /// If the window is empty, or if any [`SendWindow::take_priority`]
+    /// call is waiting, this waits until it's our turn.
     pub(crate) async fn take(&mut self, tag: &T) -> Result<u16> {
+        self.take_bytes(tag, 0).await
+    }
+
+    /// As [`SendWindow::take`], but also count `n_bytes` towards this
+    /// window's byte budget, if it has one.
+    ///
+    /// If taking these bytes would put us over the budget, this waits
+    /// until enough outstanding bytes have been acknowledged (even if
+    /// there is room in the window).  If nothing is outstanding, we
+    /// always allow the take, so that a single cell larger than the
+    /// budget can't block forever.
+    pub(crate) async fn take_bytes(&mut self, tag: &T, n_bytes: usize) -> Result<u16> {
+        self.wait_until(false, |w| self.try_take(w, tag, n_bytes))
+            .await
+    }
+
+    /// Remove one item from this window, ahead of any ordinary
+    /// [`SendWindow::take`] calls that are waiting.
+    ///
+    /// This is for control cells that shouldn't be starved by bulk data
+    /// when the window is congested.  It never overdraws the window: if
+    /// the window is empty, we still wait for a SENDME like everybody
+    /// else.  But while we're waiting, ordinary takes are held back, so
+    /// the first room that opens up in the window goes to us.
+    ///
+    /// Otherwise behaves the same as [`SendWindow::take`].
+    pub(crate) async fn take_priority(&mut self, tag: &T) -> Result<u16> {
+        self.wait_until(true, |w| self.try_take(w, tag, 0)).await
+    }
+
+    /// Reserve up to `n` cells in this window for a burst that we're
+    /// about to send.
+    ///
+    /// We never wait: if fewer than `n` cells are available, the
+    /// [`Reservation`] holds as many as there are (possibly none).  While
+    /// the window is paused, no cells are available.
+    /// Until they are consumed with [`Reservation::consume`], nobody
+    /// else can take the reserved cells.  Any that are left over go
+    /// back to the window when the Reservation is dropped.
+    pub(crate) async fn reserve(&mut self, n: u16) -> Reservation<P, T> {
+        let w = self.w.lock().await;
+        let n = if self.signals.priority_waiters.load(Ordering::SeqCst) == 0 {
+            n.min(self.available(&w))
+        } else {
+            // Leave the room for whoever is waiting with priority.
+            0
+        };
+        self.signals.reserved.fetch_add(n.into(), Ordering::SeqCst);
+        Reservation {
+            window: self.new_ref(),
+            remaining: n,
+            bytes: 0,
+        }
+    }
+
+    /// Reserve a single cell in this window, along with `n_bytes` of its
+    /// byte budget, for a cell that we're about to send.
+    ///
+    /// Unlike [`SendWindow::reserve`], this waits for room, as
+    /// [`SendWindow::take_bytes`] does.  That way, a caller that can't
+    /// know a cell's tag until it has done something else (like
+    /// encrypting the cell) can wait for the window first, without
+    /// holding up anybody else while it does.
+    pub(crate) async fn reserve_cell(&mut self, n_bytes: usize) -> Result<Reservation<P, T>> {
+        self.reserve_cell_impl(n_bytes, false).await
+    }
+
+    /// As [`SendWindow::reserve_cell`], but wait ahead of any ordinary
+    /// takes or reservations, as [`SendWindow::take_priority`] does.
+    pub(crate) async fn reserve_cell_priority(
+        &mut self,
+        n_bytes: usize,
+    ) -> Result<Reservation<P, T>> {
+        self.reserve_cell_impl(n_bytes, true).await
+    }
+
+    /// Helper: implement [`SendWindow::reserve_cell`] and
+    /// [`SendWindow::reserve_cell_priority`].
+    async fn reserve_cell_impl(
+        &mut self,
+        n_bytes: usize,
+        priority: bool,
+    ) -> Result<Reservation<P, T>> {
+        self.wait_until(priority, |w| self.try_reserve(w, n_bytes))
+            .await?;
+        Ok(Reservation {
+            window: self.new_ref(),
+            remaining: 1,
+            bytes: n_bytes,
+        })
+    }
+
+    /// Helper: call `attempt` on this window until it returns something
+    /// other than `Ok(None)`, waiting for room in between.
+    ///
+    /// If `priority` is false, we don't make any attempts while a
+    /// priority take is waiting.
+    async fn wait_until<R, F>(&self, priority: bool, mut attempt: F) -> Result<R>
+    where
+        F: FnMut(&mut SendWindowInner<T>) -> Result<Option<R>>,
+    {
+        let _waiter = if priority {
+            Some(PriorityWaiter::new(&self.signals))
+        } else {
+            None
+        };
         loop {
             let wait_on = {
                 let mut w = self.w.lock().await;
-                if let Some(val) = w.window.checked_sub(1) {
-                    w.window = val;
-                    if w.window % P::increment() == 0 {
-                        // We record this tag.
-                        // TODO: I'm not saying that this cell in particular
-                        // matches the spec, but Tor seems to like it.
-                        w.tags.push_back(tag.clone());
+                // We listen before we check, so that we can't miss the
+                // notification from a priority waiter that goes away.
+                let listener = self.signals.unblock.listen();
+                if priority || self.signals.priority_waiters.load(Ordering::SeqCst) == 0 {
+                    if let Some(val) = attempt(&mut w)? {
+                        return Ok(val);
                     }
-
-                    return Ok(val);
                 }
-
-                // Window is zero; can't send yet.
-                w.unblock.listen()
+                listener
             };
 
             // Wait on this event while _not_ holding the lock.
+            let _parked = Parked::new(&self.signals);
             wait_on.await;
         }
     }
 
+    /// Return true if any take on this window is currently waiting for
+    /// room.
+    ///
+    /// This is meant for diagnosing stalls, and for applying
+    /// backpressure above a stream.  A waiter that has been woken up by a
+    /// SENDME still counts as waiting until it runs.
+    pub(crate) fn is_blocked(&self) -> bool {
+        self.signals.parked.load(Ordering::SeqCst) > 0
+    }
+
+    /// Return the number of [`SendWindow::take`] calls that would
+    /// succeed right now without waiting.
+    ///
+    /// This is the current window, less any cells held by
+    /// [`Reservation`]s, or zero if the window is paused.  (It doesn't
+    /// account for a byte budget: see [`SendWindow::take_bytes`].)
+    ///
+    /// This is only a snapshot: other handles to this window can take
+    /// from it, or a SENDME can refill it, as soon as we return.
+    pub(crate) async fn headroom(&self) -> u16 {
+        let w = self.w.lock().await;
+        self.available(&w)
+    }
+
+    /// Helper: return the number of cells in the window `w` that aren't
+    /// reserved, or zero if the window is paused.
+    fn available(&self, w: &SendWindowInner<T>) -> u16 {
+        if self.signals.paused.load(Ordering::SeqCst) {
+            return 0;
+        }
+        let reserved = self.signals.reserved.load(Ordering::SeqCst);
+        let available = usize::from(w.window).saturating_sub(reserved);
+        u16::try_from(available).unwrap_or(u16::MAX)
+    }
+
+    /// Helper: check whether we can use one more cell from the window
+    /// `w`, and consume `n_bytes` from its byte budget if so.
+    ///
+    /// Return false if the window was paused, empty (or all reserved), or
+    /// the budget was full.  Give an error if the window has been closed.
+    fn check_room(&self, w: &SendWindowInner<T>, n_bytes: usize) -> Result<bool> {
+        if self.signals.closed.load(Ordering::SeqCst) {
+            return Err(Error::CircuitClosed);
+        }
+        if self.available(w) == 0 {
+            return Ok(false);
+        }
+        if let Some(budget) = &self.signals.byte_budget {
+            if !budget.try_consume(n_bytes) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Helper: remove one item from the window `w` if it has any that
+    /// aren't reserved, and `n_bytes` more bytes fit in its byte budget,
+    /// recording `tag` if we'll need it later.
+    ///
+    /// Return the number of cells left in the window, or None if there
+    /// wasn't room.  Give an error if the window has been closed.
+    fn try_take(&self, w: &mut SendWindowInner<T>, tag: &T, n_bytes: usize) -> Result<Option<u16>> {
+        if !self.check_room(w, n_bytes)? {
+            return Ok(None);
+        }
+        let result = self.record_take(w, tag, n_bytes);
+        if let (Err(_), Some(budget)) = (&result, &self.signals.byte_budget) {
+            budget.release(n_bytes);
+        }
+        result.map(Some)
+    }
+
+    /// Helper: reserve one cell in the window `w`, and `n_bytes` of its
+    /// byte budget, if there's room.
+    ///
+    /// Return None if there wasn't room.  Give an error if the window has
+    /// been closed.
+    fn try_reserve(&self, w: &mut SendWindowInner<T>, n_bytes: usize) -> Result<Option<()>> {
+        if !self.check_room(w, n_bytes)? {
+            return Ok(None);
+        }
+        self.signals.reserved.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(()))
+    }
+
+    /// Helper: remove one item from the window `w`, which must not be
+    /// empty, recording `tag` if we'll need it later, and `n_bytes` so
+    /// that we can release them from the byte budget (which the caller
+    /// must already have consumed them from) once they're acknowledged.
+    ///
+    /// Return the number of cells left in the window.
+    fn record_take(&self, w: &mut SendWindowInner<T>, tag: &T, n_bytes: usize) -> Result<u16> {
+        let val = w
+            .window
+            .checked_sub(1)
+            .ok_or_else(|| Error::InternalError("took from an empty send window".into()))?;
+        let tracking_bytes = self.signals.byte_budget.is_some();
+        if val % w.limits.increment() == 0 {
+            // We record this tag.
+            // TODO: I'm not saying that this cell in particular
+            // matches the spec, but Tor seems to like it.
+            if Self::outstanding_sendmes(w) >= w.limits.max_tags() {
+                return Err(Error::InternalError(
+                    "too many unacknowledged sendme tags".into(),
+                ));
+            }
+            if w.authenticated {
+                w.tags.push_back(tag.clone());
+            } else {
+                w.untagged += 1;
+            }
+            if tracking_bytes {
+                // The SENDME for this cell acknowledges it and every
+                // cell since the last one we recorded.
+                let bytes = std::mem::take(&mut w.unmarked_bytes) + n_bytes;
+                w.sendme_bytes.push_back(bytes);
+            }
+        } else if tracking_bytes {
+            w.unmarked_bytes += n_bytes;
+        }
+        w.window = val;
+        w.cells_sent += 1;
+        Ok(val)
+    }
+
     /// Handle an incoming sendme with a provided tag.
     ///
-    /// If the tag is None, then we don't enforce tag requirements. (We can
-    /// remove this option once we no longer support getting SENDME cells
-    /// from relays without the FlowCtrl=1 protocol.)
+    /// If the tag is None, or this window is unauthenticated, then we don't
+    /// enforce tag requirements. (We can remove this option once we no
+    /// longer support getting SENDME cells from relays without the
+    /// FlowCtrl=1 protocol.)
     ///
     /// On success, return the number of cells left in the window.
     ///
-    /// On failure, return None: the caller should close the stream
+    /// On failure, return an error: the caller should close the stream
     /// or circuit with a protocol error.
-    #[must_use = "didn't check whether SENDME tag was right."]
-    pub(crate) async fn put(&mut self, tag: Option<T>) -> Option<u16> {
+    pub(crate) async fn put(&mut self, tag: Option<T>) -> Result<u16> {
         let mut w = self.w.lock().await;
 
-        match (w.tags.front(), tag) {
-            (Some(t), Some(tag)) if t == &tag => {} // this is the right tag.
-            (Some(_), None) => {}                   // didn't need a tag.
-            _ => {
-                return None;
-            } // Bad tag or unexpected sendme.
+        if !w.authenticated {
+            // We can't check the tag, but the other side still needs to
+            // have something to acknowledge.
+            if w.untagged == 0 {
+                return Err(Error::CircProto(
+                    "unexpected sendme: no data outstanding".into(),
+                ));
+            }
+        } else {
+            match (w.tags.front(), tag) {
+                // This is the right tag.
+                (Some(t), Some(tag)) if t.tag_eq(&tag) => {}
+                // We were expecting a different tag.
+                (Some(_), Some(_)) => {
+                    return Err(Error::CircProto("bad auth tag on circuit sendme".into()));
+                }
+                // Didn't need a tag.
+                (Some(_), None) => {}
+                // We haven't sent enough data for the other side to
+                // acknowledge, whether or not it sent a tag.
+                (None, Some(_)) | (None, None) => {
+                    return Err(Error::CircProto(
+                        "unexpected sendme: no data outstanding".into(),
+                    ));
+                }
+            }
         }
-        w.tags.pop_front();
-
         let was_zero = w.window == 0;
 
-        let v = w.window.checked_add(P::increment())?;
+        let v = w
+            .window
+            .checked_add(w.limits.increment())
+            .ok_or_else(|| Error::CircProto("sendme would overflow window".into()))?;
+        // Only now that we know the SENDME is good do we forget about the
+        // tag that it matched.
+        if w.authenticated {
+            w.tags.pop_front();
+        } else {
+            w.untagged -= 1;
+        }
         w.window = v;
+        w.sendmes_received += 1;
+        if Self::outstanding_sendmes(&w) == 0 {
+            self.signals.drained.notify(usize::MAX);
+        }
+
+        // Only warn once per window: if the other side disagrees with us
+        // about which cells count, it'll keep on doing so.
+        let accounting = self.accounting_for(&w);
+        if accounting.sendme_lag() < 0 && !w.warned_about_lag {
+            w.warned_about_lag = true;
+            warn!(
+                "Got SENDMEs for cells that we didn't count as sent: {:?}",
+                accounting
+            );
+        }
+
+        // The sendme acknowledges the cells up to the one whose tag we
+        // recorded: their bytes no longer count against the budget.
+        let released_bytes = w.sendme_bytes.pop_front().unwrap_or(0);
+        if let Some(budget) = &self.signals.byte_budget {
+            budget.release(released_bytes);
+        }
+
+        if was_zero || released_bytes > 0 {
+            self.signals.unblock.notify(usize::MAX)
+        }
+        Ok(v)
+    }
+
+    /// Close this window, because the circuit or stream that it belongs
+    /// to is going away.
+    ///
+    /// Afterwards, every take from this window (including any that are
+    /// waiting for room) fails with [`Error::CircuitClosed`], as does
+    /// every call to [`SendWindow::drain`].
+    pub(crate) fn close(&self) {
+        self.signals.closed.store(true, Ordering::SeqCst);
+        self.signals.unblock.notify(usize::MAX);
+        self.signals.drained.notify(usize::MAX);
+    }
+
+    /// Wait until every SENDME that we're expecting has arrived, or
+    /// until `timeout` finishes.
+    ///
+    /// This is meant for shutting down a circuit gracefully: the caller
+    /// stops sending, drains the window, and then closes the circuit
+    /// knowing whether the other side got everything.  (Cells sent since
+    /// the last multiple of `increment()` don't count: nobody will ever
+    /// send a SENDME for them.)  Since this crate has no runtime of its
+    /// own, the caller supplies the timeout, usually as a sleep future.
+    ///
+    /// Return true if the window drained cleanly, and false if we timed
+    /// out first.  Give an error if the window is closed before it drains.
+    pub(crate) async fn drain<F>(&self, timeout: F) -> Result<bool>
+    where
+        F: Future<Output = ()>,
+    {
+        futures::pin_mut!(timeout);
+        loop {
+            let listener = {
+                let w = self.w.lock().await;
+                // Listen before we check, so that we can't miss a
+                // notification that happens in between.
+                let listener = self.signals.drained.listen();
+                if self.signals.closed.load(Ordering::SeqCst) {
+                    return Err(Error::CircuitClosed);
+                }
+                if Self::outstanding_sendmes(&w) == 0 {
+                    return Ok(true);
+                }
+                listener
+            };
+            if let Either::Right(_) = future::select(listener, timeout.as_mut()).await {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Return the number of cells left in this window.
+    ///
+    /// This doesn't change the window: unlike [`SendWindow::headroom`],
+    /// it ignores pauses and reservations.  It's only a snapshot, meant
+    /// for telemetry and debugging.
+    pub(crate) async fn window(&self) -> u16 {
+        self.w.lock().await.window
+    }
+
+    /// Return a snapshot of this window and the counts of cells and
+    /// SENDMEs that should explain it.
+    pub(crate) async fn accounting(&self) -> WindowAccounting {
+        self.accounting_for(&*self.w.lock().await)
+    }
 
-        if was_zero {
-            w.unblock.notify(usize::MAX)
+    /// Helper: return the [`WindowAccounting`] for the window `w`.
+    fn accounting_for(&self, w: &SendWindowInner<T>) -> WindowAccounting {
+        WindowAccounting {
+            initial: w.initial,
+            window: w.window,
+            increment: w.limits.increment(),
+            cells_sent: w.cells_sent,
+            sendmes_received: w.sendmes_received,
+            bytes: self
+                .signals
+                .byte_budget
+                .as_ref()
+                .map(|b| (b.cap(), b.used())),
         }
-        Some(v)
     }
 
     /// For testing: get a copy of the current send window, and the
@@ -210,39 +953,310 @@ where
         let tags = inner.tags.iter().map(Clone::clone).collect();
         (inner.window, tags)
     }
+
+    /// For testing: panic if this window's state is inconsistent.
+    ///
+    /// We record a tag every time the window drops to a multiple of
+    /// the increment, and forget one on every SENDME, so the tags we
+    /// hold plus the multiples of the increment still below the window
+    /// can't add up to more than [`WindowLimits::max_tags`].  (They add
+    /// up to exactly that if the window started at the maximum.)
+    #[cfg(test)]
+    pub(crate) async fn check_invariants(&self) {
+        let inner = self.w.lock().await;
+        let limits = inner.limits;
+        assert!(inner.window <= limits.maximum());
+        let outstanding = Self::outstanding_sendmes(&inner);
+        assert!(outstanding <= limits.max_tags());
+        let unreached = limits.tags_below(inner.window);
+        assert!(outstanding + unreached <= limits.max_tags());
+        assert!(usize::from(inner.window) >= self.signals.reserved.load(Ordering::SeqCst));
+        let outstanding_bytes = self
+            .signals
+            .byte_budget
+            .as_ref()
+            .map_or(0, ByteBudget::used);
+        let tracked_bytes = inner.sendme_bytes.iter().sum::<usize>() + inner.unmarked_bytes;
+        assert_eq!(outstanding_bytes, tracked_bytes);
+        if self.signals.byte_budget.is_some() {
+            assert_eq!(inner.sendme_bytes.len(), outstanding);
+        }
+    }
+}
+
+/// A number of cells set aside in a [`SendWindow`] with
+/// [`SendWindow::reserve`] or [`SendWindow::reserve_cell`].
+///
+/// Any reserved cells that haven't been consumed (and any bytes reserved
+/// along with them) are returned to the window when this is dropped.
+pub(crate) struct Reservation<P, T>
+where
+    P: WindowParams,
+    T: SendmeTag,
+{
+    /// The window that the cells are reserved in.
+    window: SendWindow<P, T>,
+    /// The number of reserved cells that we haven't consumed yet.
+    remaining: u16,
+    /// The number of bytes reserved in the window's byte budget that we
+    /// haven't consumed yet.
+    bytes: usize,
+}
+
+impl<P, T> Reservation<P, T>
+where
+    P: WindowParams,
+    T: SendmeTag,
+{
+    /// Return the number of reserved cells that we haven't consumed yet.
+    pub(crate) fn remaining(&self) -> u16 {
+        self.remaining
+    }
+
+    /// Use one of the reserved cells (since we've sent a cell), as
+    /// [`SendWindow::take`].  Any bytes reserved along with the cells
+    /// count towards the first one we consume.
+    ///
+    /// Return the number of cells left in the window, or None (and do
+    /// nothing) if there are no reserved cells left.  Cells reserved
+    /// before the window was paused can still be used; cells in a window
+    /// that has been closed can't.
+    pub(crate) async fn consume(&mut self, tag: &T) -> Result<Option<u16>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        if self.window.signals.closed.load(Ordering::SeqCst) {
+            return Err(Error::CircuitClosed);
+        }
+        let mut w = self.window.w.lock().await;
+        let val = self.window.record_take(&mut w, tag, self.bytes)?;
+        self.window.signals.reserved.fetch_sub(1, Ordering::SeqCst);
+        self.remaining -= 1;
+        self.bytes = 0;
+        Ok(Some(val))
+    }
+}
+
+impl<P, T> Drop for Reservation<P, T>
+where
+    P: WindowParams,
+    T: SendmeTag,
+{
+    fn drop(&mut self) {
+        let signals = &self.window.signals;
+        if self.remaining > 0 {
+            signals
+                .reserved
+                .fetch_sub(self.remaining.into(), Ordering::SeqCst);
+        }
+        if let Some(budget) = &signals.byte_budget {
+            budget.release(self.bytes);
+        }
+        if self.remaining > 0 || self.bytes > 0 {
+            // Somebody may be waiting for what we didn't use.
+            signals.unblock.notify(usize::MAX);
+        }
+    }
+}
+
+/// What we need to do about a cell that we just counted on a
+/// [`RecvWindow`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SendmeDue {
+    /// Nothing: we don't owe a SENDME yet.
+    No,
+    /// Send a SENDME right away.
+    Now,
+    /// We owe a SENDME, but the window is paused: send it when we
+    /// resume.
+    Deferred,
+}
+
+/// The first version of the FlowCtrl subprotocol that lets the receiving
+/// side decide how many cells each SENDME acknowledges.
+///
+/// With older versions, every SENDME acknowledges exactly the increment
+/// from the window's limits, and the other side relies on that.
+const ADAPTIVE_FLOWCTRL_VERSION: u8 = 2;
+
+/// If it took less than this long to consume one increment's worth of
+/// cells, an adaptive [`RecvWindow`] grows its increment.
+const ADAPTIVE_FAST: Duration = Duration::from_millis(100);
+
+/// If it took more than this long to consume one increment's worth of
+/// cells, an adaptive [`RecvWindow`] shrinks its increment.
+const ADAPTIVE_SLOW: Duration = Duration::from_secs(1);
+
+/// Limits on how an adaptive [`RecvWindow`] may size its increment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct AdaptiveBounds {
+    /// The smallest number of cells that one SENDME may acknowledge.
+    min_increment: u16,
+    /// The largest number of cells that one SENDME may acknowledge.
+    max_increment: u16,
+}
+
+impl AdaptiveBounds {
+    /// Construct a new AdaptiveBounds.  Give an error unless
+    /// `0 < min_increment <= max_increment`.
+    pub(crate) fn new(min_increment: u16, max_increment: u16) -> Result<Self> {
+        if min_increment == 0 || min_increment > max_increment {
+            return Err(Error::BadConfig(
+                "invalid bounds for adaptive receive window".into(),
+            ));
+        }
+        Ok(AdaptiveBounds {
+            min_increment,
+            max_increment,
+        })
+    }
+
+    /// Return the smallest and largest number of cells that one SENDME
+    /// may acknowledge.
+    pub(crate) fn increments(&self) -> (u16, u16) {
+        (self.min_increment, self.max_increment)
+    }
+}
+
+/// State for a [`RecvWindow`] in adaptive mode.
+#[derive(Clone, Debug)]
+struct AdaptiveState {
+    /// The limits on our increment.
+    bounds: AdaptiveBounds,
+    /// The number of cells that our next SENDME will acknowledge.
+    increment: u16,
+    /// The number of cells we've received since we last decided to send
+    /// a SENDME.
+    since_sendme: u16,
+    /// When we last sent a SENDME, if we have.
+    last_sendme: Option<Instant>,
 }
 
 /// Structure to track when we need to send SENDME cells for incoming data.
+///
+/// By default, a RecvWindow uses the fixed increment from its limits.
+/// In adaptive mode, which is only available when the other side has
+/// negotiated a new enough FlowCtrl version, it instead adjusts the
+/// number of cells that each SENDME acknowledges according to how fast
+/// we're consuming cells: the faster we consume, the larger the
+/// increment (and the less often we send SENDMEs).
 #[derive(Clone)]
 pub(crate) struct RecvWindow<P: WindowParams> {
     /// Number of cells that we'd be willing to receive on this window
     /// before sending a SENDME.
     window: u16,
+    /// The maximum and increment for this window.
+    limits: WindowLimits,
+    /// If present, state for adaptive mode.
+    adaptive: Option<AdaptiveState>,
+    /// True if this window has been paused with [`FlowControl::pause`].
+    paused: bool,
+    /// The number of SENDMEs that came due while we were paused, and
+    /// that we haven't sent.
+    deferred_sendmes: u16,
     /// Marker type to tell the compiler that the P type is used.
     _dummy: std::marker::PhantomData<P>,
 }
 
 impl<P: WindowParams> RecvWindow<P> {
     /// Create a new RecvWindow.
+    ///
+    /// Outside of tests, windows come from [`FlowControl::windows`].
+    #[cfg(test)]
     pub(crate) fn new(window: u16) -> RecvWindow<P> {
+        Self::new_with_limits(window, WindowLimits::default_for::<P>())
+    }
+
+    /// Create a new RecvWindow that uses `limits` in place of the
+    /// defaults from `P`.
+    pub(crate) fn new_with_limits(window: u16, limits: WindowLimits) -> RecvWindow<P> {
         RecvWindow {
             window,
+            limits,
+            adaptive: None,
+            paused: false,
+            deferred_sendmes: 0,
             _dummy: std::marker::PhantomData,
         }
     }
 
+    /// Switch this window to adaptive mode within `bounds`, if
+    /// `flowctrl_version` (the FlowCtrl subprotocol version we
+    /// negotiated with the other side) allows it.
+    ///
+    /// With an older FlowCtrl version, this does nothing.
+    fn make_adaptive(&mut self, flowctrl_version: u8, bounds: AdaptiveBounds) {
+        if flowctrl_version >= ADAPTIVE_FLOWCTRL_VERSION {
+            // An increment larger than the window would never come due.
+            let max_increment = bounds.max_increment.min(self.window.max(1));
+            let bounds = AdaptiveBounds {
+                min_increment: bounds.min_increment.min(max_increment),
+                max_increment,
+            };
+            let increment = self
+                .limits
+                .increment()
+                .clamp(bounds.min_increment, bounds.max_increment);
+            self.adaptive = Some(AdaptiveState {
+                bounds,
+                increment,
+                since_sendme: 0,
+                last_sendme: None,
+            });
+        }
+    }
+
+    /// Return the number of cells that the other side can still send
+    /// before it needs a SENDME from us.
+    pub(crate) fn window(&self) -> u16 {
+        self.window
+    }
+
+    /// Return the number of cells that our next SENDME will acknowledge.
+    pub(crate) fn increment(&self) -> u16 {
+        match &self.adaptive {
+            Some(a) => a.increment,
+            None => self.limits.increment(),
+        }
+    }
+
     /// Called when we've just sent a cell; return true if we need to send
     /// a sendme, and false otherwise.
     ///
     /// Returns None if we should not have sent the cell, and we just
     /// violated the window.
+    ///
+    /// While this window is paused, we never ask for a SENDME: we count
+    /// the ones that come due, and [`FlowControl::resume`] reports them.
     pub(crate) fn take(&mut self) -> Result<bool> {
+        Ok(self.take_deferrable()? == SendmeDue::Now)
+    }
+
+    /// As [`RecvWindow::take`], but also tell the caller when a SENDME
+    /// comes due while this window is paused.
+    ///
+    /// A caller that pauses this window needs to know that, so it can
+    /// remember what each deferred SENDME will have to carry.
+    pub(crate) fn take_deferrable(&mut self) -> Result<SendmeDue> {
         let v = self.window.checked_sub(1);
         if let Some(x) = v {
             self.window = x;
-            // TODO: same note as in SendWindow.take(). I don't know if
-            // this truly matches the spec, but tor accepts it.
-            Ok(x % P::increment() == 0)
+            let sendme_due = if let Some(a) = &mut self.adaptive {
+                a.since_sendme += 1;
+                a.since_sendme >= a.increment
+            } else {
+                // TODO: same note as in SendWindow.take(). I don't know if
+                // this truly matches the spec, but tor accepts it.
+                x % self.limits.increment() == 0
+            };
+            if !sendme_due {
+                Ok(SendmeDue::No)
+            } else if self.paused {
+                self.deferred_sendmes += 1;
+                Ok(SendmeDue::Deferred)
+            } else {
+                Ok(SendmeDue::Now)
+            }
         } else {
             Err(Error::CircProto(
                 "Received a data cell in violation of a window".into(),
@@ -264,8 +1278,188 @@ impl<P: WindowParams> RecvWindow<P> {
     }
 
     /// Called when we've just sent a SENDME.
-    pub(crate) fn put(&mut self) {
-        self.window = self.window.checked_add(P::increment()).unwrap();
+    ///
+    /// Gives an error if this would overflow the window.
+    pub(crate) fn put(&mut self) -> crate::Result<()> {
+        self.put_at(Instant::now())
+    }
+
+    /// Called when we've just sent a SENDME at time `now`.
+    ///
+    /// In adaptive mode, this is where we resize the increment for the
+    /// next SENDME.
+    fn put_at(&mut self, now: Instant) -> crate::Result<()> {
+        self.window = self
+            .window
+            .checked_add(self.increment())
+            .ok_or_else(|| crate::Error::CircProto("Receive window overflowed".into()))?;
+        if let Some(a) = &mut self.adaptive {
+            a.since_sendme = 0;
+            if let Some(last) = a.last_sendme {
+                let elapsed = now.saturating_duration_since(last);
+                if elapsed < ADAPTIVE_FAST {
+                    a.increment = a.increment.saturating_mul(2);
+                } else if elapsed > ADAPTIVE_SLOW {
+                    a.increment /= 2;
+                }
+                a.increment = a
+                    .increment
+                    .clamp(a.bounds.min_increment, a.bounds.max_increment);
+            }
+            a.last_sendme = Some(now);
+        }
+        Ok(())
+    }
+}
+
+/// The settings for both directions of flow control on a circuit hop or
+/// a stream.
+///
+/// Use [`FlowControl::windows`] to turn these into a matched [`SendWindow`]
+/// and [`RecvWindow`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WindowSettings {
+    /// Initial value for the send window.
+    send_window: u16,
+    /// Initial value for the receive window.
+    recv_window: u16,
+    /// If present, the byte budget for the send window.
+    send_byte_budget: Option<usize>,
+    /// If present, the limits to use for both windows in place of the
+    /// defaults for their [`WindowParams`].
+    limits: Option<WindowLimits>,
+    /// True if the send window expects authenticated SENDMEs.
+    authenticated: bool,
+    /// If present, the FlowCtrl version we negotiated, and the bounds
+    /// for an adaptive receive window.
+    adaptive: Option<(u8, AdaptiveBounds)>,
+}
+
+impl WindowSettings {
+    /// Construct a new WindowSettings with the given initial windows, the
+    /// default limits, no byte budget, and authenticated SENDMEs.
+    pub(crate) fn new(send_window: u16, recv_window: u16) -> Self {
+        WindowSettings {
+            send_window,
+            recv_window,
+            send_byte_budget: None,
+            limits: None,
+            authenticated: true,
+            adaptive: None,
+        }
+    }
+
+    /// Limit the send window to `budget` outstanding bytes, or remove
+    /// the limit if `budget` is None.
+    pub(crate) fn send_byte_budget(&mut self, budget: Option<usize>) -> &mut Self {
+        self.send_byte_budget = budget;
+        self
+    }
+
+    /// Use `limits` for both windows, in place of the defaults for their
+    /// [`WindowParams`].
+    pub(crate) fn limits(&mut self, limits: WindowLimits) -> &mut Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Don't expect the SENDMEs that refill the send window to carry
+    /// tags, as with [`SendWindow::new_unauthenticated`].
+    pub(crate) fn unauthenticated(&mut self) -> &mut Self {
+        self.authenticated = false;
+        self
+    }
+
+    /// Make the receive window adaptive within `bounds`, if
+    /// `flowctrl_version` allows it.  (See [`RecvWindow`].)
+    pub(crate) fn adaptive(&mut self, flowctrl_version: u8, bounds: AdaptiveBounds) -> &mut Self {
+        self.adaptive = Some((flowctrl_version, bounds));
+        self
+    }
+}
+
+/// Factory for matched pairs of send and receive windows.
+///
+/// Building both windows from the same [`WindowSettings`] and the same
+/// [`WindowParams`] keeps the two directions from disagreeing about their
+/// increments and maximums.
+pub(crate) struct FlowControl;
+
+impl FlowControl {
+    /// Construct a new send window and receive window from `params`.
+    ///
+    /// Initial windows larger than the maximum from the limits (by
+    /// default, `P::maximum()`) are reduced to it.
+    pub(crate) fn windows<P, T>(params: &WindowSettings) -> (SendWindow<P, T>, RecvWindow<P>)
+    where
+        P: WindowParams,
+        T: SendmeTag,
+    {
+        let limits = params.limits.unwrap_or_else(WindowLimits::default_for::<P>);
+        let send_window = params.send_window.min(limits.maximum());
+        let recv_window = params.recv_window.min(limits.maximum());
+        let budget = params.send_byte_budget;
+        let sendw = if params.authenticated {
+            SendWindow::new_with_limits(send_window, budget, limits)
+        } else {
+            SendWindow::new_unauthenticated(send_window, budget, limits)
+        };
+        let mut recvw = RecvWindow::new_with_limits(recv_window, limits);
+        if let Some((version, bounds)) = params.adaptive {
+            recvw.make_adaptive(version, bounds);
+        }
+        (sendw, recvw)
+    }
+
+    /// Pause both directions of flow control on a matched pair of
+    /// windows: we stop sending cells that count towards `sendw`, and
+    /// stop acknowledging the cells that we receive on `recvw`.
+    ///
+    /// While paused, every take from `sendw` waits (even if there's room
+    /// in the window), and `recvw` never asks for a SENDME.  We still
+    /// accept SENDMEs on `sendw`, since they only acknowledge cells that
+    /// we already sent.  A take that finished before the pause may still
+    /// have its cell on the way out.
+    ///
+    /// # Protocol implications
+    ///
+    /// The other side doesn't know that we've paused.  It keeps sending
+    /// until its own send window (the mirror of `recvw`) runs out, and
+    /// then waits for SENDMEs that we won't send until we resume: a
+    /// long pause stalls it, but doesn't violate the protocol.  If it
+    /// keeps sending anyway, `recvw` reports the window violation as
+    /// usual.
+    ///
+    /// Each SENDME that came due while we were paused has to be sent
+    /// when we resume, or the other side will wait forever.  On a
+    /// circuit hop with authenticated SENDMEs (FlowCtrl=1), each one
+    /// must still carry the digest of the cell that made it due, so the
+    /// caller needs to remember those digests while paused: see
+    /// [`RecvWindow::take_deferrable`].
+    pub(crate) fn pause<P, T>(sendw: &SendWindow<P, T>, recvw: &mut RecvWindow<P>)
+    where
+        P: WindowParams,
+        T: SendmeTag,
+    {
+        recvw.paused = true;
+        sendw.signals.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume both directions of flow control on a pair of windows paused
+    /// with [`FlowControl::pause`].
+    ///
+    /// Return the number of SENDMEs that came due on `recvw` while it was
+    /// paused.  The caller must send them right away, calling
+    /// [`RecvWindow::put`] after each one, as for any other SENDME.
+    pub(crate) fn resume<P, T>(sendw: &SendWindow<P, T>, recvw: &mut RecvWindow<P>) -> u16
+    where
+        P: WindowParams,
+        T: SendmeTag,
+    {
+        recvw.paused = false;
+        sendw.signals.paused.store(false, Ordering::SeqCst);
+        sendw.signals.unblock.notify(usize::MAX);
+        std::mem::take(&mut recvw.deferred_sendmes)
     }
 }
 
@@ -279,6 +1473,18 @@ pub(crate) fn cell_counts_towards_windows(cell: &RelayCell) -> bool {
     msg_counts_towards_windows(cell.msg())
 }
 
+/// Return the number of bytes in this cell that count towards a send
+/// window's byte budget.
+///
+/// That's the length of the data in a DATA cell, and zero for anything
+/// else.
+pub(crate) fn cell_data_len(cell: &RelayCell) -> usize {
+    match cell.msg() {
+        RelayMsg::Data(d) => d.as_ref().len(),
+        _ => 0,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -312,7 +1518,7 @@ mod test {
         assert!(w.decrement_n(123).is_ok());
         assert_eq!(w.window, 327);
 
-        w.put();
+        w.put().unwrap();
         assert_eq!(w.window, 377);
 
         // failing decrement.
@@ -322,10 +1528,256 @@ mod test {
         assert!(w.take().is_err());
     }
 
+    #[async_test]
+    async fn flow_control_pair() -> Result<()> {
+        let params = WindowSettings::new(500, 500);
+        let (mut sendw, mut recvw): (StreamSendWindow, StreamRecvWindow) =
+            FlowControl::windows(&params);
+        assert_eq!(sendw.w.lock().await.window, 500);
+        assert_eq!(recvw.window, 500);
+        assert_eq!(recvw.limits.increment(), StreamParams::increment());
+
+        // The receiver asks for a SENDME after exactly as many cells as
+        // the sender expects one for.
+        for _ in 1..StreamParams::increment() {
+            sendw.take(&()).await?;
+            assert_eq!(recvw.take()?, false);
+        }
+        sendw.take(&()).await?;
+        assert_eq!(recvw.take()?, true);
+        recvw.put()?;
+        assert_eq!(sendw.put(Some(())).await?, 500);
+        assert_eq!(recvw.window, 500);
+
+        // Windows can't start above the maximum.
+        let params = WindowSettings::new(2000, 1500);
+        let (sendw, recvw): (CircSendWindow, CircRecvWindow) = FlowControl::windows(&params);
+        assert_eq!(sendw.w.lock().await.window, CircParams::maximum());
+        assert!(sendw.signals.byte_budget.is_none());
+        assert_eq!(recvw.window, CircParams::maximum());
+        assert_eq!(recvw.limits.increment(), CircParams::increment());
+        assert!(recvw.adaptive.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn recvwindow_adaptive() {
+        let bounds = AdaptiveBounds::new(20, 200).unwrap();
+        assert!(AdaptiveBounds::new(0, 200).is_err());
+        assert!(AdaptiveBounds::new(30, 20).is_err());
+
+        // With an old FlowCtrl version, we don't adapt.
+        let mut params = WindowSettings::new(500, 500);
+        params.adaptive(1, bounds);
+        let (_, w): (StreamSendWindow, StreamRecvWindow) = FlowControl::windows(&params);
+        assert!(w.adaptive.is_none());
+        assert_eq!(w.increment(), 50);
+
+        params.adaptive(2, bounds);
+        let (_, mut w): (StreamSendWindow, StreamRecvWindow) = FlowControl::windows(&params);
+        assert_eq!(w.increment(), 50);
+
+        // Consume cells quickly for a while, then slowly, then quickly
+        // again, and make sure we stay in bounds.
+        let mut now = Instant::now();
+        let delays = [10_u64, 10, 10, 10, 10, 10, 5000, 5000, 5000, 5000, 10, 10];
+        let mut increments = Vec::new();
+        for delay in delays.iter() {
+            let increment = w.increment();
+            for _ in 1..increment {
+                assert_eq!(w.take().unwrap(), false);
+            }
+            assert_eq!(w.take().unwrap(), true);
+            assert_eq!(w.window, 500 - increment);
+            now += Duration::from_millis(*delay);
+            w.put_at(now).unwrap();
+            assert_eq!(w.window, 500);
+            assert!((20..=200).contains(&w.increment()));
+            increments.push(w.increment());
+        }
+        assert_eq!(
+            increments,
+            vec![50, 100, 200, 200, 200, 200, 100, 50, 25, 20, 40, 80]
+        );
+    }
+
+    #[async_test]
+    async fn flow_control_settings() -> Result<()> {
+        // Both windows use the same limits, and the send window gets the
+        // byte budget.
+        let limits = WindowLimits::new(1200, 40);
+        let mut params = WindowSettings::new(2000, 1000);
+        params.limits(limits).send_byte_budget(Some(4096));
+        let (mut sendw, recvw): (CircSendWindow, CircRecvWindow) = FlowControl::windows(&params);
+        assert_eq!(sendw.window().await, 1200);
+        assert_eq!(recvw.window(), 1000);
+        assert_eq!(sendw.w.lock().await.limits, limits);
+        assert_eq!(recvw.limits, limits);
+        assert_eq!(
+            sendw.signals.byte_budget.as_ref().map(ByteBudget::cap),
+            Some(4096)
+        );
+        assert!(sendw.w.lock().await.authenticated);
+        for _ in 0..40 {
+            sendw.take(&[0; 20]).await?;
+        }
+        assert_eq!(sendw.w.lock().await.tags.len(), 1);
+
+        // Unauthenticated windows don't keep tags.
+        params.unauthenticated();
+        let (mut sendw, _recvw): (CircSendWindow, CircRecvWindow) = FlowControl::windows(&params);
+        assert!(!sendw.w.lock().await.authenticated);
+        for _ in 0..40 {
+            sendw.take(&[0; 20]).await?;
+        }
+        assert!(sendw.w.lock().await.tags.is_empty());
+        assert_eq!(sendw.put(None).await?, 1200);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn flow_control_pause() -> Result<()> {
+        let params = WindowSettings::new(500, 500);
+        let (mut sendw, mut recvw): (StreamSendWindow, StreamRecvWindow) =
+            FlowControl::windows(&params);
+        sendw.take(&()).await?;
+
+        FlowControl::pause(&sendw, &mut recvw);
+
+        // No SENDMEs while we're paused, though two come due...
+        let mut n_deferred = 0;
+        for _ in 0..120 {
+            match recvw.take_deferrable()? {
+                SendmeDue::No => {}
+                SendmeDue::Deferred => n_deferred += 1,
+                SendmeDue::Now => panic!("SENDME due while paused"),
+            }
+        }
+        assert_eq!(n_deferred, 2);
+        assert_eq!(recvw.window, 380);
+        // ... and we can't send, even with room in the window.
+        assert!(sendw.take(&()).now_or_never().is_none());
+        assert_eq!(sendw.reserve(10).await.remaining(), 0);
+        assert_eq!(sendw.headroom().await, 0);
+
+        // Now resume: we owe the SENDMEs that came due.
+        assert_eq!(FlowControl::resume(&sendw, &mut recvw), 2);
+        recvw.put()?;
+        recvw.put()?;
+        assert_eq!(recvw.window, 480);
+        assert_eq!(FlowControl::resume(&sendw, &mut recvw), 0);
+
+        // And everything works normally again.
+        assert_eq!(sendw.take(&()).await?, 498);
+        assert_eq!(sendw.headroom().await, 498);
+        for _ in 0..29 {
+            assert!(!recvw.take()?);
+        }
+        assert!(recvw.take()?);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_long_tags() -> Result<()> {
+        // A window for an authenticator with 32-byte tags.  (Putting a
+        // 20-byte CircTag on it wouldn't compile.)
+        let mut w: SendWindow<CircParams, [u8; 32]> = SendWindow::new(1000);
+        let tag_for = |n: u8| {
+            let mut tag = [n; 32];
+            tag[0] = 0xff;
+            tag
+        };
+        for n in 1..=200_u8 {
+            // As the crypto layer would hand them to us, as a slice.
+            let tag = tag_for(n);
+            w.take(&tag_from_slice::<32>(&tag[..])?).await?;
+        }
+        assert_eq!(w.w.lock().await.window, 800);
+
+        // The tag of the 100th cell acknowledges the first 100 cells.
+        let wire_tag = tag_for(100);
+        let tag = tag_from_slice::<32>(&wire_tag[..])?;
+        assert_eq!(w.put(Some(tag)).await?, 900);
+        // A 32-byte tag with the wrong contents is refused...
+        let tag = tag_from_slice::<32>(&tag_for(201)[..])?;
+        assert!(matches!(w.put(Some(tag)).await, Err(Error::CircProto(_))));
+        // ... and so is a tag with the wrong length.
+        let e = tag_from_slice::<32>(&tag_for(200)[..20]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "circuit protocol violation: malformed tag on circuit sendme: expected 32 bytes, got 20"
+        );
+        let tag = tag_from_slice::<32>(&tag_for(200)[..])?;
+        assert_eq!(w.put(Some(tag)).await?, 1000);
+        Ok(())
+    }
+
     fn new_sendwindow() -> SendWindow<CircParams, &'static str> {
         SendWindow::new(1000)
     }
 
+    #[async_test]
+    async fn window_values() -> Result<()> {
+        let mut w = new_sendwindow();
+        assert_eq!(w.window().await, 1000);
+        for n in 1..=100 {
+            w.take(&"tag").await?;
+            assert_eq!(w.window().await, 1000 - n);
+        }
+        // Looking at the window didn't touch the tags.
+        assert_eq!(w.window_and_expected_tags().await.1, vec!["tag"]);
+        w.put(Some("tag")).await?;
+        assert_eq!(w.window().await, 900 + CircParams::increment());
+
+        let mut r: StreamRecvWindow = RecvWindow::new(500);
+        assert_eq!(r.window(), 500);
+        for _ in 0..50 {
+            r.take()?;
+        }
+        assert_eq!(r.window(), 450);
+        r.put()?;
+        assert_eq!(r.window(), 450 + StreamParams::increment());
+        Ok(())
+    }
+
+    #[test]
+    fn recvwindow_overflow() {
+        let mut w: StreamRecvWindow = RecvWindow::new(u16::MAX - 100);
+        w.put().unwrap();
+        assert_eq!(w.window, u16::MAX - 50);
+        w.put().unwrap();
+        assert_eq!(w.window, u16::MAX);
+        // One more would overflow: that's an error, and the window is
+        // unchanged.
+        assert!(matches!(w.put(), Err(Error::CircProto(_))));
+        assert_eq!(w.window, u16::MAX);
+    }
+
+    impl SendmeTag for &'static str {
+        fn tag_eq(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+
+    impl SendmeTag for u32 {
+        fn tag_eq(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+
+    #[test]
+    fn circ_tag_eq() {
+        let tag: CircTag = *b"twenty bytes of tag!";
+        assert!(tag.tag_eq(&tag));
+        let mut other = tag;
+        other[19] ^= 1;
+        assert!(!tag.tag_eq(&other));
+        other = tag;
+        other[0] ^= 0x80;
+        assert!(!tag.tag_eq(&other));
+        assert!(().tag_eq(&()));
+    }
+
     #[async_test]
     async fn sendwindow_basic() -> Result<()> {
         let mut w = new_sendwindow();
@@ -348,8 +1800,8 @@ mod test {
         assert_eq!(w.w.lock().await.tags.len(), 1);
 
         // Try putting a good tag.
-        let n = w.put(Some(&"and")).await;
-        assert_eq!(n, Some(999));
+        let n = w.put(Some(&"and")).await?;
+        assert_eq!(n, 999);
         assert_eq!(w.w.lock().await.tags.len(), 0);
 
         for _ in 0_usize..300 {
@@ -358,13 +1810,41 @@ mod test {
         assert_eq!(w.w.lock().await.tags.len(), 3);
 
         // Put without a tag.
-        let n = w.put(None).await;
-        assert_eq!(n, Some(799));
+        let n = w.put(None).await?;
+        assert_eq!(n, 799);
         assert_eq!(w.w.lock().await.tags.len(), 2);
 
         Ok(())
     }
 
+    #[async_test]
+    async fn sendwindow_tag_cap() -> Result<()> {
+        // This window is bigger than it should be, so we can send enough
+        // cells to need more tags than we're willing to keep.
+        let mut w: SendWindow<CircParams, &'static str> = SendWindow::new(1200);
+        assert_eq!(WindowLimits::default_for::<CircParams>().max_tags(), 10);
+        for _ in 0_usize..1099 {
+            w.take(&"fill").await?;
+        }
+        assert_eq!(w.w.lock().await.tags.len(), 10);
+        assert_eq!(w.w.lock().await.window, 101);
+
+        // The next cell would need an eleventh tag.
+        let n = w.take(&"overflow").await;
+        assert!(matches!(n, Err(Error::InternalError(_))));
+        assert_eq!(w.w.lock().await.window, 101);
+        assert_eq!(w.w.lock().await.tags.len(), 10);
+
+        // Once a sendme arrives, there's room again.
+        let n = w.put(Some("fill")).await?;
+        assert_eq!(n, 201);
+        let n = w.take(&"fill").await?;
+        assert_eq!(n, 200);
+        assert_eq!(w.w.lock().await.tags.len(), 10);
+
+        Ok(())
+    }
+
     #[async_test]
     async fn sendwindow_bad_put() -> Result<()> {
         let mut w = new_sendwindow();
@@ -375,25 +1855,138 @@ mod test {
         // wrong tag: won't work.
         assert_eq!(w.w.lock().await.window, 750);
         let n = w.put(Some(&"incorrect")).await;
-        assert!(n.is_none());
+        assert!(n.is_err());
 
-        let n = w.put(Some(&"correct")).await;
-        assert_eq!(n, Some(850));
-        let n = w.put(Some(&"correct")).await;
-        assert_eq!(n, Some(950));
+        let n = w.put(Some(&"correct")).await?;
+        assert_eq!(n, 850);
+        let n = w.put(Some(&"correct")).await?;
+        assert_eq!(n, 950);
 
         // no tag expected: won't work.
         let n = w.put(Some(&"correct")).await;
-        assert_eq!(n, None);
+        assert!(n.is_err());
         assert_eq!(w.w.lock().await.window, 950);
 
         let n = w.put(None).await;
-        assert_eq!(n, None);
+        assert!(n.is_err());
         assert_eq!(w.w.lock().await.window, 950);
+        w.check_invariants().await;
 
         Ok(())
     }
 
+    /// Run a random sequence of `take`s and `put`s (some of them with
+    /// bad tags) on `w`, checking its invariants after every step.
+    async fn random_takes_and_puts<P: WindowParams>(mut w: SendWindow<P, u32>, n_bytes: usize) {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let mut sent = 0_u32;
+        for _ in 0..5000 {
+            if rng.gen_bool(0.6) {
+                // Taking from an exhausted window (or a full byte
+                // budget) would block.
+                if let Some(r) = w.take_bytes(&sent, n_bytes).now_or_never() {
+                    r.unwrap();
+                    sent += 1;
+                }
+            } else {
+                let tag = match rng.gen_range(0..3) {
+                    0 => None,
+                    1 => w.w.lock().await.tags.front().cloned(),
+                    _ => Some(u32::MAX),
+                };
+                let _ = w.put(tag).await;
+            }
+            w.check_invariants().await;
+        }
+    }
+
+    #[async_test]
+    async fn sendwindow_random_invariants() {
+        random_takes_and_puts(SendWindow::<CircParams, u32>::new(1000), 0).await;
+        random_takes_and_puts(SendWindow::<StreamParams, u32>::new(500), 0).await;
+        random_takes_and_puts(SendWindow::<StreamParams, u32>::new(120), 0).await;
+        random_takes_and_puts(
+            SendWindow::<CircParams, u32>::new_with_byte_budget(1000, Some(20_000)),
+            498,
+        )
+        .await;
+    }
+
+    #[async_test]
+    async fn sendwindow_put_cases() {
+        // Every combination of (tag we're waiting for, tag we got), and
+        // the result that we expect from it.
+        let cases: &[(bool, Option<&'static str>, std::result::Result<u16, &str>)] = &[
+            (true, Some("correct"), Ok(1000)),
+            (
+                true,
+                Some("incorrect"),
+                Err("bad auth tag on circuit sendme"),
+            ),
+            (true, None, Ok(1000)),
+            (
+                false,
+                Some("correct"),
+                Err("unexpected sendme: no data outstanding"),
+            ),
+            (false, None, Err("unexpected sendme: no data outstanding")),
+        ];
+
+        for (data_outstanding, tag, expected) in cases {
+            let mut w = new_sendwindow();
+            if *data_outstanding {
+                for _ in 0_usize..100 {
+                    w.take(&"correct").await.unwrap();
+                }
+            }
+            let window_before = w.w.lock().await.window;
+
+            match (w.put(*tag).await, expected) {
+                (Ok(n), Ok(e)) => assert_eq!(n, *e),
+                (Err(Error::CircProto(m)), Err(e)) => {
+                    assert_eq!(&m, e);
+                    // A rejected sendme doesn't change the window.
+                    assert_eq!(w.w.lock().await.window, window_before);
+                }
+                (got, _) => panic!("Unexpected result {:?} for {:?}", got, tag),
+            }
+        }
+    }
+
+    #[async_test]
+    async fn sendwindow_put_overflow() -> Result<()> {
+        let limits = WindowLimits::new(u16::MAX, 100);
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_limits(u16::MAX, Some(10_000), limits);
+        for _ in 0_usize..35 {
+            w.take_bytes(&"data", 10).await?;
+        }
+
+        // This sendme would overflow the window, so we refuse it: but the
+        // tag and the bytes it would have acknowledged are still there.
+        assert!(matches!(
+            w.put(Some("data")).await,
+            Err(Error::CircProto(_))
+        ));
+        assert_eq!(
+            w.window_and_expected_tags().await,
+            (u16::MAX - 35, vec!["data"])
+        );
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 350);
+        w.check_invariants().await;
+
+        // The same goes for a window that doesn't record tags.
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_unauthenticated(u16::MAX, None, limits);
+        for _ in 0_usize..35 {
+            w.take(&"data").await?;
+        }
+        assert!(w.put(None).await.is_err());
+        assert_eq!(w.w.lock().await.untagged, 1);
+        Ok(())
+    }
+
     #[async_test]
     async fn sendwindow_blocking() -> Result<()> {
         let mut w = new_sendwindow();
@@ -409,4 +2002,497 @@ mod test {
         // TODO: test that this actually wakes up when somebody else says "put".
         Ok(())
     }
+
+    #[async_test]
+    async fn sendwindow_is_blocked() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..1000 {
+            w.take(&"data").await?;
+        }
+        assert!(!w.is_blocked());
+
+        // A take on an empty window parks.
+        let mut w2 = w.new_ref();
+        let mut data = Box::pin(w2.take(&"more data"));
+        assert!((&mut data).now_or_never().is_none());
+        assert!(w.is_blocked());
+
+        // Once there's room and the take runs, nothing is parked.
+        w.put(Some("data")).await?;
+        assert!(matches!((&mut data).now_or_never(), Some(Ok(99))));
+        assert!(!w.is_blocked());
+        drop(data);
+
+        // Giving up on a take also unparks it.
+        for _ in 0_usize..99 {
+            w.take(&"data").await?;
+        }
+        let mut data = Box::pin(w2.take(&"more data"));
+        assert!((&mut data).now_or_never().is_none());
+        assert!(w.is_blocked());
+        drop(data);
+        assert!(!w.is_blocked());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_accounting() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0..250 {
+            w.take(&"tag").await?;
+        }
+        w.put(Some("tag")).await?;
+        w.put(Some("tag")).await?;
+        let a = w.accounting().await;
+        assert_eq!(a.initial(), 1000);
+        assert_eq!(a.window(), 950);
+        assert_eq!(a.increment(), 100);
+        assert_eq!(a.cells_sent(), 250);
+        assert_eq!(a.sendmes_received(), 2);
+        assert_eq!(a.sendme_lag(), 0);
+        assert_eq!(a.byte_budget(), None);
+        assert_eq!(a.bytes_outstanding(), None);
+
+        for _ in 0..50 {
+            w.take(&"tag").await?;
+        }
+        let a = w.accounting().await;
+        assert_eq!(a.cells_sent(), 300);
+        assert_eq!(a.sendme_lag(), 1);
+
+        // Now pretend that we sent three of those cells without counting
+        // them, though the other side counted them: it acknowledges them
+        // anyway.
+        w.w.lock().await.cells_sent -= 3;
+        w.put(Some("tag")).await?;
+        let a = w.accounting().await;
+        assert_eq!(a.sendmes_received(), 3);
+        assert_eq!(a.sendme_lag(), -1);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_accounting_bytes() -> Result<()> {
+        // With a byte budget, we report on that too.
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_byte_budget(1000, Some(1000));
+        for _ in 0..99 {
+            w.take_bytes(&"tag", 10).await?;
+        }
+        let a = w.accounting().await;
+        assert_eq!(a.byte_budget(), Some(1000));
+        assert_eq!(a.bytes_outstanding(), Some(990));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_byte_budget() -> Result<()> {
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_byte_budget(1000, Some(1000));
+
+        // Lots of partial cells: the cell window has plenty of room, but
+        // we run out of bytes.
+        for _ in 0_usize..99 {
+            w.take_bytes(&"partial", 10).await?;
+        }
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 990);
+        let n = w.take_bytes(&"partial", 10).await?;
+        assert_eq!(n, 900);
+        assert!(w.take_bytes(&"partial", 10).now_or_never().is_none());
+        assert_eq!(w.w.lock().await.window, 900);
+
+        // Cells with no data don't count against the budget.
+        let n = w.take(&"empty").await?;
+        assert_eq!(n, 899);
+
+        // A sendme acknowledges the first 100 cells, and their bytes.
+        w.put(Some("partial")).await?;
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 0);
+        let n = w.take_bytes(&"partial", 10).await?;
+        assert_eq!(n, 998);
+
+        // A cell that's bigger than the whole budget can still go, once
+        // nothing else is outstanding.
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_byte_budget(1000, Some(100));
+        w.take_bytes(&"big", 498).await?;
+        assert!(w.take_bytes(&"big", 498).now_or_never().is_none());
+
+        // Without a budget, bytes don't matter.
+        let mut w = new_sendwindow();
+        for _ in 0_usize..200 {
+            w.take_bytes(&"big", 498).await?;
+        }
+        assert!(w.signals.byte_budget.is_none());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_byte_budget_short_increment() -> Result<()> {
+        // If the window doesn't start at a multiple of the increment, the
+        // first sendme acknowledges fewer cells: only their bytes go.
+        let mut w: SendWindow<StreamParams, &'static str> =
+            SendWindow::new_with_byte_budget(120, Some(1000));
+        for _ in 0_usize..30 {
+            w.take_bytes(&"partial", 10).await?;
+        }
+        w.put(Some("partial")).await?;
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 100);
+        w.check_invariants().await;
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_drain() -> Result<()> {
+        use futures::future::{pending, ready};
+
+        let mut w = new_sendwindow();
+        // A window with nothing outstanding is already drained.
+        assert!(w.drain(pending()).await?);
+
+        for _ in 0_usize..200 {
+            w.take(&"data").await?;
+        }
+
+        // Two tags are outstanding, so this times out.
+        assert!(!w.drain(ready(())).await?);
+
+        // The drain finishes once the last SENDME arrives, and not before.
+        let w_drain = w.new_ref();
+        let mut drain = Box::pin(w_drain.drain(pending()));
+        assert!((&mut drain).now_or_never().is_none());
+        w.put(Some("data")).await?;
+        assert!((&mut drain).now_or_never().is_none());
+        w.put(Some("data")).await?;
+        assert!(matches!((&mut drain).now_or_never(), Some(Ok(true))));
+        drop(drain);
+
+        // Closing the window wakes a waiting drain.
+        for _ in 0_usize..100 {
+            w.take(&"data").await?;
+        }
+        let mut drain = Box::pin(w_drain.drain(pending()));
+        assert!((&mut drain).now_or_never().is_none());
+        w.close();
+        assert!(matches!(
+            (&mut drain).now_or_never(),
+            Some(Err(Error::CircuitClosed))
+        ));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_close() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..1000 {
+            w.take(&"data").await?;
+        }
+
+        // Closing the window wakes a waiting take, and stops later ones.
+        let mut w_take = w.new_ref();
+        let mut take = Box::pin(w_take.take(&"data"));
+        assert!((&mut take).now_or_never().is_none());
+        w.close();
+        assert!(matches!(
+            (&mut take).now_or_never(),
+            Some(Err(Error::CircuitClosed))
+        ));
+        assert!(matches!(w.take(&"data").await, Err(Error::CircuitClosed)));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_two_writers() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..1000 {
+            w.take(&"data").await?;
+        }
+
+        // Two writers share the window, and both block on it.
+        let mut w1 = w.new_ref();
+        let mut w2 = w.new_ref();
+        let mut write1 = Box::pin(w1.take(&"one"));
+        let mut write2 = Box::pin(w2.take(&"two"));
+        assert!((&mut write1).now_or_never().is_none());
+        assert!((&mut write2).now_or_never().is_none());
+        assert!(w.is_blocked());
+
+        // One SENDME makes room for both of them.
+        w.put(Some("data")).await?;
+        assert!(matches!((&mut write1).now_or_never(), Some(Ok(99))));
+        assert!(matches!((&mut write2).now_or_never(), Some(Ok(98))));
+        drop((write1, write2));
+        assert!(!w.is_blocked());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_reserve() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..898 {
+            w.take(&"data").await?;
+        }
+
+        // We can reserve everything that's there, but no more.
+        let mut r = w.reserve(10).await;
+        assert_eq!(r.remaining(), 10);
+        let r2 = w.reserve(200).await;
+        assert_eq!(r2.remaining(), 92);
+        assert_eq!(w.reserve(1).await.remaining(), 0);
+
+        // Nobody else can take reserved cells.
+        assert!(w.take(&"data").now_or_never().is_none());
+        assert!(w.take_priority(&"control").now_or_never().is_none());
+
+        // Consuming reserved cells works like taking them, tags
+        // included.
+        assert_eq!(r.consume(&"burst").await?, Some(101));
+        assert_eq!(r.consume(&"burst").await?, Some(100));
+        assert_eq!(w.w.lock().await.tags.len(), 9);
+        assert_eq!(w.w.lock().await.tags[8], "burst");
+        assert_eq!(r.remaining(), 8);
+        w.check_invariants().await;
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_reserve_drop() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..898 {
+            w.take(&"data").await?;
+        }
+        let mut r = w.reserve(10).await;
+        let mut r2 = w.reserve(200).await;
+        for _ in 0_usize..2 {
+            r.consume(&"burst").await?;
+        }
+
+        // Dropping a reservation gives back what we didn't use.
+        drop(r);
+        assert_eq!(w.take(&"data").await?, 99);
+        for _ in 0_usize..7 {
+            w.take(&"data").await?;
+        }
+        assert!(w.take(&"data").now_or_never().is_none());
+
+        // Using up a reservation leaves nothing to give back.
+        for _ in 0_usize..92 {
+            assert!(r2.consume(&"burst").await?.is_some());
+        }
+        assert_eq!(r2.consume(&"burst").await?, None);
+        drop(r2);
+        assert_eq!(w.w.lock().await.window, 0);
+        assert!(w.take(&"data").now_or_never().is_none());
+        w.check_invariants().await;
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_reserve_cell() -> Result<()> {
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_byte_budget(1000, Some(1000));
+        for _ in 0_usize..99 {
+            w.take_bytes(&"data", 10).await?;
+        }
+
+        // A reserved cell holds its bytes until we use it...
+        let mut r = w.reserve_cell(10).await?;
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 1000);
+        assert!(w.take_bytes(&"data", 10).now_or_never().is_none());
+        assert_eq!(r.consume(&"data").await?, Some(900));
+        assert_eq!(r.consume(&"data").await?, None);
+        drop(r);
+        w.check_invariants().await;
+
+        // ... and waits for room if there isn't any.
+        let mut w2 = w.new_ref();
+        let mut reserve = Box::pin(w2.reserve_cell(10));
+        assert!((&mut reserve).now_or_never().is_none());
+        assert!(w.is_blocked());
+        w.put(Some("data")).await?;
+        let r = (&mut reserve).now_or_never().expect("still blocked")?;
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 10);
+
+        // Dropping it unused gives back the cell and the bytes.
+        drop(r);
+        assert_eq!(w.signals.byte_budget.as_ref().unwrap().used(), 0);
+        assert_eq!(w.signals.reserved.load(Ordering::SeqCst), 0);
+        w.check_invariants().await;
+
+        // Once the window is closed, we can't reserve or consume.
+        let mut r = w.reserve_cell(10).await?;
+        w.close();
+        assert!(matches!(
+            r.consume(&"data").await,
+            Err(Error::CircuitClosed)
+        ));
+        assert!(matches!(
+            w.reserve_cell(10).await.err(),
+            Some(Error::CircuitClosed)
+        ));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_headroom() -> Result<()> {
+        let mut w = new_sendwindow();
+        assert_eq!(w.headroom().await, 1000);
+        for expect in (990..1000).rev() {
+            w.take(&"tag").await?;
+            assert_eq!(w.headroom().await, expect);
+            assert_eq!(w.headroom().await, w.w.lock().await.window);
+        }
+
+        // Reserved cells aren't headroom.
+        let r = w.reserve(10).await;
+        assert_eq!(w.headroom().await, 980);
+        drop(r);
+
+        // Once there's no headroom, a take would block.
+        for _ in 0..990 {
+            w.take(&"tag").await?;
+        }
+        assert_eq!(w.headroom().await, 0);
+        assert!(w.take(&"tag").now_or_never().is_none());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_priority() -> Result<()> {
+        let mut w = new_sendwindow();
+        for _ in 0_usize..1000 {
+            w.take(&"data").await?;
+        }
+
+        // Some data is waiting for room in the window, and then a
+        // control cell shows up.
+        let mut w_data = w.new_ref();
+        let mut data = Box::pin(w_data.take(&"more data"));
+        assert!((&mut data).now_or_never().is_none());
+        let mut w_ctrl = w.new_ref();
+        let mut ctrl = Box::pin(w_ctrl.take_priority(&"control"));
+        assert!((&mut ctrl).now_or_never().is_none());
+
+        // Once a sendme arrives, the control cell goes first, even
+        // though there is room for both.
+        w.put(Some("data")).await?;
+        assert!((&mut data).now_or_never().is_none());
+        assert_eq!(w.reserve(10).await.remaining(), 0);
+        assert!(matches!((&mut ctrl).now_or_never(), Some(Ok(99))));
+        drop(ctrl);
+        assert!(matches!((&mut data).now_or_never(), Some(Ok(98))));
+        drop(data);
+
+        // If the window has room, a priority take just takes.
+        assert_eq!(w.take_priority(&"control").await?, 97);
+
+        // If a priority take gives up, ordinary takes aren't stuck
+        // behind it.
+        for _ in 0_usize..97 {
+            w.take(&"data").await?;
+        }
+        let mut ctrl = Box::pin(w_ctrl.take_priority(&"control"));
+        assert!((&mut ctrl).now_or_never().is_none());
+        w.put(Some("data")).await?;
+        let mut data = Box::pin(w_data.take(&"more data"));
+        assert!((&mut data).now_or_never().is_none());
+        drop(ctrl);
+        assert!(matches!((&mut data).now_or_never(), Some(Ok(99))));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn sendwindow_unauthenticated() -> Result<()> {
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_unauthenticated(1000, None, WindowLimits::default_for::<CircParams>());
+
+        // We can't take a SENDME before we've sent anything.
+        assert!(w.put(Some("nope")).await.is_err());
+
+        for _ in 0_usize..300 {
+            w.take(&"data").await?;
+        }
+        // No tags are stored...
+        let (window, tags) = w.window_and_expected_tags().await;
+        assert_eq!(window, 700);
+        assert!(tags.is_empty());
+        w.check_invariants().await;
+        // ... so any tag is fine, as is none at all.
+        assert_eq!(w.put(Some("whatever")).await?, 800);
+        assert_eq!(w.put(None).await?, 900);
+        assert_eq!(w.put(Some("data")).await?, 1000);
+        // But we still notice a SENDME that acknowledges nothing.
+        assert!(w.put(None).await.is_err());
+        w.check_invariants().await;
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn custom_increment() -> Result<()> {
+        let limits = WindowLimits::new(1000, 50);
+        assert_eq!(limits.max_tags(), 20);
+        assert_eq!(WindowLimits::new(1000, 0).increment(), 1);
+        assert_eq!(WindowLimits::new(100, 500).increment(), 100);
+
+        // A circuit send window records a tag every 50 cells...
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_limits(1000, None, limits);
+        for _ in 0_usize..49 {
+            w.take(&"data").await?;
+        }
+        assert!(w.window_and_expected_tags().await.1.is_empty());
+        w.take(&"fifty").await?;
+        w.take(&"data").await?;
+        let (window, tags) = w.window_and_expected_tags().await;
+        assert_eq!(window, 949);
+        assert_eq!(tags, vec!["fifty"]);
+        // ... and each SENDME adds 50 to it.
+        assert_eq!(w.put(Some("fifty")).await?, 999);
+        w.check_invariants().await;
+
+        // A circuit receive window wants a SENDME every 50 cells.
+        let mut rw: RecvWindow<CircParams> = RecvWindow::new_with_limits(1000, limits);
+        for _ in 0_usize..49 {
+            assert!(!rw.take()?);
+        }
+        assert!(rw.take()?);
+        rw.put()?;
+        assert_eq!(rw.window, 1000);
+        for _ in 0_usize..49 {
+            assert!(!rw.take()?);
+        }
+        assert!(rw.take()?);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn limits_near_u16_max() -> Result<()> {
+        // `window + increment` doesn't fit in a u16 here.
+        let limits = WindowLimits::new(u16::MAX, 100);
+        assert_eq!(limits.max_tags(), 656);
+        let mut w: SendWindow<CircParams, &'static str> =
+            SendWindow::new_with_limits(u16::MAX, None, limits);
+        w.check_invariants().await;
+        for _ in 0_usize..35 {
+            w.take(&"data").await?;
+        }
+        assert_eq!(
+            w.window_and_expected_tags().await,
+            (u16::MAX - 35, vec!["data"])
+        );
+        w.check_invariants().await;
+        Ok(())
+    }
 }
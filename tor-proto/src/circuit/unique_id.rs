@@ -1,6 +1,7 @@
 //! Unique identifiers for circuits.
 
 use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Process-unique identifier for a circuit.
 ///
@@ -28,3 +29,42 @@ impl Display for UniqId {
         write!(f, "Circ {}.{}", self.chan, self.circ)
     }
 }
+
+/// An identifier for a single attempt to build a circuit.
+///
+/// Unlike a [`UniqId`], which names a circuit once it exists, this names
+/// the whole build, from path selection through the last handshake, so
+/// that the log messages and events from every layer involved can be
+/// correlated.  Set it with
+/// [`CircParameters::set_build_id`](super::CircParameters::set_build_id).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CircuitBuildId(u64);
+
+impl CircuitBuildId {
+    /// Construct a CircuitBuildId from a caller-chosen number.
+    pub fn new(id: u64) -> Self {
+        CircuitBuildId(id)
+    }
+
+    /// Return a new CircuitBuildId, different from every other one that
+    /// this function has returned in this process.
+    ///
+    /// (It can still be equal to one constructed with
+    /// [`CircuitBuildId::new`].)
+    pub fn next_unique() -> Self {
+        /// The number for the next CircuitBuildId.
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        CircuitBuildId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Return the number of this CircuitBuildId.
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for CircuitBuildId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Build {}", self.0)
+    }
+}
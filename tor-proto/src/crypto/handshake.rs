@@ -8,12 +8,14 @@
 //! client knows that nobody _else_ shares those keys unless they
 //! relay's private onion key.
 //!
-//! Currently, this module implements only the "ntor" handshake used
-//! for circuits on today's Tor.
+//! Currently, this module implements the "ntor" handshake used for
+//! circuits on today's Tor, and the "ntor v3" handshake that lets a
+//! client negotiate extensions with each hop.
 pub(crate) mod fast;
 #[cfg(feature = "hs")]
 pub(crate) mod hs_ntor;
 pub(crate) mod ntor;
+pub(crate) mod ntor_v3;
 
 use crate::{Result, SecretBytes};
 //use zeroize::Zeroizing;
@@ -28,19 +30,31 @@ pub(crate) trait ClientHandshake {
     type StateType;
     /// A type that is returned and used to generate session keys.x
     type KeyGen;
-    /// Generate a new client onionskin for a relay with a given onion key.
+    /// Extra data that the client sends to the relay along with its
+    /// onionskin.  `()` for handshakes that can't send any.
+    type ClientAuxData: ?Sized;
+    /// Extra data that the relay sends back along with its onionskin.
+    /// `()` for handshakes that can't send any.
+    type ServerAuxData;
+    /// Generate a new client onionskin for a relay with a given onion key,
+    /// carrying `client_aux_data`.
     ///
     /// On success, return a state object that will be used to
     /// complete the handshake, along with the message to send.
     fn client1<R: RngCore + CryptoRng>(
         rng: &mut R,
         key: &Self::KeyType,
+        client_aux_data: &Self::ClientAuxData,
     ) -> Result<(Self::StateType, Vec<u8>)>;
-    /// Handle an onionskin from a relay, and produce a key generator.
+    /// Handle an onionskin from a relay, and produce the relay's extra
+    /// data and a key generator.
     ///
     /// The state object must match the one that was used to make the
     /// client onionskin that the server is replying to.
-    fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<Self::KeyGen>;
+    fn client2<T: AsRef<[u8]>>(
+        state: Self::StateType,
+        msg: T,
+    ) -> Result<(Self::ServerAuxData, Self::KeyGen)>;
 }
 
 /// A ServerHandshake is used to hanle a client onionskin and generate a
@@ -24,17 +24,20 @@ impl super::ClientHandshake for CreateFastClient {
     type KeyType = ();
     type StateType = CreateFastClientState;
     type KeyGen = super::TapKeyGenerator;
+    type ClientAuxData = ();
+    type ServerAuxData = ();
 
     fn client1<R: RngCore + CryptoRng>(
         rng: &mut R,
         _key: &Self::KeyType,
+        _client_aux_data: &(),
     ) -> Result<(Self::StateType, Vec<u8>)> {
         let mut state = [0_u8; FAST_C_HANDSHAKE_LEN];
         rng.fill_bytes(&mut state);
         Ok((CreateFastClientState(state), state.into()))
     }
 
-    fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<Self::KeyGen> {
+    fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<((), Self::KeyGen)> {
         let msg = msg.as_ref();
         if msg.len() != FAST_S_HANDSHAKE_LEN {
             return Err(Error::BadHandshake);
@@ -49,7 +52,7 @@ impl super::ClientHandshake for CreateFastClient {
             return Err(Error::BadHandshake);
         }
 
-        Ok(super::TapKeyGenerator::new(inp.into()))
+        Ok(((), super::TapKeyGenerator::new(inp.into())))
     }
 }
 
@@ -94,9 +97,9 @@ mod test {
     fn roundtrip() {
         let mut rng = rand::thread_rng();
 
-        let (state, cmsg) = CreateFastClient::client1(&mut rng, &()).unwrap();
+        let (state, cmsg) = CreateFastClient::client1(&mut rng, &(), &()).unwrap();
         let (s_kg, smsg) = CreateFastServer::server(&mut rng, &[()], cmsg).unwrap();
-        let c_kg = CreateFastClient::client2(state, smsg).unwrap();
+        let (_, c_kg) = CreateFastClient::client2(state, smsg).unwrap();
 
         let s_key = s_kg.expand(200).unwrap();
         let c_key = c_kg.expand(200).unwrap();
@@ -114,7 +117,7 @@ mod test {
         assert!(ans.is_err());
 
         // corrupt/ incorrect server reply.
-        let (state, cmsg) = CreateFastClient::client1(&mut rng, &()).unwrap();
+        let (state, cmsg) = CreateFastClient::client1(&mut rng, &(), &()).unwrap();
         let (_, mut smsg) = CreateFastServer::server(&mut rng, &[()], cmsg).unwrap();
         smsg[35] ^= 16;
         let ans = CreateFastClient::client2(state, smsg);
@@ -125,11 +128,11 @@ mod test {
         use crate::crypto::testing::FakePRNG;
 
         let mut rng = FakePRNG::new(&cmsg);
-        let (state, cmsg) = CreateFastClient::client1(&mut rng, &()).unwrap();
+        let (state, cmsg) = CreateFastClient::client1(&mut rng, &(), &()).unwrap();
 
         let mut rng = FakePRNG::new(&smsg);
         let (s_kg, smsg) = CreateFastServer::server(&mut rng, &[()], cmsg).unwrap();
-        let c_kg = CreateFastClient::client2(state, smsg).unwrap();
+        let (_, c_kg) = CreateFastClient::client2(state, smsg).unwrap();
 
         let s_key = s_kg.expand(100).unwrap();
         let c_key = c_kg.expand(100).unwrap();
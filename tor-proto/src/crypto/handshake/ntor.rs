@@ -25,16 +25,19 @@ impl super::ClientHandshake for NtorClient {
     type KeyType = NtorPublicKey;
     type StateType = NtorHandshakeState;
     type KeyGen = NtorHkdfKeyGenerator;
+    type ClientAuxData = ();
+    type ServerAuxData = ();
 
     fn client1<R: RngCore + CryptoRng>(
         rng: &mut R,
         key: &Self::KeyType,
+        _client_aux_data: &(),
     ) -> Result<(Self::StateType, Vec<u8>)> {
         Ok(client_handshake_ntor_v1(rng, key))
     }
 
-    fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<Self::KeyGen> {
-        client_handshake2_ntor_v1(msg, &state)
+    fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<((), Self::KeyGen)> {
+        Ok(((), client_handshake2_ntor_v1(msg, &state)?))
     }
 }
 
@@ -317,7 +320,7 @@ mod tests {
             id: relay_identity,
             pk: relay_public.clone(),
         };
-        let (state, cmsg) = NtorClient::client1(&mut rng, &relay_ntpk)?;
+        let (state, cmsg) = NtorClient::client1(&mut rng, &relay_ntpk, &())?;
 
         let relay_ntsk = NtorSecretKey {
             pk: relay_ntpk.clone(),
@@ -327,7 +330,7 @@ mod tests {
 
         let (skeygen, smsg) = NtorServer::server(&mut rng, &relay_ntsks, &cmsg)?;
 
-        let ckeygen = NtorClient::client2(state, smsg)?;
+        let (_, ckeygen) = NtorClient::client2(state, smsg)?;
 
         let skeys = skeygen.expand(55)?;
         let ckeys = ckeygen.expand(55)?;
@@ -418,9 +421,9 @@ mod tests {
 
         // If the client uses the wrong keys, the relay should reject the
         // handshake.
-        let (_, handshake1) = NtorClient::client1(&mut rng, &wrong_ntpk1).unwrap();
-        let (_, handshake2) = NtorClient::client1(&mut rng, &wrong_ntpk2).unwrap();
-        let (st3, handshake3) = NtorClient::client1(&mut rng, &relay_ntpk).unwrap();
+        let (_, handshake1) = NtorClient::client1(&mut rng, &wrong_ntpk1, &()).unwrap();
+        let (_, handshake2) = NtorClient::client1(&mut rng, &wrong_ntpk2, &()).unwrap();
+        let (st3, handshake3) = NtorClient::client1(&mut rng, &relay_ntpk, &()).unwrap();
 
         let ans1 = NtorServer::server(&mut rng, relay_ntsks, &handshake1);
         let ans2 = NtorServer::server(&mut rng, relay_ntsks, &handshake2);
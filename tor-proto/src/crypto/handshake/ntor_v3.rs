@@ -0,0 +1,791 @@
+//! Implements the ntor v3 handshake, as described in proposal 332.
+//!
+//! Unlike the original ntor handshake, ntor v3 lets the client and the
+//! relay each send an encrypted message along with their handshake.  When
+//! extending circuits, Tor uses these messages to carry a list of
+//! [`NtorV3Extension`]s, which the two sides use to negotiate per-hop
+//! features like congestion control.
+//!
+//! The client's message is encrypted to the relay's onion key, and so
+//! doesn't get forward secrecy; the relay's reply does.
+
+// We want to use the exact variable names from the proposal.
+#![allow(non_snake_case)]
+
+use super::KeyGenerator;
+use crate::util::ct;
+use crate::{Error, Result, SecretBytes};
+use tor_bytes::{Reader, Writer};
+use tor_llcrypto::cipher::aes::Aes256Ctr;
+use tor_llcrypto::d::{Sha3_256, Shake256};
+use tor_llcrypto::pk::{curve25519, ed25519::Ed25519Identity};
+use tor_llcrypto::util::rand_compat::RngCompatExt;
+
+use cipher::{NewCipher, StreamCipher};
+use digest::{Digest, ExtendableOutput, XofReader};
+use rand_core::{CryptoRng, RngCore};
+use std::convert::TryInto;
+#[cfg(test)]
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::Zeroizing;
+
+/// The protocol ID for this handshake.
+const PROTOID: &[u8] = b"ntor3-curve25519-sha3_256-1";
+/// Tweak for the KDF that makes the keys for the client's message.
+const T_MSGKDF: &[u8] = b"ntor3-curve25519-sha3_256-1:kdf_phase1";
+/// Tweak for the MAC on the client's message.
+const T_MSGMAC: &[u8] = b"ntor3-curve25519-sha3_256-1:msg_mac";
+/// Tweak for the hash that makes the key seed.
+const T_KEY_SEED: &[u8] = b"ntor3-curve25519-sha3_256-1:key_seed";
+/// Tweak for the hash that makes the verification value.
+const T_VERIFY: &[u8] = b"ntor3-curve25519-sha3_256-1:verify";
+/// Tweak for the KDF that makes the final keys.
+const T_FINAL: &[u8] = b"ntor3-curve25519-sha3_256-1:kdf_final";
+/// Tweak for the hash that authenticates the relay's reply.
+const T_AUTH: &[u8] = b"ntor3-curve25519-sha3_256-1:auth_final";
+
+/// The verification string that we use with ntor v3 when extending
+/// circuits.
+pub(crate) const NTOR3_CIRC_VERIFICATION: &[u8] = b"circuit extend";
+
+/// A key for the AES-256 encryption of a handshake message.
+type EncKey = [u8; 32];
+/// A key for the MAC on the client's handshake message.
+type MacKey = [u8; 32];
+/// The output of our hash functions.
+type DigestVal = [u8; 32];
+
+/// Extension type for a request to use congestion control.
+const CC_REQUEST: u8 = 0x01;
+/// Extension type for a relay's answer to a congestion control request.
+const CC_RESPONSE: u8 = 0x02;
+
+/// An extension sent in the encrypted part of an ntor v3 handshake.
+///
+/// Each side sends a list of these: the client to ask for per-hop
+/// features, and the relay to say which of them it agreed to (and on what
+/// terms).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NtorV3Extension {
+    /// The type of this extension.
+    ext_type: u8,
+    /// The body of this extension; at most 255 bytes long.
+    body: Vec<u8>,
+}
+
+impl NtorV3Extension {
+    /// Construct a new extension of type `ext_type`.
+    ///
+    /// Handshakes with an extension whose body is longer than 255 bytes
+    /// will fail.
+    pub fn new(ext_type: u8, body: Vec<u8>) -> Self {
+        NtorV3Extension { ext_type, body }
+    }
+
+    /// Return the type of this extension.
+    pub fn ext_type(&self) -> u8 {
+        self.ext_type
+    }
+
+    /// Return the body of this extension.
+    pub fn body(&self) -> &[u8] {
+        &self.body[..]
+    }
+
+    /// Construct an extension that asks the relay to use congestion
+    /// control on this hop.
+    pub fn congestion_control_request() -> Self {
+        Self::new(CC_REQUEST, Vec::new())
+    }
+
+    /// Return true if this is a relay's answer to a congestion control
+    /// request.
+    pub fn is_congestion_control_response(&self) -> bool {
+        self.ext_type == CC_RESPONSE
+    }
+
+    /// If this is a well-formed answer to a congestion control request,
+    /// return the number of cells that the relay wants us to send between
+    /// SENDMEs.
+    pub fn congestion_control_sendme_inc(&self) -> Option<u8> {
+        match (self.ext_type, &self.body[..]) {
+            (CC_RESPONSE, [inc]) if *inc > 0 => Some(*inc),
+            _ => None,
+        }
+    }
+}
+
+/// Encode a list of extensions as the body of a handshake message.
+pub(crate) fn encode_extensions(extensions: &[NtorV3Extension]) -> Result<Vec<u8>> {
+    let n_exts: u8 = extensions
+        .len()
+        .try_into()
+        .map_err(|_| Error::InternalError("Too many ntor v3 extensions".into()))?;
+    let mut msg = Vec::new();
+    msg.write_u8(n_exts);
+    for ext in extensions {
+        let len: u8 = ext
+            .body
+            .len()
+            .try_into()
+            .map_err(|_| Error::InternalError("ntor v3 extension too long".into()))?;
+        msg.write_u8(ext.ext_type);
+        msg.write_u8(len);
+        msg.write_all(&ext.body[..]);
+    }
+    Ok(msg)
+}
+
+/// Decode a list of extensions from the body of a handshake message.
+///
+/// An empty message is an empty list.
+pub(crate) fn decode_extensions(msg: &[u8]) -> Result<Vec<NtorV3Extension>> {
+    if msg.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut r = Reader::from_slice(msg);
+    let n_exts = r.take_u8()?;
+    let extensions = (0..n_exts)
+        .map(|_| {
+            let ext_type = r.take_u8()?;
+            let len = r.take_u8()?;
+            let body = r.take(len as usize)?.into();
+            Ok(NtorV3Extension { ext_type, body })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    r.should_be_exhausted()?;
+    Ok(extensions)
+}
+
+/// Client side of the ntor v3 handshake, as used to extend circuits.
+pub(crate) struct NtorV3Client;
+
+impl super::ClientHandshake for NtorV3Client {
+    type KeyType = NtorV3PublicKey;
+    type StateType = NtorV3HandshakeState;
+    type KeyGen = NtorV3KeyGenerator;
+    type ClientAuxData = [NtorV3Extension];
+    type ServerAuxData = Vec<NtorV3Extension>;
+
+    fn client1<R: RngCore + CryptoRng>(
+        rng: &mut R,
+        key: &Self::KeyType,
+        client_aux_data: &Self::ClientAuxData,
+    ) -> Result<(Self::StateType, Vec<u8>)> {
+        let message = encode_extensions(client_aux_data)?;
+        Ok(client_handshake_ntor_v3(
+            rng,
+            key,
+            &message[..],
+            NTOR3_CIRC_VERIFICATION,
+        ))
+    }
+
+    fn client2<T: AsRef<[u8]>>(
+        state: Self::StateType,
+        msg: T,
+    ) -> Result<(Self::ServerAuxData, Self::KeyGen)> {
+        let (message, keygen) =
+            client_handshake_ntor_v3_part2(&state, msg.as_ref(), NTOR3_CIRC_VERIFICATION)?;
+        let extensions = decode_extensions(&message[..])?;
+        if extensions
+            .iter()
+            .any(|e| e.ext_type == CC_RESPONSE && e.congestion_control_sendme_inc().is_none())
+        {
+            // We can't tell when the relay expects SENDMEs.
+            return Err(Error::CircProto(
+                "Malformed congestion control response".into(),
+            ));
+        }
+        Ok((extensions, keygen))
+    }
+}
+
+/// The public keys that a client uses to start an ntor v3 handshake.
+#[derive(Clone)]
+pub(crate) struct NtorV3PublicKey {
+    /// The relay's Ed25519 identity.
+    pub(crate) id: Ed25519Identity,
+    /// The relay's curve25519 ntor onion key.
+    pub(crate) pk: curve25519::PublicKey,
+}
+
+/// A secret key that a relay uses to answer an ntor v3 handshake.
+///
+/// Only tests act as relays so far.
+#[cfg(test)]
+pub(crate) struct NtorV3SecretKey {
+    /// Public key components; must match those held by the client.
+    pk: NtorV3PublicKey,
+    /// Secret curve25519 ntor onion key; must correspond to `pk.pk`.
+    sk: curve25519::StaticSecret,
+}
+
+#[cfg(test)]
+impl NtorV3SecretKey {
+    /// Construct a new NtorV3SecretKey from its components.
+    pub(crate) fn new(
+        sk: curve25519::StaticSecret,
+        pk: curve25519::PublicKey,
+        id: Ed25519Identity,
+    ) -> Self {
+        NtorV3SecretKey {
+            pk: NtorV3PublicKey { id, pk },
+            sk,
+        }
+    }
+
+    /// Return true if the curve25519 public key in `self` matches `pk`.
+    ///
+    /// Used for looking up keys in an array.
+    fn matches_pk(&self, pk: &curve25519::PublicKey) -> Choice {
+        self.pk.pk.as_bytes().ct_eq(pk.as_bytes())
+    }
+}
+
+/// Client state for an ntor v3 handshake.
+pub(crate) struct NtorV3HandshakeState {
+    /// The relay's public keys.
+    relay_public: NtorV3PublicKey,
+    /// Our ephemeral secret key (x) for this handshake.
+    my_sk: curve25519::StaticSecret,
+    /// The public key (X) corresponding to `my_sk`.
+    my_public: curve25519::PublicKey,
+    /// The shared secret from our ephemeral key and the relay's onion
+    /// key: EXP(B,x).
+    shared_secret: curve25519::SharedSecret,
+    /// The MAC that we sent on our message.
+    msg_mac: DigestVal,
+}
+
+/// The type of the XOF reader that our key generator reads from.
+type ShakeReader = <Shake256 as ExtendableOutput>::Reader;
+
+/// KeyGenerator for use with the ntor v3 handshake.
+pub(crate) struct NtorV3KeyGenerator {
+    /// The SHAKE-256 output that follows the reply's encryption key.
+    reader: ShakeReader,
+}
+
+impl KeyGenerator for NtorV3KeyGenerator {
+    fn expand(mut self, keylen: usize) -> Result<SecretBytes> {
+        let mut out = Zeroizing::new(vec![0_u8; keylen]);
+        self.reader.read(&mut out[..]);
+        Ok(out)
+    }
+}
+
+/// Write `s` onto `w`, prefixed with its length as an 8-byte integer.
+fn encap<W: Writer + ?Sized>(w: &mut W, s: &[u8]) {
+    w.write_u64(s.len() as u64);
+    w.write_all(s);
+}
+
+/// Compute the tweaked hash H(s, t).
+fn hash(t: &[u8], s: &[u8]) -> DigestVal {
+    let mut prefix = Vec::new();
+    encap(&mut prefix, t);
+    let mut d = Sha3_256::new();
+    d.update(&prefix[..]);
+    d.update(s);
+    d.finalize().into()
+}
+
+/// Compute the tweaked MAC of `msg` under `key`.
+fn mac(t: &[u8], key: &[u8], msg: &[u8]) -> DigestVal {
+    let mut prefix = Vec::new();
+    encap(&mut prefix, t);
+    encap(&mut prefix, key);
+    let mut d = Sha3_256::new();
+    d.update(&prefix[..]);
+    d.update(msg);
+    d.finalize().into()
+}
+
+/// Start the tweaked KDF on the secret `s`, returning a reader for its
+/// output.
+fn kdf(t: &[u8], s: &[u8]) -> ShakeReader {
+    let mut prefix = Vec::new();
+    encap(&mut prefix, t);
+    let mut xof = Shake256::default();
+    digest::Update::update(&mut xof, &prefix[..]);
+    digest::Update::update(&mut xof, s);
+    xof.finalize_xof()
+}
+
+/// Encrypt (or decrypt) `msg` with AES-256 in counter mode, using `key`
+/// and an all-zero IV.
+fn encrypt(key: &EncKey, msg: &[u8]) -> Vec<u8> {
+    let mut msg = msg.to_vec();
+    let mut cipher = Aes256Ctr::new(key.into(), &Default::default());
+    cipher.apply_keystream(&mut msg[..]);
+    msg
+}
+
+/// Derive the keys for encrypting and authenticating the client's
+/// message, given the shared secret EXP(B,x).
+fn phase1_keys(
+    Bx: &curve25519::SharedSecret,
+    relay_public: &NtorV3PublicKey,
+    X: &curve25519::PublicKey,
+    verification: &[u8],
+) -> (Zeroizing<EncKey>, Zeroizing<MacKey>) {
+    let mut secret_input = Zeroizing::new(Vec::new());
+    secret_input.write(Bx);
+    secret_input.write(&relay_public.id);
+    secret_input.write(X);
+    secret_input.write(&relay_public.pk);
+    secret_input.write_all(PROTOID);
+    encap(&mut *secret_input, verification);
+
+    let mut reader = kdf(T_MSGKDF, &secret_input[..]);
+    let mut enc_key = Zeroizing::new([0_u8; 32]);
+    let mut mac_key = Zeroizing::new([0_u8; 32]);
+    reader.read(&mut enc_key[..]);
+    reader.read(&mut mac_key[..]);
+    (enc_key, mac_key)
+}
+
+/// Compute the MAC on the client's message.
+fn client_msg_mac(
+    mac_key: &MacKey,
+    relay_public: &NtorV3PublicKey,
+    X: &curve25519::PublicKey,
+    encrypted_msg: &[u8],
+) -> DigestVal {
+    let mut mac_input = Vec::new();
+    mac_input.write(&relay_public.id);
+    mac_input.write(&relay_public.pk);
+    mac_input.write(X);
+    mac_input.write_all(encrypted_msg);
+    mac(T_MSGMAC, &mac_key[..], &mac_input[..])
+}
+
+/// Derive the key generator, the reply's encryption key, and the
+/// verification value, from the results of both Diffie-Hellman
+/// operations.
+fn final_keys(
+    xy: &curve25519::SharedSecret,
+    xb: &curve25519::SharedSecret,
+    relay_public: &NtorV3PublicKey,
+    X: &curve25519::PublicKey,
+    Y: &curve25519::PublicKey,
+    verification: &[u8],
+) -> (NtorV3KeyGenerator, Zeroizing<EncKey>, Zeroizing<DigestVal>) {
+    let mut secret_input = Zeroizing::new(Vec::new());
+    secret_input.write(xy);
+    secret_input.write(xb);
+    secret_input.write(&relay_public.id);
+    secret_input.write(&relay_public.pk);
+    secret_input.write(X);
+    secret_input.write(Y);
+    secret_input.write_all(PROTOID);
+    encap(&mut *secret_input, verification);
+
+    let key_seed = Zeroizing::new(hash(T_KEY_SEED, &secret_input[..]));
+    let verify = Zeroizing::new(hash(T_VERIFY, &secret_input[..]));
+
+    let mut reader = kdf(T_FINAL, &key_seed[..]);
+    let mut enc_key = Zeroizing::new([0_u8; 32]);
+    reader.read(&mut enc_key[..]);
+
+    (NtorV3KeyGenerator { reader }, enc_key, verify)
+}
+
+/// Compute the authenticator for the relay's reply.
+fn reply_auth(
+    verify: &DigestVal,
+    relay_public: &NtorV3PublicKey,
+    X: &curve25519::PublicKey,
+    Y: &curve25519::PublicKey,
+    msg_mac: &[u8],
+    encrypted_reply: &[u8],
+) -> DigestVal {
+    let mut auth_input = Zeroizing::new(Vec::new());
+    auth_input.write_all(&verify[..]);
+    auth_input.write(&relay_public.id);
+    auth_input.write(&relay_public.pk);
+    auth_input.write(Y);
+    auth_input.write(X);
+    auth_input.write_all(msg_mac);
+    encap(&mut *auth_input, encrypted_reply);
+    auth_input.write_all(PROTOID);
+    auth_input.write_all(b"Server");
+    hash(T_AUTH, &auth_input[..])
+}
+
+/// Start an ntor v3 handshake as a client, sending `client_msg` to the
+/// relay.
+///
+/// Return the state to use for finishing the handshake, and the message
+/// to send to the relay.
+fn client_handshake_ntor_v3<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    relay_public: &NtorV3PublicKey,
+    client_msg: &[u8],
+    verification: &[u8],
+) -> (NtorV3HandshakeState, Vec<u8>) {
+    let my_sk = curve25519::StaticSecret::new(rng.rng_compat());
+    client_handshake_ntor_v3_no_keygen(relay_public, client_msg, verification, my_sk)
+}
+
+/// Helper: as client_handshake_ntor_v3, but with a given ephemeral key.
+fn client_handshake_ntor_v3_no_keygen(
+    relay_public: &NtorV3PublicKey,
+    client_msg: &[u8],
+    verification: &[u8],
+    my_sk: curve25519::StaticSecret,
+) -> (NtorV3HandshakeState, Vec<u8>) {
+    let X = curve25519::PublicKey::from(&my_sk);
+    let Bx = my_sk.diffie_hellman(&relay_public.pk);
+    let (enc_key, mac_key) = phase1_keys(&Bx, relay_public, &X, verification);
+    let encrypted_msg = encrypt(&enc_key, client_msg);
+    let msg_mac = client_msg_mac(&mac_key, relay_public, &X, &encrypted_msg[..]);
+
+    let mut message = Vec::new();
+    message.write(&relay_public.id);
+    message.write(&relay_public.pk);
+    message.write(&X);
+    message.write_all(&encrypted_msg[..]);
+    message.write_all(&msg_mac[..]);
+
+    let state = NtorV3HandshakeState {
+        relay_public: relay_public.clone(),
+        my_sk,
+        my_public: X,
+        shared_secret: Bx,
+        msg_mac,
+    };
+    (state, message)
+}
+
+/// Finish an ntor v3 handshake as a client, given the relay's reply.
+///
+/// On success, return the relay's message and a key generator.
+fn client_handshake_ntor_v3_part2(
+    state: &NtorV3HandshakeState,
+    relay_handshake: &[u8],
+    verification: &[u8],
+) -> Result<(Vec<u8>, NtorV3KeyGenerator)> {
+    let mut r = Reader::from_slice(relay_handshake);
+    let Y: curve25519::PublicKey = r.extract()?;
+    let auth: DigestVal = r.extract()?;
+    let encrypted_reply = r.into_rest();
+
+    let yx = state.my_sk.diffie_hellman(&Y);
+    let (keygen, enc_key, verify) = final_keys(
+        &yx,
+        &state.shared_secret,
+        &state.relay_public,
+        &state.my_public,
+        &Y,
+        verification,
+    );
+    let expected_auth = reply_auth(
+        &verify,
+        &state.relay_public,
+        &state.my_public,
+        &Y,
+        &state.msg_mac[..],
+        encrypted_reply,
+    );
+    if !ct::bytes_eq(&auth[..], &expected_auth[..]) {
+        return Err(Error::BadHandshake);
+    }
+
+    Ok((encrypt(&enc_key, encrypted_reply), keygen))
+}
+
+/// Answer an ntor v3 handshake as a relay holding the onion keys in
+/// `keys`.
+///
+/// `reply_fn` is called with the client's message, and returns the
+/// message to send back, or None to reject the handshake.
+///
+/// On success, return the reply to send and a key generator.
+#[cfg(test)]
+pub(crate) fn server_handshake_ntor_v3<R, F>(
+    rng: &mut R,
+    reply_fn: F,
+    keys: &[NtorV3SecretKey],
+    message: &[u8],
+    verification: &[u8],
+) -> Result<(Vec<u8>, NtorV3KeyGenerator)>
+where
+    R: RngCore + CryptoRng,
+    F: FnOnce(&[u8]) -> Option<Vec<u8>>,
+{
+    let y = curve25519::StaticSecret::new(rng.rng_compat());
+    server_handshake_ntor_v3_no_keygen(reply_fn, keys, message, verification, &y)
+}
+
+/// Helper: as server_handshake_ntor_v3, but with a given ephemeral key.
+#[cfg(test)]
+fn server_handshake_ntor_v3_no_keygen<F>(
+    reply_fn: F,
+    keys: &[NtorV3SecretKey],
+    message: &[u8],
+    verification: &[u8],
+    y: &curve25519::StaticSecret,
+) -> Result<(Vec<u8>, NtorV3KeyGenerator)>
+where
+    F: FnOnce(&[u8]) -> Option<Vec<u8>>,
+{
+    let mut r = Reader::from_slice(message);
+    let id: Ed25519Identity = r.extract()?;
+    let B: curve25519::PublicKey = r.extract()?;
+    let X: curve25519::PublicKey = r.extract()?;
+    let rest = r.into_rest();
+    if rest.len() < 32 {
+        return Err(Error::BadHandshake);
+    }
+    let (encrypted_msg, msg_mac) = rest.split_at(rest.len() - 32);
+
+    let keypair = ct::lookup(keys, |key| key.matches_pk(&B)).ok_or(Error::MissingKey)?;
+    if id != keypair.pk.id {
+        return Err(Error::MissingKey);
+    }
+
+    let xb = keypair.sk.diffie_hellman(&X);
+    let (enc_key, mac_key) = phase1_keys(&xb, &keypair.pk, &X, verification);
+    let expected_mac = client_msg_mac(&mac_key, &keypair.pk, &X, encrypted_msg);
+    if !ct::bytes_eq(msg_mac, &expected_mac[..]) {
+        return Err(Error::BadHandshake);
+    }
+    let client_msg = Zeroizing::new(encrypt(&enc_key, encrypted_msg));
+    let reply_msg = reply_fn(&client_msg[..]).ok_or(Error::BadHandshake)?;
+
+    let Y = curve25519::PublicKey::from(y);
+    let xy = y.diffie_hellman(&X);
+    let (keygen, reply_key, verify) = final_keys(&xy, &xb, &keypair.pk, &X, &Y, verification);
+    let encrypted_reply = encrypt(&reply_key, &reply_msg[..]);
+    let auth = reply_auth(&verify, &keypair.pk, &X, &Y, msg_mac, &encrypted_reply[..]);
+
+    let mut reply = Vec::new();
+    reply.write(&Y);
+    reply.write_all(&auth[..]);
+    reply.write_all(&encrypted_reply[..]);
+    Ok((reply, keygen))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::handshake::ClientHandshake;
+    use hex_literal::hex;
+
+    fn example_keys() -> (NtorV3PublicKey, NtorV3SecretKey) {
+        let sk: curve25519::StaticSecret =
+            hex!("4051daa5921cfa2a1c27b08451324919538e79e788a81b38cbed097a5dff454a").into();
+        let pk = curve25519::PublicKey::from(&sk);
+        let id: Ed25519Identity = [9_u8; 32].into();
+        let secret = NtorV3SecretKey::new(sk, pk, id);
+        (secret.pk.clone(), secret)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut rng = rand::thread_rng();
+        let (public, secret) = example_keys();
+        let verification = &b"shared secret"[..];
+
+        let (state, cmsg) = client_handshake_ntor_v3(&mut rng, &public, b"hello", verification);
+        let (smsg, s_keygen) = server_handshake_ntor_v3(
+            &mut rng,
+            |msg| {
+                assert_eq!(msg, b"hello");
+                Some(b"goodbye".to_vec())
+            },
+            &[secret],
+            &cmsg[..],
+            verification,
+        )
+        .unwrap();
+        let (reply, c_keygen) =
+            client_handshake_ntor_v3_part2(&state, &smsg[..], verification).unwrap();
+        assert_eq!(&reply[..], b"goodbye");
+
+        let c_keys = c_keygen.expand(100).unwrap();
+        let s_keys = s_keygen.expand(100).unwrap();
+        assert_eq!(c_keys, s_keys);
+    }
+
+    #[test]
+    fn testvec() {
+        // Test vectors from proposal 332.
+        let b: curve25519::StaticSecret =
+            hex!("4051daa5921cfa2a1c27b08451324919538e79e788a81b38cbed097a5dff454a").into();
+        let x: curve25519::StaticSecret =
+            hex!("b825a3719147bcbe5fb1d0b0fcb9c09e51948048e2e3283d2ab7b45b5ef38b49").into();
+        let y: curve25519::StaticSecret =
+            hex!("4865a5b7689dafd978f529291c7171bc159be076b92186405d13220b80e2a053").into();
+        let B = curve25519::PublicKey::from(&b);
+        assert_eq!(
+            B.as_bytes(),
+            &hex!("f8307a2bc1870b00b828bb74dbb8fd88e632a6375ab3bcd1ae706aaa8b6cdd1d")
+        );
+        let id: Ed25519Identity =
+            hex!("9fad2af287ef942632833d21f946c6260c33fae6172b60006e86e4a6911753a2").into();
+        let client_message = hex!("68656c6c6f20776f726c64");
+        let verification = hex!("78797a7a79");
+        let server_message = hex!("486f6c61204d756e646f");
+
+        let secret = NtorV3SecretKey::new(b, B, id);
+        let public = secret.pk.clone();
+
+        let (state, client_handshake) =
+            client_handshake_ntor_v3_no_keygen(&public, &client_message, &verification, x);
+        assert_eq!(
+            client_handshake[..],
+            hex!(
+                "9fad2af287ef942632833d21f946c6260c33fae6172b60006e86e4a6911753a2
+                 f8307a2bc1870b00b828bb74dbb8fd88e632a6375ab3bcd1ae706aaa8b6cdd1d
+                 252fe9ae91264c91d4ecb8501f79d0387e34ad8ca0f7c995184f7d11d5da4f46
+                 3bebd9151fd3b47c180abc
+                 9e044d53565f04d82bbb3bebed3d06cea65db8be9c72b68cd461942088502f67"
+            )[..]
+        );
+
+        let (server_handshake, s_keygen) = server_handshake_ntor_v3_no_keygen(
+            |msg| {
+                assert_eq!(msg, &client_message[..]);
+                Some(server_message.to_vec())
+            },
+            &[secret],
+            &client_handshake[..],
+            &verification,
+            &y,
+        )
+        .unwrap();
+        assert_eq!(
+            server_handshake[..],
+            hex!(
+                "4bf4814326fdab45ad5184f5518bd7fae25dc59374062698201a50a22954246d
+                 2fc5f8773ca824542bc6cf6f57c7c29bbf4e5476461ab130c5b18ab0a9127665
+                 1202c3e1e87c0d32054c"
+            )[..]
+        );
+
+        let (reply, c_keygen) =
+            client_handshake_ntor_v3_part2(&state, &server_handshake[..], &verification).unwrap();
+        assert_eq!(reply[..], server_message[..]);
+
+        let expected_keys = hex!(
+            "9c19b631fd94ed86a817e01f6c80b0743a43f5faebd39cfaa8b00fa8bcc65c3b
+             feaa403d91acbd68a821bf6ee8504602b094a254392a07737d5662768c7a9fb1
+             b2814bb34780eaee6e867c773e28c212ead563e98a1cd5d5b4576f5ee61c59bd
+             e025ff2851bb19b721421694f263818e3531e43a9e4e3e2c661e2ad547d8984c
+             aa28ebecd3e4525452299be26b9185a20a90ce1eac20a91f2832d731b54502b0
+             9749b5a2a2949292f8cfcbeffb790c7790ed935a9d251e7e336148ea83b063a5
+             618fcff674a44581585fd22077ca0e52c59a24347a38d1a1ceebddbf238541f2
+             26b8f88d0fb9c07a1bcd2ea764bbbb5dacdaf5312a14c0b9e4f06309b0333b4a"
+        );
+        let c_keys = c_keygen.expand(expected_keys.len()).unwrap();
+        let s_keys = s_keygen.expand(expected_keys.len()).unwrap();
+        assert_eq!(c_keys[..], expected_keys[..]);
+        assert_eq!(s_keys[..], expected_keys[..]);
+    }
+
+    #[test]
+    fn failing_handshakes() {
+        let mut rng = rand::thread_rng();
+        let (public, secret) = example_keys();
+        let keys = [secret];
+        let v = NTOR3_CIRC_VERIFICATION;
+        let reply_fn = |_: &[u8]| Some(Vec::new());
+
+        // Wrong verification string.
+        let (_, cmsg) = client_handshake_ntor_v3(&mut rng, &public, b"", b"other");
+        let r = server_handshake_ntor_v3(&mut rng, reply_fn, &keys, &cmsg[..], v);
+        assert!(matches!(r, Err(Error::BadHandshake)));
+
+        // Wrong identity.
+        let mut wrong_public = public.clone();
+        wrong_public.id = [10_u8; 32].into();
+        let (_, cmsg) = client_handshake_ntor_v3(&mut rng, &wrong_public, b"", v);
+        let r = server_handshake_ntor_v3(&mut rng, reply_fn, &keys, &cmsg[..], v);
+        assert!(matches!(r, Err(Error::MissingKey)));
+
+        // Tampered client message.
+        let (_, mut cmsg) = client_handshake_ntor_v3(&mut rng, &public, b"xyz", v);
+        cmsg[97] ^= 1;
+        let r = server_handshake_ntor_v3(&mut rng, reply_fn, &keys, &cmsg[..], v);
+        assert!(matches!(r, Err(Error::BadHandshake)));
+
+        // The relay refuses.
+        let (_, cmsg) = client_handshake_ntor_v3(&mut rng, &public, b"", v);
+        let r = server_handshake_ntor_v3(&mut rng, |_| None, &keys, &cmsg[..], v);
+        assert!(matches!(r, Err(Error::BadHandshake)));
+
+        // Tampered relay reply.
+        let (state, cmsg) = client_handshake_ntor_v3(&mut rng, &public, b"", v);
+        let (mut smsg, _) =
+            server_handshake_ntor_v3(&mut rng, |_| Some(b"ok".to_vec()), &keys, &cmsg[..], v)
+                .unwrap();
+        smsg[64] ^= 1;
+        let r = client_handshake_ntor_v3_part2(&state, &smsg[..], v);
+        assert!(matches!(r, Err(Error::BadHandshake)));
+    }
+
+    #[test]
+    fn extensions() {
+        let exts = vec![
+            NtorV3Extension::congestion_control_request(),
+            NtorV3Extension::new(7, b"seven".to_vec()),
+        ];
+        let encoded = encode_extensions(&exts[..]).unwrap();
+        assert_eq!(&encoded[..], &hex!("02 0100 0705 736576656e")[..]);
+        assert_eq!(decode_extensions(&encoded[..]).unwrap(), exts);
+        assert!(decode_extensions(&[]).unwrap().is_empty());
+        assert!(decode_extensions(&hex!("02 0100")[..]).is_err());
+        assert!(decode_extensions(&hex!("00 01")[..]).is_err());
+        assert!(encode_extensions(&[NtorV3Extension::new(1, vec![0; 256])]).is_err());
+
+        assert_eq!(exts[0].congestion_control_sendme_inc(), None);
+        assert!(!exts[0].is_congestion_control_response());
+        let cc = NtorV3Extension::new(CC_RESPONSE, vec![31]);
+        assert!(cc.is_congestion_control_response());
+        assert_eq!(cc.congestion_control_sendme_inc(), Some(31));
+        let cc = NtorV3Extension::new(CC_RESPONSE, vec![0]);
+        assert_eq!(cc.congestion_control_sendme_inc(), None);
+    }
+
+    #[test]
+    fn client_with_extensions() {
+        let mut rng = rand::thread_rng();
+        let (public, secret) = example_keys();
+        let keys = [secret];
+        let request = [NtorV3Extension::new(7, b"seven".to_vec())];
+        let handshake = |rng: &mut rand::rngs::ThreadRng, reply: Vec<NtorV3Extension>| {
+            let (state, cmsg) = NtorV3Client::client1(rng, &public, &request[..]).unwrap();
+            let (smsg, _) = server_handshake_ntor_v3(
+                rng,
+                |msg| {
+                    assert_eq!(decode_extensions(msg).unwrap(), request);
+                    encode_extensions(&reply[..]).ok()
+                },
+                &keys,
+                &cmsg[..],
+                NTOR3_CIRC_VERIFICATION,
+            )
+            .unwrap();
+            NtorV3Client::client2(state, smsg)
+        };
+
+        let answer = NtorV3Extension::new(8, b"eight".to_vec());
+        let (reply, _) = handshake(&mut rng, vec![answer.clone()]).unwrap();
+        assert_eq!(reply, vec![answer]);
+
+        // A relay can turn on congestion control...
+        let cc = NtorV3Extension::new(CC_RESPONSE, vec![31]);
+        let (reply, _) = handshake(&mut rng, vec![cc.clone()]).unwrap();
+        assert_eq!(reply, vec![cc]);
+
+        // ... but if we can't understand its answer, the handshake fails.
+        for body in [vec![], vec![0], vec![31, 32]] {
+            let cc = NtorV3Extension::new(CC_RESPONSE, body);
+            let r = handshake(&mut rng, vec![cc]);
+            assert!(matches!(r, Err(Error::CircProto(_))));
+        }
+    }
+}
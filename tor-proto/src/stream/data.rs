@@ -36,6 +36,13 @@ pub struct DataStream {
 ///
 /// Note that this implementation writes Tor cells lazily, so it is essential to
 /// flush the stream when you need the data to do out right away.
+///
+/// A DataWriter buffers at most one cell's worth of data.  Once that
+/// buffer is full, writes return `Poll::Pending` until the cell has been
+/// sent, which can take a while if the stream's send window is exhausted.
+/// Code that reads from some other source to feed a DataWriter can use
+/// [`DataWriter::is_send_blocked`] to find out when it should stop
+/// reading.
 pub struct DataWriter {
     /// Internal state for this writer
     ///
@@ -43,6 +50,11 @@ pub struct DataWriter {
     /// AsyncWrite functions.  It might be possible to do better here,
     /// and we should refactor if so.
     state: Option<DataWriterState>,
+    /// The underlying RawCellStream object.
+    ///
+    /// We keep a reference here as well as in `state`, since `state`
+    /// doesn't have one we can look at while we're flushing.
+    s: Arc<RawCellStream>,
 }
 
 /// Wrapper for the Read part of a DataStream
@@ -70,10 +82,11 @@ impl DataStream {
         };
         let w = DataWriter {
             state: Some(DataWriterState::Ready(DataWriterImpl {
-                s,
+                s: Arc::clone(&s),
                 buf: Box::new([0; Data::MAXLEN]),
                 n_pending: 0,
             })),
+            s,
         };
         DataStream { w, r }
     }
@@ -82,6 +95,22 @@ impl DataStream {
     pub fn split(self) -> (DataReader, DataWriter) {
         (self.r, self.w)
     }
+
+    /// Return true if writes to this stream are waiting for room in its
+    /// send window.
+    ///
+    /// See [`DataWriter::is_send_blocked`].
+    pub fn is_send_blocked(&self) -> bool {
+        self.w.is_send_blocked()
+    }
+
+    /// Return the number of bytes that we could write to this stream
+    /// right now without waiting for room in its send window.
+    ///
+    /// See [`DataWriter::send_headroom`].
+    pub async fn send_headroom(&self) -> usize {
+        self.w.send_headroom().await
+    }
 }
 
 impl AsyncRead for DataStream {
@@ -140,6 +169,26 @@ struct DataWriterImpl {
 }
 
 impl DataWriter {
+    /// Return true if writes to this stream are waiting for room in its
+    /// send window.
+    ///
+    /// While this is true, the other side hasn't acknowledged enough of
+    /// our data for us to send any more, and writes will return
+    /// `Poll::Pending` once our one-cell buffer is full.
+    pub fn is_send_blocked(&self) -> bool {
+        self.s.is_send_blocked()
+    }
+
+    /// Return the number of bytes that we could write to this stream
+    /// right now without waiting for room in its send window.
+    ///
+    /// This counts a full cell's worth of data for each cell left in
+    /// the window.  It's only a snapshot, and it ignores the circuit's
+    /// send window, which can still make a write wait.
+    pub async fn send_headroom(&self) -> usize {
+        usize::from(self.s.send_headroom().await) * Data::MAXLEN
+    }
+
     /// Helper for poll_flush() and poll_close(): Performs a flush, then
     /// closes the stream if should_close is true.
     fn poll_flush_impl(
@@ -159,7 +208,7 @@ impl DataWriter {
                     return Poll::Ready(Ok(()));
                 }
 
-                Box::pin(imp.flush_buf())
+                Box::pin(imp.flush_buf(should_close))
             }
             DataWriterState::Flushing(fut) => fut,
             DataWriterState::Closed => {
@@ -209,7 +258,7 @@ impl AsyncWrite for DataWriter {
                     return Poll::Ready(Ok(n_queued));
                 }
                 // we couldn't queue anything, so the current cell must be full.
-                Box::pin(imp.flush_buf())
+                Box::pin(imp.flush_buf(false))
             }
             DataWriterState::Flushing(fut) => fut,
             DataWriterState::Closed => {
@@ -248,11 +297,20 @@ impl AsyncWrite for DataWriter {
 
 impl DataWriterImpl {
     /// Try to flush the current buffer contents as a data cell.
-    async fn flush_buf(mut self) -> (Self, Result<()>) {
+    ///
+    /// If `closing` is true, this is the last cell we'll send on this
+    /// stream, so we send it ahead of any ordinary data that is waiting
+    /// for room in a send window: that way, a stream that's shutting
+    /// down doesn't have to wait behind bulk transfers.
+    async fn flush_buf(mut self, closing: bool) -> (Self, Result<()>) {
         let result = if self.n_pending != 0 {
             let cell = Data::new(&self.buf[..self.n_pending]);
             self.n_pending = 0;
-            self.s.send(cell.into()).await
+            if closing {
+                self.s.send_control(cell.into()).await
+            } else {
+                self.s.send(cell.into()).await
+            }
         } else {
             Ok(())
         };
@@ -24,15 +24,23 @@ pub struct RawCellStream {
     /// Have we been informed that this stream is closed, or received a fatal
     /// error?
     stream_ended: AtomicBool,
+    /// A handle to the stream's send window.
+    ///
+    /// We keep this outside of `target` so that we can tell whether a
+    /// send is waiting for room in the window while that send holds the
+    /// lock on `target`.
+    send_window: sendme::StreamSendWindow,
 }
 
 impl RawCellStream {
     /// Internal: build a new RawCellStream.
     pub(crate) fn new(target: StreamTarget, receiver: mpsc::Receiver<RelayMsg>) -> Self {
+        let send_window = target.send_window_ref();
         RawCellStream {
             target: Mutex::new(target),
             receiver: Mutex::new(receiver),
             stream_ended: AtomicBool::new(false),
+            send_window,
         }
     }
 
@@ -54,6 +62,9 @@ impl RawCellStream {
         // send a SENDME if doing so took us under the threshold.
         if sendme::msg_counts_towards_windows(&msg) {
             let mut target = self.target.lock().await;
+            if let RelayMsg::Data(d) = &msg {
+                target.note_data_received(d.as_ref().len());
+            }
             if target.recvwindow.take()? {
                 self.send_sendme(&mut target).await?;
             }
@@ -80,6 +91,40 @@ impl RawCellStream {
         self.target.lock().await.send(msg).await
     }
 
+    /// Send a relay message along this stream, ahead of any ordinary
+    /// messages that are waiting for room in a send window.
+    ///
+    /// Use this only for messages that the stream needs to keep working
+    /// or to shut down cleanly.
+    pub(crate) async fn send_control(&self, msg: RelayMsg) -> Result<()> {
+        self.target.lock().await.send_control(msg).await
+    }
+
+    /// Send as many of `msgs` as this stream's send window has room for
+    /// right now, in order, without waiting for the other side to
+    /// acknowledge our earlier cells.
+    ///
+    /// Return the number of messages that we sent.
+    pub async fn send_burst(&self, msgs: Vec<RelayMsg>) -> Result<usize> {
+        self.target.lock().await.send_burst(msgs).await
+    }
+
+    /// Return true if a send on this stream is waiting for the other side
+    /// to acknowledge our earlier cells with a SENDME.
+    pub fn is_send_blocked(&self) -> bool {
+        self.send_window.is_blocked()
+    }
+
+    /// Return the number of DATA cells that we could send on this stream
+    /// right now without waiting for the other side to acknowledge our
+    /// earlier cells.
+    ///
+    /// This is only a snapshot, and it only covers the stream's own send
+    /// window: the circuit's window can still make us wait.
+    pub async fn send_headroom(&self) -> u16 {
+        self.send_window.headroom().await
+    }
+
     /// Return true if this stream is marked as having ended.
     pub fn has_ended(&self) -> bool {
         self.stream_ended.load(Ordering::SeqCst)
@@ -99,8 +144,8 @@ impl RawCellStream {
     /// Send a SENDME cell and adjust the receive window.
     async fn send_sendme(&self, target: &mut StreamTarget) -> Result<()> {
         let sendme = Sendme::new_empty();
-        target.send(sendme.into()).await?;
-        target.recvwindow.put();
+        target.send_control(sendme.into()).await?;
+        target.recvwindow.put()?;
         Ok(())
     }
 
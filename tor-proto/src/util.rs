@@ -1,4 +1,5 @@
 //! Utilities used for the tor protocol.
 
+pub(crate) mod bytebudget;
 pub(crate) mod ct;
 pub(crate) mod err;
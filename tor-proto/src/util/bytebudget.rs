@@ -0,0 +1,153 @@
+//! A cap on the number of bytes that something may hold at once.
+//!
+//! This is the accounting behind [`SendWindow`](crate::circuit::sendme::SendWindow)'s
+//! byte budget, and is meant for any other buffer that needs a cap, so
+//! that every capped buffer behaves the same way.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A count of bytes in use, and the cap that it must stay under.
+///
+/// Any number of tasks can share a `ByteBudget` (for example, in an
+/// `Arc`): every operation is atomic.
+#[derive(Debug)]
+pub(crate) struct ByteBudget {
+    /// The largest number of bytes that may be in use at once.
+    cap: usize,
+    /// The number of bytes in use.
+    used: AtomicUsize,
+}
+
+impl ByteBudget {
+    /// Construct a new ByteBudget with nothing in use, that allows up to
+    /// `cap` bytes.
+    pub(crate) fn new(cap: usize) -> Self {
+        ByteBudget {
+            cap,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the largest number of bytes that may be in use at once.
+    pub(crate) fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// Return the number of bytes in use.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Try to put `n` more bytes in use.
+    ///
+    /// Return true on success, and false (changing nothing) if that would
+    /// exceed the cap.  If nothing is in use, we always succeed, even if
+    /// `n` is over the cap: otherwise a single large item could never get
+    /// through at all.
+    pub(crate) fn try_consume(&self, n: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                if used > 0 && used.saturating_add(n) > self.cap {
+                    None
+                } else {
+                    Some(used.saturating_add(n))
+                }
+            })
+            .is_ok()
+    }
+
+    /// Note that `n` bytes that we consumed are no longer in use.
+    ///
+    /// Releasing more than is in use is a bug; we release everything
+    /// instead of underflowing.
+    pub(crate) fn release(&self, n: usize) {
+        let _ignore = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                debug_assert!(n <= used, "released more bytes than were in use");
+                Some(used.saturating_sub(n))
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn consume_and_release() {
+        let b = ByteBudget::new(1000);
+        assert_eq!(b.cap(), 1000);
+        assert!(b.try_consume(400));
+        assert!(b.try_consume(600));
+        assert_eq!(b.used(), 1000);
+
+        // Full: even one more byte is too much.
+        assert!(!b.try_consume(1));
+        assert_eq!(b.used(), 1000);
+
+        b.release(500);
+        assert!(!b.try_consume(501));
+        assert!(b.try_consume(500));
+        b.release(1000);
+        assert_eq!(b.used(), 0);
+    }
+
+    #[test]
+    fn oversized() {
+        let b = ByteBudget::new(100);
+        // With nothing in use, anything fits...
+        assert!(b.try_consume(5000));
+        assert_eq!(b.used(), 5000);
+        // ... but then nothing else does.
+        assert!(!b.try_consume(1));
+        b.release(5000);
+        assert!(b.try_consume(1));
+
+        // Huge values saturate instead of wrapping.
+        let b = ByteBudget::new(100);
+        assert!(b.try_consume(usize::MAX));
+        assert!(!b.try_consume(usize::MAX));
+        assert_eq!(b.used(), usize::MAX);
+    }
+
+    #[test]
+    fn concurrent() {
+        let b = Arc::new(ByteBudget::new(10_000));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let b = Arc::clone(&b);
+                std::thread::spawn(move || {
+                    let mut n_consumed = 0_usize;
+                    for _ in 0..1000 {
+                        if b.try_consume(10) {
+                            n_consumed += 1;
+                            assert!(b.used() <= 10_000);
+                        }
+                    }
+                    n_consumed
+                })
+            })
+            .collect();
+        let total: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        // Exactly the cap's worth of consumptions succeeded.
+        assert_eq!(total, 1000);
+        assert_eq!(b.used(), 10_000);
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let b = Arc::clone(&b);
+                std::thread::spawn(move || {
+                    for _ in 0..125 {
+                        b.release(10);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(b.used(), 0);
+    }
+}
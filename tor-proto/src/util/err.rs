@@ -55,6 +55,9 @@ pub enum Error {
     /// Circuit is closed.
     #[error("circuit closed")]
     CircuitClosed,
+    /// Circuit is closed, because its reactor stopped with an error.
+    #[error("circuit closed: {0}")]
+    ReactorFailed(#[source] Box<Error>),
     /// Can't allocate any more circuit or stream IDs on a channel.
     #[error("too many entries in map: can't allocate ID")]
     IdRangeFull,
@@ -76,6 +79,10 @@ pub enum Error {
     /// Channel does not match target
     #[error("channel mismatch: {0}")]
     ChanMismatch(String),
+    /// Tried to extend a circuit to a relay that conflicts with one of
+    /// its existing hops.
+    #[error("conflicting hop: {0}")]
+    HopConflict(String),
     /// Tried to configure an impossible value
     #[error("bad configuration value: {0}")]
     BadConfig(String),
@@ -112,12 +119,16 @@ impl From<Error> for std::io::Error {
 
             EndReceived(end_reason) => end_reason.into(),
 
-            CircDestroy(_) | ChannelClosed | CircuitClosed => ErrorKind::ConnectionReset,
+            CircDestroy(_) | ChannelClosed | CircuitClosed | ReactorFailed(_) => {
+                ErrorKind::ConnectionReset
+            }
 
             BytesErr(_) | MissingKey | BadCellAuth | BadHandshake | ChanProto(_) | CircProto(_)
             | CellErr(_) | ChanMismatch(_) | StreamProto(_) => ErrorKind::InvalidData,
 
             InternalError(_) | IdRangeFull | CircExtend(_) | BadConfig(_) => ErrorKind::Other,
+
+            HopConflict(_) => ErrorKind::InvalidInput,
         };
         std::io::Error::new(kind, err)
     }
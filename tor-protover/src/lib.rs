@@ -229,6 +229,34 @@ impl Protocols {
         }
     }
 
+    /// Check whether every protocol version in `required` is supported.
+    ///
+    /// ```
+    /// use tor_protover::*;
+    /// let protos: Protocols = "Link=1-5 Relay=1-3 Foobar=7".parse().unwrap();
+    ///
+    /// assert!(protos.supports_all(&"Link=4-5 Foobar=7".parse().unwrap()));
+    /// assert!(protos.supports_all(&"".parse().unwrap()));
+    /// assert!(! protos.supports_all(&"Link=4-6".parse().unwrap()));
+    /// assert!(! protos.supports_all(&"Wombat=1".parse().unwrap()));
+    /// ```
+    pub fn supports_all(&self, required: &Protocols) -> bool {
+        let recognized_ok = self
+            .recognized
+            .iter()
+            .zip(required.recognized.iter())
+            .all(|(have, need)| need & !have == 0);
+        recognized_ok
+            && required.unrecognized.iter().all(|need| {
+                let have = self
+                    .unrecognized
+                    .iter()
+                    .find(|ent| ent.proto == need.proto)
+                    .map_or(0, |ent| ent.supported);
+                need.supported & !have == 0
+            })
+    }
+
     /// Parsing helper: Try to add a new entry `ent` to this set of protocols.
     ///
     /// Uses `foundmask`, a bit mask saying which recognized protocols
@@ -581,4 +609,20 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_supports_all() -> Result<(), ParseError> {
+        let p: Protocols = "Link=4,5-7 Padding=2 Lonk=1-3,5".parse()?;
+        let t = |s: &str| -> Result<bool, ParseError> { Ok(p.supports_all(&s.parse()?)) };
+
+        assert!(t("")?);
+        assert!(t("Link=5-6")?);
+        assert!(t("Link=4-7 Padding=2 Lonk=5")?);
+        assert!(!t("Link=3-5")?);
+        assert!(!t("Link=5 Cons=1")?);
+        assert!(!t("Lonk=4")?);
+        assert!(!t("Zelda=1")?);
+
+        Ok(())
+    }
 }